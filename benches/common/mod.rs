@@ -0,0 +1,57 @@
+//! The MRF generator and timer the benchmarks share.
+
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Number of times each command reads the file, the fastest run counts. The commands compared
+/// run in turn, so that what the page cache holds of the file favours neither.
+pub const RUNS: usize = 3;
+
+/// Writes an MRF with items in_network items, each with a few rates for a few provider groups.
+/// Returns the size of the file.
+pub fn write_mrf(path: &Path, items: usize) -> std::io::Result<u64> {
+    let mut out = BufWriter::new(std::fs::File::create(path)?);
+    write!(out, r#"{{"reporting_entity_name":"Bench Health Plan","reporting_entity_type":"Third Party Vendor","last_updated_on":"2025-01-01","version":"1.3.1","provider_references":["#)?;
+    for group in 0..1000 {
+        if group > 0 {
+            write!(out, ",")?;
+        }
+        write!(out, r#"{{"provider_group_id":{},"provider_groups":[{{"npi":[{},{}],"tin":{{"type":"ein","value":"{:09}"}}}}]}}"#,
+               group, 1_000_000_000 + group, 1_500_000_000 + group, group)?;
+    }
+    write!(out, r#"],"in_network":["#)?;
+    for item in 0..items {
+        if item > 0 {
+            write!(out, ",")?;
+        }
+        write!(out, r#"{{"negotiation_arrangement":"ffs","name":"Office visit \"level {}\"","billing_code_type":"CPT","billing_code_type_version":"2025","billing_code":"{:05}","description":"Office or other outpatient visit for the evaluation and management of an established patient","negotiated_rates":["#,
+               item % 5, item % 90_000 + 10_000)?;
+        for rate in 0..4 {
+            if rate > 0 {
+                write!(out, ",")?;
+            }
+            write!(out, r#"{{"provider_references":[{},{}],"negotiated_prices":[{{"negotiated_type":"negotiated","negotiated_rate":{}.{:02},"expiration_date":"9999-12-31","service_code":["11","22"],"billing_class":"professional"}}]}}"#,
+                   (item + rate) % 1000, (item * 7 + rate) % 1000, 50 + item % 400, rate * 17)?;
+        }
+        write!(out, "]}}")?;
+    }
+    write!(out, "]}}")?;
+    out.flush()?;
+    Ok(std::fs::metadata(path)?.len())
+}
+
+/// Returns how long the mrfy at bin takes with args and path, output dropped.
+pub fn run(bin: &Path, args: &[&str], path: &Path) -> Duration {
+    let start = Instant::now();
+    let status = Command::new(bin)
+        .args(args)
+        .arg(path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .expect("failed to run mrfy");
+    assert!(status.code().is_some_and(|c| c <= 3), "{} {:?} failed", bin.display(), args);
+    start.elapsed()
+}
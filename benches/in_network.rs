@@ -0,0 +1,45 @@
+//! Times a query that matches most of a multi-GB generated MRF, so the time goes to reading
+//! in_network objects and their negotiated_rates rather than to skipping them.
+//!
+//! `cargo bench --bench in_network`. MRFY_BENCH_MIB sets the size of the file (default 2048,
+//! written uncompressed to the temp directory). To compare with another build, e.g. the last
+//! release, set MRFY_BENCH_BASELINE to the path of its mrfy binary.
+
+mod common;
+
+use std::path::{Path, PathBuf};
+
+/// Every provider and every code.
+const QUERY: [&str; 4] = ["--npi", "*", "--code", "*:*"];
+
+fn main() -> std::io::Result<()> {
+    let mib: u64 = std::env::var("MRFY_BENCH_MIB").ok().and_then(|n| n.parse().ok()).unwrap_or(2048);
+    let path = std::env::temp_dir().join(format!("mrfy_bench_in_network_{}.json", std::process::id()));
+
+    // Sizes the file from a small one, the items are about the same size
+    let sample = common::write_mrf(&path, 1000)? - common::write_mrf(&path, 0)?;
+    let items = (mib * 1024 * 1024 / (sample / 1000).max(1)) as usize;
+    let size = common::write_mrf(&path, items)?;
+    let mib = size as f64 / (1024.0 * 1024.0);
+    println!("{} in_network items, {:.1} MiB", items, mib);
+
+    let mut bins = vec![("current", PathBuf::from(env!("CARGO_BIN_EXE_mrfy")))];
+    if let Some(baseline) = std::env::var_os("MRFY_BENCH_BASELINE") {
+        bins.push(("baseline", PathBuf::from(baseline)));
+    }
+    let mut fastest = vec![std::time::Duration::MAX; bins.len()];
+    for _ in 0..common::RUNS {
+        for (i, (_, bin)) in bins.iter().enumerate() {
+            fastest[i] = fastest[i].min(common::run(Path::new(bin), &QUERY, &path));
+        }
+    }
+
+    let mut current = None;
+    for ((name, _), elapsed) in bins.iter().zip(fastest) {
+        let speedup = current.map_or(String::new(), |c: std::time::Duration| format!(", current is {:.2}x faster", elapsed.as_secs_f64() / c.as_secs_f64()));
+        println!("{:>8}: {:>7.3}s, {:>6.1} MiB/s{}", name, elapsed.as_secs_f64(), mib / elapsed.as_secs_f64(), speedup);
+        current.get_or_insert(elapsed);
+    }
+
+    std::fs::remove_file(&path)
+}
@@ -0,0 +1,35 @@
+//! Compares the events and simd parsers (`--parser`) on a generated MRF.
+//!
+//! `cargo bench --features simd --bench parser`. The file is written uncompressed, so the time
+//! is mostly parsing. MRFY_BENCH_ITEMS sets the number of in_network items (default 200000).
+
+mod common;
+
+use std::path::Path;
+use std::time::Duration;
+
+fn main() -> std::io::Result<()> {
+    let items = std::env::var("MRFY_BENCH_ITEMS").ok().and_then(|n| n.parse().ok()).unwrap_or(200_000);
+    let path = std::env::temp_dir().join(format!("mrfy_bench_{}.json", std::process::id()));
+    let size = common::write_mrf(&path, items)?;
+    let mib = size as f64 / (1024.0 * 1024.0);
+    println!("{} in_network items, {:.1} MiB", items, mib);
+
+    let parsers = ["events", "simd"];
+    let mut fastest = [Duration::MAX; 2];
+    for _ in 0..common::RUNS {
+        for (i, parser) in parsers.iter().enumerate() {
+            let args = ["--npi", "1000000001", "--code", "CPT:10001", "--parser", parser];
+            fastest[i] = fastest[i].min(common::run(Path::new(env!("CARGO_BIN_EXE_mrfy")), &args, &path));
+        }
+    }
+
+    let mut baseline = None;
+    for (parser, elapsed) in parsers.iter().zip(fastest) {
+        let speedup = baseline.map_or(String::new(), |b: Duration| format!(", {:.2}x", b.as_secs_f64() / elapsed.as_secs_f64()));
+        println!("{:>6}: {:>7.3}s, {:>6.1} MiB/s{}", parser, elapsed.as_secs_f64(), mib / elapsed.as_secs_f64(), speedup);
+        baseline.get_or_insert(elapsed);
+    }
+
+    std::fs::remove_file(&path)
+}
@@ -0,0 +1,332 @@
+//! # anonymize
+//!
+//! Rewrites an MRF with its NPIs and TINs scrambled and its rates perturbed (`mrfy anonymize`),
+//! so a file that shows a bug can be attached to a report without giving away who is paid what.
+//!
+//! Everything else is copied as it is, so the structure of the file, its codes, group ids and
+//! the keys mrfy doesn't support are kept. The same NPI is scrambled to the same NPI throughout
+//! the file (and no two NPIs to the same one), so queries still find a provider in both
+//! provider_references and in_network, and a valid NPI stays valid (and an invalid one invalid).
+//! TINs keep their length and punctuation. Each rate is multiplied by a random factor within
+//! spread of 1 and keeps its number of decimals, so rates keep about the same distribution.
+//!
+//! The scrambling is keyed by a seed: the same seed gives the same file, which is handy for
+//! fixtures, and without knowing it the original values can't be worked out. It is obfuscation
+//! for sharing test files, not a privacy guarantee.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{BufReader, Write};
+use std::path::Path;
+
+use json_event_parser::{JsonEvent, ReaderJsonParser, WriterJsonSerializer};
+
+use crate::asa::{create_output, next_event, unexpected_eof, CancelToken, ParseContext, SharedReporter};
+use crate::error::Interrupted;
+use crate::query::is_valid_npi;
+use crate::source::DataSource;
+
+/// How to anonymize.
+#[derive(Clone, Debug)]
+pub struct AnonymizeOptions {
+    /// Key of the scrambling, the same seed gives the same output
+    pub seed: u64,
+    /// Largest relative change of a rate, e.g. 0.1 for up to 10% up or down
+    pub spread: f64,
+    /// Stops the rewrite when cancelled
+    pub cancel: CancelToken,
+}
+
+impl Default for AnonymizeOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AnonymizeOptions {
+    /// Creates the default AnonymizeOptions, with a seed from the clock.
+    pub fn new() -> Self {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        Self { seed: nanos ^ (std::process::id() as u64) << 32, spread: 0.1, cancel: CancelToken::new() }
+    }
+}
+
+/// Counts of what was rewritten.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AnonymizeSummary {
+    pub npis: u64,
+    pub tins: u64,
+    pub rates: u64,
+}
+
+/// Keyed scrambling of the values of one file.
+struct Scrambler {
+    seed: u64,
+    spread: f64,
+    /// Random state for the rate factors
+    state: u64,
+    npis: HashMap<u64, u64>,
+    used: HashSet<u64>,
+}
+
+impl Scrambler {
+    fn new(options: &AnonymizeOptions) -> Self {
+        Self {
+            seed: options.seed,
+            spread: options.spread,
+            state: options.seed,
+            npis: HashMap::new(),
+            used: HashSet::new(),
+        }
+    }
+
+    /// Returns a hash of value keyed by the seed.
+    fn hash<T: Hash + ?Sized>(&self, value: &T, salt: u64) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.seed.hash(&mut hasher);
+        salt.hash(&mut hasher);
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Returns the next random number from 0 to 1 (splitmix64).
+    fn next_unit(&mut self) -> f64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Returns the scrambled npi, the same for the same npi. A valid NPI becomes another valid
+    /// NPI, anything else gets random digits of the same length.
+    fn npi(&mut self, npi: u64) -> u64 {
+        if let Some(scrambled) = self.npis.get(&npi) {
+            return *scrambled;
+        }
+        let mut salt = 0;
+        let scrambled = loop {
+            let h = self.hash(&npi, salt);
+            salt += 1;
+            let candidate = match is_valid_npi(npi) {
+                true => {
+                    // NPIs start with 1 or 2, the last digit is the check digit
+                    let body = (1 + h % 2) * 100_000_000 + (h / 2) % 100_000_000;
+                    match (0..10).map(|d| body * 10 + d).find(|n| is_valid_npi(*n)) {
+                        Some(n) => n,
+                        None => continue,
+                    }
+                }
+                false => match scramble_digits(&npi.to_string(), h).parse() {
+                    Ok(n) => n,
+                    Err(_) => continue,
+                },
+            };
+            // Short invalid npis may run out of unused values
+            if (candidate != npi && self.used.insert(candidate)) || salt > 64 {
+                break candidate;
+            }
+        };
+        self.npis.insert(npi, scrambled);
+        scrambled
+    }
+
+    /// Returns the scrambled tin value. A TIN that is a valid NPI is scrambled like the npis.
+    fn tin(&mut self, value: &str) -> String {
+        match value.parse::<u64>() {
+            Ok(npi) if is_valid_npi(npi) && value.len() == 10 => self.npi(npi).to_string(),
+            _ => scramble_digits(value, self.hash(value, u64::MAX)),
+        }
+    }
+
+    /// Returns rate times a random factor within spread of 1, with as many decimals as rate.
+    fn rate(&mut self, rate: &str) -> Option<String> {
+        let value: f64 = rate.parse().ok()?;
+        let decimals = rate.split_once('.').map_or(0, |(_, fraction)| {
+            fraction.chars().take_while(|c| c.is_ascii_digit()).count()
+        });
+        let factor = 1.0 + self.spread * (2.0 * self.next_unit() - 1.0);
+        Some(format!("{:.*}", decimals, (value * factor).max(0.0)))
+    }
+}
+
+/// Returns s with each digit replaced by one derived from h, other characters kept. A leading
+/// digit stays non-zero.
+fn scramble_digits(s: &str, h: u64) -> String {
+    let mut state = h;
+    s.chars().enumerate().map(|(i, c)| {
+        if !c.is_ascii_digit() {
+            return c;
+        }
+        state = state.rotate_left(7).wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ i as u64;
+        let digit = match i {
+            0 => 1 + (state >> 32) % 9,
+            _ => (state >> 32) % 10,
+        };
+        char::from(b'0' + digit as u8)
+    }).collect()
+}
+
+/// What a value is, from the keys around it.
+#[derive(Clone, Copy, PartialEq)]
+enum Field {
+    Npi,
+    TinValue,
+    Rate,
+    Other,
+}
+
+/// Reads source and writes it anonymized to out.
+pub fn anonymize(source: &dyn DataSource,
+                 buff_size: usize,
+                 out: impl Write,
+                 options: &AnonymizeOptions) -> Result<AnonymizeSummary, Box<dyn std::error::Error>> {
+    let mut ctx = ParseContext::new();
+    let reader = ctx.track_offsets(BufReader::with_capacity(buff_size, source.open(&SharedReporter::default())?));
+    let mut parser = ReaderJsonParser::new(reader);
+    let mut json = WriterJsonSerializer::new(out);
+    let mut scrambler = Scrambler::new(options);
+    let mut summary = AnonymizeSummary::default();
+
+    // Key of each open object or array (empty at the top), and whether it is an array
+    let mut containers: Vec<(String, bool)> = Vec::new();
+    let mut key = String::new();
+
+    loop {
+        if options.cancel.is_cancelled() {
+            return Err(Box::new(Interrupted { objects: 0, records: 0 }));
+        }
+        let event = next_event(&mut parser, &mut ctx, "anonymize")?;
+
+        let field = match containers.last() {
+            Some((array_key, true)) if array_key == "npi" => Field::Npi,
+            Some((object_key, false)) if object_key == "tin" && key == "value" => Field::TinValue,
+            Some((_, false)) if key == "negotiated_rate" => Field::Rate,
+            _ => Field::Other,
+        };
+
+        match event {
+            JsonEvent::StartObject | JsonEvent::StartArray => {
+                let is_array = matches!(event, JsonEvent::StartArray);
+                // An element of an array takes the key of the array
+                let opened_by = match containers.last() {
+                    Some((array_key, true)) => array_key.clone(),
+                    _ => std::mem::take(&mut key),
+                };
+                containers.push((opened_by, is_array));
+                json.serialize_event(event)?;
+            }
+            JsonEvent::EndObject | JsonEvent::EndArray => {
+                containers.pop();
+                json.serialize_event(event)?;
+                if containers.is_empty() {
+                    break;
+                }
+            }
+            JsonEvent::ObjectKey(k) => {
+                key.clear();
+                key.push_str(&k);
+                json.serialize_event(JsonEvent::ObjectKey(k))?;
+            }
+            JsonEvent::Number(n) if field == Field::Npi && n.parse::<u64>().is_ok() => {
+                let npi = scrambler.npi(n.parse()?).to_string();
+                summary.npis += 1;
+                json.serialize_event(JsonEvent::Number(npi.as_str().into()))?;
+            }
+            JsonEvent::String(s) if field == Field::Npi && s.parse::<u64>().is_ok() => {
+                let npi = scrambler.npi(s.parse()?).to_string();
+                summary.npis += 1;
+                json.serialize_event(JsonEvent::String(npi.as_str().into()))?;
+            }
+            JsonEvent::String(s) if field == Field::TinValue => {
+                let tin = scrambler.tin(&s);
+                summary.tins += 1;
+                json.serialize_event(JsonEvent::String(tin.as_str().into()))?;
+            }
+            JsonEvent::Number(n) if field == Field::Rate => {
+                match scrambler.rate(&n) {
+                    Some(rate) => {
+                        summary.rates += 1;
+                        json.serialize_event(JsonEvent::Number(rate.as_str().into()))?;
+                    }
+                    None => json.serialize_event(JsonEvent::Number(n))?,
+                }
+            }
+            JsonEvent::Eof => {
+                return Err(Box::new(unexpected_eof(&ctx, "anonymize")));
+            }
+            event => json.serialize_event(event)?,
+        }
+    }
+
+    json.finish()?.flush()?;
+    Ok(summary)
+}
+
+/// Anonymizes source to the file at path, compressed with gzip if path ends in .gz.
+pub fn anonymize_to(source: &dyn DataSource,
+                    buff_size: usize,
+                    path: &Path,
+                    options: &AnonymizeOptions) -> Result<AnonymizeSummary, Box<dyn std::error::Error>> {
+    anonymize(source, buff_size, create_output(path)?, options)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asa::{self, Options};
+    use crate::query::{Code, Provider, Query};
+    use crate::sink::MemorySink;
+    use crate::source::Location;
+
+    #[test]
+    fn test_anonymize() {
+        let source = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz"));
+        let mut options = AnonymizeOptions::new();
+        options.seed = 7;
+        let mut out = Vec::new();
+        let summary = anonymize(&source, 256, &mut out, &options).unwrap();
+        assert!(summary.npis > 0 && summary.tins > 0 && summary.rates > 0);
+
+        // Same seed, same file
+        let mut again = Vec::new();
+        anonymize(&source, 256, &mut again, &options).unwrap();
+        assert_eq!(out, again);
+
+        let mut scrambler = Scrambler::new(&options);
+        let npi = scrambler.npi(4);
+        assert_ne!(npi, 4);
+        assert_eq!(scrambler.npi(4), npi);
+        assert!(is_valid_npi(scrambler.npi(1_234_567_893)));
+        assert_eq!(scrambler.tin("12-3456789").len(), 10);
+        assert_eq!(scrambler.tin("12-3456789").as_bytes()[2], b'-');
+
+        // The scrambled provider still has the rows of the original, with other rates
+        let query = |npi: u64| {
+            let mut q = Query::new();
+            q.providers.push(Provider::new(npi));
+            q.codes.push(Code::new(&String::from("*"), &String::from("Code 3")));
+            q
+        };
+        let mut original = MemorySink::new();
+        asa::run(&mut query(4), &source, 256, &Options::new(), &mut original).unwrap();
+
+        let path = std::env::temp_dir().join(format!("mrfy_anonymize_{}.json", std::process::id()));
+        std::fs::write(&path, &out).unwrap();
+        let mut anonymized = MemorySink::new();
+        asa::run(&mut query(npi), &Location::Path(path.clone()), 256, &Options::new(), &mut anonymized).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(!original.records.is_empty());
+        assert_eq!(anonymized.records.len(), original.records.len());
+        let column = original.header.iter().position(|c| c == "negotiated_rate").unwrap();
+        for (a, o) in anonymized.records.iter().zip(original.records.iter()) {
+            let (a, o): (f64, f64) = (a[column].parse().unwrap(), o[column].parse().unwrap());
+            assert!((a - o).abs() <= o * 0.1 + 0.01);
+        }
+    }
+}
@@ -1,15 +1,16 @@
 //! # asa
 //!
-//! Stream parses gz compressed JSON mrf file for Aetna Signature Administrators.
+//! Stream parses gz (or zstd) compressed JSON mrf file for Aetna Signature Administrators.
 //!
 //! Prints data that matches query to stdout. (Might allow user choice in future.)
 //!
 //! Will report unsupported keys encountered in JSON file. 
 //!
-//! Will return Error to main upon any and all fatal errors.
+//! Will return Error to main upon any and all fatal errors. Problems with the data file are
+//! reported as a ParseError giving the part of the JSON and the byte offset.
 //!
 //! The program assumes the following basic format of the JSON
-//! ```
+//! ```json
 //! {
 //!    "reporting_entity_name": "Aetna Signature Administrators",
 //!    "reporting_entity_type": "Third Party Vendor",
@@ -52,26 +53,957 @@
 
 
 #![allow(non_camel_case_types)] // TODO remove when done
-use crate::query::{Query, Provider};
+use crate::refmap::RefMap;
+use crate::dedupe::RowSet;
+use crate::explain::{Explain, Reason};
+use crate::filter::Filter;
+use crate::compress::Codec;
+use crate::minrates::MinRates;
+use crate::nppes::{self, Nppes};
+use crate::descriptions::{self, CodeDescriptions};
+use crate::schema::{self, SchemaVersion};
+use crate::stats::{RateSummary, write_groups_csv};
+use crate::rate::{RateFormat, is_canonical_rate, normalize_rate, round_rate};
+use crate::query::{Completion, Query, QueryReport, Member, MatchPolicy, normalize_code, normalize_code_type};
+use crate::parser::{Backend, JsonEvents, JsonParser, ParseFailure};
+use crate::error::{Interrupted, ParseError, Stale, EXIT_MATCHES, EXIT_NO_MATCHES, EXIT_DATA_ISSUES};
 //use crate::error::{NonFatalError}; // TODO remove non-fatal errors?
 
-use std::fs::File;
+use crate::source::{BuffSize, CountingReader, DataSource, TimedReader};
+use crate::sink::{MatchedRecord, RecordSink, TimedSink};
+use crate::clock::Stopwatch;
+use std::rc::Rc;
+use std::borrow::Cow;
+
 use csv::Writer;
 use std::io::{BufReader, Read, Write};
-use std::collections::{HashSet, HashMap};
+use std::collections::BTreeMap;
 
-use std::cell::RefCell; 
+use std::cell::Cell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-use flate2::read::GzDecoder;
-use json_event_parser::{ReaderJsonParser, JsonEvent};
+use json_event_parser::{JsonEvent, WriterJsonSerializer};
 
+#[cfg(feature = "cli")]
 use indicatif::{ProgressBar};
 
-// Used to track keys in the JSON that we didn't expect
-// User will not be told if the key happens in more than one object type.
-// The notification will happen only where the key is first discovered. 
-thread_local! {
-    static UNSUPPORTED_KEYS: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+/// State of the parse of a file, handed to next_event and the process_* functions. A run (or a
+/// scan by another module) starts with a new one, so nothing carries over from one run to the
+/// next, even one that failed part way.
+#[derive(Default)]
+pub(crate) struct ParseContext {
+    /// Decompressed bytes handed to the parser in the current pass, for the offsets of
+    /// ParseErrors (see track_offsets)
+    bytes_parsed: Rc<Cell<u64>>,
+    /// Nesting depth of the parser ('{' and '[' not yet closed), maintained by next_event.
+    /// Lets --skip-corrupt find the end of an element after a helper bailed out part way through.
+    depth: u64,
+    /// Events of the element being read, kept for --raw-out and extract. None when nothing is
+    /// being captured, next_event appends to it otherwise.
+    raw_events: Option<Vec<JsonEvent<'static>>>,
+    /// Set by --strict, the first unsupported key aborts the run instead of being skipped
+    strict: bool,
+    /// Schema version declared by the file being read, if it declared one mrfy supports so far
+    schema: Option<SchemaVersion>,
+    /// Maps (key, object type) of the keys we didn't expect to the number of times the key was
+    /// seen there. The warning is printed only the first time a key is seen, the counts go in
+    /// the report at exit.
+    unsupported_keys: BTreeMap<(String, &'static str), u64>,
+    /// npis given as JSON strings instead of numbers in the provider_references pass being read.
+    /// Reset by process_provider_refs.
+    string_npis: u64,
+    /// negotiated_rates rewritten as canonical decimals, and those that are not numbers
+    fixed_rates: u64,
+    invalid_rates: u64,
+    /// Progress callback of the run, if any
+    progress: Option<ProgressSink>,
+    /// Receives the status messages and warnings of the parse (see Options::reporter)
+    reporter: SharedReporter,
+}
+
+impl ParseContext {
+    /// Creates the context of a new parse.
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wraps the reader given to a parser so its offset can be reported in errors.
+    /// Also resets the nesting depth, so call this once for each new parser.
+    pub(crate) fn track_offsets<R: Read>(&mut self, reader: R) -> CountingReader<R> {
+        let (reader, parsed) = CountingReader::new(reader);
+        self.bytes_parsed = parsed;
+        self.depth = 0;
+        reader
+    }
+
+    /// Returns the approximate offset of the parser in the decompressed JSON.
+    pub(crate) fn offset(&self) -> u64 {
+        self.bytes_parsed.get()
+    }
+
+    /// Returns the unsupported keys found so far, sorted by key then object type.
+    fn unsupported_keys(&self) -> Vec<UnsupportedKey> {
+        self.unsupported_keys.iter().map(|((key, context), count)| {
+            UnsupportedKey { key: key.clone(), context, count: *count }
+        }).collect()
+    }
+}
+
+/// An unsupported key, the object type it was found in, and how many times.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnsupportedKey {
+    pub key: String,
+    pub context: &'static str,
+    pub count: u64,
+}
+
+/// Writes the unsupported keys found by a run (see RunSummary) to path.
+/// Uses JSON if path ends in .json, otherwise CSV with columns key,context,count.
+pub fn write_key_report(path: &std::path::Path,
+                        keys: &[UnsupportedKey]) -> Result<(), Box<dyn std::error::Error>> {
+    let file = std::fs::File::create(path)?;
+
+    if path.extension().is_some_and(|e| e == "json") {
+        let mut json = WriterJsonSerializer::new(std::io::BufWriter::new(file));
+        json.serialize_event(JsonEvent::StartArray)?;
+        for k in keys.iter() {
+            let count = k.count.to_string();
+            json.serialize_event(JsonEvent::StartObject)?;
+            json.serialize_event(JsonEvent::ObjectKey("key".into()))?;
+            json.serialize_event(JsonEvent::String(k.key.as_str().into()))?;
+            json.serialize_event(JsonEvent::ObjectKey("context".into()))?;
+            json.serialize_event(JsonEvent::String(k.context.into()))?;
+            json.serialize_event(JsonEvent::ObjectKey("count".into()))?;
+            json.serialize_event(JsonEvent::Number(count.as_str().into()))?;
+            json.serialize_event(JsonEvent::EndObject)?;
+        }
+        json.serialize_event(JsonEvent::EndArray)?;
+        json.finish()?.flush()?;
+    }
+    else {
+        let mut writer = Writer::from_writer(file);
+        writer.write_record(["key", "context", "count"])?;
+        for k in keys.iter() {
+            writer.write_record([k.key.as_str(), k.context, &k.count.to_string()])?;
+        }
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Stores the schema version declared by the version field of the file, warning if mrfy
+/// doesn't fully support it.
+fn set_schema_version(ctx: &mut ParseContext, version: &str) {
+    let schema = SchemaVersion::parse(version);
+    if schema.is_none() {
+        ctx.reporter.warn(format_args!("schema version {} is not one mrfy fully supports ({}), reading it as 1.x",
+                          version, schema::SUPPORTED));
+    }
+    ctx.schema = schema;
+}
+
+/// Records an unsupported key found in context, warning the first time the key is seen.
+/// In strict mode returns an error naming the key and where it was found instead.
+/// Keys the declared schema version defines (see SchemaVersion::defines_key) are skipped
+/// without being recorded.
+fn unsupported_key(ctx: &mut ParseContext, key: &str, context: &'static str) -> Result<(), ParseError> {
+    if ctx.schema.is_some_and(|schema| schema.defines_key(context, key)) {
+        return Ok(());
+    }
+    if ctx.strict {
+        return Err(ParseError::UnsupportedKey { key: String::from(key),
+                                                context,
+                                                offset: ctx.offset() });
+    }
+
+    if !ctx.unsupported_keys.keys().any(|(k, _)| k == key) {
+        ctx.reporter.warn(format_args!("unsupported key {} found in {}", key, context));
+    }
+    *ctx.unsupported_keys.entry((String::from(key), context)).or_insert(0) += 1;
+
+    Ok(())
+}
+
+/// Rewrites a negotiated_rate that is a number but not a canonical decimal ("$1,234.56", 1.5e3)
+/// as one, counting it. Rates that are not numbers are counted and left as they are.
+/// The first of each is warned about (fixed rates only with RateFormat::Warn).
+/// With RateFormat::Fail either returns an error instead.
+fn fix_rate(ctx: &mut ParseContext, rate: &mut String, format: RateFormat) -> Result<(), ParseError> {
+    if rate.is_empty() || is_canonical_rate(rate) {
+        return Ok(());
+    }
+    let fixed = normalize_rate(rate);
+    if fixed.as_ref() == Some(rate) {
+        return Ok(());
+    }
+    if format == RateFormat::Fail {
+        return Err(ParseError::InvalidRate { context: "negotiated_prices",
+                                             offset: ctx.offset(),
+                                             value: rate.clone() });
+    }
+
+    match fixed {
+        Some(fixed) => {
+            if format == RateFormat::Warn && ctx.fixed_rates == 0 {
+                ctx.reporter.warn(format_args!("negotiated_rate '{}' written as {}", rate, fixed));
+            }
+            ctx.fixed_rates += 1;
+            *rate = fixed;
+        }
+        None => {
+            if ctx.invalid_rates == 0 {
+                ctx.reporter.warn(format_args!("negotiated_rate '{}' is not a number", rate));
+            }
+            ctx.invalid_rates += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the next event from parser, converting failures to a ParseError for context.
+pub(crate) fn next_event<'a, P: JsonEvents>(parser: &'a mut P,
+                                            ctx: &mut ParseContext,
+                                            context: &'static str,
+                                           ) -> Result<JsonEvent<'a>, ParseError> {
+    match parser.next_json_event() {
+        Ok(event) => {
+            match event {
+                JsonEvent::StartObject | JsonEvent::StartArray => ctx.depth += 1,
+                JsonEvent::EndObject | JsonEvent::EndArray => ctx.depth = ctx.depth.saturating_sub(1),
+                _ => {}
+            }
+            if let Some(events) = ctx.raw_events.as_mut() {
+                events.push(to_owned_event(&event));
+            }
+            Ok(event)
+        }
+        Err(ParseFailure::Io(e)) => Err(ParseError::Io(e)),
+        Err(ParseFailure::Syntax { offset, message }) => Err(ParseError::Syntax { context, offset, message }),
+    }
+}
+
+/// Copies an event so it can outlive the parser buffer.
+fn to_owned_event(event: &JsonEvent) -> JsonEvent<'static> {
+    match event {
+        JsonEvent::String(s) => JsonEvent::String(Cow::Owned(s.to_string())),
+        JsonEvent::Number(n) => JsonEvent::Number(Cow::Owned(n.to_string())),
+        JsonEvent::ObjectKey(k) => JsonEvent::ObjectKey(Cow::Owned(k.to_string())),
+        JsonEvent::Boolean(b) => JsonEvent::Boolean(*b),
+        JsonEvent::Null => JsonEvent::Null,
+        JsonEvent::StartArray => JsonEvent::StartArray,
+        JsonEvent::EndArray => JsonEvent::EndArray,
+        JsonEvent::StartObject => JsonEvent::StartObject,
+        JsonEvent::EndObject => JsonEvent::EndObject,
+        JsonEvent::Eof => JsonEvent::Eof,
+    }
+}
+
+/// Starts recording events for --raw-out. The opening event has already been read so it is
+/// added here.
+fn start_raw_capture(ctx: &mut ParseContext, first: JsonEvent<'static>) {
+    ctx.raw_events = Some(vec![first]);
+}
+
+/// Stops recording events and returns what was captured.
+fn stop_raw_capture(ctx: &mut ParseContext) -> Option<Vec<JsonEvent<'static>>> {
+    ctx.raw_events.take()
+}
+
+/// Writes the captured events of one element to out as a single line of JSON.
+fn write_raw_element(out: &mut impl Write,
+                     events: Vec<JsonEvent<'static>>,
+                    ) -> Result<(), std::io::Error> {
+    let mut json = WriterJsonSerializer::new(&mut *out);
+    for event in events {
+        json.serialize_event(event)?;
+    }
+    json.finish()?;
+    out.write_all(b"\n")
+}
+
+/// Drops the parts of a captured in_network element that don't apply to the query.
+/// Rates without a provider reference in ref_map are removed, as are the references within
+/// a kept rate that aren't in ref_map. Everything else is copied unchanged.
+fn filter_element(events: &[JsonEvent<'static>],
+                  ref_map: &RefMap,
+                 ) -> Vec<JsonEvent<'static>> {
+    let mut filtered = Vec::with_capacity(events.len());
+
+    // Events of the rate being read and whether it has a matching reference
+    let mut rate: Vec<JsonEvent<'static>> = Vec::new();
+    let mut rate_matches = false;
+
+    let mut depth = 0;
+    let mut key: Option<&str> = None;
+    let mut in_rates = false;
+    let mut in_refs = false;
+
+    for event in events.iter() {
+        match event {
+            JsonEvent::StartObject | JsonEvent::StartArray => {
+                if in_rates && depth == 2 {
+                    rate.clear();
+                    rate_matches = false;
+                }
+                else if depth == 1 && key == Some("negotiated_rates") {
+                    in_rates = true;
+                }
+                else if in_rates && depth == 3 && key == Some("provider_references") {
+                    in_refs = true;
+                }
+                depth += 1;
+            }
+            JsonEvent::EndObject | JsonEvent::EndArray => {
+                depth -= 1;
+                if in_rates && depth == 2 {
+                    rate.push(event.clone());
+                    if rate_matches {
+                        filtered.append(&mut rate);
+                    }
+                    continue;
+                }
+                else if in_rates && depth == 1 {
+                    in_rates = false;
+                }
+                else if in_refs && depth == 3 {
+                    in_refs = false;
+                }
+            }
+            JsonEvent::ObjectKey(k) => {
+                key = Some(k.as_ref());
+            }
+            JsonEvent::String(r) | JsonEvent::Number(r) if in_refs && depth == 4 => {
+                if !ref_map.contains_key(r.as_ref()) {
+                    continue;
+                }
+                rate_matches = true;
+            }
+            _ => {}
+        }
+
+        if in_rates && depth > 2 {
+            rate.push(event.clone());
+        }
+        else {
+            filtered.push(event.clone());
+        }
+    }
+
+    filtered
+}
+
+/// Writes a smaller MRF holding only the parts of the file that apply to the query.
+/// Used by `mrfy extract`. Elements are written as they are read and the metadata as soon as it
+/// is known, so only the current element is held in memory.
+struct Extract {
+    json: WriterJsonSerializer<Box<dyn Write>>,
+    started: bool,
+    sections: Vec<&'static str>,
+    meta_written: Vec<&'static str>,
+}
+
+impl Extract {
+    /// Creates an Extract that writes to out.
+    fn new(out: Box<dyn Write>) -> Self {
+        Self {
+            json: WriterJsonSerializer::new(out),
+            started: false,
+            sections: Vec::new(),
+            meta_written: Vec::new(),
+        }
+    }
+
+    /// Writes the opening brace on first use, then any metadata not yet written.
+    fn write_meta(&mut self, meta: &Meta) -> Result<(), std::io::Error> {
+        if !self.started {
+            self.json.serialize_event(JsonEvent::StartObject)?;
+            self.started = true;
+        }
+        for (key, value) in meta.fields() {
+            if let Some(value) = value && !self.meta_written.contains(&key) {
+                self.json.serialize_event(JsonEvent::ObjectKey(key.into()))?;
+                self.json.serialize_event(JsonEvent::String(value.as_str().into()))?;
+                self.meta_written.push(key);
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts the array for section. Returns false if the section was already written,
+    /// which happens on the second pass after a reset.
+    fn begin_section(&mut self, name: &'static str, meta: &Meta) -> Result<bool, std::io::Error> {
+        if self.sections.contains(&name) {
+            return Ok(false);
+        }
+        self.write_meta(meta)?;
+        self.json.serialize_event(JsonEvent::ObjectKey(name.into()))?;
+        self.json.serialize_event(JsonEvent::StartArray)?;
+        self.sections.push(name);
+        Ok(true)
+    }
+
+    /// Closes the array started by begin_section.
+    fn end_section(&mut self) -> Result<(), std::io::Error> {
+        self.json.serialize_event(JsonEvent::EndArray)
+    }
+
+    /// Writes one element of the current section.
+    fn write_element(&mut self, events: Vec<JsonEvent<'static>>) -> Result<(), std::io::Error> {
+        for event in events {
+            self.json.serialize_event(event)?;
+        }
+        Ok(())
+    }
+
+    /// Closes the file. Sections that were never reached are written as empty arrays so the
+    /// output is still a valid MRF.
+    fn finish(mut self, meta: &Meta) -> Result<(), std::io::Error> {
+        for name in ["provider_references", "in_network"] {
+            if self.begin_section(name, meta)? {
+                self.end_section()?;
+            }
+        }
+        self.write_meta(meta)?;
+        self.json.serialize_event(JsonEvent::EndObject)?;
+        self.json.finish()?.flush()
+    }
+}
+
+/// Where process_in_network copies the matched in_network elements: the raw_out file and the
+/// Extract of `mrfy extract`, either of them optional.
+#[derive(Default)]
+struct ElementCopies<'a> {
+    raw_out: Option<&'a mut Box<dyn Write>>,
+    extract: Option<&'a mut Extract>,
+}
+
+/// Creates an output file, compressed with gzip if path ends in .gz
+/// (or zstd for .zst with the zstd feature).
+pub(crate) fn create_output(path: &std::path::Path) -> Result<Box<dyn Write>, std::io::Error> {
+    create_output_with(path, None)
+}
+
+/// Creates an output file compressed with codec, or by the extension of path without one (see
+/// create_output).
+pub(crate) fn create_output_with(path: &std::path::Path, codec: Option<Codec>) -> Result<Box<dyn Write>, std::io::Error> {
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    codec.unwrap_or_else(|| Codec::for_path(path)).wrap(file)
+}
+
+/// Builds the error for an event that should not occur in context.
+fn unexpected(ctx: &ParseContext, context: &'static str, event: &JsonEvent) -> ParseError {
+    let found = match event {
+        JsonEvent::String(s) => format!("string \"{}\"", s),
+        JsonEvent::Number(n) => format!("number {}", n),
+        JsonEvent::Boolean(b) => format!("boolean {}", b),
+        JsonEvent::Null => String::from("null"),
+        JsonEvent::StartArray => String::from("'['"),
+        JsonEvent::EndArray => String::from("']'"),
+        JsonEvent::StartObject => String::from("'{'"),
+        JsonEvent::EndObject => String::from("'}'"),
+        JsonEvent::ObjectKey(k) => format!("key \"{}\"", k),
+        JsonEvent::Eof => String::from("end of file"),
+    };
+    ParseError::Unexpected { context, offset: ctx.offset(), found }
+}
+
+/// Builds the error for reaching the end of the file in context.
+pub(crate) fn unexpected_eof(ctx: &ParseContext, context: &'static str) -> ParseError {
+    ParseError::UnexpectedEof { context, offset: ctx.offset() }
+}
+
+/// Returns true for errors caused by a malformed element, which --skip-corrupt can skip past.
+fn is_recoverable(err: &(dyn std::error::Error + 'static)) -> bool {
+    matches!(err.downcast_ref::<ParseError>(),
+             Some(ParseError::Syntax { .. }) |
+             Some(ParseError::Unexpected { .. }) |
+             Some(ParseError::InvalidNpi { .. }))
+}
+
+/// Logs the error for a corrupt in_network element, then reads events until the element has
+/// been closed so processing can resume at the next element.
+/// base_depth is the depth at which the in_network key was found (the array is one deeper).
+fn skip_corrupt_element<R: Read>(parser: &mut JsonParser<R>,
+                                 ctx: &mut ParseContext,
+                                 base_depth: u64,
+                                 err: Box<dyn std::error::Error>,
+                                ) -> Result<(), Box<dyn std::error::Error>> {
+    ctx.reporter.warn(format_args!("skipping corrupt in_network element: {}", err));
+
+    // Give up if the parser can't get past the damage
+    const MAX_ERRORS: u32 = 1000;
+    let mut errors = 0;
+
+    while ctx.depth > base_depth + 1 {
+        match next_event(parser, ctx, "corrupt in_network element") {
+            Ok(JsonEvent::Eof) => {
+                return Err(Box::new(unexpected_eof(ctx, "corrupt in_network element")));
+            }
+            Ok(_) => {}
+            Err(ParseError::Syntax { .. }) if errors < MAX_ERRORS => {
+                errors += 1;
+            }
+            Err(e) => {
+                return Err(Box::new(e));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Behaviour switches for a run, set from the command line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    /// Log and skip in_network elements that fail to parse instead of aborting the run
+    pub skip_corrupt: bool,
+    /// Abort on the first unsupported key rather than skipping it
+    pub strict: bool,
+    /// Keep unsupported keys in negotiated_prices and write them to an extra column as JSON
+    pub capture_extra: bool,
+    /// Write the original JSON of every matched in_network element to this file, one per line
+    pub raw_out: Option<std::path::PathBuf>,
+    /// Write a filtered MRF holding only the provider_references and in_network items that
+    /// apply to the query to this file
+    pub extract: Option<std::path::PathBuf>,
+    /// Keep only negotiated_prices with one of these service codes (all of them if empty)
+    pub service_codes: Vec<String>,
+    /// Drop negotiated_prices that expired before this (year, month, day)
+    pub as_of: Option<Date>,
+    /// Skip items under another billing_code_type_version than the one their code is pinned to,
+    /// instead of only counting them
+    pub exclude_version_mismatch: bool,
+    /// Keep only negotiated_prices with one of these billing classes (all of them if empty)
+    pub billing_classes: Vec<String>,
+    /// Keep only negotiated_prices with a negotiated_rate of at least this
+    pub min_rate: Option<f64>,
+    /// Keep only negotiated_prices with a negotiated_rate of at most this
+    pub max_rate: Option<f64>,
+    /// How query codes are compared with the codes of the file (case and wildcards)
+    pub match_policy: MatchPolicy,
+    /// Keep the npi and tin strings of each provider group in a temporary file instead of in
+    /// memory, for queries with very many providers (see refmap)
+    pub disk_ref_map: bool,
+    /// Skip output rows identical to a row already written (see dedupe)
+    pub dedupe: bool,
+    /// Normalize billing codes and their types in the query and in the file before matching them
+    /// (see query::normalize_code). The normalized values are written to the output.
+    pub normalize_codes: bool,
+    /// Write one row per service code of a price instead of joining them in one column
+    pub explode_service_codes: bool,
+    /// Write one row per billing code modifier of a price instead of joining them in one column
+    pub explode_modifiers: bool,
+    /// Put between the values of multi-valued fields (service_code, billing_code_modifier)
+    /// joined in one column
+    pub list_delimiter: String,
+    /// What to do with negotiated_rates that are not plain decimal numbers (see rate)
+    pub rate_format: RateFormat,
+    /// Write negotiated_rates with this many decimals (see rate::round_rate)
+    pub rate_precision: Option<usize>,
+    /// Don't write the CSV header
+    pub no_header: bool,
+    /// (column, name) pairs, to write name in the header instead of column
+    pub header_map: Vec<(String, String)>,
+    /// Write only the row with the lowest negotiated_rate of each billing code and npi, at the
+    /// end of the run (see minrates)
+    pub min_per_code: bool,
+    /// Write count, min, percentiles, mean and max of the rates matched for each billing code
+    /// to this file as CSV
+    pub rate_summary: Option<std::path::PathBuf>,
+    /// Percentiles (0 to 100) of the rate summary
+    pub percentiles: Vec<f64>,
+    /// Add the name, taxonomy and practice address of each npi from this NPPES file (see nppes)
+    pub nppes: Option<std::path::PathBuf>,
+    /// Add the description of each billing code from this lookup file (see descriptions)
+    pub code_descriptions: Option<std::path::PathBuf>,
+    /// Reject files whose last_updated_on is more than this many days ago (see check_freshness)
+    pub max_age_days: Option<u64>,
+    /// Only warn about files older than max_age_days
+    pub warn_stale: bool,
+    /// Stops the run with an Interrupted error when cancelled, output written so far is flushed
+    pub cancel: CancelToken,
+    /// Receives the status messages and warnings of the run, stderr by default
+    pub reporter: SharedReporter,
+    /// Parser to read the datafile with (see parser)
+    pub parser: Backend,
+    /// Stop reading in_network once every code of the query has been recorded for every
+    /// provider (see query::Completion)
+    pub stop_when_complete: bool,
+    /// Stop reading in_network once this many rows matched
+    pub limit: Option<u64>,
+    /// Keep each matched price (of each provider) with this probability, from 0 to 1
+    pub sample: Option<f64>,
+    /// Seed of the random choice of sample, the same seed keeps the same prices
+    pub sample_seed: u64,
+    /// Skip in_network items whose name contains none of these (ignoring ASCII case) as soon as
+    /// the name is read, before billing_code and negotiated_rates (all items if empty)
+    pub name_prefilter: Vec<String>,
+    /// Skip the in_network items before this
+    pub start_offset: Option<Offset>,
+    /// Stop reading in_network at this
+    pub end_offset: Option<Offset>,
+    /// Write each in_network item that gave no rows, with why, to this file as CSV (see explain)
+    pub explain: Option<std::path::PathBuf>,
+    /// Keep only the rows passing this filter (see filter)
+    pub filter: Option<Filter>,
+    /// Compress the output files (raw_out, extract, explain, rate_summary) with this codec
+    /// instead of by their extension (see compress)
+    pub compression: Option<Codec>,
+    /// Stop once provider_references is read, without reading in_network, to check the query
+    /// against the file
+    pub dry_run: bool,
+    /// Keep the rates matched for each billing code and the number of in_network items skipped
+    /// for each reason in the RunStats, e.g. for a report of the run
+    pub run_details: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Options {
+    /// Creates Options with every switch off (the original behaviour).
+    pub fn new() -> Self {
+        Self {
+            skip_corrupt: false,
+            strict: false,
+            capture_extra: false,
+            raw_out: None,
+            extract: None,
+            service_codes: Vec::new(),
+            as_of: None,
+            exclude_version_mismatch: false,
+            billing_classes: Vec::new(),
+            min_rate: None,
+            max_rate: None,
+            match_policy: MatchPolicy::default(),
+            disk_ref_map: false,
+            dedupe: false,
+            normalize_codes: false,
+            explode_service_codes: false,
+            explode_modifiers: false,
+            list_delimiter: String::from(" "),
+            rate_format: RateFormat::default(),
+            rate_precision: None,
+            no_header: false,
+            header_map: Vec::new(),
+            min_per_code: false,
+            rate_summary: None,
+            percentiles: crate::stats::PERCENTILES.to_vec(),
+            nppes: None,
+            code_descriptions: None,
+            max_age_days: None,
+            warn_stale: false,
+            cancel: CancelToken::new(),
+            reporter: SharedReporter::default(),
+            parser: Backend::default(),
+            stop_when_complete: false,
+            limit: None,
+            sample: None,
+            sample_seed: 0,
+            name_prefilter: Vec::new(),
+            start_offset: None,
+            end_offset: None,
+            explain: None,
+            filter: None,
+            dry_run: false,
+            run_details: false,
+            compression: None,
+        }
+    }
+
+    /// Returns these options with the price filters and output options of query added.
+    pub fn with_query(&self, query: &Query) -> Self {
+        let mut options = self.clone();
+        options.capture_extra |= query.capture_extra;
+        options.raw_out = self.raw_out.clone().or(query.raw_out.clone());
+        options.billing_classes.extend(query.billing_classes.iter().cloned());
+        options.min_rate = query.min_rate.or(self.min_rate);
+        options.max_rate = query.max_rate.or(self.max_rate);
+        options
+    }
+
+    /// Returns true if negotiated_prices are filtered by service code, billing class or rate.
+    fn filters_prices(&self) -> bool {
+        !self.service_codes.is_empty() || !self.billing_classes.is_empty() ||
+        self.min_rate.is_some() || self.max_rate.is_some()
+    }
+
+    /// Returns true if price passes the service code, billing class and rate filters.
+    fn keeps_price(&self, price: &Price) -> bool {
+        let service_code = self.service_codes.is_empty() ||
+            price.service_code.split(self.list_delimiter.as_str()).any(|c| self.service_codes.iter().any(|s| s == c));
+        let billing_class = self.billing_classes.is_empty() ||
+            self.billing_classes.iter().any(|b| b.eq_ignore_ascii_case(&price.billing_class));
+        let rate = (self.min_rate.is_none() && self.max_rate.is_none()) ||
+            price.negotiated_rate.parse::<f64>().is_ok_and(|rate| {
+                self.min_rate.is_none_or(|min| rate >= min) && self.max_rate.is_none_or(|max| rate <= max)
+            });
+        service_code && billing_class && rate
+    }
+}
+
+/// Returns true if text contains part, ignoring ASCII case, without allocating.
+fn contains_ignore_ascii_case(text: &str, part: &str) -> bool {
+    part.is_empty() ||
+    text.as_bytes().windows(part.len()).any(|w| w.eq_ignore_ascii_case(part.as_bytes()))
+}
+
+/// A (year, month, day) date, ordered by time.
+pub type Date = (u32, u32, u32);
+
+/// Where a slice of in_network starts or ends (--start-offset, --end-offset). An item belongs
+/// to the slice its start is in, so slices next to each other share no item.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Offset {
+    /// Decompressed bytes from the start of the file, as far as the parser has read (see
+    /// ParseContext::offset), so it depends on the read sizes of the parser and of the buffer
+    Byte(u64),
+    /// Index of an in_network item, from 0
+    Item(u64),
+}
+
+impl Offset {
+    /// Parses item:N, or a number of bytes with an optional K, M or G suffix (see
+    /// source::BuffSize::parse).
+    pub fn parse(s: &str) -> Option<Self> {
+        if let Some(item) = s.strip_prefix("item:") {
+            return item.trim().parse().ok().map(Offset::Item);
+        }
+        match BuffSize::parse(s) {
+            Some(BuffSize::Bytes(bytes)) => Some(Offset::Byte(bytes as u64)),
+            _ => (s.trim() == "0").then_some(Offset::Byte(0)),
+        }
+    }
+
+    /// Returns true if the item with index item, starting at byte offset, is at or after self.
+    fn reached(&self, item: u64, offset: u64) -> bool {
+        match self {
+            Offset::Byte(byte) => offset >= *byte,
+            Offset::Item(index) => item >= *index,
+        }
+    }
+}
+
+/// Parses s as a date in YYYY-MM-DD format. Returns None if it isn't one.
+pub fn parse_date(s: &str) -> Option<Date> {
+    let b = s.as_bytes();
+    if b.len() != 10 || b[4] != b'-' || b[7] != b'-' {
+        return None;
+    }
+    if !b.iter().enumerate().all(|(i, c)| i == 4 || i == 7 || c.is_ascii_digit()) {
+        return None;
+    }
+    let year: u32 = s[0..4].parse().ok()?;
+    let month: u32 = s[5..7].parse().ok()?;
+    let day: u32 = s[8..10].parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    Some((year, month, day))
+}
+
+/// Returns the number of days from 1970-01-01 to date, in the proleptic Gregorian calendar.
+pub(crate) fn days_since_epoch((year, month, day): Date) -> i64 {
+    let (year, month, day) = (year as i64, month as i64, day as i64);
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Returns the number of days from 1970-01-01 to today (UTC).
+fn today() -> i64 {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+    (now.as_secs() / 86_400) as i64
+}
+
+/// Checks the last_updated_on of a file (None if it has none) against options.max_age_days,
+/// with today in days since 1970-01-01. A file more than max_age_days old, or without a date
+/// to check, is stale: returns a Stale error, or only warns with options.warn_stale.
+fn check_freshness(last_updated_on: Option<&str>, options: &Options, today: i64) -> Result<(), Stale> {
+    let Some(max_age_days) = options.max_age_days else {
+        return Ok(());
+    };
+    let age_days = last_updated_on.and_then(parse_date).map(|date| today - days_since_epoch(date));
+    if age_days.is_some_and(|age| age <= max_age_days as i64) {
+        return Ok(());
+    }
+
+    let stale = Stale { last_updated_on: last_updated_on.map(String::from), age_days, max_age_days };
+    if options.warn_stale {
+        options.reporter.warn(format_args!("{}", stale));
+        return Ok(());
+    }
+    Err(stale)
+}
+
+/// Number of in_network objects between two progress reports.
+pub const PROGRESS_INTERVAL: u64 = 100;
+
+/// How far a run has got, see run_with_progress.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Progress {
+    /// Decompressed bytes read so far
+    pub bytes_read: u64,
+    pub objects_scanned: u64,
+    pub objects_matched: u64,
+    pub rows_written: u64,
+    /// True for the last report, once in_network has been read
+    pub finished: bool,
+}
+
+/// Returns a progress callback drawing a bar on stderr, based on an estimate of the number of
+/// in_network objects in an Aetna file. What the bar is based on goes to reporter.
+#[cfg(feature = "cli")]
+pub fn progress_bar(reporter: SharedReporter) -> impl FnMut(&Progress) + 'static {
+    const APPRX_TOTAL_OBJS: u64 = 148400;
+    let mut bar: Option<ProgressBar> = None;
+    move |progress: &Progress| {
+        let bar = bar.get_or_insert_with(|| {
+            reporter.status(format_args!("Progress bar based on estimate of {} total objects", APPRX_TOTAL_OBJS));
+            reporter.status(format_args!("Progress bar will update after every {} objects", PROGRESS_INTERVAL));
+            ProgressBar::new(APPRX_TOTAL_OBJS)
+        });
+        bar.set_position(progress.objects_scanned);
+        if progress.finished {
+            bar.finish_with_message("Done processing in_network.");
+        }
+    }
+}
+
+/// Without the `cli` feature nothing is drawn, a host application reads the progress of a run
+/// with run_with_progress.
+#[cfg(not(feature = "cli"))]
+pub fn progress_bar(_reporter: SharedReporter) -> impl FnMut(&Progress) + 'static {
+    |_: &Progress| {}
+}
+
+/// A progress callback and the decompressed byte counter of its run.
+type ProgressSink = (Box<dyn FnMut(&Progress)>, Rc<Cell<u64>>);
+
+/// Calls the progress callback of the run, if any, with stats.
+fn report_progress(ctx: &mut ParseContext, stats: &RunStats, finished: bool) {
+    if let Some((callback, bytes)) = ctx.progress.as_mut() {
+        callback(&Progress { bytes_read: bytes.get(),
+                             objects_scanned: stats.objects_scanned,
+                             objects_matched: stats.objects_matched,
+                             rows_written: stats.rows_written,
+                             finished });
+    }
+}
+
+/// Kind of a message of a run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    /// What the run is doing, e.g. "Processing in_network..."
+    Status,
+    /// A problem with the data or the query the run carries on despite
+    Warning,
+}
+
+/// Receives the messages of the runs holding it in Options::reporter, e.g. to log them or drop
+/// them when mrfy is embedded in a server. A run may not report from the thread that started it
+/// (remote::run parses on the tokio runtime), hence Send + Sync.
+pub trait Reporter: Send + Sync {
+    fn report(&self, level: Level, message: &str);
+}
+
+/// Prints messages to stderr, warnings prefixed with "WARNING: ". The default reporter.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StderrReporter;
+
+impl Reporter for StderrReporter {
+    fn report(&self, level: Level, message: &str) {
+        match level {
+            Level::Status => eprintln!("{}", message),
+            Level::Warning => eprintln!("WARNING: {}", message),
+        }
+    }
+}
+
+/// Reporter of the runs holding it in Options::reporter, a StderrReporter unless set. Clones
+/// share the reporter.
+#[derive(Clone)]
+pub struct SharedReporter(Arc<dyn Reporter>);
+
+impl SharedReporter {
+    /// Wraps reporter so runs can share it.
+    pub fn new(reporter: impl Reporter + 'static) -> Self {
+        Self(Arc::new(reporter))
+    }
+
+    /// Hands message to the reporter.
+    pub fn report(&self, level: Level, message: std::fmt::Arguments) {
+        self.0.report(level, &message.to_string());
+    }
+
+    /// Reports a status message.
+    pub fn status(&self, message: std::fmt::Arguments) {
+        self.report(Level::Status, message);
+    }
+
+    /// Reports a warning.
+    pub fn warn(&self, message: std::fmt::Arguments) {
+        self.report(Level::Warning, message);
+    }
+}
+
+impl Default for SharedReporter {
+    fn default() -> Self {
+        Self::new(StderrReporter)
+    }
+}
+
+impl std::fmt::Debug for SharedReporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SharedReporter")
+    }
+}
+
+impl PartialEq for SharedReporter {
+    /// Reporters are equal if they are shared.
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// Cancelled (e.g. by a ctrl-C handler or a host application) to ask the runs holding it in
+/// Options::cancel to stop at the next record boundary. Output written so far is flushed and run
+/// returns an Interrupted error. Clones share the flag, so a clone can be cancelled from another
+/// thread.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Creates a token that is not cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks the runs holding this token (or a clone of it) to stop at the next record boundary.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns true once the token is cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl PartialEq for CancelToken {
+    /// Tokens are equal if they share the flag.
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
 }
 
 #[derive(Debug)]
@@ -98,28 +1030,29 @@ impl Meta {
         }
     }
 
-    /// Displays the JSON file metadata by printing to stderr
-    fn e_print(&mut self) {
-        if let Some(s) = &self.reporting_entity_name {
-            eprintln!("reporting_entity_name: {}", s);
-        }
-        if let Some(s) = &self.reporting_entity_type {
-            eprintln!("reporting_entity_type: {}", s); 
-        }
-        if let Some(s) = &self.last_updated_on {
-            eprintln!("last_updated_on: {}", s);
-        }
-        if let Some(s) = &self.version {
-            eprintln!("version: {}", s);
-        }
+    /// Returns the metadata keys and their values.
+    fn fields(&self) -> [(&'static str, &Option<String>); 4] {
+        [("reporting_entity_name", &self.reporting_entity_name),
+         ("reporting_entity_type", &self.reporting_entity_type),
+         ("last_updated_on", &self.last_updated_on),
+         ("version", &self.version)]
     }
 
+    /// Returns the metadata keys that were found with their values.
+    fn found(&self) -> Vec<(&'static str, String)> {
+        self.fields().into_iter()
+            .filter_map(|(key, value)| value.clone().map(|v| (key, v)))
+            .collect()
+    }
+
+    /// Returns true once every metadata field has been set.
+    fn is_complete(&self) -> bool {
+        self.count == self.num_fields
+    }
 
-    /// Gets the metadata value that corresponds to key and adds it to the
-    /// appropriate field in the Meta struct. 
+    /// Stores value in the field named by key.
     /// Panics if given a bad key 
-    /// Prints to stderr when all fields have been added.
-    fn add(&mut self, key: &str, value: &str) -> Result<(), Box<dyn std::error::Error>> {
+    fn set(&mut self, key: &str, value: &str) {
 
         // Use the key to store the value in the Meta struct
         // Panic if the key isn't supported
@@ -143,19 +1076,310 @@ impl Meta {
         else {
             panic!("Assertion broken");
         }
+    }
+
+} // End impl for Meta
 
-        if self.count == self.num_fields {
-            self.e_print();
+/// Outcome of a run, returned by run for the caller to render (see summarize).
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RunSummary {
+    /// Metadata keys found in the file and their values
+    pub metadata: Vec<(&'static str, String)>,
+    pub stats: RunStats,
+    /// Keys that were skipped because they aren't supported
+    pub unsupported_keys: Vec<UnsupportedKey>,
+    /// What matched (and what didn't) of the query
+    pub query: QueryReport,
+    /// True if the run stopped after provider_references because no provider of the query is in
+    /// the file
+    pub no_providers: bool,
+    /// How much of the query and of in_network gave records
+    pub coverage: Coverage,
+}
+
+/// How many of the codes and npis of the query, and of the in_network items read, gave at
+/// least one record, out of how many.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Coverage {
+    pub codes_matched: u64,
+    pub codes: u64,
+    pub npis_matched: u64,
+    pub npis: u64,
+    pub items_matched: u64,
+    pub items: u64,
+}
+
+impl Coverage {
+    /// Creates the coverage of a run from the report of its query and its statistics.
+    fn new(query: &QueryReport, stats: &RunStats) -> Self {
+        Self {
+            codes_matched: query.codes.iter().filter(|c| c.matched).count() as u64,
+            codes: query.codes.len() as u64,
+            npis_matched: query.npis.iter().filter(|n| n.matched).count() as u64,
+            npis: query.npis.len() as u64,
+            items_matched: stats.objects_matched,
+            items: stats.in_network_items,
         }
+    }
 
-        Ok(())
+    /// Fraction of the codes of the query with records, None if it has no codes.
+    pub fn code_coverage(&self) -> Option<f64> {
+        fraction(self.codes_matched, self.codes)
+    }
 
-    } // End add for Meta
+    /// Fraction of the npis of the query with records, None if it has no npis.
+    pub fn npi_coverage(&self) -> Option<f64> {
+        fraction(self.npis_matched, self.npis)
+    }
 
-} // End impl for Meta
+    /// Fraction of the in_network items read that gave records, None if none was read.
+    pub fn item_coverage(&self) -> Option<f64> {
+        fraction(self.items_matched, self.items)
+    }
+
+    /// Hands the coverage to reporter, leaving out what the query has none of.
+    pub fn summarize(&self, reporter: &SharedReporter) {
+        let lines = [("codes covered:", self.codes_matched, self.codes, self.code_coverage()),
+                     ("npis covered:", self.npis_matched, self.npis, self.npi_coverage()),
+                     ("items matched:", self.items_matched, self.items, self.item_coverage())];
+        for (label, part, whole, fraction) in lines {
+            if let Some(fraction) = fraction {
+                reporter.status(format_args!("  {:<19} {} of {} ({:.1}%)", label, part, whole, fraction * 100.0));
+            }
+        }
+    }
+}
+
+/// Returns part / whole, None if whole is 0.
+fn fraction(part: u64, whole: u64) -> Option<f64> {
+    (whole > 0).then(|| part as f64 / whole as f64)
+}
+
+impl RunSummary {
+    /// Hands the metadata, unsupported keys and statistics of the run to reporter.
+    /// Unmatched parts of the query are left to Query::warn_not_recorded.
+    pub fn summarize(&self, reporter: &SharedReporter) {
+        for (key, value) in self.metadata.iter() {
+            reporter.status(format_args!("{}: {}", key, value));
+        }
+        if self.no_providers {
+            reporter.status(format_args!("No providers from query found in file."));
+        }
+        if !self.unsupported_keys.is_empty() {
+            reporter.status(format_args!("Found the following unsupported keys"));
+            for k in self.unsupported_keys.iter() {
+                reporter.status(format_args!("{} (in {}, {} times)", k.key, k.context, k.count));
+            }
+        }
+        self.stats.summarize(reporter);
+        self.coverage.summarize(reporter);
+    }
+
+    /// Returns the exit code for the outcome of the run: EXIT_DATA_ISSUES if items were skipped
+    /// as corrupt or provider groups had no id, else EXIT_NO_MATCHES if no record was written,
+    /// else EXIT_MATCHES.
+    pub fn exit_code(&self) -> i32 {
+        if self.stats.corrupt_skipped > 0 || self.stats.missing_group_ids > 0 {
+            EXIT_DATA_ISSUES
+        }
+        else if self.stats.rows_written == 0 {
+            EXIT_NO_MATCHES
+        }
+        else {
+            EXIT_MATCHES
+        }
+    }
+}
+
+/// End of run statistics, used to tune buffer sizes and compare parser changes.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RunStats {
+    pub elapsed: Duration,
+    pub decompressed_bytes: u64,
+    pub objects_scanned: u64,
+    pub objects_matched: u64,
+    /// in_network items read, including the ones skipped before their billing code
+    pub in_network_items: u64,
+    pub rows_written: u64,
+    pub corrupt_skipped: u64,
+    /// provider_references entries without a provider_group_id
+    pub missing_group_ids: u64,
+    /// Rows not written because they were already written (with --dedupe)
+    pub duplicates_skipped: u64,
+    /// npis in provider_references given as JSON strings instead of numbers
+    pub string_npis: u64,
+    /// negotiated_rates rewritten as canonical decimals (see rate::normalize_rate)
+    pub fixed_rates: u64,
+    /// negotiated_rates that are not numbers
+    pub invalid_rates: u64,
+    pub peak_rss_kb: Option<u64>,
+    /// Where the wall time went
+    pub timings: Timings,
+    /// (billing_code_type, billing_code) -> rates matched, with Options::run_details
+    pub code_rates: BTreeMap<Vec<String>, RateSummary>,
+    /// in_network items that gave no rows by reason, with Options::run_details
+    pub skipped: BTreeMap<Reason, u64>,
+}
+
+/// Where the time of a run went, to tell whether a slow run is bound by reading, parsing or
+/// writing. Reading the file and writing records happen within the sections.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Timings {
+    /// Reading provider_references
+    pub provider_references: Duration,
+    /// Reading in_network, including writing its records
+    pub in_network: Duration,
+    /// Skipping in_network found before provider_references, and reading provider_references
+    /// again on the second pass
+    pub reset: Duration,
+    /// Reading and decompressing the datafile, in every section
+    pub read: Duration,
+    /// Writing records to the sink, in in_network
+    pub output: Duration,
+}
+
+impl Timings {
+    /// Returns the time of the sections not spent reading the file or writing records, i.e.
+    /// parsing and matching.
+    pub fn parse(&self) -> Duration {
+        (self.provider_references + self.in_network + self.reset).saturating_sub(self.read + self.output)
+    }
+
+    /// Hands the breakdown to reporter.
+    pub fn summarize(&self, reporter: &SharedReporter) {
+        reporter.status(format_args!("  time breakdown:"));
+        reporter.status(format_args!("    provider_references: {:.1}s", self.provider_references.as_secs_f64()));
+        reporter.status(format_args!("    in_network:          {:.1}s", self.in_network.as_secs_f64()));
+        if !self.reset.is_zero() {
+            reporter.status(format_args!("    reset overhead:      {:.1}s", self.reset.as_secs_f64()));
+        }
+        reporter.status(format_args!("    of which reading:    {:.1}s", self.read.as_secs_f64()));
+        reporter.status(format_args!("    of which parsing:    {:.1}s", self.parse().as_secs_f64()));
+        reporter.status(format_args!("    of which output:     {:.1}s", self.output.as_secs_f64()));
+    }
+}
+
+impl RunStats {
+    /// Hands the statistics to reporter
+    pub fn summarize(&self, reporter: &SharedReporter) {
+        let secs = self.elapsed.as_secs_f64();
+        let mib = self.decompressed_bytes as f64 / (1024.0 * 1024.0);
+
+        reporter.status(format_args!("\nRun summary"));
+        reporter.status(format_args!("  wall time:          {:.1}s", secs));
+        if secs > 0.0 {
+            reporter.status(format_args!("  decompressed bytes: {} ({:.1} MiB/s)", self.decompressed_bytes, mib / secs));
+        }
+        else {
+            reporter.status(format_args!("  decompressed bytes: {}", self.decompressed_bytes));
+        }
+        reporter.status(format_args!("  objects scanned:    {}", self.objects_scanned));
+        reporter.status(format_args!("  objects matched:    {}", self.objects_matched));
+        reporter.status(format_args!("  rows written:       {}", self.rows_written));
+        if self.corrupt_skipped > 0 {
+            reporter.status(format_args!("  corrupt skipped:    {}", self.corrupt_skipped));
+        }
+        if self.missing_group_ids > 0 {
+            reporter.status(format_args!("  missing group ids:  {}", self.missing_group_ids));
+        }
+        if self.duplicates_skipped > 0 {
+            reporter.status(format_args!("  duplicates skipped: {}", self.duplicates_skipped));
+        }
+        if self.string_npis > 0 {
+            reporter.status(format_args!("  string npis:        {}", self.string_npis));
+        }
+        if self.fixed_rates > 0 {
+            reporter.status(format_args!("  fixed rates:        {}", self.fixed_rates));
+        }
+        if self.invalid_rates > 0 {
+            reporter.status(format_args!("  invalid rates:      {}", self.invalid_rates));
+        }
+        if let Some(kb) = self.peak_rss_kb {
+            reporter.status(format_args!("  peak RSS:           {:.1} MiB", kb as f64 / 1024.0));
+        }
+        self.timings.summarize(reporter);
+    }
+}
+
+/// Reads the peak resident set size (VmHWM) of this process in KiB.
+/// Only available on Linux, returns None elsewhere.
+fn peak_rss_kb() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Something that can be emptied and filled again, keeping its buffers.
+trait Reset: Default {
+    /// Empties self, keeping the memory it holds.
+    fn reset(&mut self);
+}
+
+impl Reset for String {
+    fn reset(&mut self) {
+        self.clear();
+    }
+}
+
+/// A Vec whose items are kept when it is cleared and reused by push, so that reading the
+/// in_network objects one after the other doesn't allocate once the buffers are big enough.
+/// Derefs to the live items.
+#[derive(Debug, Clone)]
+struct Pool<T> {
+    items: Vec<T>,
+    /// Number of live items, the first len of items
+    len: usize,
+}
+
+impl<T> Default for Pool<T> {
+    fn default() -> Self {
+        Self { items: Vec::new(), len: 0 }
+    }
+}
+
+impl<T: Reset> Pool<T> {
+    /// Adds an empty item, reusing one left by clear or pop if there is one, and returns it.
+    fn push(&mut self) -> &mut T {
+        if self.len == self.items.len() {
+            self.items.push(T::default());
+        } else {
+            self.items[self.len].reset();
+        }
+        self.len += 1;
+        &mut self.items[self.len - 1]
+    }
+
+    /// Drops the last item, keeping its buffers.
+    fn pop(&mut self) {
+        self.len = self.len.saturating_sub(1);
+    }
+
+    /// Drops every item, keeping their buffers.
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl<T> std::ops::Deref for Pool<T> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        &self.items[..self.len]
+    }
+}
+
+impl<T> std::ops::DerefMut for Pool<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        &mut self.items[..self.len]
+    }
+}
+
+impl<T: PartialEq> PartialEq for Pool<T> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
 
 /// Holds information for a negotiated price
-// TODO: add billing_code_modifier support
 #[derive(Debug,PartialEq,Clone)]
 struct Price {
     negotiated_type: String,
@@ -164,6 +1388,8 @@ struct Price {
     service_code: String,
     billing_class: String,
     billing_code_modifier: String,
+    // Key and value events for unsupported keys, only filled with --capture-extra
+    extra: Vec<JsonEvent<'static>>,
 }
 impl Price {
     /// Creates a Price struct
@@ -175,18 +1401,7 @@ impl Price {
             service_code: String::from(""),
             billing_class: String::from(""),
             billing_code_modifier: String::from(""),
-        }
-    }
-
-    /// Returns a new Price struct where all fields are set to the String "null".
-    fn new_null() -> Self {
-        Self {
-            negotiated_type: String::from("null"),
-            negotiated_rate: String::from("null"),
-            expiration_date: String::from("null"),
-            service_code: String::from("null"),
-            billing_class: String::from("null"),
-            billing_code_modifier: String::from("null"),
+            extra: Vec::new(),
         }
     }
 
@@ -198,6 +1413,22 @@ impl Price {
         self.service_code.clear();
         self.billing_class.clear();
         self.billing_code_modifier.clear();
+        self.extra.clear();
+    }
+
+    /// Returns the captured unsupported keys as a JSON object, or "null" if there are none.
+    fn extra_json(&self) -> Result<String, std::io::Error> {
+        if self.extra.is_empty() {
+            return Ok(String::from("null"));
+        }
+        let mut json = WriterJsonSerializer::new(Vec::new());
+        json.serialize_event(JsonEvent::StartObject)?;
+        for event in self.extra.iter() {
+            json.serialize_event(event.clone())?;
+        }
+        json.serialize_event(JsonEvent::EndObject)?;
+        let buffer = json.finish()?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
     }
 
     /// Fills empty values in a Price struct with "null"
@@ -244,23 +1475,28 @@ impl Price {
 
 }
 
-/// Holds negotiated price information and providers the associated provider references for which
-/// those prices are valid. Progam will filter provider_refercnes to only those that are relevant. 
-#[derive(Debug,Clone,PartialEq)]
-struct Rate {
-    provider_references: Vec<String>,
-    negotiated_prices: Vec<Price>, 
+impl Default for Price {
+    fn default() -> Self {
+        Self::new()
+    }
 }
-impl Rate {
-    /// Creates a new Rate struct.
-    fn new() -> Self {
-        Self {
-            provider_references: Vec::new(),
-            negotiated_prices: Vec::new(),
-        }
+
+impl Reset for Price {
+    fn reset(&mut self) {
+        self.clear_fields();
     }
+}
+
+/// Holds negotiated price information and providers the associated provider references for which
+/// those prices are valid. Progam will filter provider_refercnes to only those that are relevant. 
+#[derive(Debug,Clone,PartialEq,Default)]
+struct Rate {
+    provider_references: Pool<String>,
+    negotiated_prices: Pool<Price>, 
+}
+impl Reset for Rate {
     /// Calls clear on the fields in the Rate struct.
-    fn clear_fields(&mut self) {
+    fn reset(&mut self) {
         self.provider_references.clear();
         self.negotiated_prices.clear();
     }
@@ -275,7 +1511,8 @@ struct Network {
     billing_code_type_version: String,
     billing_code: String,
     description: String,
-    negotiated_rates: Option<Vec<Rate>>,
+    /// Rates with a provider from the query, empty until negotiated_rates is read
+    negotiated_rates: Pool<Rate>,
 }
 
 impl Network {
@@ -288,11 +1525,11 @@ impl Network {
             billing_code_type_version: String::from(""),
             billing_code: String::from(""),
             description: String::from(""),
-            negotiated_rates: None ,
+            negotiated_rates: Pool::default(),
         }
     }
 
-    /// Calls clear on all String fields in the Network struct and sets negotiated_rates to None
+    /// Calls clear on all String fields in the Network struct and on negotiated_rates
     fn clear_entries(&mut self) {
         self.negotiation_arrangement.clear();
         self.name.clear();
@@ -300,7 +1537,7 @@ impl Network {
         self.billing_code_type_version.clear();
         self.billing_code.clear();
         self.description.clear();
-        self.negotiated_rates = None;
+        self.negotiated_rates.clear();
     }
 
     /// Sets all fields in Network struct to String: "null" except billing_code and
@@ -347,16 +1584,48 @@ impl Network {
     }
 }
 
-/// Prints the header using a csv::Writer.
-fn print_header2<W: std::io::Write>(writer: &mut csv::Writer<W>) -> Result< (), Box<dyn std::error::Error>> {
-    let h = "npi,tin_type,tin_value,group_id,negotiation_arrangement,name,\
-             billing_code_type,billing_code_type_version,billing_code,\
-             description,negotiated_type,negotiated_rate,expiration_date,\
-             service_code,billing_class,billing_code_modifier";
+/// Names of the CSV columns, in order. nppes adds nppes::COLUMNS after them, code_descriptions
+/// a descriptions::COLUMN, then capture_extra adds an extra column.
+pub const COLUMNS: [&str; 16] = ["npi", "tin_type", "tin_value", "group_id", "negotiation_arrangement",
+                                 "name", "billing_code_type", "billing_code_type_version",
+                                 "billing_code", "description", "negotiated_type", "negotiated_rate",
+                                 "expiration_date", "service_code", "billing_class",
+                                 "billing_code_modifier"];
+
+/// Returns the columns of the rows of a run with options, before they are renamed: COLUMNS,
+/// then the NPPES columns with options.nppes, the code description column with
+/// options.code_descriptions and the extra column with options.capture_extra.
+pub fn columns(options: &Options) -> Vec<&'static str> {
+    let mut columns: Vec<&str> = COLUMNS.to_vec();
+    if options.nppes.is_some() {
+        columns.extend(nppes::COLUMNS);
+    }
+    if options.code_descriptions.is_some() {
+        columns.push(descriptions::COLUMN);
+    }
+    if options.capture_extra {
+        columns.push("extra");
+    }
+    columns
+}
+
+/// Writes the header (see columns) to sink.
+/// Renames the columns of options.header_map. Prints nothing with options.no_header.
+fn print_header2(sink: &mut dyn RecordSink,
+                 options: &Options,
+                                    ) -> Result< (), Box<dyn std::error::Error>> {
+    if options.no_header {
+        return Ok(());
+    }
 
-    let header: Vec<&str> = h.split(',').collect();
-    writer.write_record(header)?;
-    writer.flush()?;
+    let mut header = columns(options);
+    // Columns renamed with --header-map
+    for column in header.iter_mut() {
+        if let Some((_, name)) = options.header_map.iter().find(|(c, _)| c == column) {
+            *column = name;
+        }
+    }
+    sink.write_header(&header)?;
 
     Ok(())
 }
@@ -383,57 +1652,254 @@ fn _print_header(out: &mut impl Write) -> Result< (), std::io::Error> {
     Ok(())
 }
 
-/// Print record using a csv::Writer
-fn print_record2<W: std::io::Write> (network: &Network, 
-                                       query: &mut Query,
-                                     ref_map: &HashMap<String, Vec<String>>,
-                                      writer: &mut csv::Writer<W>,
-                                   ) -> Result<(), Box<dyn std::error::Error>> {
+/// Keeps each of a stream of items with a given probability (--sample), drawing from a
+/// splitmix64 generator so the same seed keeps the same items.
+#[derive(Debug)]
+struct Sampler {
+    rate: f64,
+    state: u64,
+}
+
+impl Sampler {
+    fn new(rate: f64, seed: u64) -> Self {
+        Self { rate, state: seed }
+    }
 
-    let neg_rates = network.negotiated_rates.as_ref().unwrap();
+    /// Returns true if the next item is kept.
+    fn keep(&mut self) -> bool {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        ((z >> 11) as f64 / (1u64 << 53) as f64) < self.rate
+    }
+}
 
-    for rate in neg_rates.iter() {
+/// What happens to the rows of matching records besides being written: skipping duplicates
+/// (--dedupe), keeping only the lowest rate of each code and npi (--min-per-code),
+/// summarizing the rates of each code (--rate-summary), keeping a random share of the prices
+/// (--sample), counting rows towards --limit and dropping rows failing --where. Each is None
+/// when not asked for.
+#[derive(Debug, Default)]
+struct RowSinks {
+    seen: Option<RowSet>,
+    mins: Option<MinRates>,
+    /// (billing_code_type, billing_code) -> rates matched
+    rates: Option<BTreeMap<Vec<String>, RateSummary>>,
+    sample: Option<Sampler>,
+    /// Rows that can still match before the limit is reached
+    left: Option<u64>,
+    /// options.filter, bound to the columns of the rows
+    filter: Option<Filter>,
+}
+
+impl RowSinks {
+    /// Creates the RowSinks asked for by options. Fails if the filter uses a column the rows
+    /// don't have.
+    fn new(options: &Options) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            seen: options.dedupe.then(RowSet::new),
+            mins: options.min_per_code.then(MinRates::new),
+            rates: (options.rate_summary.is_some() || options.run_details).then(BTreeMap::new),
+            sample: options.sample.map(|rate| Sampler::new(rate, options.sample_seed)),
+            left: options.limit,
+            filter: options.filter.as_ref().map(|f| f.bind(&columns(options))).transpose()?,
+        })
+    }
+
+    /// Returns true once --limit rows matched.
+    fn limit_reached(&self) -> bool {
+        self.left == Some(0)
+    }
+
+    /// Counts a matched row towards the limit.
+    fn count_row(&mut self) {
+        if let Some(left) = self.left.as_mut() {
+            *left = left.saturating_sub(1);
+        }
+    }
+
+    /// Adds rate (if it is a number) to the summary of the code of network.
+    fn summarize(&mut self, network: &Network, rate: &str) {
+        if let Some(rates) = self.rates.as_mut() && let Ok(rate) = rate.parse::<f64>() {
+            let code = vec![network.billing_code_type.clone(), network.billing_code.clone()];
+            rates.entry(code).or_default().add(rate);
+        }
+    }
+
+    /// Writes the rows kept by --min-per-code to sink, counting them in stats, and the rate
+    /// summary to its file. Keeps the rates in stats with options.run_details.
+    fn finish(&mut self,
+              sink: &mut dyn RecordSink,
+              stats: &mut RunStats,
+              options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(mins) = self.mins.take() {
+            for row in mins.into_rows() {
+                let values: Vec<&str> = row.iter().map(|v| v.as_str()).collect();
+                sink.write_record(&MatchedRecord { values: &values })?;
+                stats.rows_written += 1;
+            }
+        }
+        if let Some(rates) = self.rates.take() {
+            if let Some(path) = &options.rate_summary {
+                let file = create_output_with(path, options.compression)?;
+                write_groups_csv(rates.clone(), &["billing_code_type", "billing_code"], &options.percentiles, file)?;
+            }
+            if options.run_details {
+                stats.code_rates = rates;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Files joined to the rows of matching records: NPPES by npi (--nppes) and code descriptions
+/// by billing code (--code-descriptions). Each is None when not asked for.
+#[derive(Debug, Default)]
+struct Lookups {
+    nppes: Option<Nppes>,
+    descriptions: Option<CodeDescriptions>,
+}
+
+impl Lookups {
+    /// Reads the files asked for by options. Keeps only the NPPES providers of query, so it is
+    /// read once every provider of the query is known.
+    fn read(options: &Options, query: &Query) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut lookups = Self::default();
+        if let Some(path) = &options.nppes {
+            options.reporter.status(format_args!("Reading NPPES file {}...", path.display()));
+            lookups.nppes = Some(Nppes::read(path, &query.providers.iter().map(|p| p.npi).collect())?);
+        }
+        if let Some(path) = &options.code_descriptions {
+            options.reporter.status(format_args!("Reading code descriptions {}...", path.display()));
+            lookups.descriptions = Some(CodeDescriptions::read(path)?);
+        }
+        Ok(lookups)
+    }
+}
+
+/// Writes the records of network to sink.
+/// Returns the number of records written.
+fn print_record2(network: &Network, 
+                                       query: &mut Query,
+                                     ref_map: &RefMap,
+                                        sink: &mut dyn RecordSink,
+                                     options: &Options,
+                                       sinks: &mut RowSinks,
+                                     lookups: &Lookups,
+                                   ) -> Result<u64, Box<dyn std::error::Error>> {
+
+    let neg_rates = &network.negotiated_rates;
+    let code_description = lookups.descriptions.as_ref().map(|d| {
+        d.get(&network.billing_code_type, &network.billing_code).unwrap_or("null")
+    });
+    let mut written: u64 = 0;
+    // Rows written, or kept for later with --min-per-code
+    let mut matched: u64 = 0;
+
+    'rows: for rate in neg_rates.iter() {
         for reference in rate.provider_references.iter() {
-            query.log_ref(reference);
-            for prov in ref_map.get(reference).unwrap().iter(){
+            query.log_ref(reference, rate.negotiated_prices.len() as u64);
+            for prov in ref_map.get(reference)?.unwrap().iter(){
                 for price in rate.negotiated_prices.iter() {
-                    
-                    // BUILD Vec here
-                    let mut rec = Vec::new();
-                    //rec.push(&prov); // BUG!
-                    for i in prov.split(',') {
-                        rec.push(i);
+                    // With --sample only some of the prices are kept
+                    if let Some(sample) = sinks.sample.as_mut() && !sample.keep() {
+                        continue;
                     }
-                    rec.push(&reference);
-
-                    // network._print_out(out)?;
-                    rec.push(&network.negotiation_arrangement);
-                    rec.push(&network.name);
-                    rec.push(&network.billing_code_type);
-                    rec.push(&network.billing_code_type_version);
-                    rec.push(&network.billing_code);
-                    rec.push(&network.description);
-
-                    // price._print_out(out)?;
-                    rec.push(&price.negotiated_type);
-                    rec.push(&price.negotiated_rate);
-                    rec.push(&price.expiration_date);
-                    rec.push(&price.service_code);
-                    rec.push(&price.billing_class);
-                    rec.push(&price.billing_code_modifier);
-                    
+                    // With --explode-service-codes each service code gets its own row
+                    let mut service_codes: Vec<&str> = vec![price.service_code.as_str()];
+                    if options.explode_service_codes {
+                        service_codes = price.service_code.split(options.list_delimiter.as_str()).collect();
+                    }
+                    // and with --explode-modifiers each modifier
+                    let mut modifiers: Vec<&str> = vec![price.billing_code_modifier.as_str()];
+                    if options.explode_modifiers {
+                        modifiers = price.billing_code_modifier.split(options.list_delimiter.as_str()).collect();
+                    }
+                    for (service_code, modifier) in service_codes.iter()
+                        .flat_map(|s| modifiers.iter().map(move |m| (*s, *m))) {
+                        if sinks.limit_reached() {
+                            break 'rows;
+                        }
+
+                        // BUILD Vec here
+                        let mut rec = Vec::new();
+                        //rec.push(&prov); // BUG!
+                        for i in prov.split(',') {
+                            rec.push(i);
+                        }
+                        rec.push(reference);
+
+                        // network._print_out(out)?;
+                        rec.push(&network.negotiation_arrangement);
+                        rec.push(&network.name);
+                        rec.push(&network.billing_code_type);
+                        rec.push(&network.billing_code_type_version);
+                        rec.push(&network.billing_code);
+                        rec.push(&network.description);
+
+                        // price._print_out(out)?;
+                        rec.push(&price.negotiated_type);
+                        rec.push(&price.negotiated_rate);
+                        rec.push(&price.expiration_date);
+                        rec.push(service_code);
+                        rec.push(&price.billing_class);
+                        rec.push(modifier);
+
+                        if let Some(nppes) = &lookups.nppes {
+                            let npi = prov.split(',').next().and_then(|n| n.parse().ok());
+                            match npi.and_then(|n| nppes.get(n)) {
+                                Some(info) => rec.extend([info.name.as_str(), &info.taxonomy, &info.address]),
+                                None => rec.extend(["null"; 3]),
+                            }
+                        }
+                        if let Some(description) = code_description {
+                            rec.push(description);
+                        }
+
+                        let extra;
+                        if options.capture_extra {
+                            extra = price.extra_json()?;
+                            rec.push(&extra);
+                        }
 
-                    writer.write_record(rec)?;
-                    writer.flush()?;
+                        // With --where only the rows passing the filter are kept
+                        if let Some(filter) = sinks.filter.as_ref() && !filter.matches(&rec) {
+                            continue;
+                        }
+
+                        // With --min-per-code the row is kept if it has the lowest rate of
+                        // its code and npi so far, and written at the end
+                        if let Some(mins) = sinks.mins.as_mut() {
+                            let key = [network.billing_code_type.as_str(), network.billing_code.as_str(), rec[0]];
+                            mins.offer(&key, &price.negotiated_rate, &rec);
+                            sinks.summarize(network, &price.negotiated_rate);
+                            sinks.count_row();
+                            matched += 1;
+                            continue;
+                        }
+
+                        // With --dedupe a row already written is skipped
+                        if let Some(seen) = sinks.seen.as_mut() && !seen.insert(&rec)? {
+                            continue;
+                        }
+
+                        sink.write_record(&MatchedRecord { values: &rec })?;
+                        sinks.summarize(network, &price.negotiated_rate);
+                        sinks.count_row();
+                        written += 1;
+                        matched += 1;
 
+                    }
                 }
             }
         }
     }
 
-    query.log_code(&network.billing_code, &network.billing_code_type);
+    query.log_code(&network.billing_code, &network.billing_code_type, matched);
 
-    Ok(())
+    Ok(written)
 }
 
 
@@ -444,7 +1910,7 @@ fn print_record2<W: std::io::Write> (network: &Network,
 /// Calls log_ref and log_code from Query implementation.
 fn _print_record(network: &Network, 
                 query: &mut Query,
-                ref_map: &HashMap<String, Vec<String>>,
+                ref_map: &RefMap,
                 out: &mut impl Write,
                 ) -> Result<(), Box<dyn std::error::Error>> {
     
@@ -460,13 +1926,15 @@ fn _print_record(network: &Network,
     //
     // EXIT
 
-    let neg_rates = network.negotiated_rates.as_ref().unwrap();
+    let neg_rates = &network.negotiated_rates;
+    let mut written: u64 = 0;
 
     for rate in neg_rates.iter() {
         for reference in rate.provider_references.iter() {
-            query.log_ref(reference);
-            for prov in ref_map.get(reference).unwrap().iter(){
+            query.log_ref(reference, rate.negotiated_prices.len() as u64);
+            for prov in ref_map.get(reference)?.unwrap().iter(){
                 for price in rate.negotiated_prices.iter() {
+                    written += 1;
                     write!(out, "{}", prov)?;
                     write!(out, ",")?;
                     write!(out, "{}", reference)?;
@@ -483,18 +1951,27 @@ fn _print_record(network: &Network,
 
     out.flush()?;
 
-    query.log_code(&network.billing_code, &network.billing_code_type);
+    query.log_code(&network.billing_code, &network.billing_code_type, written);
 
     Ok(())
 }
 
 /// Handles data in pegotiated_prices array
-/// Returns Vec<Price> with either the data OR if no data found Vec<Price> containing
-/// a sinlge Price struct with all default values ("null").
+/// Fills prices (which must be empty) with either the data OR if no data found a sinlge Price
+/// struct with all default values ("null"). Prices reuse the buffers of earlier rates.
 /// WARNING: Doesn't handle dupe keys at all
-/// NOTE: Uses ' ' delimited string for service codes
-fn process_negotiated_prices<R: Read>(parser: &mut ReaderJsonParser<R>,
-                                     ) -> Result< Vec<Price>, Box<dyn std::error::Error> > {
+/// NOTE: Service codes and billing code modifiers (a string or an array of them) are joined in
+/// one string with options.list_delimiter between them
+/// With options.capture_extra, values under unsupported keys are kept in Price.extra.
+/// Prices that don't pass the service code, billing class and rate filters of options are
+/// dropped, and prices is left empty if none is left. With options.as_of, prices that expired before it are
+/// dropped (9999-12-31 never expires, a missing or malformed date is kept), and prices is left
+/// empty if all of them were.
+fn process_negotiated_prices<R: Read>(parser: &mut JsonParser<R>,
+                                      ctx: &mut ParseContext,
+                                      prices: &mut Pool<Price>,
+                                      options: &Options,
+                                     ) -> Result<(), Box<dyn std::error::Error> > {
 
     // Used to handle price object keys 
     #[derive(PartialEq)]
@@ -512,21 +1989,49 @@ fn process_negotiated_prices<R: Read>(parser: &mut ReaderJsonParser<R>,
     let mut sq = 0;
     let mut cb = 0;
 
-    let mut prices: Vec<Price> = Vec::new();
-    let mut price = Price::new();
+    let mut expired = 0;
+    // Takes values found outside a price object, which are dropped
+    let mut stray = Price::new();
 
     loop {
-        let event = {parser.parse_next()?};
+        let event = {next_event(parser, ctx, "negotiated_prices")?};
+        // The price being read, the last of prices
+        let price = match prices.last_mut() {
+            Some(price) if cb > 0 => price,
+            _ => {
+                stray.clear_fields();
+                &mut stray
+            }
+        };
         match event {
             JsonEvent::StartObject => {
                 cb += 1;
+                if cb == 1 {
+                    prices.push();
+                }
             }
             JsonEvent::EndObject => {
                 cb -= 1;
                 if cb == 0 {
-                    price.push_defaults();
-                    prices.push(price.clone());
-                    price.clear_fields();
+                    fix_rate(ctx, &mut price.negotiated_rate, options.rate_format)?;
+                    if let Some(precision) = options.rate_precision &&
+                       let Some(rate) = round_rate(&price.negotiated_rate, precision) {
+                        price.negotiated_rate = rate;
+                    }
+                    let kept = options.keeps_price(price);
+                    let is_expired = match (options.as_of, parse_date(&price.expiration_date)) {
+                        (Some(as_of), Some(expiration)) => expiration < as_of,
+                        _ => false,
+                    };
+                    if kept && !is_expired {
+                        price.push_defaults();
+                    }
+                    else {
+                        if is_expired {
+                            expired += 1;
+                        }
+                        prices.pop();
+                    }
                 }
             }
             JsonEvent::StartArray => {
@@ -557,14 +2062,13 @@ fn process_negotiated_prices<R: Read>(parser: &mut ReaderJsonParser<R>,
                 else if key == "billing_code_modifier" {
                     state = State::billing_code_modifier;
                 }
+                else if options.capture_extra {
+                    price.extra.push(JsonEvent::ObjectKey(Cow::Owned(key.into_owned())));
+                    capture_value(parser, ctx, &mut price.extra)?;
+                }
                 else {
-                    UNSUPPORTED_KEYS.with(|set| {
-                        if !set.borrow().contains(key.as_ref()) {
-                            eprintln!("Unsupported key {} found in Price", key.as_ref());
-                        }
-                        set.borrow_mut().insert(String::from(key.as_ref()));
-                    });
-                    bypass_key(parser)?;
+                    unsupported_key(ctx, key.as_ref(), "negotiated_prices")?;
+                    bypass_key(parser, ctx)?;
                 }
             } // End ObjectKeys
 
@@ -582,17 +2086,22 @@ fn process_negotiated_prices<R: Read>(parser: &mut ReaderJsonParser<R>,
                     price.expiration_date.push_str(s.as_ref());
                 }
                 else if state == State::service_code {
+                    if !price.service_code.is_empty() {
+                        price.service_code.push_str(&options.list_delimiter);
+                    }
                     price.service_code.push_str(s.as_ref());
-                    price.service_code.push_str(" ");
                 }
                 else if state == State::billing_class {
                     price.billing_class.push_str(s.as_ref());
                 }
                 else if state == State::billing_code_modifier {
+                    if !price.billing_code_modifier.is_empty() {
+                        price.billing_code_modifier.push_str(&options.list_delimiter);
+                    }
                     price.billing_code_modifier.push_str(s.as_ref());
                 }
                 else if state == State::undefined {
-                    panic!("Unsupported key encountered in asa::process_negotiated_prices");
+                    return Err(Box::new(unexpected(ctx, "negotiated_prices", &JsonEvent::String(s))));
                 }
             } // End String
 
@@ -604,6 +2113,10 @@ fn process_negotiated_prices<R: Read>(parser: &mut ReaderJsonParser<R>,
                 price.negotiated_rate.push_str(num.as_ref());
             }
 
+            JsonEvent::Eof => {
+                return Err(Box::new(unexpected_eof(ctx, "negotiated_prices")));
+            }
+
             _ => {
             }
 
@@ -611,12 +2124,13 @@ fn process_negotiated_prices<R: Read>(parser: &mut ReaderJsonParser<R>,
 
     } // End loop
 
-    // If no prices were found we fill in with "null" default price
-    if prices.len() == 0 {
-        prices.push(Price::new_null());
+    // If no prices were found we fill in with "null" default price,
+    // unless filtering prices (a missing price can't pass a filter)
+    if prices.len() == 0 && !options.filters_prices() && expired == 0 {
+        prices.push().push_defaults();
     }
 
-    Ok(prices)
+    Ok(())
 
 
 }
@@ -624,31 +2138,35 @@ fn process_negotiated_prices<R: Read>(parser: &mut ReaderJsonParser<R>,
 
 /// Processes the negotiated_rates array in the objects found in the in_network array. 
 /// Uses a helper for negotiated_prices array.
-/// If no relevant data (matching query) is found, returns Ok(None)
-fn process_negotiated_rates<R: Read>(parser: &mut ReaderJsonParser<R>,
-                                     ref_map: &HashMap<String, Vec<String>>,
-                                     ) -> Result< Option<Vec<Rate>>, Box<dyn std::error::Error> > {
-
-
-    let mut rates: Vec<Rate> = Vec::new();
-    let mut rate: Rate = Rate::new(); 
+/// The rates are read into rates, whose buffers are reused from one object to the next.
+/// If no relevant data (matching query) is found, returns Ok(false)
+fn process_negotiated_rates<R: Read>(parser: &mut JsonParser<R>,
+                                     ctx: &mut ParseContext,
+                                     ref_map: &RefMap,
+                                     rates: &mut Pool<Rate>,
+                                     options: &Options,
+                                     ) -> Result< bool, Box<dyn std::error::Error> > {
+
+    // Set when every price of the rate was filtered out
+    let mut no_prices = false;
 
     let mut cb = 0;
     let mut sq = 0;
     
     loop {
-        let event = {parser.parse_next()?};
+        let event = {next_event(parser, ctx, "negotiated_rates")?};
         match event {
             JsonEvent::StartObject => {
                 cb += 1;
+                if cb == 1 {
+                    rates.push();
+                    no_prices = false;
+                }
             }
             JsonEvent::EndObject => {
                 cb -= 1;
-                if cb == 0 {
-                    if rate.provider_references.len() != 0 {
-                        rates.push(rate.clone());
-                    }
-                    rate.clear_fields();
+                if cb == 0 && rates.last().is_some_and(|rate| rate.provider_references.len() == 0 || no_prices) {
+                    rates.pop();
                 }
             }
             JsonEvent::StartArray => {
@@ -661,8 +2179,10 @@ fn process_negotiated_rates<R: Read>(parser: &mut ReaderJsonParser<R>,
                 }
                 // ASSERTION this will always be at end of provider_references array
                 else {
-                    if rate.provider_references.len() == 0 {
-                        ff_to_next_obj(parser, &mut cb, &mut sq)?;
+                    if rates.last().is_some_and(|rate| rate.provider_references.len() == 0) {
+                        // Skips the end of the rate object too
+                        ff_to_next_obj(parser, ctx, &mut cb, &mut sq)?;
+                        rates.pop();
                     }
                 }
             }
@@ -670,31 +2190,26 @@ fn process_negotiated_rates<R: Read>(parser: &mut ReaderJsonParser<R>,
                 if key == "provider_references" {
                     continue; // TODO Should I use an enum here too? 
                 }
-                else if key == "negotiated_prices" {
-                    let prices = {process_negotiated_prices(parser)?};
-                    rate.negotiated_prices = prices;
+                else if key == "negotiated_prices" && let Some(rate) = rates.last_mut() {
+                    rate.negotiated_prices.clear();
+                    process_negotiated_prices(parser, ctx, &mut rate.negotiated_prices, options)?;
+                    no_prices = rate.negotiated_prices.is_empty();
                 }
                 else {
-                    UNSUPPORTED_KEYS.with(|set| {
-                        if !set.borrow().contains(key.as_ref()) {
-                            eprintln!("Unsupported key {} found in \
-                                      negotiated_rates", key.as_ref());
-                        }
-                        set.borrow_mut().insert(String::from(key.as_ref()));
-                    });
-                    bypass_key(parser)?;
+                    unsupported_key(ctx, key.as_ref(), "negotiated_rates")?;
+                    bypass_key(parser, ctx)?;
                 }
             }
             
             JsonEvent::Number(val) | JsonEvent::String(val) => {
-                if ref_map.contains_key(val.as_ref()) {
-                    rate.provider_references.push(val.to_string());
+                if cb > 0 && let Some(rate) = rates.last_mut() && ref_map.contains_key(val.as_ref()) {
+                    rate.provider_references.push().push_str(&val);
                 }
             }
 
 
             JsonEvent::Eof => {
-                panic!("FATAL ERROR: Eof encountered in asa::process_negotiated_rates");
+                return Err(Box::new(unexpected_eof(ctx, "negotiated_rates")));
             }
             
             _ => {}
@@ -704,35 +2219,65 @@ fn process_negotiated_rates<R: Read>(parser: &mut ReaderJsonParser<R>,
 
     } // End of loop
 
-    if rates.len() == 0 {
-        return Ok(None);
+    Ok(!rates.is_empty())
+}
+
+/// Reads the value following an object key and appends its events to events.
+/// Used by --capture-extra so the value can be written back out as JSON.
+fn capture_value<R: Read>(parser: &mut JsonParser<R>,
+                          ctx: &mut ParseContext,
+                          events: &mut Vec<JsonEvent<'static>>,
+                         ) -> Result<(), Box<dyn std::error::Error>> {
+    let mut depth: u64 = 0;
+    loop {
+        let event = {next_event(parser, ctx, "unsupported key")?};
+        match event {
+            JsonEvent::StartObject | JsonEvent::StartArray => {
+                depth += 1;
+            }
+            JsonEvent::EndObject | JsonEvent::EndArray if depth == 0 => {
+                return Err(Box::new(unexpected(ctx, "unsupported key", &event)));
+            }
+            JsonEvent::EndObject | JsonEvent::EndArray => {
+                depth -= 1;
+            }
+            JsonEvent::Eof => {
+                return Err(Box::new(unexpected_eof(ctx, "unsupported key")));
+            }
+            _ => {}
+        }
+        events.push(to_owned_event(&event));
+        if depth == 0 {
+            break;
+        }
     }
-    
-    Ok(Some(rates))
+
+    Ok(())
 }
 
 /// Used to bypass unsupported keys. 
-fn bypass_key<R: Read>(parser: &mut ReaderJsonParser<R>,
+fn bypass_key<R: Read>(parser: &mut JsonParser<R>,
+                       ctx: &mut ParseContext,
                       ) -> Result<(), Box<dyn std::error::Error>> {
 
-    let event = {parser.parse_next()?};
+    let event = {next_event(parser, ctx, "unsupported key")?};
 
     match event {
         JsonEvent::StartObject => {
             let mut cb = 1;
             let mut sq = 0;
-            ff_to_next_obj(parser, &mut cb, &mut sq)?;
+            ff_to_next_obj(parser, ctx, &mut cb, &mut sq)?;
         }
         JsonEvent::StartArray => {
-            skip_array(parser, 1)?;
+            skip_array(parser, ctx, 1)?;
         }
         JsonEvent::Eof => {
-            panic!("FATAL ERROR: Eof found in bypass_key.");
+            return Err(Box::new(unexpected_eof(ctx, "unsupported key")));
         }
         JsonEvent::EndObject => {
         }
         JsonEvent::EndArray => {
-            panic!("FATAL ERROR: Malformed JSON");
+            return Err(Box::new(unexpected(ctx, "unsupported key", &event)));
         }
         _ => {}
     }
@@ -741,14 +2286,15 @@ fn bypass_key<R: Read>(parser: &mut ReaderJsonParser<R>,
 }
 
 /// Used to skip objects that have been partially processed and found not to match query.
-fn ff_to_next_obj<R: Read>(parser: &mut ReaderJsonParser<R>,
+fn ff_to_next_obj<R: Read>(parser: &mut JsonParser<R>,
+                           ctx: &mut ParseContext,
                            cb: &mut u64,
                            sq: &mut u64,
                           ) -> Result<(), Box<dyn std::error::Error>> {
 
 
     loop {
-        let event = {parser.parse_next()?};
+        let event = {next_event(parser, ctx, "skipped object")?};
         match event {
             JsonEvent::StartObject => {
                 *cb += 1;
@@ -766,7 +2312,7 @@ fn ff_to_next_obj<R: Read>(parser: &mut ReaderJsonParser<R>,
                 *sq -= 1;
             } 
             JsonEvent::Eof => {
-                panic!("EOF encountered when trying to skip object!");
+                return Err(Box::new(unexpected_eof(ctx, "skipped object")));
             }
             _ => {}
         }
@@ -780,12 +2326,18 @@ fn ff_to_next_obj<R: Read>(parser: &mut ReaderJsonParser<R>,
 /// Handles the data in the in_network array.
 /// Uses helper functions process_negotiated_rates which then chains a call to
 /// process_negotiated_prices.
-fn process_in_network<R: Read>(parser: &mut ReaderJsonParser<R>,
+/// Returns true if it stopped before the end of the array, with options.stop_when_complete,
+/// options.limit or options.end_offset.
+fn process_in_network<R: Read>(parser: &mut JsonParser<R>,
+                               ctx: &mut ParseContext,
                                query: &mut Query,
-                               out: &mut impl Write,
-                               ) -> Result<(), Box<dyn std::error::Error>> {
+                               sink: &mut dyn RecordSink,
+                               stats: &mut RunStats,
+                               options: &Options,
+                               mut copies: ElementCopies,
+                               ) -> Result<bool, Box<dyn std::error::Error>> {
 
-    // Make codeset hashset
+    // Make code matcher
     // Make reference hasmap (prov ref) -> Vec[ (npi,tintype,tinvalue) ]
     // ! Make sure we can do case insensitive checks wtih contains
     //   so maybe make these all lowercase then check against lowercase.
@@ -808,15 +2360,6 @@ fn process_in_network<R: Read>(parser: &mut ReaderJsonParser<R>,
     //        Iterate trhough codes Vec and mark recorded is code and code type match OR code
     //        type is '*' in struct. 
     
-    let mut obj_count: u64 = 0;
-    const INCR: u64 = 100;
-    const APPRX_TOTAL_OBJS: u64 = 148400;
-    let _progress = ProgressBar::new(APPRX_TOTAL_OBJS);
-    eprintln!("Progress bar based on estimate of {} total objects", APPRX_TOTAL_OBJS);
-    eprintln!("Progress bar will update after every {} objects", INCR);
-    
-    let mut writer = Writer::from_writer(out);
-
     let mut header_written: bool = false;
 
     let mut network = Network::new();
@@ -835,25 +2378,153 @@ fn process_in_network<R: Read>(parser: &mut ReaderJsonParser<R>,
 
     let mut state = State::undefined;
 
-    let codeset = query.make_code_set();
-    let ref_map = query.make_ref_map();
+    let codes = query.make_code_matcher();
+    let mut sinks = RowSinks::new(options)?;
+    let ref_map = if options.disk_ref_map {
+        RefMap::on_disk(query)?
+    }
+    else {
+        RefMap::from(query.make_ref_map())
+    };
+    query.index_providers();
+
+    let lookups = Lookups::read(options, query)?;
+
+    let mut completion = match options.stop_when_complete {
+        true => Completion::new(query),
+        false => None,
+    };
+    if options.stop_when_complete && completion.is_none() {
+        ctx.reporter.warn(format_args!("the query has code patterns or no listed providers, every item is read"));
+    }
+    let mut explain = match &options.explain {
+        Some(path) => Some(Explain::create(path, options.compression)?),
+        // Counted only
+        None if options.run_details => Some(Explain::counting()?),
+        None => None,
+    };
+    // Set to stop before the end of in_network
+    let mut stop = false;
+    // Index of the next in_network item
+    let mut item: u64 = 0;
+
+    // Records why the current item is skipped, before its fields are cleared
+    macro_rules! explain {
+        ($reason:expr) => {
+            if let Some(explain) = explain.as_mut() {
+                explain.skip(item.saturating_sub(1), &network.billing_code_type, &network.billing_code,
+                             &network.name, $reason)?;
+            }
+        };
+    }
 
     let mut sq = 0;
     let mut cb = 0;
 
+    // Depth of the in_network key, elements are two levels below it
+    let base_depth = ctx.depth;
+
+    // With skip_corrupt, an error from a malformed element is logged and the rest of the
+    // element is skipped. Any other error (or without skip_corrupt) ends the run.
+    // Nothing is written for an element until its end, so a skipped element leaves no output.
+    macro_rules! recover {
+        ($result:expr) => {
+            match $result.map_err(Into::<Box<dyn std::error::Error>>::into) {
+                Ok(value) => value,
+                Err(e) if options.skip_corrupt && is_recoverable(e.as_ref()) => {
+                    skip_corrupt_element(parser, ctx, base_depth, e)?;
+                    stats.corrupt_skipped += 1;
+                    explain!(Reason::Corrupt);
+                    stop_raw_capture(ctx);
+                    network.clear_entries();
+                    state = State::undefined;
+                    if ctx.depth <= base_depth {
+                        break;
+                    }
+                    cb = 0;
+                    sq = 1;
+                    continue;
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        };
+    }
+
     loop {
-        let event = {parser.parse_next()?};
+        // Records are flushed as they are written so stopping here never leaves a partial line
+        if options.cancel.is_cancelled() {
+            sinks.finish(sink, stats, options)?;
+            sink.finish()?;
+            if let Some(explain) = explain.as_mut() {
+                explain.finish(&ctx.reporter)?;
+            }
+            return Err(Box::new(Interrupted { objects: stats.objects_scanned,
+                                              records: stats.rows_written }));
+        }
+
+        let event = recover!(next_event(parser, ctx, "in_network"));
         match event {
             JsonEvent::StartObject => {
                 cb += 1;
+                // Only the elements from start_offset to end_offset are read
+                if cb == 1 {
+                    let (index, offset) = (item, ctx.offset());
+                    if options.end_offset.is_some_and(|end| end.reached(index, offset)) {
+                        ctx.reporter.status(format_args!("Reached the end offset at in_network item {}, stopping...", index));
+                        stop = true;
+                        break;
+                    }
+                    item += 1;
+                    if options.start_offset.is_some_and(|start| !start.reached(index, offset)) {
+                        explain!(Reason::BeforeStartOffset);
+                        recover!(ff_to_next_obj(parser, ctx, &mut cb, &mut sq));
+                        continue;
+                    }
+                }
+                // Start of an element, keep its events in case it matches
+                if cb == 1 && (copies.raw_out.is_some() || copies.extract.is_some()) {
+                    start_raw_capture(ctx, JsonEvent::StartObject);
+                }
             }
             JsonEvent::EndObject => {
                 cb -= 1;
+                if cb == 0 && options.normalize_codes {
+                    network.billing_code_type = normalize_code_type(&network.billing_code_type);
+                }
+                // Why the item ends without anything to write, checked in this order
+                let skipped = if cb != 0 {
+                    None
+                }
+                else if network.billing_code == "" {
+                    Some(Reason::NoBillingCode)
+                }
+                else if network.negotiated_rates.is_empty() {
+                    Some(Reason::NoMatchingRates)
+                }
+                else if query.excludes_code(&network.billing_code_type, &network.billing_code) {
+                    Some(Reason::ExcludedCode)
+                }
+                else if !query.matches_name(&network.name, &network.description) {
+                    Some(Reason::NameNotMatched)
+                }
+                else if !query.check_version(&network.billing_code_type, &network.billing_code,
+                                             &network.billing_code_type_version) &&
+                        options.exclude_version_mismatch {
+                    Some(Reason::VersionMismatch)
+                }
+                else {
+                    None
+                };
+                if let Some(reason) = skipped {
+                    explain!(reason);
+                }
                 // ASSERTION: We will reach this only when we have something to write.
-                if cb == 0  && network.billing_code != "" && network.negotiated_rates.is_some() {
+                if cb == 0 && skipped.is_none() {
                     if header_written == false {
                         //_print_header(out)?;
-                        print_header2(&mut writer)?;
+                        print_header2(sink, options)?;
                         header_written = true;
                     }
 
@@ -865,15 +2536,49 @@ fn process_in_network<R: Read>(parser: &mut ReaderJsonParser<R>,
                                   &ref_map,
                                   out)?;
                     */
-                    print_record2(&network,
-                                  query,
-                                  &ref_map,
-                                  &mut writer)?;
+                    stats.rows_written += print_record2(&network,
+                                                        query,
+                                                        &ref_map,
+                                                        sink,
+                                                        options,
+                                                        &mut sinks,
+                                                        &lookups)?;
+                    stats.objects_matched += 1;
+
+                    if let Some(completion) = completion.as_mut() {
+                        for reference in network.negotiated_rates.iter().flat_map(|r| r.provider_references.iter()) {
+                            if let Some(members) = ref_map.get(reference)? {
+                                let npis = members.iter().filter_map(|m| m.split(',').next()?.parse().ok());
+                                completion.record(&network.billing_code_type, &network.billing_code, npis);
+                            }
+                        }
+                        if completion.is_complete() {
+                            ctx.reporter.status(format_args!("Every code of the query was found for every provider, stopping..."));
+                            stop = true;
+                        }
+                    }
+                    if sinks.limit_reached() {
+                        ctx.reporter.status(format_args!("{} rows matched, stopping...", options.limit.unwrap_or_default()));
+                        stop = true;
+                    }
 
-                    
+                    if let Some(events) = stop_raw_capture(ctx) {
+                        if let Some(extract) = copies.extract.as_mut() {
+                            extract.write_element(filter_element(&events, &ref_map))?;
+                        }
+                        if let Some(raw) = copies.raw_out.as_mut() {
+                            write_raw_element(raw, events)?;
+                        }
+                    }
                 }
                 // Clear for re-use
+                if cb == 0 {
+                    stop_raw_capture(ctx);
+                }
                 network.clear_entries();
+                if stop {
+                    break;
+                }
             }
             JsonEvent::StartArray => {
                 sq += 1;
@@ -906,29 +2611,18 @@ fn process_in_network<R: Read>(parser: &mut ReaderJsonParser<R>,
                 else if key == "negotiated_rates" {
                     // Reset state
                     state = State::undefined;
-                    let rates = process_negotiated_rates(parser, &ref_map);
-                    match rates {
-                        Ok(Some(rates)) => {
-                            network.negotiated_rates = Some(rates);
-                        }
-                        Ok(None) => {
-                            network.clear_entries();
-                            ff_to_next_obj(parser, &mut cb, &mut sq)?;
-                        }
-                        Err(e) => {
-                            return Err(e);
-                        }
+                    network.negotiated_rates.clear();
+                    let found = recover!(process_negotiated_rates(parser, ctx, &ref_map, &mut network.negotiated_rates, options));
+                    if !found {
+                        explain!(Reason::NoMatchingRates);
+                        network.clear_entries();
+                        stop_raw_capture(ctx);
+                        recover!(ff_to_next_obj(parser, ctx, &mut cb, &mut sq));
                     }
                 }
                 else { 
-                    UNSUPPORTED_KEYS.with(|set| {
-                        if !set.borrow().contains(key.as_ref()) {
-                            eprintln!("Unsupported key {} found in Network",
-                                     key.as_ref());
-                        }
-                        set.borrow_mut().insert(String::from(key.as_ref()));
-                    });
-                    bypass_key(parser)?;
+                    unsupported_key(ctx, key.as_ref(), "in_network")?;
+                    recover!(bypass_key(parser, ctx));
                 }
             }
             JsonEvent::String(s) => {
@@ -937,6 +2631,15 @@ fn process_in_network<R: Read>(parser: &mut ReaderJsonParser<R>,
                }
                else if state == State::name {
                    network.name.push_str(s.as_ref());
+
+                   // The name can rule the item out before its code is read
+                   if !options.name_prefilter.is_empty() &&
+                      !options.name_prefilter.iter().any(|p| contains_ignore_ascii_case(&network.name, p)) {
+                       explain!(Reason::NamePrefilter);
+                       network.clear_entries();
+                       stop_raw_capture(ctx);
+                       recover!(ff_to_next_obj(parser, ctx, &mut cb, &mut sq));
+                   }
                }
                else if state == State::billing_code_type {
                    network.billing_code_type.push_str(s.as_ref());
@@ -949,59 +2652,84 @@ fn process_in_network<R: Read>(parser: &mut ReaderJsonParser<R>,
                    network.billing_code.push_str(s.as_ref());
 
                    
-                   obj_count += 1;
-                   if obj_count % INCR == 1 {
-
-                       #[cfg(not(test))] {
-                           _progress.set_position(obj_count);
-                       }
+                   stats.objects_scanned += 1;
+                   if stats.objects_scanned % PROGRESS_INTERVAL == 1 {
+                       report_progress(ctx, stats, false);
                    }
                    
 
-                   network.billing_code.make_ascii_uppercase();
+                   if !options.match_policy.case_sensitive {
+                       network.billing_code.make_ascii_uppercase();
+                   }
+                   if options.normalize_codes {
+                       network.billing_code = normalize_code(&network.billing_code);
+                   }
 
-                   if !codeset.contains(&network.billing_code) &&
-                      !codeset.contains("*") {
+                   if !codes.matches(&network.billing_code) {
+                       explain!(Reason::CodeNotInQuery);
                        network.clear_entries();
-                       ff_to_next_obj(parser, &mut cb, &mut sq)?;
+                       stop_raw_capture(ctx);
+                       recover!(ff_to_next_obj(parser, ctx, &mut cb, &mut sq));
                    }
                }
                else if state == State::description {
                    network.description.push_str(s.as_ref());
                }
                else if state == State::undefined {
-                   panic!("String encountered in asa::process_in_network with unsupported key");
+                   let err = unexpected(ctx, "in_network", &JsonEvent::String(s));
+                   recover!(Err::<(), _>(err));
                }
 
                // Reset the state
                state = State::undefined;
 
             }
+            JsonEvent::Eof => {
+                return Err(Box::new(unexpected_eof(ctx, "in_network")));
+            }
             _ => {}
 
         }
     }
 
-    #[cfg(not(test))] {
-        _progress.finish_with_message("Done processing in_network.");
+    if let Some(seen) = sinks.seen.as_ref() {
+        stats.duplicates_skipped = seen.duplicates;
+    }
+    stats.in_network_items = item;
+    sinks.finish(sink, stats, options)?;
+    sink.finish()?;
+    if let Some(explain) = explain.as_mut() {
+        explain.finish(&ctx.reporter)?;
+        if options.run_details {
+            stats.skipped = explain.counts().clone();
+        }
     }
+    report_progress(ctx, stats, true);
     
-    Ok(())
+    Ok(stop)
 }
 
 
 /// Helper function to process_provider_refs. Works on the provider_groups array.
 /// Write in tin type and tin values for matching NPIs and marks them as needing group id.
 /// Writing in a group id is handed in process_provider_refs.
-fn process_provider_groups<R: Read>(parser: &mut ReaderJsonParser<R>,
+/// If the query selects whole groups, returns every (npi, tin_type, tin_value) in the array,
+/// otherwise returns an empty Vec.
+fn process_provider_groups<R: Read>(parser: &mut JsonParser<R>,
+                                    ctx: &mut ParseContext,
                                     query: &mut Query,
                                     //providers: &mut Vec<Provider>,
-                                    ) -> Result<(), Box<dyn std::error::Error>> {
+                                    ) -> Result<Vec<Member>, Box<dyn std::error::Error>> {
 
     // To hold the tin type and tin values temporarily. 
     let mut t_type: Option<String> = None;
     let mut t_value: Option<String> = None;
 
+    // Members of the groups, kept only when the query selects groups
+    let collect = query.selects_groups();
+    let mut group_npis: Vec<u64> = Vec::new();
+    let mut members: Vec<Member> = Vec::new();
+
     #[derive(PartialEq)]
     enum CaptureState {
         Ttype,
@@ -1015,13 +2743,17 @@ fn process_provider_groups<R: Read>(parser: &mut ReaderJsonParser<R>,
     let mut sq = 0; // For "[" counting
     let mut cb = 0; // For "{" counting
 
-    let npi_set = query.make_npi_set();
-
-    let providers = &mut query.providers;
-
     // Read from the parser
     loop {
-        let event = {parser.parse_next()?};
+        let event = {next_event(parser, ctx, "provider_groups")?};
+        // Some files give npis as strings ("1234567893"), they are read as numbers
+        let event = match event {
+            JsonEvent::String(npi) if state == CaptureState::Npi => {
+                ctx.string_npis += 1;
+                JsonEvent::Number(npi)
+            }
+            event => event,
+        };
         match event {
             JsonEvent::StartObject => {
                 cb += 1;
@@ -1039,12 +2771,9 @@ fn process_provider_groups<R: Read>(parser: &mut ReaderJsonParser<R>,
                     }
 
                     // write tin_type and tin_values
-                    for p in providers.iter_mut() {
-                        if p.needs_tin == true {
-                            p.tin_value = t_value.clone();
-                            p.tin_type  = t_type.clone();
-                            p.needs_tin = false;
-                        }
+                    query.assign_tin(t_type.as_deref().unwrap(), t_value.as_deref().unwrap());
+                    for npi in group_npis.drain(..) {
+                        members.push((npi, t_type.clone().unwrap(), t_value.clone().unwrap()));
                     }
 
                     // reset them
@@ -1076,15 +2805,8 @@ fn process_provider_groups<R: Read>(parser: &mut ReaderJsonParser<R>,
                     continue
                 }
                 else {
-                    UNSUPPORTED_KEYS.with(|set| {
-                        if !set.borrow().contains(key.as_ref()) {
-                            eprintln!("Unsupported key {} found in \
-                                      provider_groups",key.as_ref());
-                        }
-
-                        set.borrow_mut().insert(String::from(key.as_ref()));
-                    });
-                    bypass_key(parser)?;
+                    unsupported_key(ctx, key.as_ref(), "provider_groups")?;
+                    bypass_key(parser, ctx)?;
                 }
             
             }
@@ -1100,74 +2822,89 @@ fn process_provider_groups<R: Read>(parser: &mut ReaderJsonParser<R>,
                     state = CaptureState::Undefined;
                 }
                 else {
-                    panic!("Error: string value encountered in invalid state in asa:process_provider_groups");
+                    return Err(Box::new(unexpected(ctx, "provider_groups", &JsonEvent::String(value))));
                 }
 
             }
             // Should be in state Npi here, and process the npi's
             JsonEvent::Number(num) => {
-                let curr_npi: u64 = num.as_ref().parse().expect("Failed to parse npi");
                 if state != CaptureState::Npi {
-                    panic!("Error: number value encountered in invalid state in asa::process_provider_groups");
+                    return Err(Box::new(unexpected(ctx, "provider_groups", &JsonEvent::Number(num))));
                 }
+                let curr_npi: u64 = match num.as_ref().parse() {
+                    Ok(npi) => npi,
+                    Err(_) => {
+                        return Err(Box::new(ParseError::InvalidNpi { context: "provider_groups",
+                                                                     offset: ctx.offset(),
+                                                                     value: num.to_string() }));
+                    }
+                };
 
+                if collect {
+                    group_npis.push(curr_npi);
+                }
 
                 // Speed up in case of lots of NPIs 
-                if !npi_set.contains(&curr_npi) {
+                if !query.has_npi(curr_npi) {
                     continue;
                 }
 
-                for i in (0..providers.len()).rev() {
-                    if providers[i].npi != curr_npi {
-                        continue;
-                    }
-                    else if providers[i].npi == curr_npi {
-                        match providers[i] {
-                            // TODO check assertion will not miss tin
-                            Provider {group_id: None, tin_value: None,.. } => {
-                                providers[i].needs_gid = true;
-                                providers[i].needs_tin  = true;
-                                break;
-                            }
-                            _ => {
-                                let mut p = Provider::new(curr_npi);
-                                p.needs_gid = true;
-                                p.needs_tin = true;
-                                providers.push(p);
-                                break;
-                            }
-                        }
-                    } // End npi match section
-                } // End provider vector loop
+                // Flags the last Provider of the npi, or a new one if it already has a group
+                query.flag_member(curr_npi);
             } // End JsonEvent::Number
+            JsonEvent::Eof => {
+                return Err(Box::new(unexpected_eof(ctx, "provider_groups")));
+            }
             _ => {}
         }
     }
 
-    Ok(())
+    Ok(members)
 
 }
 
 
 /// Processes the provider_references array using the helper function process_provider_groups
 /// Will create a new Provider struct for npis with multiple tins, and/or multiple group ids.
-fn process_provider_refs<R: Read>(parser: &mut ReaderJsonParser<R>,
+/// Every member of a group selected by the query is added as a Provider as well.
+/// Elements whose group has a provider from the query are written to extract if given.
+/// Returns the number of elements without a provider_group_id, or an Interrupted error once cancel
+/// is cancelled.
+fn process_provider_refs<R: Read>(parser: &mut JsonParser<R>,
+                                  ctx: &mut ParseContext,
                                   //providers: &mut Vec<Provider>,
                                   query: &mut Query,
-                                  ) -> Result<(), Box<dyn std::error::Error>> {
+                                  mut extract: Option<&mut Extract>,
+                                  cancel: &CancelToken,
+                                  ) -> Result<u64, Box<dyn std::error::Error>> {
 
     // To hold the provider_group_id number 
     let mut pg_id: Option<String> = None; 
+    let mut missing_gids: u64 = 0;
+
+    // Members of the element's provider groups, in case the group is selected
+    let mut members: Vec<Member> = Vec::new();
+
+    // Providers are looked up by npi while reading the groups
+    query.index_providers();
+    ctx.string_npis = 0;
 
     // To count '{' and '['
     let mut cb = 0;
     let mut sq = 0;
     
     loop {
-        let event = {parser.parse_next()?};
+        if cancel.is_cancelled() {
+            return Err(Box::new(Interrupted { objects: 0, records: 0 }));
+        }
+
+        let event = {next_event(parser, ctx, "provider_references")?};
         match event {
             JsonEvent::StartObject => {
                 cb += 1;
+                if cb == 1 && extract.is_some() {
+                    start_raw_capture(ctx, JsonEvent::StartObject);
+                }
             }
             JsonEvent::EndObject => {
                 cb -= 1;
@@ -1175,25 +2912,20 @@ fn process_provider_refs<R: Read>(parser: &mut ReaderJsonParser<R>,
                 // All Provider structs that need g_id have been flagged.
                 // We merely need to record the value (if there is one) and reset the flags.
                 if cb == 0 {
-                    // Handle care of missing pg_id
+                    let events = stop_raw_capture(ctx);
                     if pg_id.is_none() {
-                        // Clean up needs_gid fields
-                        for p in query.providers.iter_mut() {
-                            if p.needs_gid == true {
-                                p.needs_gid = false;
-                            }
-                        }
-                        continue;
+                        missing_gids += 1;
+                    }
+                    // A missing pg_id only clears the needs_gid flags
+                    let mut relevant = query.assign_group_id(pg_id.as_deref());
+                    if let Some(gid) = pg_id.take() {
+                        relevant |= query.add_group_members(&gid, members.drain(..));
                     }
+                    members.clear();
 
-                    for p in query.providers.iter_mut() {
-                        if p.needs_gid == true {
-                            p.group_id = pg_id.clone();
-                            p.needs_gid = false;
-                        }
+                    if let (Some(extract), Some(events)) = (extract.as_mut(), events) && relevant {
+                        extract.write_element(events)?;
                     }
-                    // Reset group_id to None
-                    pg_id = None;
                 }
 
             }
@@ -1211,21 +2943,15 @@ fn process_provider_refs<R: Read>(parser: &mut ReaderJsonParser<R>,
                     continue;
                 }
                 else if key == "provider_groups" {
-                    process_provider_groups(parser, 
-                                          //providers,
-                                            query)?;
+                    members.extend(process_provider_groups(parser, ctx, 
+                                                         //providers,
+                                                           query)?);
                     
                     
                 }
                 else {
-                    UNSUPPORTED_KEYS.with(|set| {
-                        if !set.borrow().contains(key.as_ref()) {
-                            eprintln!("Unsupported key {} found in \
-                                      provider_references", key.as_ref());
-                        }
-                        set.borrow_mut().insert(String::from(key.as_ref()));
-                    });
-                    bypass_key(parser)?;
+                    unsupported_key(ctx, key.as_ref(), "provider_references")?;
+                    bypass_key(parser, ctx)?;
 
                 }
             }
@@ -1235,61 +2961,243 @@ fn process_provider_refs<R: Read>(parser: &mut ReaderJsonParser<R>,
                 pg_id = Some(val.to_string());
             }
             
+            JsonEvent::Eof => {
+                return Err(Box::new(unexpected_eof(ctx, "provider_references")));
+            }
+
+            _ => {}
+        }
+    }
+
+    Ok(missing_gids)
+}
 
+/// Assumes that we have not yet entered the array but are going to do this next
+/// Consider putting this in a general JSON tool module? 
+fn skip_array<R: Read>(parser: &mut JsonParser<R>,
+                       ctx: &mut ParseContext,
+                       mut sq: u64) -> Result<(), Box<dyn std::error::Error>> {
+
+    //let mut sq = 0;
+
+    loop {
+        let event = {next_event(parser, ctx, "skipped array")?};
+        match event {
+            JsonEvent::StartArray => {
+            sq += 1;
+            }
+            JsonEvent::EndArray => {
+                sq -= 1;
+                if sq == 0 {
+                    break;
+                }
+            }
+            JsonEvent::Eof => {
+                return Err(Box::new(unexpected_eof(ctx, "skipped array")));
+            }
             _ => {}
         }
     }
 
-    Ok(())
+    Ok(())
+        
+}
+
+
+/// Metadata of a file and, when asked for, the number of items in its arrays.
+/// Returned by read_meta.
+#[derive(Debug)]
+pub struct FileMeta {
+    meta: Meta,
+    pub provider_references: Option<u64>,
+    pub in_network: Option<u64>,
+}
+
+impl FileMeta {
+    /// Prints the metadata (and item counts if present) to out, one "key: value" per line.
+    /// Missing values are printed as null.
+    pub fn print_out(&self, out: &mut impl Write) -> Result<(), std::io::Error> {
+        for (key, value) in self.meta.fields() {
+            writeln!(out, "{}: {}", key, value.as_deref().unwrap_or("null"))?;
+        }
+        if let Some(n) = self.provider_references {
+            writeln!(out, "provider_references: {}", n)?;
+        }
+        if let Some(n) = self.in_network {
+            writeln!(out, "in_network: {}", n)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the array (or value) that follows a key and returns the number of items in it.
+fn count_items<R: Read>(parser: &mut JsonParser<R>,
+                        ctx: &mut ParseContext,
+                       ) -> Result<u64, Box<dyn std::error::Error>> {
+    let mut depth = 0;
+    let mut items = 0;
+
+    loop {
+        let event = {next_event(parser, ctx, "skipped array")?};
+        match event {
+            JsonEvent::StartObject | JsonEvent::StartArray => {
+                if depth == 1 {
+                    items += 1;
+                }
+                depth += 1;
+            }
+            JsonEvent::EndObject | JsonEvent::EndArray => {
+                depth -= 1;
+            }
+            JsonEvent::Eof => {
+                return Err(Box::new(unexpected_eof(ctx, "skipped array")));
+            }
+            _ => {
+                if depth == 1 {
+                    items += 1;
+                }
+            }
+        }
+        if depth == 0 {
+            break;
+        }
+    }
+
+    Ok(items)
 }
 
-/// Assumes that we have not yet entered the array but are going to do this next
-/// Consider putting this in a general JSON tool module? 
-fn skip_array<R: Read>(parser: &mut ReaderJsonParser<R>,
-                       mut sq: u64) -> Result<(), Box<dyn std::error::Error>> {
+/// Reads the metadata of the file provided by source, stopping as soon as all of it is found.
+/// With with_counts the whole file is read to count the provider_references and in_network
+/// items as well.
+pub fn read_meta(source: &dyn DataSource,
+                 buff_size: usize,
+                 with_counts: bool,
+                ) -> Result<FileMeta, Box<dyn std::error::Error>> {
+
+    let mut ctx = ParseContext::new();
+    let reader = ctx.track_offsets(BufReader::with_capacity(buff_size, source.open(&SharedReporter::default())?));
+    let mut parser = JsonParser::new(reader);
+    let mut depth = 0;
 
-    //let mut sq = 0;
+    let mut file_meta = FileMeta { meta: Meta::new(), provider_references: None, in_network: None };
+    let mut meta_key: Option<String> = None;
 
     loop {
-        let event = {parser.parse_next()?};
+        if file_meta.meta.is_complete() && !with_counts {
+            break;
+        }
+
+        let event = {next_event(&mut parser, &mut ctx, "top level")?};
         match event {
-            JsonEvent::StartArray => {
-            sq += 1;
+            JsonEvent::StartObject | JsonEvent::StartArray => {
+                depth += 1;
             }
-            JsonEvent::EndArray => {
-                sq -= 1;
-                if sq == 0 {
+            JsonEvent::EndObject | JsonEvent::EndArray => {
+                depth -= 1;
+                if depth == 0 {
                     break;
                 }
             }
+            JsonEvent::ObjectKey(key) if depth == 1 => {
+                meta_key = None;
+                if key == "reporting_entity_name" || 
+                   key == "reporting_entity_type" ||
+                   key == "last_updated_on"       ||
+                   key == "version"
+                {
+                    meta_key = Some(String::from(key));
+                }
+                else if key == "provider_references" {
+                    let n = count_items(&mut parser, &mut ctx)?;
+                    if with_counts {
+                        file_meta.provider_references = Some(n);
+                    }
+                }
+                else if key == "in_network" {
+                    let n = count_items(&mut parser, &mut ctx)?;
+                    if with_counts {
+                        file_meta.in_network = Some(n);
+                    }
+                }
+                else {
+                    bypass_key(&mut parser, &mut ctx)?;
+                }
+            }
+            JsonEvent::String(value) if depth == 1 => {
+                if let Some(key) = meta_key.take() {
+                    file_meta.meta.set(&key, value.as_ref());
+                }
+            }
+            JsonEvent::Eof => {
+                return Err(Box::new(unexpected_eof(&ctx, "top level")));
+            }
             _ => {}
         }
     }
 
-    Ok(())
-        
+    Ok(file_meta)
 }
 
-
-/// Processes query by looking for matching records in file specified by data_path.
-/// buff_size is used to determine the buffer size to use when stream parsing the gz compressed JSON
-/// file.
-/// Prints status and error messages to stderr
-/// Print results (as CSV) to out.
+/// Processes query by looking for matching records in the file provided by source.
+/// buff_size is used to determine the buffer size to use when stream parsing the compressed JSON
+/// file (gzip, or zstd with the `zstd` feature).
+/// Hands status messages and warnings to options.reporter (stderr unless set), and draws
+/// progress as a bar (see progress_bar).
+/// Writes the matching records to sink (e.g. a sink::CsvSink).
+/// Returns a RunSummary of the run, with its statistics, for the caller to display.
 pub fn run(query: &mut Query, 
-           data_path: &std::path::PathBuf, 
+           source: &dyn DataSource, 
            buff_size: usize,
-           mut out: impl Write) -> Result<(), Box<dyn std::error::Error>> {
+           options: &Options,
+           sink: &mut dyn RecordSink) -> Result<RunSummary, Box<dyn std::error::Error>> {
+    run_with_progress(query, source, buff_size, options, sink, progress_bar(options.reporter.clone()))
+}
 
-    let mut file = File::open(data_path)?;
-    let mut gz = GzDecoder::new(file);
-    let mut reader = BufReader::with_capacity(buff_size, &mut gz);
+/// Same as run, but hands the progress of the run to progress instead of drawing a bar, so an
+/// embedding application can show it its own way (progress must be 'static, e.g. a closure
+/// sending to a channel). It is called every PROGRESS_INTERVAL in_network objects, and once more
+/// with finished set at the end of in_network.
+pub fn run_with_progress(query: &mut Query,
+                         source: &dyn DataSource,
+                         buff_size: usize,
+                         options: &Options,
+                         sink: &mut dyn RecordSink,
+                         progress: impl FnMut(&Progress) + 'static) -> Result<RunSummary, Box<dyn std::error::Error>> {
+
+    let start = Stopwatch::start();
+    let mut stats = RunStats::default();
+
+    // Price filters and output options may come from the query file as well
+    let options = &options.with_query(query);
+    query.match_policy = options.match_policy;
+    if options.normalize_codes {
+        query.normalize_codes();
+    }
 
-    let mut parser = ReaderJsonParser::new(reader);
+    let mut raw_out = match &options.raw_out {
+        Some(path) => Some(create_output_with(path, options.compression)?),
+        None => None,
+    };
+    let mut extract = match &options.extract {
+        Some(path) => Some(Extract::new(create_output_with(path, options.compression)?)),
+        None => None,
+    };
+
+    // Counts decompressed bytes and the time spent reading them across both passes if a reset
+    // is needed
+    let read_time = Rc::new(Cell::new(Duration::ZERO));
+    let (counted, bytes_read) = CountingReader::new(TimedReader::new(source.open(&options.reporter)?, read_time.clone()));
+    let mut ctx = ParseContext { strict: options.strict,
+                                 progress: Some((Box::new(progress), bytes_read.clone())),
+                                 reporter: options.reporter.clone(),
+                                 ..ParseContext::new() };
+    let mut reader = ctx.track_offsets(BufReader::with_capacity(buff_size, counted));
+    let mut parser = JsonParser::with_backend(reader, options.parser);
     let mut depth = 0;
 
     let mut metadata = Meta::new();
     let mut meta_key: Option<String> = None;
+    let mut no_providers = false;
 
     // If we hit in_network before provider_references
     // we flag needs_reset, then when we hit provider_references
@@ -1301,9 +3209,15 @@ pub fn run(query: &mut Query,
     let mut providers_seen: bool = false;
     let mut network_seen: bool = false;
     let mut needs_reset: bool = false;
+    // Set when in_network is left with options.stop_when_complete, options.limit or
+    // options.end_offset, or not read with options.dry_run
+    let mut stopped_early = false;
+    // Set once the file is read again, what is read again counts as the overhead of the reset
+    let mut reset_done = false;
+    let mut sink = TimedSink::new(sink);
 
     loop {
-        let event = {parser.parse_next()?};
+        let event = {next_event(&mut parser, &mut ctx, "top level")?};
         match event {
             JsonEvent::StartObject => {
                 depth += 1;
@@ -1314,15 +3228,11 @@ pub fn run(query: &mut Query,
                  // By the time we reach the end of the object we should
                  // have seen both provider_references and in_network
                  if providers_seen == false {
-                     eprintln!("FATAL ERROR: provider_references not found in file.");
+                     return Err(Box::new(ParseError::MissingSection("provider_references")));
                  }
 
                  if network_seen == false {
-                     eprintln!("FATAL ERROR: in_network not found in file.");
-                 }
-
-                 if providers_seen == false || network_seen == false {
-                     panic!("");
+                     return Err(Box::new(ParseError::MissingSection("in_network")));
                  }
 
                  if depth == 0 && needs_reset == false {
@@ -1331,15 +3241,16 @@ pub fn run(query: &mut Query,
                  else if depth == 0 && needs_reset == true {
                      // RESET
                     
-                     eprintln!("Resetting...");
+                     ctx.reporter.status(format_args!("Resetting..."));
 
-                     file = File::open(data_path)?;
-                     gz = GzDecoder::new(file);
-                     reader = BufReader::with_capacity(buff_size, &mut  gz);
+                     let reopened = TimedReader::new(source.reopen(&options.reporter)?, read_time.clone());
+                     let (counted, _) = CountingReader::with_counter(reopened, bytes_read.clone());
+                     reader = ctx.track_offsets(BufReader::with_capacity(buff_size, counted));
 
-                     parser = ReaderJsonParser::new(reader);
+                     parser = JsonParser::with_backend(reader, options.parser);
                      
                      needs_reset = false;
+                     reset_done = true;
                  }
             }
             JsonEvent::ObjectKey(key) => {
@@ -1357,15 +3268,40 @@ pub fn run(query: &mut Query,
 
                 else if key == "provider_references" {
                     providers_seen = true;
-                    eprintln!("Processing provider_references...");
-                    process_provider_refs(&mut parser, query)?;
+                    ctx.reporter.status(format_args!("Processing provider_references..."));
+                    let writing = match extract.as_mut() {
+                        Some(e) => e.begin_section("provider_references", &metadata)?,
+                        None => false,
+                    };
+                    // Not added up, provider_references is read again after a reset
+                    let started = Stopwatch::start();
+                    stats.missing_group_ids = process_provider_refs(&mut parser, &mut ctx, query,
+                                                                    extract.as_mut().filter(|_| writing),
+                                                                    &options.cancel)?;
+                    match reset_done {
+                        true => stats.timings.reset += started.elapsed(),
+                        false => stats.timings.provider_references += started.elapsed(),
+                    }
+                    let string_npis = ctx.string_npis;
+                    if string_npis > 0 && stats.string_npis == 0 {
+                        ctx.reporter.warn(format_args!("{} npis in provider_references are strings instead of numbers", string_npis));
+                    }
+                    stats.string_npis = string_npis;
+                    if writing {
+                        extract.as_mut().unwrap().end_section()?;
+                    }
 
                     // Exit early is there is nothing left to process
                     let stat: bool = query.stat_providers();
                     if !stat {
-                        eprintln!("No providers from query found in file.");
-                        eprintln!("Exiting early...");
-                        return Ok(());
+                        ctx.reporter.status(format_args!("Exiting early..."));
+                        no_providers = true;
+                        break;
+                    }
+                    if options.dry_run {
+                        ctx.reporter.status(format_args!("Dry run, in_network is not read"));
+                        stopped_early = true;
+                        break;
                     }
                                     
                 } // End provider_references key
@@ -1374,26 +3310,38 @@ pub fn run(query: &mut Query,
                     network_seen = true;
                     if providers_seen == false {
                         // Skip
-                        eprintln!("in_network seen first... skipping...");
-                        skip_array(&mut parser, 0)?;
+                        ctx.reporter.status(format_args!("in_network seen first... skipping..."));
+                        let started = Stopwatch::start();
+                        skip_array(&mut parser, &mut ctx, 0)?;
+                        stats.timings.reset += started.elapsed();
                         needs_reset = true;
                         continue;
                     }
                     else {
-                        eprintln!("Processing in_network...");
-                        process_in_network(&mut parser, query, &mut out)?;
+                        ctx.reporter.status(format_args!("Processing in_network..."));
+                        let writing = match extract.as_mut() {
+                            Some(e) => e.begin_section("in_network", &metadata)?,
+                            None => false,
+                        };
+                        let started = Stopwatch::start();
+                        let copies = ElementCopies { raw_out: raw_out.as_mut(),
+                                                     extract: extract.as_mut().filter(|_| writing) };
+                        stopped_early = process_in_network(&mut parser, &mut ctx, query, &mut sink, &mut stats, options,
+                                                           copies)?;
+                        stats.timings.in_network += started.elapsed();
+                        if writing {
+                            extract.as_mut().unwrap().end_section()?;
+                        }
+                        // The rest of the file isn't read
+                        if stopped_early {
+                            break;
+                        }
                     }
                 }
 
                 else {
-                    UNSUPPORTED_KEYS.with(|set| {
-                        if !set.borrow().contains(key.as_ref()) {
-                            eprintln!("Unsupported key {} found in \
-                                       top level", key.as_ref());
-                        }
-                        set.borrow_mut().insert(String::from(key.as_ref()));
-                    });
-                    bypass_key(&mut parser)?;
+                    unsupported_key(&mut ctx, key.as_ref(), "top level")?;
+                    bypass_key(&mut parser, &mut ctx)?;
                 }
 
             }
@@ -1406,37 +3354,77 @@ pub fn run(query: &mut Query,
                     panic!("Key for field not saved correctly in asa::run");
                 }
 
-                metadata.add(&meta_key.unwrap(), value.as_ref())?;
-                meta_key = None;
+                let key = meta_key.take().unwrap();
+                if key == "version" {
+                    set_schema_version(&mut ctx, value.as_ref());
+                }
+                // Checked as soon as it is read, so a stale file is rejected before in_network.
+                // The clock is only read when there is an age to check, wasm32 has none
+                if key == "last_updated_on" && options.max_age_days.is_some() {
+                    check_freshness(Some(value.as_ref()), options, today())?;
+                }
+                metadata.set(&key, value.as_ref());
             }
 
             JsonEvent::Eof => {
-                panic!("ERROR: Either the programming logic is wrong or, the JSON is corrupted!");
+                return Err(Box::new(unexpected_eof(&ctx, "top level")));
             }
 
             _ => {}
         }
     }
 
-    UNSUPPORTED_KEYS.with(|set| {
-        let is_empty = set.borrow().is_empty();
-        if !is_empty {
-            eprintln!("Found the following unsupported keys");
-            for key in set.borrow().iter() {
-                eprintln!("{}", key);
-            }
-        }
-    });
+    if let Some(raw) = raw_out.as_mut() {
+        raw.flush()?;
+    }
+    if let Some(e) = extract {
+        e.finish(&metadata)?;
+    }
+    if metadata.last_updated_on.is_none() && !stopped_early && options.max_age_days.is_some() {
+        check_freshness(None, options, today())?;
+    }
+    if metadata.version.is_none() && !no_providers && !stopped_early {
+        ctx.reporter.warn(format_args!("the file declares no schema version, it was read as 1.x"));
+    }
 
-    Ok(())
+    stats.decompressed_bytes = bytes_read.get();
+    stats.elapsed = start.elapsed();
+    stats.timings.read = read_time.get();
+    stats.timings.output = sink.elapsed;
+    stats.peak_rss_kb = peak_rss_kb();
+    stats.fixed_rates = ctx.fixed_rates;
+    stats.invalid_rates = ctx.invalid_rates;
+
+    let query = query.report();
+    Ok(RunSummary {
+        metadata: metadata.found(),
+        coverage: Coverage::new(&query, &stats),
+        stats,
+        unsupported_keys: ctx.unsupported_keys(),
+        query,
+        no_providers,
+    })
 
 }
 
 #[cfg(test)]
 mod test_asa {
     use super::*;
+    use std::cell::RefCell;
     use std::io::Cursor; // For testing
-    use crate::query::{Code};
+    use crate::query::{Code, Provider};
+    use crate::sink::{CsvSink, MemorySink};
+    use crate::source::Location;
+    use std::collections::HashMap;
+
+    /// Returns the prices process_negotiated_prices reads from parser.
+    fn negotiated_prices<R: Read>(parser: &mut JsonParser<R>,
+                                  ctx: &mut ParseContext,
+                                  options: &Options) -> Result<Vec<Price>, Box<dyn std::error::Error>> {
+        let mut prices = Pool::default();
+        process_negotiated_prices(parser, ctx, &mut prices, options)?;
+        Ok(prices.to_vec())
+    }
 
     #[test]
     fn test_process_provider_refs_basic() {
@@ -1481,7 +3469,7 @@ mod test_asa {
 
         let cursor = Cursor::new(json);
 
-        let mut parser = ReaderJsonParser::new(cursor);
+        let mut parser = JsonParser::new(cursor);
 
         // Make query structs
         let p_ = Provider::new(3001); // Case missing tin_value
@@ -1502,7 +3490,7 @@ mod test_asa {
         q_test.providers = p_test;
 
         // Process via function call to mutate
-        let _ = process_provider_refs(&mut parser, &mut q_test);
+        let _ = process_provider_refs(&mut parser, &mut ParseContext::new(), &mut q_test, None, &CancelToken::new());
 
         // Make Structs to compare to
 
@@ -1590,6 +3578,11 @@ mod test_asa {
         check[3].recorded = true;
         check[6].recorded = true;
         check[7].recorded = true;
+        check[0].records = 2;
+        check[2].records = 2;
+        check[3].records = 2;
+        check[6].records = 2;
+        check[7].records = 2;
 
         // Push codes to code vec
         codes.push(c0);
@@ -1605,7 +3598,7 @@ mod test_asa {
         q.codes = codes; // Move to Query to call log_code
 
         // Mutate codes to mark recorded 
-        let _ = q.log_code(&c, &t);
+        let _ = q.log_code(&c, &t, 2);
 
         assert_eq!(q.codes, check);
 
@@ -1644,6 +3637,8 @@ mod test_asa {
         // Hard code expected values to check vector
         check[0].recorded = true;
         check[4].recorded = true;
+        check[0].records = 3;
+        check[4].records = 3;
 
         // Push unmodified originals to provider vec
         providers.push(n0);
@@ -1654,9 +3649,10 @@ mod test_asa {
 
         let mut q = Query::new();
         q.providers = providers; // Move to Query to call log_code
+        q.index_providers();
 
         // Mutate codes to mark recorded 
-        let _ = q.log_ref(&g);
+        let _ = q.log_ref(&g, 3);
 
         assert_eq!(q.providers, check);
 
@@ -1692,9 +3688,9 @@ mod test_asa {
         ]"#;
 
         let cursor = Cursor::new(json);
-        let mut parser = ReaderJsonParser::new(cursor);
+        let mut parser = JsonParser::new(cursor);
 
-        let res = process_negotiated_prices(&mut parser);
+        let res = negotiated_prices(&mut parser, &mut ParseContext::new(), &Options::new());
         let prices = res.unwrap();
 
         let mut check: Vec<Price> = Vec::new();
@@ -1703,7 +3699,7 @@ mod test_asa {
         p0.negotiated_type.push_str("alpha");
         p0.negotiated_rate.push_str("498.21");
         p0.expiration_date.push_str("9999-12-31");
-        p0.service_code.push_str("21 31 ");
+        p0.service_code.push_str("21 31");
         p0.billing_class.push_str("institutional");
 
         let mut p1 = Price::new();
@@ -1717,7 +3713,7 @@ mod test_asa {
         p2.negotiated_type.push_str("gamma");
         p2.negotiated_rate.push_str("null");
         p2.expiration_date.push_str("9999-12-31");
-        p2.service_code.push_str("77 ");
+        p2.service_code.push_str("77");
         p2.billing_class.push_str("nope");
         p2.billing_code_modifier.push_str("FSX");
 
@@ -1730,6 +3726,51 @@ mod test_asa {
         check.push(p2);
 
         assert_eq!(prices, check);
+
+        // Only prices with a wanted service code are kept
+        let mut options = Options::new();
+        options.service_codes = vec![String::from("31"), String::from("77")];
+        let mut parser = JsonParser::new(Cursor::new(json));
+        let prices = negotiated_prices(&mut parser, &mut ParseContext::new(), &options).unwrap();
+        let types: Vec<&str> = prices.iter().map(|p| p.negotiated_type.as_str()).collect();
+        assert_eq!(types, vec!["alpha", "gamma"]);
+
+        options.service_codes = vec![String::from("99")];
+        let mut parser = JsonParser::new(Cursor::new(json));
+        assert!(negotiated_prices(&mut parser, &mut ParseContext::new(), &options).unwrap().is_empty());
+
+        // Another list delimiter, still filtering on each service code
+        let mut options = Options::new();
+        options.list_delimiter = String::from("|");
+        options.service_codes = vec![String::from("31")];
+        let mut parser = JsonParser::new(Cursor::new(json));
+        let prices = negotiated_prices(&mut parser, &mut ParseContext::new(), &options).unwrap();
+        assert_eq!(prices.len(), 1);
+        assert_eq!(prices[0].service_code, "21|31");
+
+        // 9999-12-31 never expires
+        let expiring = r#"[{"negotiated_type":"alpha","expiration_date":"2024-06-30"},
+                           {"negotiated_type":"beta","expiration_date":"9999-12-31"},
+                           {"negotiated_type":"gamma"}]"#;
+        let mut options = Options::new();
+        options.as_of = parse_date("2024-07-01");
+        let mut parser = JsonParser::new(Cursor::new(expiring));
+        let prices = negotiated_prices(&mut parser, &mut ParseContext::new(), &options).unwrap();
+        let types: Vec<&str> = prices.iter().map(|p| p.negotiated_type.as_str()).collect();
+        assert_eq!(types, vec!["beta", "gamma"]);
+
+        options.as_of = parse_date("2024-06-30");
+        let mut parser = JsonParser::new(Cursor::new(expiring));
+        assert_eq!(negotiated_prices(&mut parser, &mut ParseContext::new(), &options).unwrap().len(), 3);
+
+        // Billing class and rate bounds, a missing rate is out of bounds
+        let mut options = Options::new();
+        options.billing_classes = vec![String::from("INSTITUTIONAL"), String::from("nope")];
+        options.max_rate = Some(500.0);
+        let mut parser = JsonParser::new(Cursor::new(json));
+        let prices = negotiated_prices(&mut parser, &mut ParseContext::new(), &options).unwrap();
+        let types: Vec<&str> = prices.iter().map(|p| p.negotiated_type.as_str()).collect();
+        assert_eq!(types, vec!["alpha"]);
         
     }
 
@@ -1741,7 +3782,7 @@ mod test_asa {
         let mut expected_out = String::from("");
         expected_out.push_str("npi,tin_type,tin_value,group_id,negotiation_arrangement,name,billing_code_type,billing_code_type_version,billing_code,description,negotiated_type,negotiated_rate,expiration_date,service_code,billing_class,billing_code_modifier\n");
 
-        expected_out.push_str("1701,ein,101,11,alpha,Item 1,Type 1,2022,CODE 1,Item 1,neg type 1,9.99,9999-12-31,A B C ,class 1,null\n");
+        expected_out.push_str("1701,ein,101,11,alpha,Item 1,Type 1,2022,CODE 1,Item 1,neg type 1,9.99,9999-12-31,A B C,class 1,null\n");
 
 
         // Case normal input
@@ -1760,7 +3801,7 @@ mod test_asa {
 
         let mut q2 = q.clone();
 
-        let res = run(&mut q, &filepath1, 256, &mut buffer);
+        let res = run(&mut q, &Location::Path(filepath1), 256, &Options::new(), &mut CsvSink::new(&mut buffer));
         match res {
             Err(_) => {
                 eprintln!("ERROR when processing run!");
@@ -1772,13 +3813,23 @@ mod test_asa {
         
         assert_eq!(output, expected_out);
 
+        // One row per service code
+        let mut options = Options::new();
+        options.explode_service_codes = true;
+        let mut buffer = Vec::new();
+        let mut q3 = q2.clone();
+        run(&mut q3, &Location::Path(std::path::PathBuf::from(path_str1)), 256, &options, &mut CsvSink::new(&mut buffer)).unwrap();
+        let output = String::from_utf8(buffer).unwrap();
+        let service_codes: Vec<&str> = output.lines().skip(1).map(|l| l.split(',').nth(13).unwrap()).collect();
+        assert_eq!(service_codes, vec!["A", "B", "C"]);
+
         let mut buffer2 = Vec::new();
 
         // Case 2 in_network and provider_references are swapped
         let path_str2 = "tests/testfiles/data_files/backward_basic.json.gz";
         let filepath2: std::path::PathBuf = std::path::PathBuf::from(path_str2);
 
-        let res2 = run(&mut q2, &filepath2, 256, &mut buffer2);
+        let res2 = run(&mut q2, &Location::Path(filepath2), 256, &Options::new(), &mut CsvSink::new(&mut buffer2));
         match res2 {
             Err(_) => {
                 eprintln!("ERROR when processing run!");
@@ -1792,4 +3843,815 @@ mod test_asa {
 
     }
 
+    #[test]
+    fn test_string_ids() {
+        let json = r#"[{"provider_group_id":"12",
+                        "provider_groups":[{"npi":["1701",55],"tin":{"type":"ein","value":"9"}}]}]"#;
+        let mut parser = JsonParser::new(Cursor::new(json));
+        let mut q = Query::new();
+        q.providers.push(Provider::new(1701));
+
+        let mut ctx = ParseContext::new();
+        assert_eq!(process_provider_refs(&mut parser, &mut ctx, &mut q, None, &CancelToken::new()).unwrap(), 0);
+        assert_eq!(q.providers[0].group_id.as_deref(), Some("12"));
+        assert_eq!(q.providers[0].tin_value.as_deref(), Some("9"));
+        assert_eq!(ctx.string_npis, 1);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        // npi that is not an integer
+        let json = r#"[{"npi":[1.5],"tin":{"type":"ein","value":"1"}}]"#;
+        let mut parser = JsonParser::new(Cursor::new(json));
+        let mut q = Query::new();
+        q.providers.push(Provider::new(1));
+
+        let err = process_provider_groups(&mut parser, &mut ParseContext::new(), &mut q).unwrap_err();
+        assert!(matches!(err.downcast_ref::<ParseError>(),
+                         Some(ParseError::InvalidNpi { value, .. }) if value == "1.5"));
+
+        // Same for an npi string that is not a number
+        let json = r#"[{"npi":["12x"],"tin":{"type":"ein","value":"1"}}]"#;
+        let mut parser = JsonParser::new(Cursor::new(json));
+        let err = process_provider_groups(&mut parser, &mut ParseContext::new(), &mut q).unwrap_err();
+        assert!(matches!(err.downcast_ref::<ParseError>(),
+                         Some(ParseError::InvalidNpi { value, .. }) if value == "12x"));
+
+        // File cut off in the middle of the prices
+        let json = r#"[{"negotiated_type":"alpha","negotiated_rate":1.5"#;
+        let mut parser = JsonParser::new(Cursor::new(json));
+
+        let err = negotiated_prices(&mut parser, &mut ParseContext::new(), &Options::new()).unwrap_err();
+        assert!(err.downcast_ref::<ParseError>().is_some());
+    }
+
+    #[test]
+    fn test_strict_unsupported_key() {
+        let json = r#"[{"negotiated_type":"alpha","surprise":[1,2],"negotiated_rate":1.5}]"#;
+
+        // Skipped by default
+        let mut parser = JsonParser::new(Cursor::new(json));
+        let prices = negotiated_prices(&mut parser, &mut ParseContext::new(), &Options::new()).unwrap();
+        assert_eq!(prices[0].negotiated_rate, "1.5");
+
+        // Fatal in strict mode
+        let mut ctx = ParseContext { strict: true, ..ParseContext::new() };
+        let mut parser = JsonParser::new(Cursor::new(json));
+        let err = negotiated_prices(&mut parser, &mut ctx, &Options::new()).unwrap_err();
+
+        assert!(matches!(err.downcast_ref::<ParseError>(),
+                         Some(ParseError::UnsupportedKey { key, context: "negotiated_prices", .. })
+                         if key == "surprise"));
+
+        // Keys of a declared 2.0 file are expected, even in strict mode
+        let json = r#"[{"negotiated_type":"alpha","setting":"outpatient","negotiated_rate":1.5}]"#;
+        let mut ctx = ParseContext { strict: true, ..ParseContext::new() };
+        set_schema_version(&mut ctx, "1.3.1");
+        let mut parser = JsonParser::new(Cursor::new(json));
+        assert!(negotiated_prices(&mut parser, &mut ctx, &Options::new()).is_err());
+        set_schema_version(&mut ctx, "2.0.0");
+        let mut parser = JsonParser::new(Cursor::new(json));
+        let prices = negotiated_prices(&mut parser, &mut ctx, &Options::new()).unwrap();
+        assert_eq!(prices[0].negotiated_rate, "1.5");
+    }
+
+    #[test]
+    fn test_lenient_rates() {
+        let json = r#"[{"negotiated_rate":"$1,234.56"},{"negotiated_rate":1.5e3},
+                       {"negotiated_rate":"N/A"},{"negotiated_rate":9.9}]"#;
+        let mut ctx = ParseContext::new();
+        let mut parser = JsonParser::new(Cursor::new(json));
+        let prices = negotiated_prices(&mut parser, &mut ctx, &Options::new()).unwrap();
+
+        let rates: Vec<&str> = prices.iter().map(|p| p.negotiated_rate.as_str()).collect();
+        assert_eq!(rates, vec!["1234.56", "1500", "N/A", "9.9"]);
+        assert_eq!(ctx.fixed_rates, 2);
+        assert_eq!(ctx.invalid_rates, 1);
+
+        // Fixed rates are rounded as well, rates that are not numbers can't be
+        let mut options = Options::new();
+        options.rate_precision = Some(2);
+        let mut parser = JsonParser::new(Cursor::new(json));
+        let prices = negotiated_prices(&mut parser, &mut ParseContext::new(), &options).unwrap();
+        let rates: Vec<&str> = prices.iter().map(|p| p.negotiated_rate.as_str()).collect();
+        assert_eq!(rates, vec!["1234.56", "1500.00", "N/A", "9.90"]);
+
+        let mut options = Options::new();
+        options.rate_format = RateFormat::Fail;
+        let mut parser = JsonParser::new(Cursor::new(json));
+        let err = negotiated_prices(&mut parser, &mut ParseContext::new(), &options).unwrap_err();
+        assert!(matches!(err.downcast_ref::<ParseError>(),
+                         Some(ParseError::InvalidRate { value, .. }) if value == "$1,234.56"));
+    }
+
+    #[test]
+    fn test_print_header() {
+        let mut options = Options::new();
+        options.capture_extra = true;
+        options.header_map = vec![(String::from("npi"), String::from("provider_npi")),
+                                  (String::from("extra"), String::from("other"))];
+        let mut sink = MemorySink::new();
+        print_header2(&mut sink, &options).unwrap();
+        let header = sink.header.join(",");
+        assert!(header.starts_with("provider_npi,tin_type,"));
+        assert!(header.ends_with(",billing_code_modifier,other"));
+
+        options.code_descriptions = Some(std::path::PathBuf::from("descriptions.csv"));
+        let mut sink = MemorySink::new();
+        print_header2(&mut sink, &options).unwrap();
+        assert!(sink.header.join(",").ends_with(",billing_code_modifier,code_description,other"));
+
+        options.no_header = true;
+        let mut sink = MemorySink::new();
+        print_header2(&mut sink, &options).unwrap();
+        assert!(sink.header.is_empty());
+    }
+
+    #[test]
+    fn test_modifier_arrays() {
+        let json = r#"[{"negotiated_type":"a","billing_code_modifier":"26"},
+                       {"negotiated_type":"b","billing_code_modifier":["26","TC"],"service_code":["11"]},
+                       {"negotiated_type":"c","billing_code_modifier":[]}]"#;
+        let mut options = Options::new();
+        options.list_delimiter = String::from("|");
+        let mut parser = JsonParser::new(Cursor::new(json));
+        let prices = negotiated_prices(&mut parser, &mut ParseContext::new(), &options).unwrap();
+
+        let modifiers: Vec<&str> = prices.iter().map(|p| p.billing_code_modifier.as_str()).collect();
+        assert_eq!(modifiers, vec!["26", "26|TC", "null"]);
+        assert_eq!(prices[1].service_code, "11");
+    }
+
+    #[test]
+    fn test_capture_extra() {
+        let json = r#"[{"negotiated_type":"alpha","note":"x","tiers":[1,{"a":null}],"negotiated_rate":1.5},
+                       {"negotiated_type":"beta"}]"#;
+        let mut options = Options::new();
+        options.capture_extra = true;
+
+        let mut parser = JsonParser::new(Cursor::new(json));
+        let prices = negotiated_prices(&mut parser, &mut ParseContext::new(), &options).unwrap();
+
+        assert_eq!(prices[0].negotiated_rate, "1.5");
+        assert_eq!(prices[0].extra_json().unwrap(), r#"{"note":"x","tiers":[1,{"a":null}]}"#);
+        assert_eq!(prices[1].extra_json().unwrap(), "null");
+    }
+
+    #[test]
+    fn test_unsupported_key_counts() {
+        let json = r#"[{"negotiated_type":"a","extra":1},{"negotiated_type":"b","extra":{"x":2}}]"#;
+        let mut ctx = ParseContext::new();
+        let mut parser = JsonParser::new(Cursor::new(json));
+        negotiated_prices(&mut parser, &mut ctx, &Options::new()).unwrap();
+
+        let check = vec![UnsupportedKey { key: String::from("extra"), context: "negotiated_prices", count: 2 }];
+        assert_eq!(ctx.unsupported_keys(), check);
+    }
+
+    #[test]
+    fn test_skip_corrupt() {
+        // First element has a string where a price object should be
+        let json = r#"[
+            {"billing_code_type":"CPT","billing_code":"1",
+             "negotiated_rates":[{"provider_references":[7],
+                                  "negotiated_prices":["oops", {"negotiated_rate":1.0}]}]},
+            {"billing_code_type":"CPT","billing_code":"1",
+             "negotiated_rates":[{"provider_references":[7],
+                                  "negotiated_prices":[{"negotiated_rate":2.0}]}]}
+        ]"#;
+
+        let mut p = Provider::new(99);
+        p.group_id = Some(String::from("7"));
+        p.tin_type = Some(String::from("ein"));
+        p.tin_value = Some(String::from("5"));
+
+        let mut q = Query::new();
+        q.providers.push(p);
+        q.codes.push(Code::new(&String::from("CPT"), &String::from("1")));
+
+        let mut options = Options::new();
+
+        // Without skip_corrupt the run fails
+        let mut parser = JsonParser::new(Cursor::new(json));
+        let mut stats = RunStats::default();
+        let res = process_in_network(&mut parser, &mut ParseContext::new(), &mut q.clone(), &mut CsvSink::new(Vec::new()), &mut stats, &options, ElementCopies::default());
+        assert!(res.is_err());
+
+        // With it the second element is still written
+        options.skip_corrupt = true;
+        let mut parser = JsonParser::new(Cursor::new(json));
+        let mut stats = RunStats::default();
+        let mut buffer = Vec::new();
+        process_in_network(&mut parser, &mut ParseContext::new(), &mut q, &mut CsvSink::new(&mut buffer), &mut stats, &options, ElementCopies::default()).unwrap();
+
+        let output = String::from_utf8(buffer).unwrap();
+        assert_eq!(stats.corrupt_skipped, 1);
+        assert_eq!(stats.rows_written, 1);
+        assert!(output.ends_with(",2.0,null,null,null,null\n"));
+    }
+
+    #[test]
+    fn test_run_stats() {
+        let path = std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz");
+        let query_path = std::path::PathBuf::from("tests/testfiles/input_testfiles/input_for_intermediate");
+        let mut q = crate::query::read_input(&query_path).unwrap();
+
+        let mut buffer = Vec::new();
+        let summary = run(&mut q, &Location::Path(path.clone()), 256, &Options::new(), &mut CsvSink::new(&mut buffer)).unwrap();
+        let stats = summary.stats.clone();
+
+        assert!(!summary.no_providers);
+        assert_eq!(summary.metadata.len(), 4);
+        assert_eq!(summary.metadata[0].0, "reporting_entity_name");
+        // Type Infinity GWLeibniz isn't in the file
+        let unmatched: Vec<&str> = summary.query.codes.iter()
+            .filter(|c| !c.matched).map(|c| c.code.as_str()).collect();
+        assert!(unmatched.contains(&"GWLeibniz"));
+        assert_eq!(summary.query.codes.iter().map(|c| c.records).sum::<u64>(), stats.rows_written);
+
+        let mut json = Vec::new();
+        Location::Path(path).open(&SharedReporter::default()).unwrap().read_to_end(&mut json).unwrap();
+
+        // Header line is not a row
+        let rows = String::from_utf8(buffer).unwrap().lines().count() as u64 - 1;
+
+        assert_eq!(stats.rows_written, rows);
+        assert_eq!(stats.decompressed_bytes, json.len() as u64);
+        assert!(stats.objects_matched <= stats.objects_scanned);
+        assert!(stats.objects_matched > 0);
+    }
+
+    #[test]
+    fn test_group_id_query() {
+        let path = std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz");
+        let query_path = std::path::PathBuf::from("tests/testfiles/input_testfiles/input_group_id");
+        let mut q = crate::query::read_input(&query_path).unwrap();
+        assert_eq!(q.group_ids, vec![String::from("3")]);
+
+        let mut buffer = Vec::new();
+        run(&mut q, &Location::Path(path), 256, &Options::new(), &mut CsvSink::new(&mut buffer)).unwrap();
+        let output = String::from_utf8(buffer).unwrap();
+
+        // npi 3 comes from the group alone, npi 2 is in both and written once
+        let rows: Vec<&str> = output.lines().skip(1).collect();
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows.iter().filter(|r| r.starts_with("2,ein,44,3,")).count(), 2);
+        assert_eq!(rows.iter().filter(|r| r.starts_with("3,ein,55,3,")).count(), 2);
+
+        // Group members are not warned about as npis from the query
+        assert!(q.providers.iter().all(|p| p.recorded));
+        assert_eq!(q.providers.iter().filter(|p| !p.queried).count(), 1);
+    }
+
+    #[test]
+    fn test_tin_query() {
+        let path = std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz");
+        let query_path = std::path::PathBuf::from("tests/testfiles/input_testfiles/input_tin");
+        let mut q = crate::query::read_input(&query_path).unwrap();
+        assert_eq!(q.tins, vec![String::from("777")]);
+
+        let mut buffer = Vec::new();
+        run(&mut q, &Location::Path(path), 256, &Options::new(), &mut CsvSink::new(&mut buffer)).unwrap();
+        let output = String::from_utf8(buffer).unwrap();
+
+        // tin 777 is in groups 5 and 7, only group 5 has rates for the codes
+        let rows: Vec<&str> = output.lines().skip(1).collect();
+        assert_eq!(rows.len(), 3);
+        assert!(rows.iter().all(|r| r.starts_with("4,ein,777,5,")));
+        assert_eq!(q.providers.len(), 5);
+    }
+
+    #[test]
+    fn test_wildcard_npi_query() {
+        let path = std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz");
+        let query_path = std::path::PathBuf::from("tests/testfiles/input_testfiles/input_all_npis");
+        let mut q = crate::query::read_input(&query_path).unwrap();
+        assert!(q.all_providers);
+        assert!(q.providers.is_empty());
+
+        let mut buffer = Vec::new();
+        run(&mut q, &Location::Path(path), 256, &Options::new(), &mut CsvSink::new(&mut buffer)).unwrap();
+        let output = String::from_utf8(buffer).unwrap();
+
+        // Every provider with a group id, same rows as listing npis 1 to 4
+        assert_eq!(q.providers.len(), 9);
+        let rows: Vec<&str> = output.lines().skip(1).collect();
+        assert_eq!(rows.len(), 4);
+        assert!(rows.iter().all(|r| r.contains(",CODE 5,")));
+    }
+
+    #[test]
+    fn test_exclusions() {
+        let path = std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz");
+        let query_path = std::path::PathBuf::from("tests/testfiles/input_testfiles/input_exclusions");
+        let mut q = crate::query::read_input(&query_path).unwrap();
+        assert_eq!(q.excluded_npis, vec![2]);
+        assert!(q.excludes_code("type 5", "code 5"));
+        assert!(!q.excludes_code("Type 6", "Code 5"));
+        assert!(q.excludes_code("Type 7", "Code 7"));
+        assert!(q.excludes_code("Type 1", "anything"));
+
+        let mut buffer = Vec::new();
+        run(&mut q, &Location::Path(path), 256, &Options::new(), &mut CsvSink::new(&mut buffer)).unwrap();
+        let output = String::from_utf8(buffer).unwrap();
+
+        let rows: Vec<&str> = output.lines().skip(1).collect();
+        assert!(!rows.is_empty());
+        assert!(rows.iter().all(|r| !r.starts_with("2,")));
+        assert!(rows.iter().all(|r| !r.contains(",CODE 1,") && !r.contains(",CODE 5,") && !r.contains(",CODE 7,")));
+        assert!(rows.iter().any(|r| r.contains(",CODE 4,")));
+    }
+
+    #[test]
+    fn test_name_filters() {
+        let path = std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz");
+        let query_path = std::path::PathBuf::from("tests/testfiles/input_testfiles/input_name_filters");
+        let mut q = crate::query::read_input(&query_path).unwrap();
+        assert_eq!(q.name_filters.len(), 2);
+        assert!(q.codes.is_empty());
+
+        let mut buffer = Vec::new();
+        run(&mut q, &Location::Path(path), 256, &Options::new(), &mut CsvSink::new(&mut buffer)).unwrap();
+        let output = String::from_utf8(buffer).unwrap();
+
+        let mut reader = csv::Reader::from_reader(output.as_bytes());
+        let mut codes: Vec<String> = reader.records().map(|r| r.unwrap()[8].to_string()).collect();
+        codes.dedup();
+        assert_eq!(codes, vec!["CODE 3", "CODE 7", "CODE 8"]);
+    }
+
+    #[test]
+    fn test_pinned_versions() {
+        let path = std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz");
+        let query_path = std::path::PathBuf::from("tests/testfiles/input_testfiles/input_versions");
+
+        // Type 8 items are under 2017, flagged but written
+        let mut q = crate::query::read_input(&query_path).unwrap();
+        assert_eq!(q.codes[0].code_type, "Type 1");
+        assert_eq!(q.codes[0].version.as_deref(), Some("2022"));
+        let mut buffer = Vec::new();
+        run(&mut q, &Location::Path(path.clone()), 256, &Options::new(), &mut CsvSink::new(&mut buffer)).unwrap();
+        let written = String::from_utf8(buffer).unwrap().lines().count();
+        let mismatches: Vec<u64> = q.codes.iter().map(|c| c.version_mismatches).collect();
+        assert_eq!(mismatches, vec![0, 1, 0]);
+
+        let mut q = crate::query::read_input(&query_path).unwrap();
+        let mut options = Options::new();
+        options.exclude_version_mismatch = true;
+        let mut buffer = Vec::new();
+        run(&mut q, &Location::Path(path), 256, &options, &mut CsvSink::new(&mut buffer)).unwrap();
+        let output = String::from_utf8(buffer).unwrap();
+        assert_eq!(output.lines().count(), written - 2);
+        assert!(!output.contains(",CODE 8,"));
+    }
+
+    #[test]
+    fn test_raw_out() {
+        let path = std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz");
+        let query_path = std::path::PathBuf::from("tests/testfiles/input_testfiles/input_for_intermediate");
+        let mut q = crate::query::read_input(&query_path).unwrap();
+
+        let raw_path = std::env::temp_dir().join(format!("mrfy_raw_out_{}.ndjson.gz", std::process::id()));
+        let mut options = Options::new();
+        options.raw_out = Some(raw_path.clone());
+
+        let stats = run(&mut q, &Location::Path(path), 256, &options, &mut CsvSink::new(std::io::sink())).unwrap().stats;
+
+        let mut raw = String::new();
+        Location::Path(raw_path.clone()).open(&SharedReporter::default()).unwrap().read_to_string(&mut raw).unwrap();
+        std::fs::remove_file(&raw_path).unwrap();
+
+        // One complete element per matched object, including keys the CSV leaves out
+        let lines: Vec<&str> = raw.lines().collect();
+        assert_eq!(lines.len() as u64, stats.objects_matched);
+        assert!(lines[0].starts_with('{') && lines[0].ends_with('}'));
+        assert!(lines[0].contains(r#""billing_code":"cOdE 1""#));
+        assert!(lines[0].contains(r#""CASE":"#));
+        assert!(lines[0].contains(r#""provider_references":"#));
+    }
+
+    #[test]
+    fn test_extract() {
+        let path = std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz");
+        let query_path = std::path::PathBuf::from("tests/testfiles/input_testfiles/input_for_intermediate");
+
+        let extract_path = std::env::temp_dir().join(format!("mrfy_extract_{}.json.gz", std::process::id()));
+        let mut options = Options::new();
+        options.extract = Some(extract_path.clone());
+
+        let mut q = crate::query::read_input(&query_path).unwrap();
+        let mut expected = Vec::new();
+        run(&mut q, &Location::Path(path), 256, &options, &mut CsvSink::new(&mut expected)).unwrap();
+
+        // The extracted file is a valid MRF that gives the same results
+        let mut q = crate::query::read_input(&query_path).unwrap();
+        let mut buffer = Vec::new();
+        run(&mut q, &Location::Path(extract_path.clone()), 256, &Options::new(), &mut CsvSink::new(&mut buffer)).unwrap();
+
+        let mut json = String::new();
+        Location::Path(extract_path.clone()).open(&SharedReporter::default()).unwrap().read_to_string(&mut json).unwrap();
+        std::fs::remove_file(&extract_path).unwrap();
+
+        assert_eq!(String::from_utf8(buffer).unwrap(), String::from_utf8(expected).unwrap());
+
+        // Metadata is kept, groups and rates that don't apply are not
+        assert!(json.starts_with(r#"{"reporting_entity_name":"Aetna Signature Administrators","#));
+        assert!(!json.contains(r#""provider_group_id":7"#));
+        assert!(!json.contains("NO matches on this g_id"));
+    }
+
+    #[test]
+    fn test_filter_element() {
+        let json = r#"{"billing_code":"1","negotiated_rates":[
+                         {"provider_references":[1,2],"negotiated_prices":[{"negotiated_rate":1}]},
+                         {"provider_references":[3],"negotiated_prices":[{"negotiated_rate":2}]}]}"#;
+        let mut parser = json_event_parser::ReaderJsonParser::new(Cursor::new(json));
+        let mut events = Vec::new();
+        loop {
+            match parser.parse_next().unwrap() {
+                JsonEvent::Eof => break,
+                event => events.push(to_owned_event(&event)),
+            }
+        }
+
+        let mut ref_map = HashMap::new();
+        ref_map.insert(String::from("2"), vec![String::from("9,ein,1")]);
+        let ref_map = RefMap::from(ref_map);
+
+        let mut out = WriterJsonSerializer::new(Vec::new());
+        for event in filter_element(&events, &ref_map) {
+            out.serialize_event(event).unwrap();
+        }
+        let out = String::from_utf8(out.finish().unwrap()).unwrap();
+
+        assert_eq!(out, r#"{"billing_code":"1","negotiated_rates":[{"provider_references":[2],"negotiated_prices":[{"negotiated_rate":1}]}]}"#);
+    }
+
+    #[test]
+    fn test_run_with_progress() {
+        let mut q = Query::new();
+        q.providers.push(Provider::new(1));
+        q.codes.push(Code::new(&String::from("*"), &String::from("*")));
+        let source = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz"));
+
+        let reports: Rc<RefCell<Vec<Progress>>> = Rc::new(RefCell::new(Vec::new()));
+        let seen = reports.clone();
+        let summary = run_with_progress(&mut q, &source, 256, &Options::new(), &mut CsvSink::new(Vec::new()),
+                                        move |p| seen.borrow_mut().push(p.clone())).unwrap();
+
+        let reports = reports.borrow();
+        // The first object, then the end of in_network
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].objects_scanned, 1);
+        assert!(reports[0].bytes_read > 0);
+        let last = reports.last().unwrap();
+        assert!(last.finished);
+        assert_eq!(last.objects_scanned, summary.stats.objects_scanned);
+        assert_eq!(last.rows_written, summary.stats.rows_written);
+    }
+
+    #[test]
+    fn test_reporter() {
+        struct Collect(Arc<std::sync::Mutex<Vec<(Level, String)>>>);
+        impl Reporter for Collect {
+            fn report(&self, level: Level, message: &str) {
+                self.0.lock().unwrap().push((level, message.to_string()));
+            }
+        }
+
+        let mut q = Query::new();
+        q.providers.push(Provider::new(1));
+        q.codes.push(Code::new(&String::from("*"), &String::from("*")));
+        let source = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz"));
+
+        let messages = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut options = Options::new();
+        options.reporter = SharedReporter::new(Collect(messages.clone()));
+        assert_eq!(options.reporter, options.reporter.clone());
+        assert_ne!(options.reporter, SharedReporter::default());
+        run_with_progress(&mut q, &source, 256, &options, &mut CsvSink::new(Vec::new()), |_: &Progress| {}).unwrap();
+
+        let messages = messages.lock().unwrap();
+        assert!(messages.contains(&(Level::Status, String::from("Processing in_network..."))));
+        // The file has keys mrfy doesn't read, e.g. CASE
+        assert!(messages.iter().any(|(level, m)| *level == Level::Warning && m.starts_with("unsupported key CASE")));
+    }
+
+    #[test]
+    fn test_cancel_token() {
+        let mut q = Query::new();
+        q.providers.push(Provider::new(1));
+        q.codes.push(Code::new(&String::from("*"), &String::from("*")));
+        let source = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz"));
+
+        // Cancelled from the host once the first object is reported
+        let options = Options::new();
+        let cancel = options.cancel.clone();
+        assert_eq!(cancel, options.cancel);
+        assert_ne!(cancel, CancelToken::new());
+        let mut out = Vec::new();
+        let err = run_with_progress(&mut q, &source, 256, &options, &mut CsvSink::new(&mut out), move |_| cancel.cancel()).unwrap_err();
+
+        let interrupted = err.downcast_ref::<Interrupted>().unwrap();
+        assert_eq!(interrupted.objects, 1);
+        // No partial line is left
+        assert!(out.is_empty() || out.ends_with(b"\n"));
+
+        // Other runs are not affected
+        let mut q = Query::new();
+        q.providers.push(Provider::new(1));
+        q.codes.push(Code::new(&String::from("*"), &String::from("*")));
+        assert!(run(&mut q, &source, 256, &Options::new(), &mut CsvSink::new(Vec::new())).is_ok());
+    }
+
+    #[test]
+    fn test_stop_when_complete() {
+        let source = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz"));
+        let mut q = Query::new();
+        q.providers.push(Provider::new(1));
+        q.codes.push(Code::new(&String::from("*"), &String::from("Code 1")));
+        q.codes.push(Code::new(&String::from("Type 4"), &String::from("Code 4")));
+
+        let mut all = MemorySink::new();
+        let whole = run(&mut q.clone(), &source, 256, &Options::new(), &mut all).unwrap();
+
+        // Code 4 is the fourth of nine items
+        let options = Options { stop_when_complete: true, ..Options::new() };
+        let mut some = MemorySink::new();
+        let stopped = run(&mut q.clone(), &source, 256, &options, &mut some).unwrap();
+        assert_eq!(some.records, all.records);
+        assert_eq!(stopped.stats.objects_scanned, 4);
+        assert!(whole.stats.objects_scanned > 4);
+
+        // A pattern can't be known to be complete
+        q.codes.push(Code::new(&String::from("*"), &String::from("Code*")));
+        let mut rest = MemorySink::new();
+        let summary = run(&mut q, &source, 256, &options, &mut rest).unwrap();
+        assert_eq!(summary.stats.objects_scanned, whole.stats.objects_scanned);
+    }
+
+    #[test]
+    fn test_limit_and_sample() {
+        let source = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz"));
+        let mut q = Query::new();
+        q.providers.push(Provider::new(1));
+        q.providers.push(Provider::new(2));
+        q.codes.push(Code::new(&String::from("*"), &String::from("*")));
+        let mut all = MemorySink::new();
+        let whole = run(&mut q.clone(), &source, 256, &Options::new(), &mut all).unwrap();
+        assert!(all.records.len() > 3);
+
+        let options = Options { limit: Some(3), ..Options::new() };
+        let mut some = MemorySink::new();
+        let limited = run(&mut q.clone(), &source, 256, &options, &mut some).unwrap();
+        assert_eq!(some.records, all.records[..3]);
+        assert!(limited.stats.objects_scanned < whole.stats.objects_scanned);
+
+        // The same seed keeps the same rows, a share of them
+        let options = Options { sample: Some(0.5), sample_seed: 7, ..Options::new() };
+        let mut sampled = MemorySink::new();
+        run(&mut q.clone(), &source, 256, &options, &mut sampled).unwrap();
+        let mut again = MemorySink::new();
+        run(&mut q.clone(), &source, 256, &options, &mut again).unwrap();
+        assert_eq!(sampled.records, again.records);
+        assert!(sampled.records.iter().all(|r| all.records.contains(r)));
+        assert!(sampled.records.len() < all.records.len());
+
+        let mut sampler = Sampler::new(0.01, 1);
+        let kept = (0..100_000).filter(|_| sampler.keep()).count();
+        assert!((800..1200).contains(&kept), "{}", kept);
+    }
+
+    #[test]
+    fn test_offsets() {
+        assert_eq!(Offset::parse("item:12"), Some(Offset::Item(12)));
+        assert_eq!(Offset::parse("2G"), Some(Offset::Byte(2 << 30)));
+        assert_eq!(Offset::parse("0"), Some(Offset::Byte(0)));
+        assert_eq!(Offset::parse("item:"), None);
+        assert_eq!(Offset::parse("auto"), None);
+
+        let source = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz"));
+        let mut q = Query::new();
+        q.providers.push(Provider::new(1));
+        q.providers.push(Provider::new(2));
+        q.codes.push(Code::new(&String::from("*"), &String::from("*")));
+        let mut all = MemorySink::new();
+        run(&mut q.clone(), &source, 256, &Options::new(), &mut all).unwrap();
+
+        // Slices next to each other have the rows of the whole file between them. Byte offsets
+        // move by the reads of the parser, so either slice of 4096 may be empty.
+        for split in [Offset::Item(4), Offset::Byte(4096)] {
+            let mut rows = Vec::new();
+            for (start, end) in [(None, Some(split)), (Some(split), None)] {
+                let options = Options { start_offset: start, end_offset: end, ..Options::new() };
+                let mut slice = MemorySink::new();
+                run(&mut q.clone(), &source, 256, &options, &mut slice).unwrap();
+                if let Offset::Item(_) = split {
+                    assert!(!slice.records.is_empty() && slice.records.len() < all.records.len());
+                }
+                rows.extend(slice.records);
+            }
+            assert_eq!(rows, all.records);
+        }
+    }
+
+    #[test]
+    fn test_where() {
+        let source = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz"));
+        let mut q = Query::new();
+        q.providers.push(Provider::new(1));
+        q.providers.push(Provider::new(2));
+        q.codes.push(Code::new(&String::from("*"), &String::from("*")));
+        let mut all = MemorySink::new();
+        run(&mut q.clone(), &source, 256, &Options::new(), &mut all).unwrap();
+
+        let filter = Filter::parse("negotiated_rate < 200 && npi != 2", &COLUMNS).unwrap();
+        let options = Options { filter: Some(filter), ..Options::new() };
+        let mut sink = MemorySink::new();
+        run(&mut q.clone(), &source, 256, &options, &mut sink).unwrap();
+        let expected: Vec<Vec<String>> = all.records.into_iter()
+            .filter(|r| r[11].parse::<f64>().is_ok_and(|rate| rate < 200.0) && r[0] != "2")
+            .collect();
+        assert!(!expected.is_empty());
+        assert_eq!(sink.records, expected);
+
+        // A column the rows don't have
+        let filter = Filter::parse("provider_name == 'x'", &[nppes::COLUMNS[0]]).unwrap();
+        let options = Options { filter: Some(filter), ..Options::new() };
+        assert!(run(&mut q, &source, 256, &options, &mut MemorySink::new()).is_err());
+    }
+
+    #[test]
+    fn test_timings() {
+        let mut q = Query::new();
+        q.codes.push(Code::new(&String::from("*"), &String::from("Code 1")));
+        q.providers.push(Provider::new(1701));
+
+        // in_network comes first, so it is skipped and read again
+        let source = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/backward_basic.json.gz"));
+        let summary = run(&mut q.clone(), &source, 256, &Options::new(), &mut MemorySink::new()).unwrap();
+        let timings = &summary.stats.timings;
+        assert!(!timings.reset.is_zero() && !timings.in_network.is_zero() && !timings.read.is_zero());
+        assert!(timings.parse() + timings.read + timings.output <= summary.stats.elapsed);
+
+        let source = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/basic_test.json.gz"));
+        let summary = run(&mut q, &source, 256, &Options::new(), &mut MemorySink::new()).unwrap();
+        assert!(summary.stats.timings.reset.is_zero() && !summary.stats.timings.output.is_zero());
+    }
+
+    #[test]
+    fn test_coverage() {
+        let source = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz"));
+        let mut q = Query::new();
+        q.providers.push(Provider::new(1));
+        q.providers.push(Provider::new(42));
+        q.codes.push(Code::new(&String::from("Type 1"), &String::from("Code 1")));
+        q.codes.push(Code::new(&String::from("Type 9"), &String::from("Code 9")));
+
+        let summary = run(&mut q, &source, 256, &Options::new(), &mut MemorySink::new()).unwrap();
+        let coverage = &summary.coverage;
+        assert_eq!((coverage.codes_matched, coverage.codes), (1, 2));
+        assert_eq!((coverage.npis_matched, coverage.npis), (1, 2));
+        assert_eq!((coverage.items_matched, coverage.items), (1, 9));
+        assert_eq!(coverage.code_coverage(), Some(0.5));
+        assert_eq!(Coverage::default().item_coverage(), None);
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let source = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz"));
+        let mut q = Query::new();
+        q.providers.push(Provider::new(1));
+        q.providers.push(Provider::new(42));
+        q.codes.push(Code::new(&String::from("*"), &String::from("*")));
+
+        let options = Options { dry_run: true, ..Options::new() };
+        let mut sink = MemorySink::new();
+        let summary = run(&mut q.clone(), &source, 256, &options, &mut sink).unwrap();
+        assert!(sink.records.is_empty());
+        assert_eq!(summary.stats.objects_scanned, 0);
+        assert!(!summary.no_providers);
+        let resolved: Vec<(u64, bool)> = summary.query.npis.iter().map(|n| (n.npi, !n.groups.is_empty())).collect();
+        assert_eq!(resolved, [(1, true), (42, false)]);
+
+        q.providers.remove(0);
+        assert!(run(&mut q, &source, 256, &options, &mut sink).unwrap().no_providers);
+    }
+
+    #[test]
+    fn test_explain() {
+        let source = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz"));
+        let path = std::env::temp_dir().join(format!("mrfy_explain_run_{}.csv", std::process::id()));
+        let mut q = Query::new();
+        q.providers.push(Provider::new(1));
+        q.codes.push(Code::new(&String::from("Type 1"), &String::from("Code 1")));
+        q.codes.push(Code::new(&String::from("Type 2"), &String::from("Code 2")));
+
+        let options = Options { explain: Some(path.clone()), ..Options::new() };
+        let mut sink = MemorySink::new();
+        let summary = run(&mut q, &source, 256, &options, &mut sink).unwrap();
+        assert_eq!(summary.stats.objects_matched, 1);
+
+        // Every other item is in the file once, with why it was skipped
+        let explained = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = explained.lines().collect();
+        assert_eq!(lines[0], "item,billing_code_type,billing_code,name,reason");
+        assert_eq!(lines[1], "1,Type 2,CODE 2,Item 2,no_matching_rates");
+        // Without a billing code the item is dropped once its rates have no provider of the query
+        assert_eq!(lines[4], "4,Type NO,,NO CODE,no_matching_rates");
+        assert_eq!(lines.len(), 9);
+        assert_eq!(lines.iter().filter(|l| l.ends_with(",code_not_in_query")).count(), 6);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_name_prefilter() {
+        assert!(contains_ignore_ascii_case("Office VISIT", "visit"));
+        assert!(!contains_ignore_ascii_case("Office", "office visit"));
+
+        let source = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz"));
+        let mut q = Query::new();
+        q.providers.push(Provider::new(1));
+        q.providers.push(Provider::new(2));
+        q.codes.push(Code::new(&String::from("*"), &String::from("*")));
+
+        let options = Options { name_prefilter: vec![String::from("item 5"), String::from("ITEM 7")], ..Options::new() };
+        let mut sink = MemorySink::new();
+        let summary = run(&mut q, &source, 256, &options, &mut sink).unwrap();
+        assert!(!sink.records.is_empty());
+        assert!(sink.records.iter().all(|r| r.contains(&String::from("CODE 5")) || r.contains(&String::from("CODE 7"))));
+        // Items ruled out by name are skipped before their billing code, except the one
+        // listing billing_code before name
+        assert!(summary.stats.objects_scanned < 4);
+    }
+
+    #[test]
+    fn test_check_freshness() {
+        assert_eq!(days_since_epoch((1970, 1, 1)), 0);
+        assert_eq!(days_since_epoch((2000, 3, 1)), 11_017);
+        assert_eq!(days_since_epoch((2025, 4, 5)) - days_since_epoch((2024, 4, 5)), 365);
+        assert_eq!(days_since_epoch((2024, 4, 5)) - days_since_epoch((2023, 4, 5)), 366);
+
+        let today = days_since_epoch((2025, 5, 5));
+        let mut options = Options::new();
+        assert!(check_freshness(None, &options, today).is_ok());
+
+        options.max_age_days = Some(30);
+        assert!(check_freshness(Some("2025-04-05"), &options, today).is_ok());
+        let stale = check_freshness(Some("2025-04-04"), &options, today).unwrap_err();
+        assert_eq!(stale.age_days, Some(31));
+        assert!(check_freshness(Some("04/05/2025"), &options, today).unwrap_err().age_days.is_none());
+        assert!(check_freshness(None, &options, today).is_err());
+
+        options.warn_stale = true;
+        assert!(check_freshness(Some("2020-01-01"), &options, today).is_ok());
+
+        // intermediate.json.gz was last updated on 2025-04-05
+        let mut q = Query::new();
+        q.providers.push(Provider::new(1));
+        q.codes.push(Code::new(&String::from("*"), &String::from("*")));
+        let source = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz"));
+        let options = Options { max_age_days: Some(1), ..Options::new() };
+        let mut out = Vec::new();
+        let err = run(&mut q, &source, 256, &options, &mut CsvSink::new(&mut out)).unwrap_err();
+        assert!(err.downcast_ref::<Stale>().is_some());
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_read_meta() {
+        let source = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz"));
+
+        let file_meta = read_meta(&source, 256, false).unwrap();
+        assert_eq!(file_meta.meta.last_updated_on.as_deref(), Some("2025-04-05"));
+        assert_eq!(file_meta.meta.version.as_deref(), Some("1.3.1"));
+        assert_eq!(file_meta.in_network, None);
+
+        let file_meta = read_meta(&source, 256, true).unwrap();
+        assert_eq!(file_meta.provider_references, Some(6));
+        assert_eq!(file_meta.in_network, Some(9));
+
+        let mut out = Vec::new();
+        file_meta.print_out(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("reporting_entity_name: Aetna Signature Administrators\n"));
+        assert!(out.ends_with("provider_references: 6\nin_network: 9\n"));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_run_zstd() {
+        let path = std::path::PathBuf::from("tests/testfiles/data_files/basic_test.json.zst");
+
+        let mut q = Query::new();
+        q.codes.push(Code::new(&String::from("*"), &String::from("Code 1")));
+        q.providers.push(Provider::new(1701));
+
+        let mut buffer = Vec::new();
+        run(&mut q, &Location::Path(path), 256, &Options::new(), &mut CsvSink::new(&mut buffer)).unwrap();
+
+        let output = String::from_utf8(buffer).unwrap();
+        assert!(output.ends_with("1701,ein,101,11,alpha,Item 1,Type 1,2022,CODE 1,Item 1,neg type 1,9.99,9999-12-31,A B C,class 1,null\n"));
+    }
+
 }
@@ -0,0 +1,228 @@
+//! # avro
+//!
+//! Writes the rows of a query as an Avro object container file (`--avro`), built with the `avro`
+//! feature, for pipelines that ingest Avro (e.g. through Kafka).
+//!
+//! The schema is a record `mrfy.Rate` with a field for each column of the header, embedded in the
+//! file as its `avro.schema` metadata, so the file can be read without anything else. Every field
+//! is a union of null and string, except negotiated_rate, a union of null and double. A value of
+//! `null` (and a negotiated_rate that isn't a number) is written as null. Rows are written in
+//! blocks of BLOCK_ROWS, compressed with the `deflate` codec, by the Writer of apache-avro.
+
+use std::io::Write;
+
+use apache_avro::types::Value;
+use apache_avro::{Codec, Schema, Writer};
+
+use crate::sink::{MatchedRecord, RecordSink};
+
+/// Rows of a block at most.
+pub const BLOCK_ROWS: usize = 4096;
+
+/// The type of the values of a field.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FieldType {
+    String,
+    Double,
+}
+
+impl FieldType {
+    /// Returns the type of the column named column (before --header-map).
+    pub fn of(column: &str) -> Self {
+        match column {
+            "negotiated_rate" => FieldType::Double,
+            _ => FieldType::String,
+        }
+    }
+
+    /// Returns the name of the type in a schema.
+    fn as_str(&self) -> &'static str {
+        match self {
+            FieldType::String => "string",
+            FieldType::Double => "double",
+        }
+    }
+
+    /// Returns value as a value of the field, a union of null and this type.
+    fn value(&self, value: &str) -> Value {
+        let typed = match self {
+            FieldType::Double => value.parse::<f64>().ok().map(Value::Double),
+            FieldType::String if value == "null" => None,
+            FieldType::String => Some(Value::String(String::from(value))),
+        };
+        // The branch of the union, 0 for null
+        match typed {
+            Some(typed) => Value::Union(1, Box::new(typed)),
+            None => Value::Union(0, Box::new(Value::Null)),
+        }
+    }
+}
+
+/// Returns true if name can name an Avro field: a letter or _, then letters, digits and _.
+fn is_avro_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_') &&
+        chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Returns the schema of rows with the fields named by columns, of types.
+pub fn schema(columns: &[&str], types: &[FieldType]) -> Result<Schema, Box<dyn std::error::Error>> {
+    let mut fields = Vec::new();
+    for (i, column) in columns.iter().enumerate() {
+        if !is_avro_name(column) {
+            return Err(format!("'{}' is not an Avro field name, rename it with --header-map", column).into());
+        }
+        let field_type = types.get(i).copied().unwrap_or(FieldType::String);
+        fields.push(serde_json::json!({
+            "name": column,
+            "type": ["null", field_type.as_str()],
+            "default": null,
+        }));
+    }
+    let schema = serde_json::json!({"type": "record", "name": "Rate", "namespace": "mrfy", "fields": fields});
+    Ok(Schema::parse(&schema)?)
+}
+
+/// Returns a sync marker for a file, different from one file to the next.
+fn sync_marker() -> [u8; 16] {
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut marker = [0; 16];
+    for half in marker.chunks_mut(8) {
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_u128(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos());
+        half.copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+    marker
+}
+
+/// Writes the header and records as an Avro object container file.
+pub struct AvroSink<W: Write> {
+    out: W,
+    types: Vec<FieldType>,
+    /// Set by write_header, with the names of the fields
+    schema: Option<(Schema, Vec<String>)>,
+    /// Ends each block, the same throughout the file
+    sync: [u8; 16],
+    /// The rows of the block being written
+    block: Vec<Value>,
+}
+
+impl<W: Write> AvroSink<W> {
+    /// Creates an AvroSink writing to out, the columns of the rows having types.
+    pub fn new(out: W, types: Vec<FieldType>) -> Self {
+        Self { out, types, schema: None, sync: sync_marker(), block: Vec::new() }
+    }
+
+    /// Writes the rows of the block, if any, after the header, and empties it.
+    fn write_block(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let Some((schema, _)) = &self.schema else {
+            return Ok(());
+        };
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        Writer::append_to_with_codec(schema, &mut self.out, Codec::Deflate, self.sync).extend_from_slice(&self.block)?;
+        self.out.flush()?;
+        self.block.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> RecordSink for AvroSink<W> {
+    fn write_header(&mut self, columns: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+        let schema = schema(columns, &self.types)?;
+        // A writer without rows writes the header only
+        Writer::builder().schema(&schema).writer(&mut self.out).codec(Codec::Deflate).marker(self.sync).build()
+            .into_inner()?;
+        self.schema = Some((schema, columns.iter().map(|c| c.to_string()).collect()));
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &MatchedRecord) -> Result<(), Box<dyn std::error::Error>> {
+        let Some((_, names)) = &self.schema else {
+            return Err("an Avro file needs the header for its schema, it can't be written with --no-header".into());
+        };
+        let fields = names.iter().zip(record.values).enumerate().map(|(i, (name, value))| {
+            let field_type = self.types.get(i).copied().unwrap_or(FieldType::String);
+            (name.clone(), field_type.value(value))
+        });
+        self.block.push(Value::Record(fields.collect()));
+        if self.block.len() >= BLOCK_ROWS {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.write_block()?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema() {
+        assert!(is_avro_name("billing_code"));
+        assert!(!is_avro_name("billing-code"));
+        assert!(!is_avro_name("1npi"));
+        let schema = schema(&["npi", "negotiated_rate"], &[FieldType::String, FieldType::Double]).unwrap();
+        let expected = Schema::parse(&serde_json::json!({
+            "type": "record",
+            "name": "Rate",
+            "namespace": "mrfy",
+            "fields": [{"name": "npi", "type": ["null", "string"], "default": null},
+                       {"name": "negotiated_rate", "type": ["null", "double"], "default": null}],
+        })).unwrap();
+        assert_eq!(schema.canonical_form(), expected.canonical_form());
+        assert!(super::schema(&["npi", "billing-code"], &[]).is_err());
+
+        assert_eq!(FieldType::Double.value("2.5"), Value::Union(1, Box::new(Value::Double(2.5))));
+        assert_eq!(FieldType::Double.value("n/a"), Value::Union(0, Box::new(Value::Null)));
+        assert_eq!(FieldType::String.value("null"), Value::Union(0, Box::new(Value::Null)));
+    }
+
+    #[test]
+    fn test_avro_sink() {
+        let columns = ["npi", "negotiated_rate"];
+        let types: Vec<FieldType> = columns.iter().map(|c| FieldType::of(c)).collect();
+        let mut sink = AvroSink::new(Vec::new(), types.clone());
+        sink.write_header(&columns).unwrap();
+        sink.write_record(&MatchedRecord { values: &["12", "2.5"] }).unwrap();
+        sink.write_record(&MatchedRecord { values: &["null", "n/a"] }).unwrap();
+        sink.finish().unwrap();
+
+        let reader = apache_avro::Reader::new(&sink.out[..]).unwrap();
+        assert_eq!(reader.writer_schema().canonical_form(), schema(&columns, &types).unwrap().canonical_form());
+        assert!(reader.writer_schema().canonical_form().starts_with(r#"{"name":"mrfy.Rate","type":"record""#));
+        let rows: Vec<Value> = reader.collect::<Result<_, _>>().unwrap();
+        let field = |name: &str, branch: u32, value: Value| (name.to_string(), Value::Union(branch, Box::new(value)));
+        assert_eq!(rows, [
+            Value::Record(vec![field("npi", 1, Value::String(String::from("12"))),
+                               field("negotiated_rate", 1, Value::Double(2.5))]),
+            Value::Record(vec![field("npi", 0, Value::Null),
+                               field("negotiated_rate", 0, Value::Null)]),
+        ]);
+
+        // More than a block, all with the sync marker of the header
+        let mut sink = AvroSink::new(Vec::new(), types.clone());
+        sink.write_header(&columns).unwrap();
+        for i in 0..BLOCK_ROWS + 1 {
+            sink.write_record(&MatchedRecord { values: &[&i.to_string(), "1.5"] }).unwrap();
+        }
+        sink.finish().unwrap();
+        let rows: Vec<Value> = apache_avro::Reader::new(&sink.out[..]).unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(rows.len(), BLOCK_ROWS + 1);
+        assert_eq!(rows[BLOCK_ROWS], Value::Record(vec![field("npi", 1, Value::String(BLOCK_ROWS.to_string())),
+                                                        field("negotiated_rate", 1, Value::Double(1.5))]));
+
+        // The schema comes from the header
+        let mut sink = AvroSink::new(Vec::new(), types);
+        assert!(sink.write_record(&MatchedRecord { values: &["12", "2.5"] }).is_err());
+    }
+}
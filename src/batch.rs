@@ -0,0 +1,409 @@
+//! # batch
+//!
+//! Runs the jobs of a manifest (`mrfy batch`), e.g. a monthly refresh of many files and queries,
+//! with shared settings, a limit on how many jobs run at once and one summary at the end.
+//!
+//! The manifest is TOML, JSON or YAML (by extension), see Manifest for its layout. Paths in it
+//! are relative to the manifest.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::asa::{self, create_output_with, CancelToken, Coverage, Options, RunSummary, SharedReporter};
+use crate::compress::Codec;
+use crate::meta::{self, Sidecar};
+use crate::error::{EXIT_DATA_ISSUES, EXIT_NO_MATCHES};
+use crate::merge::{merge_to, MergeSummary};
+use crate::metrics::Metrics;
+use crate::query::read_input_with;
+use crate::sink::CsvSink;
+use crate::source::{BuffSize, DEFAULT_BUFF_SIZE, Location};
+
+/// A batch manifest, e.g. in TOML:
+/// ```toml
+/// parallel = 4
+/// summary = "out/summary.csv"
+/// merge = "out/all.csv"
+///
+/// [defaults]
+/// min_per_code = true
+/// max_age_days = 45
+///
+/// [[jobs]]
+/// data = "/data/aetna.json.gz"
+/// query = "queries/ortho.toml"
+/// output = "out/aetna.csv"
+///
+/// [[jobs]]
+/// name = "cigna"
+/// url = "https://example.com/cigna_in_network.json.gz"
+/// query = "queries/ortho.toml"
+/// output = "out/cigna.json.gz"
+/// format = "mrf"
+/// min_per_code = false
+/// ```
+/// A job gives its data file as data (a path) or url, and may set any of the settings of
+/// defaults (see Settings) for itself.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    /// Number of jobs run at once
+    #[serde(default = "one")]
+    pub parallel: usize,
+    /// CSV file the summary of the jobs is written to, besides stderr
+    pub summary: Option<PathBuf>,
+    /// CSV file the outputs of the CSV jobs are merged into, sorted and without duplicate rows
+    /// (see merge)
+    pub merge: Option<PathBuf>,
+    /// Settings of every job that doesn't set them itself
+    #[serde(default)]
+    pub defaults: Settings,
+    pub jobs: Vec<Job>,
+}
+
+fn one() -> usize {
+    1
+}
+
+/// What a job writes.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// The matching records as CSV, like `mrfy query`
+    #[default]
+    Csv,
+    /// A smaller MRF, like `mrfy extract`
+    Mrf,
+}
+
+/// One run of a query against a data file.
+#[derive(Debug, Deserialize)]
+pub struct Job {
+    /// Name of the job in the summary, the output path if not given
+    pub name: Option<String>,
+    pub data: Option<PathBuf>,
+    pub url: Option<String>,
+    pub query: PathBuf,
+    pub output: PathBuf,
+    #[serde(default)]
+    pub format: Format,
+    #[serde(flatten)]
+    pub settings: Settings,
+}
+
+/// Options of a run, as their command line flags. Unset ones fall back to defaults, then to the
+/// command line default.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Settings {
+    pub skip_corrupt: Option<bool>,
+    pub strict: Option<bool>,
+    pub dedupe: Option<bool>,
+    pub normalize_codes: Option<bool>,
+    pub capture_extra: Option<bool>,
+    pub explode_service_codes: Option<bool>,
+    pub explode_modifiers: Option<bool>,
+    pub no_header: Option<bool>,
+    pub min_per_code: Option<bool>,
+    pub rate_precision: Option<usize>,
+    pub max_age_days: Option<u64>,
+    pub nppes: Option<PathBuf>,
+    pub code_descriptions: Option<PathBuf>,
+    /// A size as for --buff-size, e.g. "256M" or "auto"
+    pub buff_size: Option<String>,
+    /// A codec as for --compress, e.g. "zstd:19", for the output of the job
+    pub compress: Option<String>,
+    /// Write the provenance of the output next to it, as for --meta
+    pub meta: Option<bool>,
+}
+
+impl Settings {
+    /// Returns these settings, with those not set taken from defaults.
+    fn or(&self, defaults: &Settings) -> Settings {
+        Settings {
+            skip_corrupt: self.skip_corrupt.or(defaults.skip_corrupt),
+            strict: self.strict.or(defaults.strict),
+            dedupe: self.dedupe.or(defaults.dedupe),
+            normalize_codes: self.normalize_codes.or(defaults.normalize_codes),
+            capture_extra: self.capture_extra.or(defaults.capture_extra),
+            explode_service_codes: self.explode_service_codes.or(defaults.explode_service_codes),
+            explode_modifiers: self.explode_modifiers.or(defaults.explode_modifiers),
+            no_header: self.no_header.or(defaults.no_header),
+            min_per_code: self.min_per_code.or(defaults.min_per_code),
+            rate_precision: self.rate_precision.or(defaults.rate_precision),
+            max_age_days: self.max_age_days.or(defaults.max_age_days),
+            nppes: self.nppes.clone().or_else(|| defaults.nppes.clone()),
+            code_descriptions: self.code_descriptions.clone().or_else(|| defaults.code_descriptions.clone()),
+            buff_size: self.buff_size.clone().or_else(|| defaults.buff_size.clone()),
+            compress: self.compress.clone().or_else(|| defaults.compress.clone()),
+            meta: self.meta.or(defaults.meta),
+        }
+    }
+
+    /// Sets the options of these settings, with paths relative to base.
+    fn apply(&self, options: &mut Options, base: &Path) {
+        options.skip_corrupt = self.skip_corrupt.unwrap_or(false);
+        options.strict = self.strict.unwrap_or(false);
+        options.dedupe = self.dedupe.unwrap_or(false);
+        options.normalize_codes = self.normalize_codes.unwrap_or(false);
+        options.capture_extra = self.capture_extra.unwrap_or(false);
+        options.explode_service_codes = self.explode_service_codes.unwrap_or(false);
+        options.explode_modifiers = self.explode_modifiers.unwrap_or(false);
+        options.no_header = self.no_header.unwrap_or(false);
+        options.min_per_code = self.min_per_code.unwrap_or(false);
+        options.rate_precision = self.rate_precision;
+        options.max_age_days = self.max_age_days;
+        options.nppes = self.nppes.as_ref().map(|p| base.join(p));
+        options.code_descriptions = self.code_descriptions.as_ref().map(|p| base.join(p));
+    }
+}
+
+impl Manifest {
+    /// Reads the manifest at path, written in the format of its extension.
+    pub fn read(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read manifest '{}'", path.display()))?;
+        let context = || format!("could not parse manifest '{}'", path.display());
+        let manifest: Manifest = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&text).with_context(context)?,
+            Some("json") => serde_json::from_str(&text).with_context(context)?,
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&text).with_context(context)?,
+            _ => return Err(format!("manifest '{}' must end in .toml, .json, .yaml or .yml", path.display()).into()),
+        };
+        Ok(manifest)
+    }
+}
+
+/// How a job went.
+#[derive(Debug)]
+pub struct Outcome {
+    pub name: String,
+    pub elapsed: Duration,
+    /// The summary of the run, or why the job failed
+    pub result: Result<RunSummary, String>,
+}
+
+impl Outcome {
+    /// Returns the status of the job for the summary.
+    fn status(&self) -> &str {
+        match &self.result {
+            Ok(summary) => match summary.exit_code() {
+                EXIT_NO_MATCHES => "no matches",
+                EXIT_DATA_ISSUES => "data issues",
+                _ => "ok",
+            },
+            Err(_) => "failed",
+        }
+    }
+}
+
+/// Runs one job, with the paths of the manifest relative to base, adding its progress to metrics.
+fn run_job(job: &Job,
+           defaults: &Settings,
+           base: &Path,
+           cancel: &CancelToken,
+           metrics: &Arc<Metrics>,
+           reporter: &SharedReporter) -> Result<RunSummary, Box<dyn std::error::Error>> {
+    let source = match (&job.data, &job.url) {
+        (Some(data), None) => match Location::from(data.clone()) {
+            Location::Path(path) => Location::Path(base.join(path)),
+            location => location,
+        },
+        (None, Some(url)) => Location::Url(url.clone()),
+        _ => return Err("a job needs one of data or url".into()),
+    };
+    let mut query = read_input_with(&base.join(&job.query), reporter)?;
+
+    let settings = job.settings.or(defaults);
+    let mut options = Options::new();
+    options.cancel = cancel.clone();
+    options.reporter = reporter.clone();
+    settings.apply(&mut options, base);
+    let buff_size = match &settings.buff_size {
+        Some(size) => BuffSize::parse(size).ok_or_else(|| format!("'{}' is not a buffer size", size))?.resolve(&source),
+        None => DEFAULT_BUFF_SIZE,
+    };
+    options.compression = match &settings.compress {
+        Some(codec) => Some(Codec::parse(codec).ok_or_else(|| format!("'{}' is not a compression codec", codec))?),
+        None => None,
+    };
+
+    // Before the run adds the providers of the groups it selects
+    let query_sha256 = settings.meta.unwrap_or(false).then(|| meta::sha256(query.canonical().as_bytes()));
+
+    let output = base.join(&job.output);
+    if let Some(dir) = output.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    // Several jobs may run at once, so no progress bars
+    let summary = match job.format {
+        Format::Csv => {
+            let out = create_output_with(&output, options.compression)?;
+            asa::run_with_progress(&mut query, &source, buff_size, &options, &mut CsvSink::new(out), metrics.observer())?
+        }
+        Format::Mrf => {
+            options.extract = Some(output.clone());
+            asa::run_with_progress(&mut query, &source, buff_size, &options, &mut CsvSink::new(std::io::sink()), metrics.observer())?
+        }
+    };
+    if let Some(query_sha256) = query_sha256 {
+        Sidecar::new(&source, &summary, query_sha256)?.write_next_to(&output)?;
+    }
+    Ok(summary)
+}
+
+/// Runs the jobs of manifest, manifest.parallel at a time, with the paths of the manifest
+/// relative to base. Returns how each job went, in manifest order. Jobs not started when cancel
+/// is cancelled are left out. The progress of the jobs and those that fail are added to metrics.
+/// The jobs starting and finishing, and the messages of their runs, go to reporter.
+pub fn run(manifest: &Manifest,
+           base: &Path,
+           cancel: &CancelToken,
+           metrics: &Arc<Metrics>,
+           reporter: &SharedReporter) -> Vec<Outcome> {
+    let next = AtomicUsize::new(0);
+    let outcomes: Mutex<Vec<(usize, Outcome)>> = Mutex::new(Vec::new());
+
+    std::thread::scope(|scope| {
+        for _ in 0..manifest.parallel.clamp(1, manifest.jobs.len().max(1)) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let Some(job) = manifest.jobs.get(i) else {
+                    break;
+                };
+                if cancel.is_cancelled() {
+                    break;
+                }
+                let name = job.name.clone().unwrap_or_else(|| job.output.display().to_string());
+                reporter.status(format_args!("Starting job {} of {}: {}", i + 1, manifest.jobs.len(), name));
+
+                let start = Instant::now();
+                let result = run_job(job, &manifest.defaults, base, cancel, metrics, reporter).map_err(|e| e.to_string());
+                if result.is_err() {
+                    metrics.add_error();
+                }
+                let outcome = Outcome { name, elapsed: start.elapsed(), result };
+                reporter.status(format_args!("Finished job {}: {}", outcome.name, outcome.status()));
+                outcomes.lock().unwrap().push((i, outcome));
+            });
+        }
+    });
+
+    let mut outcomes = outcomes.into_inner().unwrap();
+    outcomes.sort_by_key(|(i, _)| *i);
+    outcomes.into_iter().map(|(_, outcome)| outcome).collect()
+}
+
+/// Merges the outputs of the CSV jobs of manifest that succeeded into manifest.merge, if set.
+/// The jobs must all write a header or all not.
+pub fn merge_outputs(manifest: &Manifest,
+                     outcomes: &[Outcome],
+                     base: &Path) -> Result<Option<MergeSummary>, Box<dyn std::error::Error>> {
+    let Some(path) = &manifest.merge else {
+        return Ok(None);
+    };
+    let jobs: Vec<&Job> = manifest.jobs.iter().zip(outcomes)
+        .filter(|(job, outcome)| job.format == Format::Csv && outcome.result.is_ok())
+        .map(|(job, _)| job)
+        .collect();
+    let no_header = |job: &Job| job.settings.or(&manifest.defaults).no_header.unwrap_or(false);
+    if jobs.windows(2).any(|pair| no_header(pair[0]) != no_header(pair[1])) {
+        return Err(format!("cannot merge into {}, some jobs write a header and some don't", path.display()).into());
+    }
+    let inputs: Vec<PathBuf> = jobs.iter().map(|job| base.join(&job.output)).collect();
+    let headers = !jobs.first().is_some_and(|job| no_header(job));
+    Ok(Some(merge_to(&inputs, headers, &base.join(path))?))
+}
+
+/// Hands the summary of outcomes to reporter.
+pub fn summarize(outcomes: &[Outcome], reporter: &SharedReporter) {
+    reporter.status(format_args!("\n{:<30} {:<12} {:>12} {:>10} {:>10}", "job", "status", "rows", "matched", "seconds"));
+    for outcome in outcomes {
+        let (rows, matched) = match &outcome.result {
+            Ok(summary) => (summary.stats.rows_written, summary.stats.objects_matched),
+            Err(_) => (0, 0),
+        };
+        reporter.status(format_args!("{:<30} {:<12} {:>12} {:>10} {:>10.1}",
+                                     outcome.name, outcome.status(), rows, matched, outcome.elapsed.as_secs_f64()));
+        if let Err(e) = &outcome.result {
+            reporter.status(format_args!("    {}", e));
+        }
+    }
+}
+
+/// Writes the summary of outcomes as CSV to out.
+pub fn write_summary(outcomes: &[Outcome],
+                     out: impl std::io::Write) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record(["job", "status", "rows_written", "objects_matched", "code_coverage", "npi_coverage",
+                         "item_coverage", "seconds", "error"])?;
+    // Fractions from 0 to 1, empty when the job failed or had nothing to cover
+    let fraction = |f: Option<f64>| f.map_or(String::new(), |f| format!("{:.4}", f));
+    for outcome in outcomes {
+        let (rows, matched, coverage, error) = match &outcome.result {
+            Ok(summary) => (summary.stats.rows_written, summary.stats.objects_matched, summary.coverage.clone(), ""),
+            Err(e) => (0, 0, Coverage::default(), e.as_str()),
+        };
+        writer.write_record([outcome.name.as_str(), outcome.status(), &rows.to_string(), &matched.to_string(),
+                             &fraction(coverage.code_coverage()), &fraction(coverage.npi_coverage()),
+                             &fraction(coverage.item_coverage()),
+                             &format!("{:.1}", outcome.elapsed.as_secs_f64()), error])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch() {
+        let path = Path::new("tests/testfiles/batch/manifest.toml");
+        let mut manifest = Manifest::read(path).unwrap();
+        assert_eq!(manifest.parallel, 2);
+        assert_eq!(manifest.jobs[1].format, Format::Mrf);
+
+        let out = std::env::temp_dir().join(format!("mrfy_batch_{}", std::process::id()));
+        for job in manifest.jobs.iter_mut() {
+            job.output = out.join(&job.output);
+        }
+        let metrics = Metrics::new();
+        let outcomes = run(&manifest, path.parent().unwrap(), &CancelToken::new(), &metrics, &SharedReporter::default());
+        assert_eq!(outcomes.len(), 3);
+        assert_eq!(outcomes[0].name, "intermediate");
+        // intermediate.json.gz has keys mrfy doesn't support
+        assert_eq!(outcomes[0].status(), "data issues");
+        assert_eq!(outcomes[1].status(), "ok");
+        assert_eq!(outcomes[2].status(), "failed");
+        assert!(metrics.render().contains("\nmrfy_errors_total 1\n"));
+
+        // min_per_code from defaults, no_header from the job
+        let csv = std::fs::read_to_string(out.join("intermediate.csv")).unwrap();
+        assert!(!csv.starts_with("npi,"));
+        assert_eq!(csv.lines().count() as u64, outcomes[0].result.as_ref().unwrap().stats.rows_written);
+        assert!(std::fs::read_to_string(out.join("basic.json")).unwrap().starts_with('{'));
+
+        // Only the CSV jobs that succeeded are merged
+        manifest.merge = Some(out.join("all.csv"));
+        let merged = merge_outputs(&manifest, &outcomes, path.parent().unwrap()).unwrap().unwrap();
+        assert_eq!(merged.rows + merged.duplicates, outcomes[0].result.as_ref().unwrap().stats.rows_written);
+        let mut lines: Vec<&str> = csv.lines().collect();
+        lines.sort();
+        lines.dedup();
+        assert_eq!(std::fs::read_to_string(out.join("all.csv")).unwrap().lines().collect::<Vec<_>>(), lines);
+
+        let mut summary = Vec::new();
+        write_summary(&outcomes, &mut summary).unwrap();
+        let summary = String::from_utf8(summary).unwrap();
+        assert!(summary.starts_with("job,status,rows_written,objects_matched,code_coverage,npi_coverage,item_coverage"));
+        assert_eq!(summary.lines().count(), 4);
+
+        std::fs::remove_dir_all(&out).unwrap();
+    }
+}
@@ -0,0 +1,34 @@
+//! # clock
+//!
+//! Times the parts of a run (see asa::Timings). wasm32 in a browser has no clock the standard
+//! library can read, Instant::now panics there, so on wasm32 a Stopwatch always reads zero.
+
+use std::time::Duration;
+
+/// Measures the time since it was started.
+#[derive(Clone, Copy, Debug)]
+pub struct Stopwatch {
+    #[cfg(not(target_arch = "wasm32"))]
+    start: std::time::Instant,
+}
+
+impl Stopwatch {
+    /// Starts a Stopwatch.
+    pub fn start() -> Self {
+        Self {
+            #[cfg(not(target_arch = "wasm32"))]
+            start: std::time::Instant::now(),
+        }
+    }
+
+    /// Returns the time since the Stopwatch was started.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn elapsed(&self) -> Duration {
+        Duration::ZERO
+    }
+}
@@ -0,0 +1,136 @@
+//! # compare
+//!
+//! Runs one query against several files (different payers or plans) and writes a wide table
+//! with one row per (npi, billing_code_type, billing_code) and one rate column per file.
+//!
+//! Each file is read with --min-per-code, so a file gives at most one rate per row of the table:
+//! the lowest rate it has for that code and npi. A file without a rate for a row leaves its cell
+//! empty.
+
+use crate::asa::{Options, RunSummary};
+use crate::query::Query;
+use crate::sink::MemorySink;
+use crate::source::Location;
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// Rates of each (npi, billing_code_type, billing_code), one per file in file order.
+pub type Table = BTreeMap<(String, String, String), Vec<Option<String>>>;
+
+/// Columns of asa::COLUMNS read from each run.
+const COLUMNS: [&str; 4] = ["npi", "billing_code_type", "billing_code", "negotiated_rate"];
+
+/// Runs query against each of sources and returns the table of their rates, with the summary of
+/// each run. The output options of options (extract, raw_out, rate_summary, explain, nppes,
+/// code_descriptions, explode options) are not used.
+pub fn compare(query: &Query,
+               sources: &[Location],
+               buff_size: usize,
+               options: &Options) -> Result<(Table, Vec<RunSummary>), Box<dyn std::error::Error>> {
+    let mut options = options.clone();
+    options.min_per_code = true;
+    options.no_header = false;
+    options.header_map.clear();
+    options.capture_extra = false;
+    options.explode_service_codes = false;
+    options.explode_modifiers = false;
+    options.extract = None;
+    options.raw_out = None;
+    options.rate_summary = None;
+    options.explain = None;
+    options.nppes = None;
+    options.code_descriptions = None;
+
+    let mut table = Table::new();
+    let mut summaries = Vec::new();
+    for (i, (summary, sink)) in run_all(query, sources, buff_size, &options)?.into_iter().enumerate() {
+        summaries.push(summary);
+        // The header is only written with the first record
+        if sink.records.is_empty() {
+            continue;
+        }
+
+        let column = |name: &str| sink.header.iter().position(|h| h == name).ok_or("missing column");
+        let [npi, code_type, code, rate] = COLUMNS.map(column);
+        let (npi, code_type, code, rate) = (npi?, code_type?, code?, rate?);
+        for record in sink.records {
+            let key = (record[npi].clone(), record[code_type].clone(), record[code].clone());
+            let rates = table.entry(key).or_insert_with(|| vec![None; sources.len()]);
+            rates[i] = Some(record[rate].clone());
+        }
+    }
+
+    Ok((table, summaries))
+}
+
+/// Runs query against each of sources in turn, keeping the rows in memory (one per code and npi,
+/// so the rows of a file fit).
+#[cfg(not(feature = "tokio"))]
+fn run_all(query: &Query,
+           sources: &[Location],
+           buff_size: usize,
+           options: &Options) -> Result<Vec<(RunSummary, MemorySink)>, Box<dyn std::error::Error>> {
+    let mut runs = Vec::new();
+    for source in sources {
+        let mut sink = MemorySink::new();
+        let summary = crate::asa::run(&mut query.clone(), source, buff_size, options, &mut sink)?;
+        runs.push((summary, sink));
+    }
+    Ok(runs)
+}
+
+/// Runs query against sources concurrently, keeping the rows in memory.
+#[cfg(feature = "tokio")]
+fn run_all(query: &Query,
+           sources: &[Location],
+           buff_size: usize,
+           options: &Options) -> Result<Vec<(RunSummary, MemorySink)>, Box<dyn std::error::Error>> {
+    crate::remote::run_concurrently(query, sources, buff_size, options)
+}
+
+/// Writes table as CSV to out, with names as the headers of the rate columns.
+pub fn write_table(table: &Table,
+                   names: &[String],
+                   out: impl Write) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writer = csv::Writer::from_writer(out);
+    let mut header = vec!["npi", "billing_code_type", "billing_code"];
+    header.extend(names.iter().map(|n| n.as_str()));
+    writer.write_record(&header)?;
+
+    for ((npi, code_type, code), rates) in table.iter() {
+        let mut record = vec![npi.as_str(), code_type.as_str(), code.as_str()];
+        record.extend(rates.iter().map(|r| r.as_deref().unwrap_or("")));
+        writer.write_record(&record)?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::{Code, Provider};
+
+    #[test]
+    fn test_compare() {
+        let mut q = Query::new();
+        q.providers.push(Provider::new(4));
+        q.codes.push(Code::new(&String::from("*"), &String::from("Code 3")));
+        q.codes.push(Code::new(&String::from("*"), &String::from("Code 8")));
+
+        let sources = [Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz")),
+                       Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/basic_test.json.gz"))];
+        let (table, summaries) = compare(&q, &sources, 256, &Options::new()).unwrap();
+        assert_eq!(summaries.len(), 2);
+
+        let mut out: Vec<u8> = Vec::new();
+        write_table(&table, &[String::from("a"), String::from("b")], &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out, "npi,billing_code_type,billing_code,a,b\n\
+                         4,Type 3,CODE 3,3000.17,\n\
+                         4,Type 8,CODE 8,8.45,\n");
+    }
+}
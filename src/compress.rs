@@ -0,0 +1,96 @@
+//! # compress
+//!
+//! How output files are compressed. By default a file is compressed by its extension (gzip for
+//! .gz, zstd for .zst with the `zstd` feature); `--compress` chooses the codec and its level
+//! instead, for every output file of a run and for CSV written to stdout.
+
+use std::io::Write;
+use std::path::Path;
+
+/// Default gzip level, as flate2's.
+const GZIP_LEVEL: u32 = 6;
+
+/// Default zstd level, as zstd's.
+const ZSTD_LEVEL: i32 = 3;
+
+/// A compression codec with its level.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    None,
+    /// Level 0 to 9
+    Gzip(u32),
+    /// Level 1 to 22 (requires the `zstd` feature)
+    Zstd(i32),
+}
+
+impl Codec {
+    /// Parses a --compress value: none, gzip, zstd, or gzip:LEVEL or zstd:LEVEL. Returns None for
+    /// anything else, a level out of range, and zstd in a build without the `zstd` feature.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (name, level) = match s.split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (s, None),
+        };
+        match (name, level) {
+            ("none", None) => Some(Codec::None),
+            ("gzip", None) => Some(Codec::Gzip(GZIP_LEVEL)),
+            ("gzip", Some(level)) => level.parse().ok().filter(|l| *l <= 9).map(Codec::Gzip),
+            ("zstd", None) if cfg!(feature = "zstd") => Some(Codec::Zstd(ZSTD_LEVEL)),
+            ("zstd", Some(level)) if cfg!(feature = "zstd") => {
+                level.parse().ok().filter(|l| (1..=22).contains(l)).map(Codec::Zstd)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the codec of a file at path by its extension, gzip for .gz, zstd for .zst (with the
+    /// `zstd` feature) and none for anything else.
+    pub fn for_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("gz") => Codec::Gzip(GZIP_LEVEL),
+            Some("zst") if cfg!(feature = "zstd") => Codec::Zstd(ZSTD_LEVEL),
+            _ => Codec::None,
+        }
+    }
+
+    /// Returns out, compressing what is written to it. The compressed stream is finished when it
+    /// is dropped.
+    pub fn wrap<W: Write + 'static>(self, out: W) -> Result<Box<dyn Write>, std::io::Error> {
+        match self {
+            Codec::None => Ok(Box::new(out)),
+            Codec::Gzip(level) => Ok(Box::new(flate2::write::GzEncoder::new(out, flate2::Compression::new(level)))),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd(level) => Ok(Box::new(zstd::Encoder::new(out, level)?.auto_finish())),
+            #[cfg(not(feature = "zstd"))]
+            Codec::Zstd(_) => unreachable!("Codec::parse only gives zstd with the `zstd` feature"),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codec() {
+        assert_eq!(Codec::parse("none"), Some(Codec::None));
+        assert_eq!(Codec::parse("gzip"), Some(Codec::Gzip(6)));
+        assert_eq!(Codec::parse("gzip:9"), Some(Codec::Gzip(9)));
+        assert_eq!(Codec::parse("gzip:10"), None);
+        assert_eq!(Codec::parse("none:1"), None);
+        assert_eq!(Codec::parse("brotli"), None);
+        assert_eq!(Codec::parse("zstd:19").is_some(), cfg!(feature = "zstd"));
+        assert_eq!(Codec::for_path(Path::new("out.csv.gz")), Codec::Gzip(6));
+        assert_eq!(Codec::for_path(Path::new("out.csv")), Codec::None);
+
+        let path = std::env::temp_dir().join(format!("mrfy_compress_{}.csv", std::process::id()));
+        let mut out = Codec::Gzip(9).wrap(std::fs::File::create(&path).unwrap()).unwrap();
+        out.write_all(b"npi,billing_code\n").unwrap();
+        drop(out);
+        let mut text = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(std::fs::File::open(&path).unwrap()), &mut text).unwrap();
+        assert_eq!(text, "npi,billing_code\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
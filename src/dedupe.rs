@@ -0,0 +1,215 @@
+//! # dedupe
+//!
+//! Remembers the output rows written so far, to drop exact duplicates (payer files often repeat
+//! the same provider, code and price across rate objects).
+//!
+//! Rows are kept as 128 bit hashes. Past MEMORY_ROWS hashes the set is sorted and spilled to a
+//! temporary file, with one hash of every block kept in memory to find the block to read, so
+//! memory stays bounded however many rows a run writes. Spilled files of about the same size are
+//! merged, so a lookup reads at most a few blocks.
+
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of hashes kept in memory before they are spilled to disk (16 bytes each).
+const MEMORY_ROWS: usize = 1 << 22;
+
+/// Number of hashes in a block of a spilled file.
+const BLOCK: usize = 4096;
+
+/// Returns a 128 bit hash of the fields of a row.
+fn row_hash<T: AsRef<str>>(fields: &[T]) -> u128 {
+    let mut halves = [0u64; 2];
+    for (seed, half) in halves.iter_mut().enumerate() {
+        let mut hasher = DefaultHasher::new();
+        seed.hash(&mut hasher);
+        for field in fields {
+            field.as_ref().hash(&mut hasher);
+        }
+        *half = hasher.finish();
+    }
+    (halves[0] as u128) << 64 | halves[1] as u128
+}
+
+/// Sorted hashes in a temporary file, removed when dropped.
+#[derive(Debug)]
+struct Spill {
+    file: File,
+    path: PathBuf,
+    /// First hash of each block
+    firsts: Vec<u128>,
+    len: usize,
+}
+
+impl Spill {
+    /// Writes the sorted hashes to a new temporary file.
+    fn new(sorted: impl Iterator<Item = Result<u128, std::io::Error>>) -> Result<Self, std::io::Error> {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!("mrfy-dedupe-{}-{}.tmp",
+                                                    std::process::id(),
+                                                    FILES.fetch_add(1, Ordering::Relaxed)));
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        let mut spill = Spill { file, path, firsts: Vec::new(), len: 0 };
+
+        let mut writer = BufWriter::new(&mut spill.file);
+        for hash in sorted {
+            let hash = hash?;
+            if spill.len.is_multiple_of(BLOCK) {
+                spill.firsts.push(hash);
+            }
+            writer.write_all(&hash.to_le_bytes())?;
+            spill.len += 1;
+        }
+        writer.flush()?;
+        drop(writer);
+
+        Ok(spill)
+    }
+
+    /// Returns the hashes of the file in order.
+    fn iter(&mut self) -> Result<impl Iterator<Item = Result<u128, std::io::Error>> + '_, std::io::Error> {
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(&mut self.file);
+        Ok((0..self.len).map(move |_| {
+            let mut buf = [0u8; 16];
+            reader.read_exact(&mut buf)?;
+            Ok(u128::from_le_bytes(buf))
+        }))
+    }
+
+    /// Merges two files into a new one.
+    fn merge(mut a: Spill, mut b: Spill) -> Result<Self, std::io::Error> {
+        let mut a = a.iter()?.peekable();
+        let mut b = b.iter()?.peekable();
+        let merged = std::iter::from_fn(|| {
+            let take_a = match (a.peek(), b.peek()) {
+                (Some(Ok(x)), Some(Ok(y))) => x <= y,
+                (Some(_), None) | (Some(Err(_)), _) => true,
+                _ => false,
+            };
+            if take_a { a.next() } else { b.next() }
+        });
+        Spill::new(merged)
+    }
+
+    /// Returns true if hash is in the file, reading the one block it could be in.
+    fn contains(&mut self, hash: u128) -> Result<bool, std::io::Error> {
+        let block = match self.firsts.partition_point(|first| *first <= hash) {
+            0 => return Ok(false),
+            n => n - 1,
+        };
+        let start = block * BLOCK;
+        let count = BLOCK.min(self.len - start);
+
+        let mut buf = vec![0u8; count * 16];
+        self.file.seek(SeekFrom::Start(start as u64 * 16))?;
+        self.file.read_exact(&mut buf)?;
+        let hashes: Vec<u128> = buf.chunks_exact(16)
+            .map(|b| u128::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        Ok(hashes.binary_search(&hash).is_ok())
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// The set of rows written so far.
+#[derive(Debug)]
+pub struct RowSet {
+    seen: HashSet<u128>,
+    spills: Vec<Spill>,
+    memory_rows: usize,
+    /// Number of duplicate rows found by insert
+    pub duplicates: u64,
+}
+
+impl Default for RowSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RowSet {
+    /// Creates an empty RowSet.
+    pub fn new() -> Self {
+        Self::with_memory_rows(MEMORY_ROWS)
+    }
+
+    /// Creates an empty RowSet that spills to disk past memory_rows rows.
+    fn with_memory_rows(memory_rows: usize) -> Self {
+        Self {
+            seen: HashSet::new(),
+            spills: Vec::new(),
+            memory_rows,
+            duplicates: 0,
+        }
+    }
+
+    /// Adds the row with these fields. Returns false (and counts a duplicate) if the same row
+    /// was added before.
+    pub fn insert<T: AsRef<str>>(&mut self, fields: &[T]) -> Result<bool, std::io::Error> {
+        let hash = row_hash(fields);
+        let mut known = self.seen.contains(&hash);
+        for spill in self.spills.iter_mut() {
+            if known {
+                break;
+            }
+            known = spill.contains(hash)?;
+        }
+        if known {
+            self.duplicates += 1;
+            return Ok(false);
+        }
+
+        self.seen.insert(hash);
+        if self.seen.len() >= self.memory_rows {
+            let mut sorted: Vec<u128> = self.seen.drain().collect();
+            sorted.sort_unstable();
+            self.spills.push(Spill::new(sorted.into_iter().map(Ok))?);
+
+            // Keeps the files in decreasing sizes, each at least twice the next
+            while let [.., a, b] = self.spills.as_slice() && a.len < 2 * b.len {
+                let b = self.spills.pop().unwrap();
+                let a = self.spills.pop().unwrap();
+                self.spills.push(Spill::merge(a, b)?);
+            }
+        }
+        Ok(true)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_row_set() {
+        let mut rows = RowSet::new();
+        assert!(rows.insert(&["1", "ein", "CODE 1", "9.99"]).unwrap());
+        assert!(!rows.insert(&["1", "ein", "CODE 1", "9.99"]).unwrap());
+        // Field boundaries count
+        assert!(rows.insert(&["1", "ein", "CODE 19", ".99"]).unwrap());
+        assert_eq!(rows.duplicates, 1);
+
+        // Spills every 10 rows
+        let mut rows = RowSet::with_memory_rows(10);
+        for i in 0..10_000 {
+            assert!(rows.insert(&[i.to_string()]).unwrap());
+        }
+        assert!(rows.spills.len() <= 10);
+        assert_eq!(rows.spills.iter().map(|s| s.len).sum::<usize>(), 10_000);
+        for i in (0..10_000).step_by(7) {
+            assert!(!rows.insert(&[i.to_string()]).unwrap());
+        }
+        assert!(rows.insert(&["10000"]).unwrap());
+    }
+}
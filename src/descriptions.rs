@@ -0,0 +1,96 @@
+//! # descriptions
+//!
+//! Reads a lookup file of billing code descriptions (e.g. CPT or HCPCS short descriptors) to add
+//! one consistent description of each code to the output rows, since the description of an
+//! in_network item is whatever the payer wrote, or nothing.
+//!
+//! The file is CSV with a header. The code column is named code, billing_code, hcpc or hcpcs, the
+//! description column description, short_description, short description or descriptor (ignoring
+//! case). An optional code_type or billing_code_type column keeps codes of different types apart,
+//! codes without a type describe that code of any type.
+
+use std::collections::HashMap;
+
+use crate::query::{normalize_code, normalize_code_type};
+
+/// Output column added from the lookup file, after the NPPES columns.
+pub const COLUMN: &str = "code_description";
+
+const CODE_COLUMNS: [&str; 4] = ["code", "billing_code", "hcpc", "hcpcs"];
+const TYPE_COLUMNS: [&str; 2] = ["code_type", "billing_code_type"];
+const DESCRIPTION_COLUMNS: [&str; 4] = ["description", "short_description", "short description", "descriptor"];
+
+/// Returns description trimmed, with each run of whitespace replaced by one space.
+pub fn normalize_description(description: &str) -> String {
+    description.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+/// Descriptions by (normalized code type, or "" for any type, normalized code).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CodeDescriptions {
+    descriptions: HashMap<(String, String), String>,
+}
+
+impl CodeDescriptions {
+    /// Reads the lookup file at path. Rows without a code or a description are skipped.
+    pub fn read(path: &std::path::Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut reader = csv::Reader::from_path(path)?;
+        let headers = reader.headers()?.clone();
+        let column = |names: &[&str]| headers.iter().position(|h| names.iter().any(|n| h.trim().eq_ignore_ascii_case(n)));
+
+        let code = column(&CODE_COLUMNS).ok_or_else(|| {
+            format!("{} has no code column ({})", path.display(), CODE_COLUMNS.join(", "))
+        })?;
+        let description = column(&DESCRIPTION_COLUMNS).ok_or_else(|| {
+            format!("{} has no description column ({})", path.display(), DESCRIPTION_COLUMNS.join(", "))
+        })?;
+        let code_type = column(&TYPE_COLUMNS);
+
+        let mut descriptions = HashMap::new();
+        for record in reader.records() {
+            let record = record?;
+            let (c, d) = (record.get(code).unwrap_or(""), record.get(description).unwrap_or(""));
+            if c.trim().is_empty() || d.trim().is_empty() {
+                continue;
+            }
+            let c_type = match code_type.and_then(|i| record.get(i)).map(str::trim) {
+                Some(t) if !t.is_empty() => normalize_code_type(t),
+                _ => String::new(),
+            };
+            descriptions.insert((c_type, normalize_code(c)), normalize_description(d));
+        }
+
+        Ok(Self { descriptions })
+    }
+
+    /// Returns the description of code c of type c_type, or of code c of any type.
+    pub fn get(&self, c_type: &str, c: &str) -> Option<&str> {
+        let c = normalize_code(c);
+        self.descriptions.get(&(normalize_code_type(c_type), c.clone()))
+            .or_else(|| self.descriptions.get(&(String::new(), c)))
+            .map(|d| d.as_str())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code_descriptions() {
+        let path = std::path::Path::new("tests/testfiles/descriptions/code_descriptions.csv");
+        let descriptions = CodeDescriptions::read(path).unwrap();
+
+        assert_eq!(descriptions.get("CPT", "99213"), Some("Office/outpatient visit est"));
+        assert_eq!(descriptions.get("cpt-4", " 99213 "), Some("Office/outpatient visit est"));
+        // Typed codes are kept apart, untyped ones match any type
+        assert_eq!(descriptions.get("MS-DRG", "023"), Some("Craniotomy w MCC"));
+        assert_eq!(descriptions.get("APR-DRG", "23"), None);
+        assert_eq!(descriptions.get("HCPCS", "j1100"), Some("Dexamethasone sodium phos"));
+        assert_eq!(descriptions.get("RC", "J1100"), Some("Dexamethasone sodium phos"));
+        assert_eq!(descriptions.get("CPT", "00000"), None);
+
+        assert_eq!(normalize_description("  a\tb   c "), "a b c");
+    }
+}
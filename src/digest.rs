@@ -0,0 +1,165 @@
+//! # digest
+//!
+//! A t-digest (Dunning and Ertl), to estimate the median and other quantiles of a stream of
+//! rates in a fixed amount of memory.
+//!
+//! Values are buffered and merged into a sorted list of centroids (mean, weight). Centroids near
+//! the middle of the distribution may hold many values, those near the tails only a few, so
+//! extreme quantiles stay accurate. Small sets keep one centroid per value and give exact
+//! quantiles.
+
+use std::f64::consts::PI;
+
+/// Default compression, about the number of centroids kept.
+const COMPRESSION: f64 = 100.0;
+
+/// Streaming quantile estimates of the values added.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TDigest {
+    compression: f64,
+    /// (mean, weight) sorted by mean
+    centroids: Vec<(f64, f64)>,
+    buffer: Vec<f64>,
+    count: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Default for TDigest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TDigest {
+    /// Creates an empty TDigest.
+    pub fn new() -> Self {
+        Self {
+            compression: COMPRESSION,
+            centroids: Vec::new(),
+            buffer: Vec::new(),
+            count: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    /// Adds value.
+    pub fn add(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        self.buffer.push(value);
+        self.count += 1.0;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        if self.buffer.len() as f64 >= 5.0 * self.compression {
+            self.compress();
+        }
+    }
+
+    /// Returns the scale function k(q), centroids span at most 1 in k.
+    fn k(&self, q: f64) -> f64 {
+        self.compression / (2.0 * PI) * (2.0 * q - 1.0).clamp(-1.0, 1.0).asin()
+    }
+
+    /// Returns the q with k(q) = k.
+    fn k_inverse(&self, k: f64) -> f64 {
+        let q = ((2.0 * PI * k / self.compression).sin() + 1.0) / 2.0;
+        q.clamp(0.0, 1.0)
+    }
+
+    /// Merges the buffered values into the centroids.
+    fn compress(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let mut all: Vec<(f64, f64)> = self.buffer.drain(..).map(|v| (v, 1.0)).collect();
+        all.append(&mut self.centroids);
+        all.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let total = self.count;
+        let mut merged: Vec<(f64, f64)> = Vec::new();
+        let mut current = all[0];
+        let mut q_start = 0.0;
+        let mut q_limit = self.k_inverse(self.k(q_start) + 1.0);
+        for &next in all[1..].iter() {
+            let q = q_start + (current.1 + next.1) / total;
+            if q <= q_limit {
+                let weight = current.1 + next.1;
+                current = (current.0 + (next.0 - current.0) * next.1 / weight, weight);
+            }
+            else {
+                q_start += current.1 / total;
+                q_limit = self.k_inverse(self.k(q_start) + 1.0);
+                merged.push(current);
+                current = next;
+            }
+        }
+        merged.push(current);
+        self.centroids = merged;
+    }
+
+    /// Returns the estimated q quantile (0 to 1) of the values added, or None if there are none.
+    /// Interpolates between the centers of neighbouring centroids.
+    pub fn quantile(&mut self, q: f64) -> Option<f64> {
+        self.compress();
+        let (first, last) = (self.centroids.first()?, self.centroids.last()?);
+        if self.centroids.len() == 1 {
+            return Some(first.0);
+        }
+
+        let target = q.clamp(0.0, 1.0) * self.count;
+        if target < first.1 / 2.0 {
+            return Some(self.min + (first.0 - self.min) * target / (first.1 / 2.0));
+        }
+        if target > self.count - last.1 / 2.0 {
+            let from_end = self.count - target;
+            return Some(self.max - (self.max - last.0) * from_end / (last.1 / 2.0));
+        }
+
+        let mut seen = 0.0;
+        for pair in self.centroids.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            let (center_a, center_b) = (seen + a.1 / 2.0, seen + a.1 + b.1 / 2.0);
+            if target <= center_b {
+                return Some(a.0 + (b.0 - a.0) * (target - center_a) / (center_b - center_a));
+            }
+            seen += a.1;
+        }
+        Some(last.0)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tdigest() {
+        let mut digest = TDigest::new();
+        assert_eq!(digest.quantile(0.5), None);
+
+        // Small sets are exact
+        for v in [3.0, 1.0, 2.0] {
+            digest.add(v);
+        }
+        assert_eq!(digest.quantile(0.5), Some(2.0));
+        digest.add(4.0);
+        assert_eq!(digest.quantile(0.5), Some(2.5));
+        assert_eq!(digest.quantile(0.0), Some(1.0));
+        assert_eq!(digest.quantile(1.0), Some(4.0));
+
+        // Large sets are close, in far fewer centroids
+        let mut digest = TDigest::new();
+        for i in 0..100_000 {
+            digest.add(((i * 7919) % 100_000) as f64);
+        }
+        for q in [0.01, 0.25, 0.5, 0.75, 0.9, 0.99] {
+            let estimate = digest.quantile(q).unwrap();
+            assert!((estimate - q * 100_000.0).abs() < 300.0, "q {} estimate {}", q, estimate);
+        }
+        assert!(digest.centroids.len() < 200);
+    }
+}
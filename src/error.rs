@@ -1,10 +1,26 @@
 //! # error
 //!
 //! Basic error struct for handling non-fatal errors. 
+//! Also holds the error returned when a run is interrupted and the typed errors for
+//! problems found in the query and data files.
 
 
 use std::error::Error;
 
+/// Exit code of a run that wrote records.
+pub const EXIT_MATCHES: i32 = 0;
+/// Exit code of a fatal error (also what returning an error from main gives).
+pub const EXIT_FATAL: i32 = 1;
+/// Exit code of a run that completed without problems but matched nothing.
+pub const EXIT_NO_MATCHES: i32 = 2;
+/// Exit code of a run that completed with non-fatal data issues, e.g. skipped corrupt items
+/// or provider groups without a provider_group_id.
+pub const EXIT_DATA_ISSUES: i32 = 3;
+/// Exit code of a run rejected because the file is older than --max-age-days.
+pub const EXIT_STALE: i32 = 4;
+/// Exit code of a run stopped with ctrl-C.
+pub const EXIT_INTERRUPTED: i32 = 130;
+
 /// Used to incur non-zero exit code while continuing to process data.
 #[derive(Debug)]
 pub struct NonFatalError(pub String);
@@ -16,3 +32,89 @@ impl std::fmt::Display for NonFatalError {
 }
 
 impl Error for NonFatalError {}
+
+/// Returned by asa::run when the user stops the run with ctrl-C, or the host application cancels
+/// it (see asa::CancelToken).
+/// Carries how far the run got so main can report a partial summary.
+#[derive(Debug)]
+pub struct Interrupted {
+    pub objects: u64,
+    pub records: u64,
+}
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Interrupted after processing {} in_network objects ({} records written)",
+               self.objects, self.records)
+    }
+}
+
+impl Error for Interrupted {}
+
+/// Returned by asa::run when the file's last_updated_on is more than max_age_days ago, or is
+/// missing or not a date (age_days None).
+#[derive(Debug)]
+pub struct Stale {
+    pub last_updated_on: Option<String>,
+    pub age_days: Option<i64>,
+    pub max_age_days: u64,
+}
+
+impl std::fmt::Display for Stale {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.last_updated_on, self.age_days) {
+            (Some(date), Some(age)) => write!(f, "file last updated on {} is {} days old, more than {} days",
+                                              date, age, self.max_age_days),
+            (Some(date), None) => write!(f, "file last_updated_on '{}' is not a YYYY-MM-DD date", date),
+            (None, _) => write!(f, "file has no last_updated_on to check its age"),
+        }
+    }
+}
+
+impl Error for Stale {}
+
+/// Problems found in a query input file, line is the 1-based line number.
+#[derive(Debug, thiserror::Error)]
+pub enum QueryError {
+    #[error("line {line}: '{value}' is not a valid NPI")]
+    InvalidNpi { line: usize, value: String },
+
+    #[error("line {line}: '{value}' is indented but comes before any npi or billing code type line")]
+    NoSection { line: usize, value: String },
+
+    #[error("line {line}: invalid regex '{value}': {message}")]
+    InvalidRegex { line: usize, value: String, message: String },
+
+    #[error("line {line}: expected an npi or code_type,code")]
+    InvalidRow { line: usize },
+}
+
+/// Fatal problems found while parsing the data file.
+/// context names the part of the JSON being processed and offset is the decompressed byte
+/// offset of the problem (approximate, except for syntax errors).
+#[derive(Debug, thiserror::Error)]
+pub enum ParseError {
+    #[error("malformed JSON in {context} at byte {offset}: {message}")]
+    Syntax { context: &'static str, offset: u64, message: String },
+
+    #[error("unexpected end of file in {context} near byte {offset}")]
+    UnexpectedEof { context: &'static str, offset: u64 },
+
+    #[error("unexpected {found} in {context} near byte {offset}")]
+    Unexpected { context: &'static str, offset: u64, found: String },
+
+    #[error("invalid npi '{value}' in {context} near byte {offset}")]
+    InvalidNpi { context: &'static str, offset: u64, value: String },
+
+    #[error("negotiated_rate '{value}' is not a plain decimal number in {context} near byte {offset}")]
+    InvalidRate { context: &'static str, offset: u64, value: String },
+
+    #[error("unsupported key \"{key}\" in {context} near byte {offset}")]
+    UnsupportedKey { context: &'static str, offset: u64, key: String },
+
+    #[error("{0} not found in file")]
+    MissingSection(&'static str),
+
+    #[error("failed to read data file: {0}")]
+    Io(#[from] std::io::Error),
+}
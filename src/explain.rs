@@ -0,0 +1,146 @@
+//! # explain
+//!
+//! Says why in_network items gave no rows (`--explain`), to debug a run that writes nothing
+//! without bisecting the file.
+//!
+//! Each skipped item is written to a CSV file as it is skipped, with its index in in_network,
+//! what of its code and name was read by then and the reason. The number of items skipped for
+//! each reason is reported at the end of the run.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+use crate::asa::SharedReporter;
+use crate::compress::Codec;
+
+/// Why an in_network item gave no rows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Reason {
+    /// Before --start-offset
+    BeforeStartOffset,
+    /// The name has none of the --name-prefilter values
+    NamePrefilter,
+    /// The billing code isn't one of the query
+    CodeNotInQuery,
+    /// No negotiated_rates with a provider of the query and a price passing the price filters
+    NoMatchingRates,
+    /// The item has no billing_code
+    NoBillingCode,
+    /// The code is excluded by a ! section of the query
+    ExcludedCode,
+    /// Neither name nor description matches the keyword and regex sections of the query
+    NameNotMatched,
+    /// billing_code_type_version differs from the version pinned in the query
+    /// (--exclude-version-mismatch)
+    VersionMismatch,
+    /// The item failed to parse (--skip-corrupt)
+    Corrupt,
+}
+
+impl Reason {
+    /// Returns the name of the reason written to the file.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Reason::BeforeStartOffset => "before_start_offset",
+            Reason::NamePrefilter => "name_prefilter",
+            Reason::CodeNotInQuery => "code_not_in_query",
+            Reason::NoMatchingRates => "no_matching_rates",
+            Reason::NoBillingCode => "no_billing_code",
+            Reason::ExcludedCode => "excluded_code",
+            Reason::NameNotMatched => "name_not_matched",
+            Reason::VersionMismatch => "version_mismatch",
+            Reason::Corrupt => "corrupt",
+        }
+    }
+}
+
+/// Writes the skipped items of a run to a CSV file and counts them by reason.
+pub struct Explain {
+    writer: csv::Writer<Box<dyn Write>>,
+    counts: BTreeMap<Reason, u64>,
+    /// Print the counts when finished
+    print: bool,
+}
+
+impl Explain {
+    /// Creates the file at path (compressed with codec, else gzip compressed if it ends in .gz)
+    /// with its header.
+    pub fn create(path: &Path, codec: Option<Codec>) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::new(crate::asa::create_output_with(path, codec)?)
+    }
+
+    /// Writes the skipped items to out.
+    pub fn new(out: Box<dyn Write>) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut writer = csv::Writer::from_writer(out);
+        writer.write_record(["item", "billing_code_type", "billing_code", "name", "reason"])?;
+        Ok(Self { writer, counts: BTreeMap::new(), print: true })
+    }
+
+    /// Counts the skipped items without writing or printing them, e.g. for a report.
+    pub fn counting() -> Result<Self, Box<dyn std::error::Error>> {
+        let mut explain = Self::new(Box::new(std::io::sink()))?;
+        explain.print = false;
+        Ok(explain)
+    }
+
+    /// Records that the item-th element of in_network was skipped for reason, with what of its
+    /// code and name was read.
+    pub fn skip(&mut self,
+                item: u64,
+                billing_code_type: &str,
+                billing_code: &str,
+                name: &str,
+                reason: Reason) -> Result<(), csv::Error> {
+        *self.counts.entry(reason).or_default() += 1;
+        self.writer.write_record([&item.to_string(), billing_code_type, billing_code, name, reason.as_str()])
+    }
+
+    /// Returns the number of items skipped for each reason that skipped any.
+    pub fn counts(&self) -> &BTreeMap<Reason, u64> {
+        &self.counts
+    }
+
+    /// Flushes the file and hands the counts to reporter.
+    pub fn finish(&mut self, reporter: &SharedReporter) -> Result<(), std::io::Error> {
+        self.writer.flush()?;
+        if !self.print {
+            return Ok(());
+        }
+        if self.counts().is_empty() {
+            reporter.status(format_args!("No in_network items were skipped"));
+        }
+        else {
+            reporter.status(format_args!("Skipped in_network items by reason:"));
+            for (reason, count) in self.counts() {
+                reporter.status(format_args!("  {}: {}", reason.as_str(), count));
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_explain() {
+        let path = std::env::temp_dir().join(format!("mrfy_explain_{}.csv", std::process::id()));
+        let mut explain = Explain::create(&path, None).unwrap();
+        explain.skip(0, "CPT", "99213", "Office visit", Reason::CodeNotInQuery).unwrap();
+        explain.skip(3, "", "", "Knee, left", Reason::NamePrefilter).unwrap();
+        explain.skip(4, "CPT", "99214", "", Reason::CodeNotInQuery).unwrap();
+        explain.finish(&SharedReporter::default()).unwrap();
+
+        assert_eq!(explain.counts().iter().map(|(r, c)| (*r, *c)).collect::<Vec<_>>(),
+                   [(Reason::NamePrefilter, 1), (Reason::CodeNotInQuery, 2)]);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(),
+                   "item,billing_code_type,billing_code,name,reason\n\
+                    0,CPT,99213,Office visit,code_not_in_query\n\
+                    3,,,\"Knee, left\",name_prefilter\n\
+                    4,CPT,99214,,code_not_in_query\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
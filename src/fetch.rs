@@ -0,0 +1,260 @@
+//! # fetch
+//!
+//! Downloads MRF files to a cache directory (`mrfy fetch`), so a file is downloaded once and
+//! queried as often as needed. Requires the `http` feature.
+//!
+//! A file is kept at CACHE_DIR/host/path of its URL, without the query string, so a signed link
+//! and its next signature share a copy. It is downloaded to a .part file next to it, resumed with
+//! a Range request after a failure, and renamed once complete (and, with a SHA-256 to check,
+//! verified). `--url` of a query reads the cached copy when there is one (see cached).
+
+use std::path::{Path, PathBuf};
+
+use crate::asa::{CancelToken, SharedReporter};
+
+/// Returns the default cache directory: MRFY_CACHE_DIR, else mrfy under XDG_CACHE_HOME or
+/// ~/.cache.
+pub fn default_cache_dir() -> PathBuf {
+    let var = |name: &str| std::env::var_os(name).filter(|v| !v.is_empty()).map(PathBuf::from);
+    if let Some(dir) = var("MRFY_CACHE_DIR") {
+        return dir;
+    }
+    match (var("XDG_CACHE_HOME"), var("HOME")) {
+        (Some(cache), _) => cache.join("mrfy"),
+        (None, Some(home)) => home.join(".cache").join("mrfy"),
+        (None, None) => PathBuf::from(".mrfy-cache"),
+    }
+}
+
+/// Returns where the file at url is kept under dir: dir/host/path, without the query string.
+pub fn cache_path(dir: &Path, url: &str) -> Result<PathBuf, String> {
+    let rest = url.strip_prefix("https://").or_else(|| url.strip_prefix("http://"))
+        .ok_or_else(|| format!("'{}' is not an http(s) URL", url))?;
+    let rest = rest.split(['?', '#']).next().unwrap_or("");
+    let mut segments = rest.split('/').filter(|s| !s.is_empty() && *s != "." && *s != "..");
+
+    let host = segments.next().ok_or_else(|| format!("'{}' has no host", url))?;
+    let mut path = dir.join(host.replace(':', "_"));
+    let mut named = false;
+    for segment in segments {
+        path.push(segment);
+        named = true;
+    }
+    if !named {
+        return Err(format!("'{}' has no file name", url));
+    }
+    Ok(path)
+}
+
+/// Returns the cached copy of the file at url under dir, if it was fetched completely.
+pub fn cached(dir: &Path, url: &str) -> Option<PathBuf> {
+    cache_path(dir, url).ok().filter(|path| path.is_file())
+}
+
+/// How to download.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FetchOptions {
+    /// Attempts after the first before giving up
+    pub retries: u32,
+    /// Bytes per second at most, None for no limit
+    pub limit_rate: Option<u64>,
+    /// Expected SHA-256 of the file, as hex
+    pub sha256: Option<String>,
+    /// Stops the download when cancelled, keeping the .part file to resume from
+    pub cancel: CancelToken,
+    /// Told about cached files and retried downloads
+    pub reporter: SharedReporter,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FetchOptions {
+    /// Creates the default FetchOptions.
+    pub fn new() -> Self {
+        Self { retries: 8, limit_rate: None, sha256: None, cancel: CancelToken::new(), reporter: SharedReporter::default() }
+    }
+}
+
+/// Returns how long to wait so that sent bytes after elapsed keep to limit bytes per second.
+#[cfg(feature = "http")]
+fn throttle_delay(sent: u64, elapsed: std::time::Duration, limit: u64) -> std::time::Duration {
+    let due = std::time::Duration::from_secs_f64(sent as f64 / limit.max(1) as f64);
+    due.saturating_sub(elapsed)
+}
+
+/// Downloads the file at url to its place under dir (see cache_path) and returns its path. A
+/// complete copy already there is kept, after checking its SHA-256 if one is given. A cancelled
+/// download returns an error of kind Interrupted.
+#[cfg(feature = "http")]
+pub fn fetch(url: &str,
+             dir: &Path,
+             options: &FetchOptions) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let path = cache_path(dir, url)?;
+    if path.is_file() {
+        if let Some(expected) = &options.sha256 {
+            http::verify(&path, expected)?;
+        }
+        options.reporter.status(format_args!("{} is already in the cache", url));
+        return Ok(path);
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mut part = path.clone().into_os_string();
+    part.push(".part");
+    let part = PathBuf::from(part);
+
+    let mut attempt = 0;
+    loop {
+        match http::download(url, &part, options) {
+            Ok(()) => break,
+            Err(e) if attempt < options.retries && !options.cancel.is_cancelled() => {
+                attempt += 1;
+                let offset = std::fs::metadata(&part).map_or(0, |m| m.len());
+                options.reporter.warn(format_args!("download of {} failed ({}), resuming at byte {} (attempt {} of {})...",
+                                                   url, e, offset, attempt, options.retries));
+                std::thread::sleep(std::time::Duration::from_secs(2u64.pow(attempt.min(5))));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    if let Some(expected) = &options.sha256 && let Err(e) = http::verify(&part, expected) {
+        // A corrupt copy would be resumed, not replaced
+        std::fs::remove_file(&part)?;
+        return Err(e);
+    }
+    std::fs::rename(&part, &path)?;
+    Ok(path)
+}
+
+#[cfg(not(feature = "http"))]
+pub fn fetch(url: &str,
+             _dir: &Path,
+             _options: &FetchOptions) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    Err(format!("cannot fetch '{}'; rebuild mrfy with the `http` feature to download files", url).into())
+}
+
+#[cfg(feature = "http")]
+mod http {
+    //! The download itself, with ureq.
+
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Read, Write};
+    use std::path::Path;
+    use std::time::Instant;
+
+    /// Downloads url to part, continuing from the end of part if it exists.
+    pub fn download(url: &str,
+                    part: &Path,
+                    options: &super::FetchOptions) -> Result<(), Box<dyn std::error::Error>> {
+        let offset = std::fs::metadata(part).map_or(0, |m| m.len());
+        let mut request = ureq::get(url);
+        if offset > 0 {
+            request = request.set("Range", &format!("bytes={}-", offset));
+        }
+        let response = match request.call() {
+            Ok(response) => response,
+            // Nothing left after offset, the last attempt got it all
+            Err(ureq::Error::Status(416, _)) if offset > 0 => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        // A server that ignores Range sends the whole file again
+        let ranged = response.status() == 206;
+        let mut file = match ranged {
+            true => OpenOptions::new().append(true).open(part)?,
+            false => File::create(part)?,
+        };
+        let start_at = if ranged { offset } else { 0 };
+        let total = response.header("Content-Length")
+            .and_then(|l| l.parse::<u64>().ok())
+            .map(|length| length + start_at);
+
+        let mut body = response.into_reader();
+        let mut buf = vec![0; 64 * 1024];
+        let mut written = start_at;
+        let (start, mut sent) = (Instant::now(), 0u64);
+        loop {
+            if options.cancel.is_cancelled() {
+                file.flush()?;
+                return Err(Box::new(io::Error::new(io::ErrorKind::Interrupted, "download interrupted")));
+            }
+            let n = body.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            file.write_all(&buf[..n])?;
+            written += n as u64;
+            sent += n as u64;
+            if let Some(limit) = options.limit_rate {
+                std::thread::sleep(super::throttle_delay(sent, start.elapsed(), limit));
+            }
+        }
+        file.flush()?;
+
+        if total.is_some_and(|t| written < t) {
+            return Err(Box::new(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed early")));
+        }
+        Ok(())
+    }
+
+    /// Returns the SHA-256 of the file at path, as lowercase hex.
+    pub fn sha256(path: &Path) -> io::Result<String> {
+        crate::meta::sha256_file(path)
+    }
+
+    /// Checks that the file at path has the SHA-256 expected (hex, in any case).
+    pub fn verify(path: &Path, expected: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let actual = sha256(path)?;
+        if !actual.eq_ignore_ascii_case(expected.trim()) {
+            return Err(format!("SHA-256 of {} is {}, expected {}", path.display(), actual, expected.trim()).into());
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_path() {
+        let dir = Path::new("/cache");
+        assert_eq!(cache_path(dir, "https://mrf.example.com/2025-05/in_network.json.gz?sig=abc&exp=1").unwrap(),
+                   PathBuf::from("/cache/mrf.example.com/2025-05/in_network.json.gz"));
+        assert_eq!(cache_path(dir, "http://host:8080//a/../b.json").unwrap(),
+                   PathBuf::from("/cache/host_8080/a/b.json"));
+        assert!(cache_path(dir, "https://host/?x=1").is_err());
+        assert!(cache_path(dir, "s3://bucket/key").is_err());
+
+        assert_eq!(cached(Path::new("tests/testfiles"), "https://data_files/basic_test.json.gz?v=2"),
+                   Some(PathBuf::from("tests/testfiles/data_files/basic_test.json.gz")));
+        assert_eq!(cached(Path::new("tests/testfiles"), "https://data_files/missing.json.gz"), None);
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_throttle_delay() {
+        use std::time::Duration;
+        // 1000 bytes at 100 per second take 10 seconds
+        assert_eq!(throttle_delay(1000, Duration::from_secs(4), 100), Duration::from_secs(6));
+        assert_eq!(throttle_delay(1000, Duration::from_secs(20), 100), Duration::ZERO);
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_verify() {
+        let path = Path::new("tests/testfiles/batch/query.toml");
+        let sha = http::sha256(path).unwrap();
+        assert_eq!(sha.len(), 64);
+        assert!(http::verify(path, &sha.to_uppercase()).is_ok());
+        assert!(http::verify(path, &"0".repeat(64)).is_err());
+    }
+}
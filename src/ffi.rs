@@ -0,0 +1,286 @@
+//! # ffi
+//!
+//! A C API to run queries from other languages (Java through JNI, Go through cgo, ...) in the
+//! same process, built into the `cdylib` and `staticlib` of the crate:
+//!
+//! ```c
+//! MrfyFile *file = mrfy_open("in-network.json.gz");
+//! if (file == NULL || mrfy_query(file, "{\"providers\": {\"npi\": [1234567893]}, \"codes\": {\"CPT\": [\"99213\"]}}") != 0) {
+//!     fprintf(stderr, "%s\n", mrfy_last_error());
+//! }
+//! const char *record;
+//! while ((record = mrfy_next_record(file)) != NULL) {
+//!     puts(record);  /* {"npi":"1234567893","billing_code":"99213",...} */
+//! }
+//! mrfy_close(file);
+//! ```
+//!
+//! A query is the text of a JSON query file (see query::QueryFile). It runs on its own thread,
+//! at most RECORDS_AHEAD records ahead of mrfy_next_record, so memory stays bounded however many
+//! rows match. Each record is a JSON object of the columns of the CSV output, as `mrfy serve`
+//! returns them. Strings passed in and returned are UTF-8 and NUL-terminated; the strings returned
+//! belong to mrfy.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::JoinHandle;
+
+use crate::asa::{self, CancelToken, Options, Progress};
+use crate::query;
+use crate::sink::{MatchedRecord, RecordSink};
+use crate::source::{DEFAULT_BUFF_SIZE, Location};
+
+/// Records a query runs ahead of mrfy_next_record at most.
+const RECORDS_AHEAD: usize = 1024;
+
+// Error of the last call on this thread, if it failed.
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Sets the error of the call being made on this thread.
+fn set_error(message: impl std::fmt::Display) {
+    let message = CString::new(message.to_string().replace('\0', " ")).expect("NULs were replaced");
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+/// Clears the error of the last call, at the start of a call.
+fn clear_error() {
+    LAST_ERROR.with(|e| e.borrow_mut().take());
+}
+
+/// Returns the string at s, or None if it is NULL or not UTF-8.
+///
+/// # Safety
+/// s must be NULL or a NUL-terminated string.
+unsafe fn str_arg<'a>(s: *const c_char) -> Option<&'a str> {
+    match s.is_null() {
+        true => None,
+        false => unsafe { CStr::from_ptr(s) }.to_str().ok(),
+    }
+}
+
+/// A datafile opened by mrfy_open, with the query running on it, if any.
+pub struct MrfyFile {
+    source: Location,
+    run: Option<Run>,
+    /// The record last returned by mrfy_next_record
+    record: Option<CString>,
+}
+
+/// A query running on its own thread.
+struct Run {
+    records: Receiver<CString>,
+    cancel: CancelToken,
+    /// None once joined
+    thread: Option<JoinHandle<Result<(), String>>>,
+}
+
+impl Drop for Run {
+    /// Stops the query and waits for its thread.
+    fn drop(&mut self) {
+        self.cancel.cancel();
+        // Unblocks the run until it notices it is cancelled
+        while self.records.recv().is_ok() {}
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Sends each record to a channel as a JSON object of its columns.
+struct ChannelSink {
+    header: Vec<String>,
+    records: SyncSender<CString>,
+}
+
+impl RecordSink for ChannelSink {
+    fn write_header(&mut self, columns: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+        self.header = columns.iter().map(|c| c.to_string()).collect();
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &MatchedRecord) -> Result<(), Box<dyn std::error::Error>> {
+        let row: serde_json::Map<String, serde_json::Value> = self.header.iter().zip(record.values)
+            .map(|(column, value)| (column.clone(), (*value).into()))
+            .collect();
+        // JSON escapes NULs, so there are none in the text
+        let json = CString::new(serde_json::Value::Object(row).to_string())?;
+        self.records.send(json).map_err(|_| "the query was closed")?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+}
+
+/// Opens the datafile at path, a file or an http(s), s3://, gs:// or az:// URL as on the command
+/// line. Returns NULL if it can't be opened, see mrfy_last_error. The file is freed by mrfy_close.
+///
+/// # Safety
+/// path must be NULL or a NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mrfy_open(path: *const c_char) -> *mut MrfyFile {
+    clear_error();
+    let Some(path) = (unsafe { str_arg(path) }) else {
+        set_error("the path is NULL or not UTF-8");
+        return ptr::null_mut();
+    };
+    let source = match path.starts_with("http://") || path.starts_with("https://") {
+        true => Location::Url(path.to_string()),
+        false => Location::from(PathBuf::from(path)),
+    };
+    if let Location::Path(path) = &source && let Err(e) = std::fs::metadata(path) {
+        set_error(format!("{}: {}", path.display(), e));
+        return ptr::null_mut();
+    }
+    Box::into_raw(Box::new(MrfyFile { source, run: None, record: None }))
+}
+
+/// Starts running query, the text of a JSON query file, on file, stopping the query running on
+/// it if any. Returns 0, or -1 if the query is invalid, see mrfy_last_error. The records are read
+/// with mrfy_next_record.
+///
+/// # Safety
+/// file must be NULL or returned by mrfy_open and not closed, query NULL or a NUL-terminated
+/// string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mrfy_query(file: *mut MrfyFile, query: *const c_char) -> c_int {
+    clear_error();
+    let Some(file) = (unsafe { file.as_mut() }) else {
+        set_error("the file is NULL");
+        return -1;
+    };
+    file.run = None;
+    file.record = None;
+    let Some(text) = (unsafe { str_arg(query) }) else {
+        set_error("the query is NULL or not UTF-8");
+        return -1;
+    };
+    let mut q = match query::parse_json(text) {
+        Ok(q) => q,
+        Err(e) => {
+            set_error(e);
+            return -1;
+        }
+    };
+
+    let cancel = CancelToken::new();
+    let mut options = Options::new();
+    options.cancel = cancel.clone();
+    let source = file.source.clone();
+    let (sender, records) = mpsc::sync_channel(RECORDS_AHEAD);
+    let thread = std::thread::spawn(move || {
+        let mut sink = ChannelSink { header: Vec::new(), records: sender };
+        asa::run_with_progress(&mut q, &source, DEFAULT_BUFF_SIZE, &options, &mut sink, |_: &Progress| {})
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    });
+    file.run = Some(Run { records, cancel, thread: Some(thread) });
+    0
+}
+
+/// Returns the next record of the query running on file, valid until the next call on file.
+/// Returns NULL once every record has been returned, or if the query failed, when
+/// mrfy_last_error returns why.
+///
+/// # Safety
+/// file must be NULL or returned by mrfy_open and not closed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mrfy_next_record(file: *mut MrfyFile) -> *const c_char {
+    clear_error();
+    let Some(file) = (unsafe { file.as_mut() }) else {
+        set_error("the file is NULL");
+        return ptr::null();
+    };
+    let Some(run) = &mut file.run else {
+        set_error("no query is running, call mrfy_query first");
+        return ptr::null();
+    };
+    match run.records.recv() {
+        Ok(record) => file.record.insert(record).as_ptr(),
+        // The run is over
+        Err(_) => {
+            let result = run.thread.take().map(|thread| {
+                thread.join().unwrap_or_else(|_| Err(String::from("the query panicked")))
+            });
+            if let Some(Err(e)) = result {
+                set_error(e);
+            }
+            file.record = None;
+            ptr::null()
+        }
+    }
+}
+
+/// Stops the query running on file, if any, and frees file.
+///
+/// # Safety
+/// file must be NULL or returned by mrfy_open and not closed yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mrfy_close(file: *mut MrfyFile) {
+    if !file.is_null() {
+        drop(unsafe { Box::from_raw(file) });
+    }
+}
+
+/// Returns the error of the last call made on this thread, or NULL if it succeeded. The string
+/// is valid until the next call on this thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn mrfy_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::MemorySink;
+
+    #[test]
+    fn test_ffi() {
+        const QUERY: &str = r#"{"providers": {"npi": ["*"]}, "codes": {"*": ["*"]}}"#;
+        let path = c"tests/testfiles/data_files/intermediate.json.gz";
+        let query = CString::new(QUERY).unwrap();
+
+        let mut expected = MemorySink::new();
+        let source = Location::Path(PathBuf::from(path.to_str().unwrap()));
+        asa::run_with_progress(&mut query::parse_json(QUERY).unwrap(), &source, DEFAULT_BUFF_SIZE,
+                               &Options::new(), &mut expected, |_: &Progress| {}).unwrap();
+        assert!(!expected.records.is_empty());
+
+        unsafe {
+            let file = mrfy_open(path.as_ptr());
+            assert!(!file.is_null());
+            assert_eq!(mrfy_query(file, c"{\"nope\": 1}".as_ptr()), -1);
+            assert!(!mrfy_last_error().is_null());
+            assert_eq!(mrfy_query(file, query.as_ptr()), 0);
+            assert!(mrfy_last_error().is_null());
+
+            let mut records = Vec::new();
+            loop {
+                let record = mrfy_next_record(file);
+                if record.is_null() {
+                    break;
+                }
+                let json: serde_json::Value = serde_json::from_str(CStr::from_ptr(record).to_str().unwrap()).unwrap();
+                records.push(json);
+            }
+            assert!(mrfy_last_error().is_null());
+            assert_eq!(records.len(), expected.records.len());
+            assert_eq!(records[0]["npi"], expected.records[0][0].as_str());
+
+            // Closed while running
+            assert_eq!(mrfy_query(file, query.as_ptr()), 0);
+            assert!(!mrfy_next_record(file).is_null());
+            mrfy_close(file);
+
+            assert!(mrfy_open(c"no/such/file.json".as_ptr()).is_null());
+            assert!(!mrfy_last_error().is_null());
+        }
+    }
+}
@@ -0,0 +1,306 @@
+//! # filter
+//!
+//! Expressions that keep only some of the output rows of a query (`--where`), e.g.
+//! `negotiated_rate < 500 && billing_class == 'professional'`.
+//!
+//! A comparison is two operands, a column or a literal ('text', "text" or a number), and one of
+//! `<`, `<=`, `>`, `>=`, `==` and `!=`. Two numbers are compared as numbers and anything else as
+//! text, except that a number compared with text that isn't one (e.g. a rate of null) is false.
+//! Comparisons are combined with `&&`, `||` and `!`, and grouped with parentheses; `&&` binds
+//! tighter than `||`.
+//!
+//! Filter::parse checks the syntax and the column names, and bind turns the names into the
+//! positions of the columns of a run, so a row is checked without a lookup by name.
+
+/// A comparison operator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+impl Op {
+    /// Returns true if the ordering of the left operand to the right one satisfies the operator.
+    fn holds(&self, ordering: std::cmp::Ordering) -> bool {
+        use std::cmp::Ordering::*;
+        match self {
+            Op::Lt => ordering == Less,
+            Op::Le => ordering != Greater,
+            Op::Gt => ordering == Greater,
+            Op::Ge => ordering != Less,
+            Op::Eq => ordering == Equal,
+            Op::Ne => ordering != Equal,
+        }
+    }
+}
+
+/// An operand of a comparison, a column by name (Column) or position (Index) or a literal.
+#[derive(Clone, Debug, PartialEq)]
+enum Operand {
+    Column(String),
+    Index(usize),
+    Text(String),
+    Number(f64),
+}
+
+/// A filter expression.
+#[derive(Clone, Debug, PartialEq)]
+enum Expr {
+    Compare(Operand, Op, Operand),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+/// A token of a filter expression.
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Name(String),
+    Text(String),
+    Number(f64),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+/// Splits text into tokens.
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let (token, len) = match (c, next) {
+            (c, _) if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ('<', Some('=')) => (Token::Op(Op::Le), 2),
+            ('>', Some('=')) => (Token::Op(Op::Ge), 2),
+            ('=', Some('=')) => (Token::Op(Op::Eq), 2),
+            ('!', Some('=')) => (Token::Op(Op::Ne), 2),
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('<', _) => (Token::Op(Op::Lt), 1),
+            ('>', _) => (Token::Op(Op::Gt), 1),
+            ('!', _) => (Token::Not, 1),
+            ('(', _) => (Token::Open, 1),
+            (')', _) => (Token::Close, 1),
+            ('\'', _) | ('"', _) => {
+                let end = chars[i + 1..].iter().position(|&q| q == c)
+                    .ok_or_else(|| format!("unterminated {} at {}", c, i))?;
+                (Token::Text(chars[i + 1..i + 1 + end].iter().collect()), end + 2)
+            }
+            (c, _) if c.is_ascii_digit() || c == '-' || c == '.' => {
+                let len = chars[i..].iter().take_while(|d| d.is_ascii_digit() || **d == '.' || **d == '-').count();
+                let number: String = chars[i..i + len].iter().collect();
+                let value = number.parse().map_err(|_| format!("'{}' is not a number", number))?;
+                (Token::Number(value), len)
+            }
+            (c, _) if c.is_ascii_alphabetic() || c == '_' => {
+                let len = chars[i..].iter().take_while(|d| d.is_ascii_alphanumeric() || **d == '_').count();
+                (Token::Name(chars[i..i + len].iter().collect()), len)
+            }
+            (c, _) => return Err(format!("unexpected '{}' at {}", c, i)),
+        };
+        tokens.push(token);
+        i += len;
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser over the tokens of an expression.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    /// Returns the next token without taking it.
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    /// Takes the next token.
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    /// or := and ('||' and)*
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    /// and := unary ('&&' unary)*
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    /// unary := '!' unary | '(' or ')' | operand op operand
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some(Token::Not) => {
+                self.next();
+                Ok(Expr::Not(Box::new(self.unary()?)))
+            }
+            Some(Token::Open) => {
+                self.next();
+                let expr = self.or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    _ => Err(String::from("missing )")),
+                }
+            }
+            _ => {
+                let left = self.operand()?;
+                let op = match self.next() {
+                    Some(Token::Op(op)) => op,
+                    other => return Err(format!("expected a comparison after {:?}, found {:?}", left, other)),
+                };
+                Ok(Expr::Compare(left, op, self.operand()?))
+            }
+        }
+    }
+
+    /// operand := column | 'text' | number
+    fn operand(&mut self) -> Result<Operand, String> {
+        match self.next() {
+            Some(Token::Name(name)) => Ok(Operand::Column(name)),
+            Some(Token::Text(text)) => Ok(Operand::Text(text)),
+            Some(Token::Number(number)) => Ok(Operand::Number(number)),
+            other => Err(format!("expected a column or a value, found {:?}", other)),
+        }
+    }
+}
+
+/// A parsed --where expression.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Filter {
+    expr: Box<Expr>,
+}
+
+impl Filter {
+    /// Parses text, checking its columns are among columns.
+    pub fn parse(text: &str, columns: &[&str]) -> Result<Self, String> {
+        let mut parser = Parser { tokens: tokenize(text)?, pos: 0 };
+        let expr = parser.or()?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("unexpected {:?} after the expression", token));
+        }
+        let filter = Filter { expr: Box::new(expr) };
+        filter.bind(columns)?;
+        Ok(filter)
+    }
+
+    /// Returns the filter with its column names replaced by their positions in columns.
+    pub fn bind(&self, columns: &[&str]) -> Result<Self, String> {
+        fn operand(operand: &Operand, columns: &[&str]) -> Result<Operand, String> {
+            match operand {
+                Operand::Column(name) => columns.iter().position(|c| c == name).map(Operand::Index)
+                    .ok_or_else(|| format!("no column {} in the output", name)),
+                other => Ok(other.clone()),
+            }
+        }
+        fn expr(e: &Expr, columns: &[&str]) -> Result<Expr, String> {
+            Ok(match e {
+                Expr::Compare(left, op, right) => Expr::Compare(operand(left, columns)?, *op, operand(right, columns)?),
+                Expr::Not(e) => Expr::Not(Box::new(expr(e, columns)?)),
+                Expr::And(a, b) => Expr::And(Box::new(expr(a, columns)?), Box::new(expr(b, columns)?)),
+                Expr::Or(a, b) => Expr::Or(Box::new(expr(a, columns)?), Box::new(expr(b, columns)?)),
+            })
+        }
+        Ok(Filter { expr: Box::new(expr(&self.expr, columns)?) })
+    }
+
+    /// Returns true if row passes the filter. The filter must be bound to the columns of row.
+    pub fn matches(&self, row: &[&str]) -> bool {
+        fn value<'a>(operand: &'a Operand, row: &[&'a str]) -> (&'a str, Option<f64>) {
+            match operand {
+                Operand::Index(i) => {
+                    let text = row.get(*i).copied().unwrap_or("");
+                    (text, text.parse().ok())
+                }
+                Operand::Text(text) => (text, None),
+                Operand::Number(number) => ("", Some(*number)),
+                Operand::Column(_) => unreachable!("the filter is bound before it is used"),
+            }
+        }
+        fn eval(e: &Expr, row: &[&str]) -> bool {
+            match e {
+                Expr::Compare(left, op, right) => {
+                    let is_number = |o: &Operand| matches!(o, Operand::Number(_));
+                    let ((l_text, l_num), (r_text, r_num)) = (value(left, row), value(right, row));
+                    match (l_num, r_num) {
+                        (Some(l), Some(r)) => l.partial_cmp(&r).is_some_and(|o| op.holds(o)),
+                        // A number literal against a value that isn't one
+                        _ if is_number(left) || is_number(right) => false,
+                        _ => op.holds(l_text.cmp(r_text)),
+                    }
+                }
+                Expr::Not(e) => !eval(e, row),
+                Expr::And(a, b) => eval(a, row) && eval(b, row),
+                Expr::Or(a, b) => eval(a, row) || eval(b, row),
+            }
+        }
+        eval(&self.expr, row)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let columns = ["billing_code", "negotiated_rate", "billing_class"];
+        let filter = Filter::parse("negotiated_rate < 500 && billing_class == 'professional'", &columns).unwrap();
+        let filter = filter.bind(&columns).unwrap();
+        assert!(filter.matches(&["99213", "75.50", "professional"]));
+        assert!(!filter.matches(&["99213", "500", "professional"]));
+        assert!(!filter.matches(&["99213", "75.50", "institutional"]));
+        assert!(!filter.matches(&["99213", "null", "professional"]));
+
+        // && binds tighter than ||, parentheses and ! group
+        let filter = Filter::parse("billing_code == \"99213\" || billing_code == '99214' && negotiated_rate >= 100",
+                                   &columns).unwrap().bind(&columns).unwrap();
+        assert!(filter.matches(&["99213", "1", ""]));
+        assert!(!filter.matches(&["99214", "1", ""]));
+        assert!(filter.matches(&["99214", "100", ""]));
+        let filter = Filter::parse("!(negotiated_rate > 10 || billing_class != 'x')", &columns).unwrap()
+            .bind(&columns).unwrap();
+        assert!(filter.matches(&["", "5", "x"]));
+        assert!(!filter.matches(&["", "5", "y"]));
+
+        // Columns of another run are positions of its own
+        let filter = Filter::parse("negotiated_rate > -1.5", &columns).unwrap();
+        assert!(filter.bind(&["negotiated_rate"]).unwrap().matches(&["0"]));
+        assert!(filter.bind(&["npi"]).is_err());
+
+        assert!(Filter::parse("rate < 5", &columns).is_err());
+        assert!(Filter::parse("negotiated_rate <", &columns).is_err());
+        assert!(Filter::parse("(negotiated_rate < 5", &columns).is_err());
+        assert!(Filter::parse("billing_class == 'x", &columns).is_err());
+        assert!(Filter::parse("negotiated_rate < 5 5", &columns).is_err());
+    }
+}
@@ -0,0 +1,255 @@
+//! # html
+//!
+//! Writes the report of a run as one self-contained HTML page (`--report-html`), to keep with the
+//! output or attach to a review: the file metadata, the run statistics and coverage, the codes and
+//! npis of the query with how many rows each gave (the unmatched ones first), the in_network items
+//! skipped by reason and the rates matched for each billing code.
+//!
+//! The page has its style inline and no scripts, so it opens the same anywhere.
+
+use std::fmt::Write as _;
+use std::io::Write;
+use std::path::Path;
+
+use crate::asa::RunSummary;
+use crate::stats::percentile_label;
+
+/// Style of the page.
+const STYLE: &str = "body { font-family: sans-serif; margin: 2em; color: #222; }
+h1 { font-size: 1.5em; } h2 { font-size: 1.2em; margin-top: 2em; }
+table { border-collapse: collapse; margin: 0.5em 0; }
+th, td { border: 1px solid #ccc; padding: 0.25em 0.6em; text-align: left; }
+th { background: #f0f0f0; } td.n { text-align: right; font-variant-numeric: tabular-nums; }
+tr.miss td { background: #fdecea; } p.note { color: #666; }";
+
+/// Returns text with the characters HTML gives a meaning to escaped.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A cell of a table: text, or a number aligned to the right.
+enum Cell {
+    Text(String),
+    Number(String),
+}
+
+/// Appends a table with header and rows to html. Rows whose flag is set are highlighted.
+fn table(html: &mut String, header: &[&str], rows: &[(bool, Vec<Cell>)]) {
+    html.push_str("<table>\n<tr>");
+    for name in header {
+        let _ = write!(html, "<th>{}</th>", escape(name));
+    }
+    html.push_str("</tr>\n");
+    for (highlight, cells) in rows {
+        html.push_str(if *highlight { "<tr class=\"miss\">" } else { "<tr>" });
+        for cell in cells {
+            let _ = match cell {
+                Cell::Text(text) => write!(html, "<td>{}</td>", escape(text)),
+                Cell::Number(number) => write!(html, "<td class=\"n\">{}</td>", escape(number)),
+            };
+        }
+        html.push_str("</tr>\n");
+    }
+    html.push_str("</table>\n");
+}
+
+/// Returns part of whole as "part of whole (x%)", or "-" without a fraction (nothing to cover).
+fn share(part: u64, whole: u64, fraction: Option<f64>) -> String {
+    match fraction {
+        Some(fraction) => format!("{} of {} ({:.1}%)", part, whole, fraction * 100.0),
+        None => String::from("-"),
+    }
+}
+
+/// Returns the report of the run of summary over the datafile named source as an HTML page,
+/// with the percentiles of the rates of each code.
+pub fn render(summary: &RunSummary, source: &str, percentiles: &[f64]) -> String {
+    let stats = &summary.stats;
+    let mut html = String::new();
+    let _ = write!(html, "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+                          <title>mrfy report: {0}</title>\n<style>\n{1}\n</style>\n</head>\n<body>\n\
+                          <h1>mrfy report: {0}</h1>\n", escape(source), STYLE);
+
+    html.push_str("<h2>File</h2>\n");
+    let mut rows: Vec<(bool, Vec<Cell>)> = summary.metadata.iter()
+        .map(|(key, value)| (false, vec![Cell::Text(key.to_string()), Cell::Text(value.clone())]))
+        .collect();
+    rows.insert(0, (false, vec![Cell::Text(String::from("datafile")), Cell::Text(source.to_string())]));
+    table(&mut html, &["key", "value"], &rows);
+
+    html.push_str("<h2>Run</h2>\n");
+    let coverage = &summary.coverage;
+    let mut rows = vec![
+        ("rows written", stats.rows_written.to_string()),
+        ("in_network items read", stats.in_network_items.to_string()),
+        ("codes covered", share(coverage.codes_matched, coverage.codes, coverage.code_coverage())),
+        ("npis covered", share(coverage.npis_matched, coverage.npis, coverage.npi_coverage())),
+        ("items matched", share(coverage.items_matched, coverage.items, coverage.item_coverage())),
+        ("wall time", format!("{:.1}s", stats.elapsed.as_secs_f64())),
+        ("decompressed bytes", stats.decompressed_bytes.to_string()),
+    ];
+    for (label, count) in [("corrupt items skipped", stats.corrupt_skipped),
+                           ("missing group ids", stats.missing_group_ids),
+                           ("duplicates skipped", stats.duplicates_skipped),
+                           ("rates fixed", stats.fixed_rates),
+                           ("rates not numbers", stats.invalid_rates)] {
+        if count > 0 {
+            rows.push((label, count.to_string()));
+        }
+    }
+    let rows: Vec<_> = rows.into_iter()
+        .map(|(label, value)| (false, vec![Cell::Text(label.to_string()), Cell::Number(value)]))
+        .collect();
+    table(&mut html, &["statistic", "value"], &rows);
+    if summary.no_providers {
+        html.push_str("<p class=\"note\">No provider of the query is in the file.</p>\n");
+    }
+
+    if !summary.query.codes.is_empty() {
+        html.push_str("<h2>Codes of the query</h2>\n");
+        let mut codes: Vec<_> = summary.query.codes.iter().collect();
+        codes.sort_by_key(|c| c.matched);
+        let rows: Vec<_> = codes.iter().map(|c| (!c.matched, vec![
+            Cell::Text(c.code_type.clone()),
+            Cell::Text(c.code.clone()),
+            Cell::Text(c.version.clone().unwrap_or_default()),
+            Cell::Text(String::from(if c.matched { "yes" } else { "no" })),
+            Cell::Number(c.records.to_string()),
+        ])).collect();
+        table(&mut html, &["code type", "code", "version", "matched", "rows"], &rows);
+    }
+
+    if !summary.query.npis.is_empty() {
+        html.push_str("<h2>NPIs of the query</h2>\n");
+        let mut npis: Vec<_> = summary.query.npis.iter().collect();
+        npis.sort_by_key(|n| n.matched);
+        let rows: Vec<_> = npis.iter().map(|n| (!n.matched, vec![
+            Cell::Text(n.npi.to_string()),
+            Cell::Text(n.groups.iter().map(|g| g.group_id.as_str()).collect::<Vec<_>>().join(" ")),
+            Cell::Text(String::from(if n.matched { "yes" } else { "no" })),
+            Cell::Number(n.records.to_string()),
+        ])).collect();
+        table(&mut html, &["npi", "provider groups", "matched", "rows"], &rows);
+    }
+
+    let selections = [("Group ids of the query", &summary.query.group_ids), ("TINs of the query", &summary.query.tins)];
+    for (title, selection) in selections {
+        if selection.is_empty() {
+            continue;
+        }
+        let _ = writeln!(html, "<h2>{}</h2>", title);
+        let rows: Vec<_> = selection.iter().map(|s| (!s.matched, vec![
+            Cell::Text(s.value.clone()),
+            Cell::Text(String::from(if s.matched { "yes" } else { "no" })),
+            Cell::Number(s.records.to_string()),
+        ])).collect();
+        table(&mut html, &["value", "matched", "rows"], &rows);
+    }
+
+    html.push_str("<h2>Skipped in_network items</h2>\n");
+    match stats.skipped.is_empty() {
+        true => html.push_str("<p class=\"note\">No in_network item was skipped.</p>\n"),
+        false => {
+            let rows: Vec<_> = stats.skipped.iter()
+                .map(|(reason, count)| (false, vec![Cell::Text(reason.as_str().to_string()), Cell::Number(count.to_string())]))
+                .collect();
+            table(&mut html, &["reason", "items"], &rows);
+        }
+    }
+
+    if !summary.unsupported_keys.is_empty() {
+        html.push_str("<h2>Unsupported keys</h2>\n");
+        let rows: Vec<_> = summary.unsupported_keys.iter().map(|k| (false, vec![
+            Cell::Text(k.key.clone()),
+            Cell::Text(k.context.to_string()),
+            Cell::Number(k.count.to_string()),
+        ])).collect();
+        table(&mut html, &["key", "in", "times"], &rows);
+    }
+
+    html.push_str("<h2>Rates by billing code</h2>\n");
+    match stats.code_rates.is_empty() {
+        true => html.push_str("<p class=\"note\">No rate was matched.</p>\n"),
+        false => {
+            let mut header = vec![String::from("code type"), String::from("code"), String::from("count"), String::from("min")];
+            header.extend(percentiles.iter().map(|p| percentile_label(*p)));
+            header.extend([String::from("mean"), String::from("max")]);
+            let header: Vec<&str> = header.iter().map(|h| h.as_str()).collect();
+
+            let rows: Vec<_> = stats.code_rates.iter().map(|(code, rates)| {
+                let mut rates = rates.clone();
+                let mut cells: Vec<Cell> = code.iter().map(|c| Cell::Text(c.clone())).collect();
+                cells.push(Cell::Number(rates.count.to_string()));
+                let mut values = vec![rates.min];
+                values.extend(percentiles.iter().map(|p| rates.percentile(*p)));
+                values.extend([rates.mean(), rates.max]);
+                cells.extend(values.iter().map(|v| Cell::Number(format!("{:.2}", v))));
+                (false, cells)
+            }).collect();
+            table(&mut html, &header, &rows);
+        }
+    }
+
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+/// Writes the report of render to the file at path.
+pub fn write_report(path: &Path,
+                    summary: &RunSummary,
+                    source: &str,
+                    percentiles: &[f64]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = std::fs::File::create(path)
+        .map_err(|e| format!("Failed to create report {}: {}", path.display(), e))?;
+    file.write_all(render(summary, source, percentiles).as_bytes())?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::{CodeReport, NpiReport};
+    use crate::stats::RateSummary;
+
+    #[test]
+    fn test_render() {
+        let mut summary = RunSummary {
+            metadata: vec![("reporting_entity_name", String::from("A & B <Health>"))],
+            ..Default::default()
+        };
+        summary.query.codes = vec![
+            CodeReport { code_type: String::from("CPT"), code: String::from("99213"), matched: true, records: 3, ..Default::default() },
+            CodeReport { code_type: String::from("CPT"), code: String::from("99999"), ..Default::default() },
+        ];
+        summary.query.npis = vec![NpiReport { npi: 1234567893, ..Default::default() }];
+        let mut rates = RateSummary::new();
+        for rate in [10.0, 20.0, 30.0] {
+            rates.add(rate);
+        }
+        summary.stats.code_rates.insert(vec![String::from("CPT"), String::from("99213")], rates);
+        summary.stats.skipped.insert(crate::explain::Reason::CodeNotInQuery, 7);
+
+        let html = render(&summary, "in-network.json.gz", &[50.0]);
+        assert!(html.contains("<td>A &amp; B &lt;Health&gt;</td>"));
+        // Unmatched codes first, highlighted
+        let missing = html.find("<tr class=\"miss\"><td>CPT</td><td>99999</td>").unwrap();
+        assert!(missing < html.find("<td>99213</td>").unwrap());
+        assert!(html.contains("<tr class=\"miss\"><td>1234567893</td>"));
+        assert!(html.contains("<td>code_not_in_query</td><td class=\"n\">7</td>"));
+        assert!(html.contains("<th>median</th>"));
+        assert!(html.contains("<td>99213</td><td class=\"n\">3</td><td class=\"n\">10.00</td>"));
+        assert!(html.ends_with("</html>\n"));
+    }
+}
@@ -0,0 +1,304 @@
+//! # index
+//!
+//! A seek index of an MRF, so a rate can be looked up without reading the whole file (used by
+//! `mrfy serve`).
+//!
+//! Building the index reads the file once and copies each provider_references and in_network
+//! element to an elements file, each as its own gzip member, noting where it starts. The index
+//! maps each billing code to its in_network elements and each npi to the provider_references
+//! elements listing it. A lookup reads only those elements back, puts them together as a small
+//! MRF and runs the query on it with asa::run, like on any other file.
+//!
+//! The index of a file is a directory holding index.json and elements.gz.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use json_event_parser::{JsonEvent, ReaderJsonParser, WriterJsonSerializer};
+use serde::{Deserialize, Serialize};
+
+use crate::asa::{self, next_event, unexpected_eof, Options, ParseContext, Progress, SharedReporter};
+use crate::query::{normalize_code, Code, Provider, Query};
+use crate::sink::MemorySink;
+use crate::source::{DataSource, Location};
+
+const INDEX_FILE: &str = "index.json";
+const ELEMENTS_FILE: &str = "elements.gz";
+
+/// Where an element is in the elements file: (offset, length) of its gzip member.
+type Span = (u64, u64);
+
+/// What the index knows about a file.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Index {
+    /// Top level fields other than the two arrays (reporting_entity_name, version, ...)
+    metadata: Vec<(String, String)>,
+    /// in_network elements by normalized billing code
+    codes: HashMap<String, Vec<Span>>,
+    /// provider_references elements by npi
+    npis: HashMap<u64, Vec<Span>>,
+}
+
+/// Returns the default index directory of a local data file, DATA_PATH.index next to it.
+pub fn default_dir(source: &Location) -> Option<PathBuf> {
+    match source {
+        Location::Path(path) => {
+            let mut dir = path.clone().into_os_string();
+            dir.push(".index");
+            Some(PathBuf::from(dir))
+        }
+        _ => None,
+    }
+}
+
+impl Index {
+    /// Reads source and writes its index to dir, which is created if needed. What happens while
+    /// reading source goes to reporter.
+    pub fn build(source: &dyn DataSource,
+                 buff_size: usize,
+                 dir: &Path,
+                 reporter: &SharedReporter) -> Result<Self, Box<dyn std::error::Error>> {
+        std::fs::create_dir_all(dir)?;
+        let mut elements = BufWriter::new(File::create(dir.join(ELEMENTS_FILE))?);
+        let mut ctx = ParseContext::new();
+        let reader = ctx.track_offsets(BufReader::with_capacity(buff_size, source.open(reporter)?));
+        let mut parser = ReaderJsonParser::new(reader);
+
+        // Depths: 3 element, 5 provider group, 6 its npi array
+        const ELEMENT: u64 = 3;
+        const GROUP: u64 = 5;
+        const FIELD: u64 = 6;
+
+        let mut index = Index::default();
+        let mut offset: u64 = 0;
+        let mut depth: u64 = 0;
+        let mut section = String::new();
+        let mut key = String::new();
+        let mut group_key = String::new();
+
+        // The element being copied, and its codes or npis
+        let mut json: Option<WriterJsonSerializer<GzEncoder<Vec<u8>>>> = None;
+        let mut codes: Vec<String> = Vec::new();
+        let mut npis: Vec<u64> = Vec::new();
+
+        loop {
+            let event = next_event(&mut parser, &mut ctx, "index")?;
+            let in_elements = section == "provider_references" || section == "in_network";
+            if json.is_none() && in_elements && depth == ELEMENT - 1 && matches!(event, JsonEvent::StartObject) {
+                json = Some(WriterJsonSerializer::new(GzEncoder::new(Vec::new(), flate2::Compression::fast())));
+            }
+            if let Some(json) = json.as_mut() {
+                json.serialize_event(event.clone())?;
+            }
+
+            match event {
+                JsonEvent::StartObject | JsonEvent::StartArray => {
+                    depth += 1;
+                }
+                JsonEvent::EndObject | JsonEvent::EndArray => {
+                    depth -= 1;
+                    if depth == ELEMENT - 1 && let Some(element) = json.take() {
+                        let member = element.finish()?.finish()?;
+                        elements.write_all(&member)?;
+                        let span = (offset, member.len() as u64);
+                        offset += span.1;
+                        for code in codes.drain(..) {
+                            index.codes.entry(code).or_default().push(span);
+                        }
+                        for npi in npis.drain(..) {
+                            index.npis.entry(npi).or_default().push(span);
+                        }
+                    }
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                JsonEvent::ObjectKey(k) => {
+                    if depth == 1 {
+                        section = k.to_string();
+                    }
+                    else if depth == GROUP {
+                        group_key = k.to_string();
+                    }
+                    key.clear();
+                    key.push_str(k.as_ref());
+                }
+                JsonEvent::String(value) | JsonEvent::Number(value) => {
+                    if depth == 1 {
+                        index.metadata.push((section.clone(), value.to_string()));
+                    }
+                    else if section == "in_network" && depth == ELEMENT && key == "billing_code" {
+                        codes.push(normalize_code(&value));
+                    }
+                    else if section == "provider_references" && depth == FIELD && group_key == "npi" &&
+                            let Ok(npi) = value.parse::<u64>() {
+                        npis.push(npi);
+                    }
+                }
+                JsonEvent::Eof => {
+                    return Err(Box::new(unexpected_eof(&ctx, "index")));
+                }
+                _ => {}
+            }
+        }
+        elements.flush()?;
+
+        for spans in index.npis.values_mut() {
+            spans.dedup();
+        }
+        serde_json::to_writer(BufWriter::new(File::create(dir.join(INDEX_FILE))?), &index)?;
+        Ok(index)
+    }
+
+    /// Reads the index written to dir by build.
+    pub fn load(dir: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = BufReader::new(File::open(dir.join(INDEX_FILE))?);
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    /// Returns the number of codes and npis indexed.
+    pub fn counts(&self) -> (usize, usize) {
+        (self.codes.len(), self.npis.len())
+    }
+}
+
+/// A data file with its index, ready for lookups.
+pub struct IndexedFile {
+    /// Name of the data file, for messages and results
+    pub name: String,
+    index: Index,
+    dir: PathBuf,
+}
+
+impl IndexedFile {
+    /// Loads the index of source from dir, or builds it there if there is none yet or the data
+    /// file changed since. Building it is reported to reporter.
+    pub fn open(source: &Location,
+                buff_size: usize,
+                dir: &Path,
+                reporter: &SharedReporter) -> Result<Self, Box<dyn std::error::Error>> {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let built = modified(&dir.join(INDEX_FILE));
+        let stale = match (source, built) {
+            (_, None) => true,
+            (Location::Path(path), Some(built)) => modified(path).is_some_and(|m| m > built),
+            _ => false,
+        };
+
+        let index = if stale {
+            reporter.status(format_args!("Indexing {}...", source));
+            Index::build(source, buff_size, dir, reporter)?
+        }
+        else {
+            Index::load(dir)?
+        };
+        Ok(Self { name: source.to_string(), index, dir: dir.to_path_buf() })
+    }
+
+    /// Returns the index of the file.
+    pub fn index(&self) -> &Index {
+        &self.index
+    }
+
+    /// Runs a query for npi and billing code on the elements of the file that have them, and
+    /// returns the rows, with the header.
+    pub fn lookup(&self,
+                  npi: u64,
+                  code: &str,
+                  options: &Options) -> Result<MemorySink, Box<dyn std::error::Error>> {
+        let no_spans = Vec::new();
+        let items = self.index.codes.get(&normalize_code(code)).unwrap_or(&no_spans);
+        let refs = self.index.npis.get(&npi).unwrap_or(&no_spans);
+
+        let mut sink = MemorySink::new();
+        if items.is_empty() {
+            return Ok(sink);
+        }
+
+        let mut elements = File::open(self.dir.join(ELEMENTS_FILE))?;
+        let mut json: Vec<u8> = Vec::new();
+        json.push(b'{');
+        for (key, value) in self.index.metadata.iter() {
+            json.extend(serde_json::to_vec(key)?);
+            json.push(b':');
+            json.extend(serde_json::to_vec(value)?);
+            json.push(b',');
+        }
+        for (section, spans) in [("provider_references", refs), ("in_network", items)] {
+            if section == "in_network" {
+                json.push(b',');
+            }
+            json.extend(format!("\"{}\":[", section).as_bytes());
+            for (i, (start, len)) in spans.iter().enumerate() {
+                if i > 0 {
+                    json.push(b',');
+                }
+                elements.seek(SeekFrom::Start(*start))?;
+                GzDecoder::new((&mut elements).take(*len)).read_to_end(&mut json)?;
+            }
+            json.push(b']');
+        }
+        json.push(b'}');
+
+        let mut query = Query::new();
+        query.providers.push(Provider::new(npi));
+        query.codes.push(Code::new(&String::from("*"), &String::from(code)));
+        let source = Elements { name: &self.name, json };
+        asa::run_with_progress(&mut query, &source, 64 * 1024, options, &mut sink, |_: &Progress| {})?;
+        Ok(sink)
+    }
+}
+
+/// The elements read back for a lookup, as a DataSource.
+struct Elements<'a> {
+    name: &'a str,
+    json: Vec<u8>,
+}
+
+impl std::fmt::Display for Elements<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (indexed)", self.name)
+    }
+}
+
+impl DataSource for Elements<'_> {
+    fn open_raw(&self, _reporter: &SharedReporter) -> Result<Box<dyn Read>, Box<dyn std::error::Error>> {
+        Ok(Box::new(std::io::Cursor::new(self.json.clone())))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index_lookup() {
+        let data = PathBuf::from("tests/testfiles/data_files/intermediate.json.gz");
+        let dir = std::env::temp_dir().join(format!("mrfy_index_{}", std::process::id()));
+        let source = Location::Path(data);
+
+        let file = IndexedFile::open(&source, 256, &dir, &SharedReporter::default()).unwrap();
+        assert_eq!(Index::load(&dir).unwrap(), *file.index());
+        assert!(file.index().counts().0 > 0);
+
+        // Same rows as a run over the whole file
+        let mut q = Query::new();
+        q.providers.push(Provider::new(4));
+        q.codes.push(Code::new(&String::from("*"), &String::from("Code 3")));
+        let mut expected = MemorySink::new();
+        asa::run(&mut q, &source, 256, &Options::new(), &mut expected).unwrap();
+        assert!(!expected.records.is_empty());
+
+        let found = file.lookup(4, "code 3", &Options::new()).unwrap();
+        assert_eq!(found, expected);
+        assert!(file.lookup(4, "no such code", &Options::new()).unwrap().records.is_empty());
+        assert!(file.lookup(999, "Code 3", &Options::new()).unwrap().records.is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,67 @@
+//! # mrfy (murphy)
+//!
+//! Library to process machine readable files and extract negotiated price information, used by
+//! the mrfy command line and by code that embeds the parser: asa::run runs a query::Query over a
+//! source::DataSource, handing the matched rows to a sink::RecordSink. Other languages can run
+//! queries through the C API of ffi, built into the `cdylib` and `staticlib` of the crate.
+//!
+//! The parser also builds for wasm32 (see wasm), without the modules that need threads, sockets
+//! or a file system.
+//!
+//! Currently only supports Aetna Signature Administrators.
+
+pub mod query;
+pub mod refmap;
+pub mod dedupe;
+pub mod minrates;
+pub mod rate;
+pub mod asa;
+pub mod error;
+pub mod source;
+pub mod providers;
+pub mod stats;
+pub mod nppes;
+pub mod schema;
+pub mod descriptions;
+pub mod sink;
+pub mod index;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod serve;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod batch;
+pub mod anonymize;
+pub mod split;
+pub mod merge;
+pub mod explain;
+pub mod html;
+pub mod filter;
+pub mod sql;
+pub mod template;
+pub mod table;
+pub mod xlsx;
+pub mod partition;
+pub mod upload;
+#[cfg(feature = "postgres")]
+pub mod pg;
+#[cfg(feature = "avro")]
+pub mod avro;
+pub mod parser;
+#[cfg(feature = "simd")]
+pub mod tape;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod fetch;
+#[cfg(feature = "tokio")]
+pub mod remote;
+pub mod compare;
+pub mod compress;
+pub mod meta;
+pub mod notify;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod metrics;
+pub mod digest;
+pub mod toc;
+pub mod validate;
+pub mod clock;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod ffi;
+pub mod wasm;
@@ -4,37 +4,1436 @@
 //! 
 //! Currently only supports Aetna Signature Administrators.
 
-mod query;
-mod asa;
-mod error;
+use clap::{Args, Parser, Subcommand};
+use mrfy::{anonymize, asa, batch, compare, compress, descriptions, error, fetch, filter, html, index, merge,
+           meta, metrics, notify, nppes, parser, partition, providers, query, rate, serve, sink, source, split,
+           sql, stats, table, template, toc, upload, validate, xlsx};
+#[cfg(feature = "avro")]
+use mrfy::avro;
+#[cfg(feature = "postgres")]
+use mrfy::pg;
+use source::DEFAULT_BUFF_SIZE;
 
-use clap::Parser;
-
-/// Handle user input 
+/// Process machine readable files and extract negotiated price information.
+///
+/// Running mrfy with the query arguments and no subcommand is the same as `mrfy query`.
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Cli {
-    /// The path to the query input file 
-    pub input_path: std::path::PathBuf,
-    /// The path to the datafile to process
-    pub data_path: std::path::PathBuf,
-    /// Optional buffer size in kb
-    pub buff_size: Option<usize>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub input: Option<InputArgs>,
+    #[command(flatten)]
+    pub csv: CsvArgs,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Write records matching the query as CSV to stdout
+    Query(Box<QueryArgs>),
+    /// Run the query against several datafiles and write one rate column per file as CSV
+    Compare(CompareArgs),
+    /// Write a smaller MRF holding only the provider_references and in_network items that match the query
+    Extract(ExtractArgs),
+    /// Print the file metadata (reporting entity, last updated date, version) without a query
+    Meta(MetaArgs),
+    /// Check a datafile against the CMS in-network rates schema without a query
+    Validate(ValidateArgs),
+    /// Summarize a datafile (items per code type, codes, groups, NPIs, rates) without a query
+    Stats(StatsArgs),
+    /// List the billing codes (type, code, name) of a datafile as CSV
+    Codes(CodesArgs),
+    /// List the provider_references of a datafile (group id, NPI, tin) as CSV
+    Providers(ProvidersArgs),
+    /// List the files in a table of contents file as CSV
+    Toc(TocArgs),
+    /// Build the seek index of datafiles ahead of serving them, rebuilding any there is
+    Index(IndexArgs),
+    /// Index datafiles (once) and answer rate lookups over HTTP as JSON (GET /rates?npi=...&code=...)
+    Serve(ServeArgs),
+    /// Run the jobs of a manifest (TOML, JSON or YAML) with shared settings and print one summary
+    Batch(BatchArgs),
+    /// Download MRF files (one URL, or the in-network files of a table of contents) to the cache
+    /// that --url reads from (requires `http` feature)
+    Fetch(FetchArgs),
+    /// Write a copy of a datafile with scrambled NPIs and TINs and perturbed rates, to attach to
+    /// bug reports
+    Anonymize(AnonymizeArgs),
+    /// Split a datafile into smaller valid MRFs, each with all of provider_references and a share
+    /// of in_network
+    Split(SplitArgs),
+    /// Combine the CSV outputs of several runs into one, sorted and without duplicate rows
+    Merge(MergeArgs),
+}
+
+/// Arguments for subcommands that read a datafile without a query
+#[derive(Args)]
+pub struct DataArgs {
+    /// The path to the datafile to process, - for stdin (s3://, gs:// and az:// need `object-store` feature)
+    #[arg(required_unless_present = "url", conflicts_with = "url")]
+    pub data_path: Option<std::path::PathBuf>,
+    /// Stream the datafile from this URL instead of reading it from disk (requires `http` feature)
+    #[arg(long)]
+    pub url: Option<String>,
+}
+
+impl DataArgs {
+    /// Returns the datafile to read, from data_path or url.
+    fn source(&self) -> source::Location {
+        match (&self.data_path, &self.url) {
+            (Some(path), _) => source::Location::from(path.clone()),
+            (None, Some(url)) => source::Location::Url(url.clone()),
+            (None, None) => unreachable!("clap requires a data path or url"),
+        }
+    }
+}
+
+/// Arguments shared by every way of running a query
+#[derive(Args)]
+pub struct InputArgs {
+    /// The path to the query input file (just the datafile if the query is given with --npi, --code or --query)
+    #[arg(required_unless_present_any = ["npi", "code", "queries"])]
+    pub input_path: Option<std::path::PathBuf>,
+    /// The path to the datafile to process, - for stdin (s3://, gs:// and az:// need `object-store` feature)
+    #[arg(required_unless_present_any = ["url", "npi", "code", "queries"], conflicts_with = "url")]
+    pub data_path: Option<std::path::PathBuf>,
+    /// Optional size of the read buffer, same as --buff-size
+    #[arg(value_parser = parse_buff_size)]
+    pub buff_size: Option<source::BuffSize>,
+    /// Size of the read buffer: bytes, or with a K, M or G suffix (e.g. 256M, 1G), or auto to
+    /// size it from the available memory and the file size [default: 128M]
+    #[arg(long = "buff-size", value_name = "SIZE", value_parser = parse_buff_size, conflicts_with = "buff_size")]
+    pub buff_size_option: Option<source::BuffSize>,
+    /// Stream the datafile from this URL instead of reading it from disk (requires `http` feature),
+    /// or read the copy `mrfy fetch` put in the cache
+    #[arg(long)]
+    pub url: Option<String>,
+    /// The cache directory of `mrfy fetch` [default: MRFY_CACHE_DIR, else ~/.cache/mrfy]
+    #[arg(long, value_name = "DIR")]
+    pub cache_dir: Option<std::path::PathBuf>,
+    /// Stream --url even if it was fetched to the cache
+    #[arg(long, requires = "url")]
+    pub no_cache: bool,
+    /// Log and skip in_network items that fail to parse instead of aborting
+    #[arg(long)]
+    pub skip_corrupt: bool,
+    /// Abort with an error on the first unsupported key, or on query npis failing the check digit
+    #[arg(long)]
+    pub strict: bool,
+    /// Write unsupported keys with their object type and count to this file (CSV, or JSON if .json)
+    #[arg(long)]
+    pub key_report: Option<std::path::PathBuf>,
+    /// Write a JSON report of which codes, npis, group ids and tins matched (and how many records
+    /// each) to this file
+    #[arg(long)]
+    pub report: Option<std::path::PathBuf>,
+    /// Write a self-contained HTML report of the run (file metadata, statistics, unmatched codes
+    /// and npis, skipped items, rates of each code) to this file
+    #[arg(long, value_name = "PATH")]
+    pub report_html: Option<std::path::PathBuf>,
+    /// Write the provenance of each output file (datafile and its SHA-256, reporting entity,
+    /// last_updated_on, query hash, mrfy version, rows) next to it as FILE.meta.json
+    #[arg(long)]
+    pub meta: bool,
+    /// POST how the run went as JSON (status, counts, duration, output files) to this URL once it
+    /// is done or has failed (requires `http` feature)
+    #[arg(long, value_name = "URL", value_parser = parse_notify_url)]
+    pub notify_url: Option<String>,
+    /// Keep only negotiated prices with one of these service codes, e.g. 21,22
+    #[arg(long, value_delimiter = ',')]
+    pub service_code: Vec<String>,
+    /// Drop negotiated prices that expired before this date (YYYY-MM-DD)
+    #[arg(long, value_parser = parse_as_of)]
+    pub as_of: Option<asa::Date>,
+    /// Skip items whose billing_code_type_version differs from the version pinned in the query
+    #[arg(long)]
+    pub exclude_version_mismatch: bool,
+    /// Compare billing codes and code types with case, instead of ignoring it
+    #[arg(long)]
+    pub case_sensitive: bool,
+    /// Compare '*' and '?' in query codes (and a '*' code type) literally instead of as wildcards
+    #[arg(long)]
+    pub literal_codes: bool,
+    /// Keep the provider reference map in a temporary file (in TMPDIR) instead of memory, for
+    /// queries with millions of providers
+    #[arg(long)]
+    pub disk_ref_map: bool,
+    /// Skip output rows identical to a row already written
+    #[arg(long)]
+    pub dedupe: bool,
+    /// Stop reading in_network once every code of the query has been found for every provider
+    /// (later items with the same codes are not read)
+    #[arg(long)]
+    pub stop_when_complete: bool,
+    /// Normalize billing codes and types (case, whitespace, leading zeros, type aliases) before matching
+    #[arg(long)]
+    pub normalize_codes: bool,
+    /// What to do with negotiated_rates like "$1,234.56" or 1.5e3: warn (write them as plain
+    /// decimals with a warning), fix (the same, silently) or fail
+    #[arg(long, default_value = "warn", value_parser = parse_rate_format)]
+    pub rate_format: rate::RateFormat,
+    /// Exit with status 4 before reading the file's records if its last_updated_on is more than
+    /// N days ago (or missing)
+    #[arg(long, value_name = "N")]
+    pub max_age_days: Option<u64>,
+    /// Only warn about a file older than --max-age-days instead of rejecting it
+    #[arg(long, requires = "max_age_days")]
+    pub warn_stale: bool,
+    /// Skip in_network items whose name doesn't contain this (ignoring case) as soon as the name
+    /// is read, before their billing code and rates, can be repeated
+    #[arg(long, value_name = "TEXT")]
+    pub name_prefilter: Vec<String>,
+    /// Skip the in_network items before this offset: decompressed bytes (e.g. 10G, approximate)
+    /// or item:N for the Nth item (from 0)
+    #[arg(long, value_name = "OFFSET", value_parser = parse_slice_offset)]
+    pub start_offset: Option<asa::Offset>,
+    /// Stop at this offset (decompressed bytes or item:N), the item starting there is left out
+    #[arg(long, value_name = "OFFSET", value_parser = parse_slice_offset)]
+    pub end_offset: Option<asa::Offset>,
+    /// Write each in_network item that gave no rows, with the reason (code not in the query, no
+    /// provider of the query, ...), to this file as CSV, and print the count of each reason
+    #[arg(long, value_name = "PATH")]
+    pub explain: Option<std::path::PathBuf>,
+    /// Check the query and the datafile without reading in_network: read the metadata and
+    /// provider_references, report which npis of the query are in the file and exit
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Compress the output files and the CSV written to stdout with none, gzip[:LEVEL] (0-9) or
+    /// zstd[:LEVEL] (1-22, requires `zstd` feature), instead of the files by their extension
+    #[arg(long, value_name = "CODEC", value_parser = parse_codec)]
+    pub compress: Option<compress::Codec>,
+    /// JSON parser to read the datafile with: events, or simd (faster, requires `simd` feature)
+    #[arg(long, value_name = "PARSER", default_value = "events", value_parser = parse_backend)]
+    pub parser: parser::Backend,
+    /// Add an npi (or * for every provider) to the query, can be repeated
+    #[arg(long)]
+    pub npi: Vec<String>,
+    /// Add a billing code to the query as TYPE:CODE (e.g. CPT:99213 or '*':J1100), can be repeated
+    #[arg(long, value_parser = parse_code)]
+    pub code: Vec<(String, String)>,
+    /// Merge another query file into the query (duplicate codes and npis are dropped), can be repeated
+    #[arg(long = "query", value_name = "QUERY_PATH")]
+    pub queries: Vec<std::path::PathBuf>,
+    /// NPPES file (npidata_pfile CSV, or .csv.gz) for --taxonomy, and to add provider_name,
+    /// taxonomy and practice_address columns for each npi to the CSV of a query
+    #[arg(long)]
+    pub nppes: Option<std::path::PathBuf>,
+    /// Only select providers with one of these taxonomy codes in --nppes (e.g. 207X00000X, or
+    /// 207X* for its subspecialties), every such provider if the query selects none
+    #[arg(long, value_delimiter = ',', requires = "nppes")]
+    pub taxonomy: Vec<String>,
+}
+
+impl InputArgs {
+    /// Returns the paths of the query file and the datafile. With a query given by flags and a
+    /// single path, that path is the datafile.
+    fn paths(&self) -> (Option<&std::path::PathBuf>, Option<&std::path::PathBuf>) {
+        let inline = !self.npi.is_empty() || !self.code.is_empty() || !self.queries.is_empty();
+        match (&self.input_path, &self.data_path) {
+            (Some(data), None) if inline && self.url.is_none() => (None, Some(data)),
+            (input, data) => (input.as_ref(), data.as_ref()),
+        }
+    }
+
+    /// Returns the read buffer size in bytes for source, from --buff-size or BUFF_SIZE.
+    fn buff_size(&self, source: &dyn source::DataSource) -> usize {
+        self.buff_size_option.or(self.buff_size)
+            .map_or(source::DEFAULT_BUFF_SIZE, |size| size.resolve(source))
+    }
+
+    /// Returns the datafile to read, from data_path or url, reading the cached copy of url if
+    /// there is one.
+    fn source(&self) -> Result<source::Location, String> {
+        match (self.paths().1, &self.url) {
+            (Some(path), _) => Ok(source::Location::from(path.clone())),
+            (None, Some(url)) if !self.no_cache => {
+                let dir = self.cache_dir.clone().unwrap_or_else(fetch::default_cache_dir);
+                match fetch::cached(&dir, url) {
+                    Some(path) => {
+                        eprintln!("Reading {} from the cache, {}", url, path.display());
+                        Ok(source::Location::Path(path))
+                    }
+                    None => Ok(source::Location::Url(url.clone())),
+                }
+            }
+            (None, Some(url)) => Ok(source::Location::Url(url.clone())),
+            (None, None) => Err(String::from("no datafile given, pass a DATA_PATH or --url")),
+        }
+    }
+
+    /// Returns the query from the query file, merged with the --query files, with the --npi and
+    /// --code values added and its providers narrowed to the --taxonomy npis.
+    fn query(&self) -> Result<query::Query, Box<dyn std::error::Error>> {
+        let mut q = match self.paths().0 {
+            Some(path) => query::read_input(path)?,
+            None => query::Query::new(),
+        };
+        for path in self.queries.iter() {
+            q.merge(query::read_input(path)?);
+        }
+        q.add_inline(&self.npi, &self.code)?;
+
+        if let Some(path) = self.nppes.as_ref().filter(|_| !self.taxonomy.is_empty()) {
+            eprintln!("Reading NPPES file {} for taxonomies {}...", path.display(), self.taxonomy.join(", "));
+            let npis = nppes::npis_with_taxonomy(path, &self.taxonomy)?;
+            eprintln!("Found {} npis with these taxonomies", npis.len());
+            let dropped = q.select_npis(npis);
+            if dropped > 0 {
+                eprintln!("WARNING: {} npis of the query don't have these taxonomies and are dropped", dropped);
+            }
+        }
+        Ok(q)
+    }
+
+    /// Returns the run options set by these arguments, stopped by cancel.
+    fn options(&self, cancel: &asa::CancelToken) -> asa::Options {
+        let mut options = asa::Options::new();
+        options.cancel = cancel.clone();
+        options.skip_corrupt = self.skip_corrupt;
+        options.strict = self.strict;
+        options.service_codes = self.service_code.clone();
+        options.as_of = self.as_of;
+        options.exclude_version_mismatch = self.exclude_version_mismatch;
+        options.match_policy = query::MatchPolicy { case_sensitive: self.case_sensitive,
+                                                    literal: self.literal_codes };
+        options.normalize_codes = self.normalize_codes;
+        options.disk_ref_map = self.disk_ref_map;
+        options.dedupe = self.dedupe;
+        options.stop_when_complete = self.stop_when_complete;
+        options.rate_format = self.rate_format;
+        options.nppes = self.nppes.clone();
+        options.max_age_days = self.max_age_days;
+        options.warn_stale = self.warn_stale;
+        options.parser = self.parser;
+        options.name_prefilter = self.name_prefilter.clone();
+        options.start_offset = self.start_offset;
+        options.end_offset = self.end_offset;
+        options.explain = self.explain.clone();
+        options.dry_run = self.dry_run;
+        options.run_details = self.report_html.is_some();
+        options.compression = self.compress;
+        options
+    }
+}
+
+/// Parses a --code value, TYPE:CODE.
+fn parse_code(s: &str) -> Result<(String, String), String> {
+    match s.split_once(':') {
+        Some((c_type, code)) if !c_type.is_empty() && !code.is_empty() => {
+            Ok((c_type.to_string(), code.to_string()))
+        }
+        _ => Err(format!("'{}' is not TYPE:CODE", s)),
+    }
+}
+
+/// Parses the --list-delimiter, which can't be empty.
+fn parse_list_delimiter(s: &str) -> Result<String, String> {
+    match s {
+        "" => Err(String::from("the list delimiter can't be empty")),
+        _ => Ok(s.to_string()),
+    }
+}
+
+/// Parses a --header-map value, COLUMN=NAME, where COLUMN is an output column.
+fn parse_header_name(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((column, name)) if asa::COLUMNS.contains(&column) || nppes::COLUMNS.contains(&column) ||
+                                column == descriptions::COLUMN || column == "extra" => {
+            Ok((column.to_string(), name.to_string()))
+        }
+        Some((column, _)) => Err(format!("'{}' is not an output column", column)),
+        None => Err(format!("'{}' is not COLUMN=NAME", s)),
+    }
+}
+
+/// Parses a --sql query, which needs the `sql` feature.
+fn parse_sql(s: &str) -> Result<String, String> {
+    match cfg!(feature = "sql") {
+        true => Ok(s.to_string()),
+        false => Err(String::from("rebuild mrfy with the `sql` feature to use --sql")),
+    }
+}
+
+/// Parses a --sql-table, NAME=PATH.
+fn parse_sql_table(s: &str) -> Result<(String, std::path::PathBuf), String> {
+    match s.split_once('=') {
+        Some((name, path)) if !name.is_empty() && !path.is_empty() &&
+                              name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') => {
+            Ok((name.to_string(), std::path::PathBuf::from(path)))
+        }
+        _ => Err(format!("'{}' is not NAME=PATH, with a NAME of letters, digits and _", s)),
+    }
+}
+
+/// Parses a --group-by field, one of stats::GROUP_FIELDS.
+fn parse_group_field(s: &str) -> Result<String, String> {
+    match stats::GROUP_FIELDS.contains(&s) {
+        true => Ok(s.to_string()),
+        false => Err(format!("'{}' is not one of {}", s, stats::GROUP_FIELDS.join(", "))),
+    }
+}
+
+/// Parses a --percentiles value, from 0 to 100.
+fn parse_percentile(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(p) if (0.0..=100.0).contains(&p) => Ok(p),
+        _ => Err(format!("'{}' is not a percentile from 0 to 100", s)),
+    }
+}
+
+/// Parses the --rate-format, warn, fix or fail.
+fn parse_rate_format(s: &str) -> Result<rate::RateFormat, String> {
+    rate::RateFormat::parse(s).ok_or_else(|| format!("'{}' is not warn, fix or fail", s))
+}
+
+/// Parses a buffer size, see source::BuffSize::parse.
+fn parse_buff_size(s: &str) -> Result<source::BuffSize, String> {
+    source::BuffSize::parse(s).ok_or_else(|| format!("'{}' is not a size like 65536, 256M or 1G, or auto", s))
+}
+
+/// Parses the --limit-rate, a size like a buffer size but not auto.
+fn parse_rate_limit(s: &str) -> Result<u64, String> {
+    match source::BuffSize::parse(s) {
+        Some(source::BuffSize::Bytes(bytes)) => Ok(bytes as u64),
+        _ => Err(format!("'{}' is not a rate like 500K or 10M", s)),
+    }
+}
+
+/// Parses the --spread of anonymized rates, from 0 to 1.
+fn parse_spread(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(spread) if (0.0..=1.0).contains(&spread) => Ok(spread),
+        _ => Err(format!("'{}' is not a number from 0 to 1", s)),
+    }
 }
 
+/// Parses a --sample fraction, more than 0 and at most 1.
+fn parse_sample(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(sample) if sample > 0.0 && sample <= 1.0 => Ok(sample),
+        _ => Err(format!("'{}' is not a number more than 0 and at most 1", s)),
+    }
+}
+
+/// Parses a --start-offset or --end-offset, see asa::Offset::parse.
+fn parse_slice_offset(s: &str) -> Result<asa::Offset, String> {
+    asa::Offset::parse(s).ok_or_else(|| format!("'{}' is not a byte offset like 4096 or 10G, or item:N", s))
+}
+
+/// Parses a --where expression over any column a run can write.
+fn parse_where(s: &str) -> Result<filter::Filter, String> {
+    let columns = [&asa::COLUMNS[..], &nppes::COLUMNS, &[descriptions::COLUMN, "extra"]].concat();
+    filter::Filter::parse(s, &columns)
+}
+
+/// Parses a --template over any column a run can write.
+fn parse_template(s: &str) -> Result<template::Template, String> {
+    let columns = [&asa::COLUMNS[..], &nppes::COLUMNS, &[descriptions::COLUMN, "extra"]].concat();
+    template::Template::parse(s, &columns)
+}
+
+/// Parses the --output-format, csv or table.
+fn parse_output_format(s: &str) -> Result<table::OutputFormat, String> {
+    table::OutputFormat::parse(s).ok_or_else(|| format!("'{}' is not csv or table", s))
+}
+
+/// Parses the --xlsx path, which needs the `xlsx` feature.
+fn parse_xlsx(s: &str) -> Result<std::path::PathBuf, String> {
+    match cfg!(feature = "xlsx") {
+        true => Ok(std::path::PathBuf::from(s)),
+        false => Err(String::from("rebuild mrfy with the `xlsx` feature to use --xlsx")),
+    }
+}
+
+/// Parses the --notify-url, which needs the `http` feature.
+fn parse_notify_url(s: &str) -> Result<String, String> {
+    match cfg!(feature = "http") {
+        true if s.starts_with("http://") || s.starts_with("https://") => Ok(s.to_string()),
+        true => Err(format!("'{}' is not an http(s) URL", s)),
+        false => Err(String::from("rebuild mrfy with the `http` feature to use --notify-url")),
+    }
+}
+
+/// Parses the -o destination, a file or a cloud storage URL.
+fn parse_output(s: &str) -> Result<source::Location, String> {
+    match source::Location::from(std::path::PathBuf::from(s)) {
+        source::Location::Stdin => Err(String::from("leave out -o to write to stdout")),
+        source::Location::ObjectStore(_) if !cfg!(feature = "object-store") => {
+            Err(String::from("rebuild mrfy with the `object-store` feature to upload to cloud storage"))
+        }
+        location => Ok(location),
+    }
+}
+
+/// Parses the --partition-by column, billing_code or group_id.
+fn parse_partition_by(s: &str) -> Result<partition::PartitionBy, String> {
+    partition::PartitionBy::parse(s).ok_or_else(|| format!("'{}' is not billing_code or group_id", s))
+}
+
+/// Parses the --avro path, which needs the `avro` feature.
+fn parse_avro(s: &str) -> Result<std::path::PathBuf, String> {
+    match cfg!(feature = "avro") {
+        true => Ok(std::path::PathBuf::from(s)),
+        false => Err(String::from("rebuild mrfy with the `avro` feature to use --avro")),
+    }
+}
+
+/// Parses the --pg-table name, which needs the `postgres` feature.
+fn parse_pg_table(s: &str) -> Result<String, String> {
+    match cfg!(feature = "postgres") {
+        true if !s.is_empty() && !s.split('.').any(str::is_empty) => Ok(s.to_string()),
+        true => Err(format!("'{}' is not a table name like rates or public.rates", s)),
+        false => Err(String::from("rebuild mrfy with the `postgres` feature to use --pg-table")),
+    }
+}
+
+/// Parses a --compress codec, see compress::Codec::parse.
+fn parse_codec(s: &str) -> Result<compress::Codec, String> {
+    match (compress::Codec::parse(s), s.split(':').next()) {
+        (Some(codec), _) => Ok(codec),
+        (None, Some("zstd")) if !cfg!(feature = "zstd") => Err(String::from("rebuild mrfy with the `zstd` feature to use zstd")),
+        (None, _) => Err(format!("'{}' is not none, gzip, gzip:0 to gzip:9, zstd or zstd:1 to zstd:22", s)),
+    }
+}
+
+/// Parses the --parser backend.
+fn parse_backend(s: &str) -> Result<parser::Backend, String> {
+    match (parser::Backend::parse(s), s) {
+        (Some(backend), _) => Ok(backend),
+        (None, "simd") => Err(String::from("rebuild mrfy with the `simd` feature to use the simd parser")),
+        (None, _) => Err(format!("'{}' is not events or simd", s)),
+    }
+}
+
+/// Parses the --as-of date.
+fn parse_as_of(s: &str) -> Result<asa::Date, String> {
+    asa::parse_date(s).ok_or_else(|| format!("'{}' is not a YYYY-MM-DD date", s))
+}
+
+/// Options for the CSV written by a query
+#[derive(Args)]
+pub struct CsvArgs {
+    /// Keep unsupported keys in negotiated_prices and write them as JSON in an `extra` column
+    #[arg(long)]
+    pub capture_extra: bool,
+    /// Write one row per service code of a price instead of joining them in one column
+    #[arg(long)]
+    pub explode_service_codes: bool,
+    /// Write one row per billing code modifier of a price instead of joining them in one column
+    #[arg(long)]
+    pub explode_modifiers: bool,
+    /// Write negotiated_rate with exactly N decimals (rounded half away from zero), e.g. 2 for 9.90
+    #[arg(long, value_name = "N")]
+    pub rate_precision: Option<usize>,
+    /// Don't write the CSV header, e.g. to append to an existing file
+    #[arg(long)]
+    pub no_header: bool,
+    /// Write only the lowest negotiated_rate of each billing code and npi (rows are held until
+    /// the end of the run)
+    #[arg(long)]
+    pub min_per_code: bool,
+    /// Add a code_description column from this CSV of billing code descriptions (e.g. CPT or
+    /// HCPCS short descriptors), see the README for its columns
+    #[arg(long)]
+    pub code_descriptions: Option<std::path::PathBuf>,
+    /// Write count, min, percentiles, mean and max of the matched rates of each billing code to
+    /// this file as CSV
+    #[arg(long)]
+    pub rate_summary: Option<std::path::PathBuf>,
+    /// Percentiles of --rate-summary
+    #[arg(long, value_delimiter = ',', default_value = "25,50,75,90", value_parser = parse_percentile)]
+    pub percentiles: Vec<f64>,
+    /// Rename output columns as COLUMN=NAME, e.g. npi=provider_npi,billing_code=cpt
+    #[arg(long, value_delimiter = ',', value_parser = parse_header_name)]
+    pub header_map: Vec<(String, String)>,
+    /// Put this between the values of multi-valued fields (service_code, billing_code_modifier)
+    #[arg(long, default_value = " ", value_parser = parse_list_delimiter)]
+    pub list_delimiter: String,
+    /// Write the original JSON of each matched in_network item to this file as NDJSON (gzip if .gz)
+    #[arg(long)]
+    pub raw_out: Option<std::path::PathBuf>,
+    /// Stop after N matched rows, to preview the data
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub limit: Option<u64>,
+    /// Keep each matched price with this probability (e.g. 0.01 for 1%), to preview the data
+    #[arg(long, value_name = "FRACTION", value_parser = parse_sample)]
+    pub sample: Option<f64>,
+    /// Seed of --sample, the same seed keeps the same prices
+    #[arg(long, value_name = "N", default_value_t = 0, requires = "sample")]
+    pub sample_seed: u64,
+    /// Write only the rows passing this expression of columns, e.g.
+    /// "negotiated_rate < 500 && billing_class == 'professional'" (see the README)
+    #[arg(long = "where", value_name = "EXPR", value_parser = parse_where)]
+    pub filter: Option<filter::Filter>,
+    /// Shape the rows with this SQL query over the table `records` before writing its result
+    /// instead, e.g. "SELECT billing_code, AVG(negotiated_rate) FROM records GROUP BY
+    /// billing_code" (requires `sql` feature)
+    #[arg(long, value_name = "QUERY", value_parser = parse_sql, conflicts_with_all = ["header_map", "no_header"])]
+    pub sql: Option<String>,
+    /// Add a CSV file as a table for --sql, as NAME=PATH, can be repeated
+    #[arg(long, value_name = "NAME=PATH", value_parser = parse_sql_table, requires = "sql")]
+    pub sql_table: Vec<(String, std::path::PathBuf)>,
+    /// Write each row as a line of text instead of CSV, with {column} replaced by its value, e.g.
+    /// '{npi}\t{billing_code}\t{negotiated_rate}' (see the README)
+    #[arg(long, value_parser = parse_template, conflicts_with_all = ["sql", "header_map"])]
+    pub template: Option<template::Template>,
+    /// Write the rows to this Excel workbook instead of CSV to stdout, with negotiated_rate as a
+    /// number and expiration_date as a date (requires `xlsx` feature)
+    #[arg(long, value_name = "PATH", value_parser = parse_xlsx, conflicts_with_all = ["sql", "template"])]
+    pub xlsx: Option<std::path::PathBuf>,
+    /// Rows of a sheet of --xlsx at most, the rows after them go on to the next sheet
+    #[arg(long, value_name = "N", default_value_t = xlsx::MAX_SHEET_ROWS,
+          value_parser = clap::value_parser!(u32).range(1..=xlsx::MAX_SHEET_ROWS as i64), requires = "xlsx")]
+    pub xlsx_sheet_rows: u32,
+    /// Write the rows as csv, or as an aligned table to read in the terminal, a page at a time
+    #[arg(long, value_name = "FORMAT", default_value = "csv", value_parser = parse_output_format,
+          conflicts_with_all = ["sql", "template", "xlsx"])]
+    pub output_format: table::OutputFormat,
+    /// Load the rows into this PostgreSQL table (created if needed) with COPY instead of writing
+    /// CSV, e.g. public.rates (requires `postgres` feature)
+    #[arg(long, value_name = "TABLE", value_parser = parse_pg_table,
+          conflicts_with_all = ["sql", "template", "xlsx", "output_format", "no_header"])]
+    pub pg_table: Option<String>,
+    /// Connection string of --pg-table, e.g. postgres://user@host/db [default: DATABASE_URL]
+    #[arg(long, value_name = "URL", requires = "pg_table")]
+    pub pg_url: Option<String>,
+    /// Write the rows to this Avro object container file instead of CSV to stdout, with the
+    /// schema of the columns embedded (requires `avro` feature)
+    #[arg(long, value_name = "PATH", value_parser = parse_avro,
+          conflicts_with_all = ["sql", "template", "xlsx", "output_format", "pg_table", "no_header"])]
+    pub avro: Option<std::path::PathBuf>,
+    /// Write the number of rows and the SHA-256 and length of the output (as written, after
+    /// --compress) to this file as JSON once the run is done
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["sql", "pg_table"])]
+    pub checksum: Option<std::path::PathBuf>,
+    /// Write the rows to a CSV file for each value of this column, billing_code or group_id,
+    /// under --partition-dir, Hive-style: DIR/billing_code=99213/part-0.csv
+    #[arg(long, value_name = "COLUMN", value_parser = parse_partition_by, requires = "partition_dir",
+          conflicts_with_all = ["sql", "template", "xlsx", "output_format", "pg_table", "avro", "checksum"])]
+    pub partition_by: Option<partition::PartitionBy>,
+    /// The directory of the partitions of --partition-by, new or empty
+    #[arg(long, value_name = "DIR", requires = "partition_by")]
+    pub partition_dir: Option<std::path::PathBuf>,
+    /// Write the CSV to this file instead of stdout, or upload it with the report files once the
+    /// run is done to cloud storage, e.g. s3://bucket/prefix/ (requires `object-store` feature)
+    #[arg(short, long, value_name = "DEST", value_parser = parse_output,
+          conflicts_with_all = ["sql", "template", "xlsx", "output_format", "pg_table", "avro", "partition_by"])]
+    pub output: Option<source::Location>,
+}
+
+/// Writes matching records as CSV
+#[derive(Args)]
+pub struct QueryArgs {
+    #[command(flatten)]
+    pub input: InputArgs,
+    #[command(flatten)]
+    pub csv: CsvArgs,
+}
+
+/// Compares the rates of several datafiles
+#[derive(Args)]
+pub struct CompareArgs {
+    #[command(flatten)]
+    pub input: InputArgs,
+    /// Another datafile to run the query against, can be repeated
+    #[arg(long = "with", value_name = "DATA_PATH", required = true)]
+    pub others: Vec<std::path::PathBuf>,
+}
+
+/// Writes a filtered MRF instead of CSV
+#[derive(Args)]
+pub struct ExtractArgs {
+    #[command(flatten)]
+    pub input: InputArgs,
+    /// Where to write the extracted MRF (gzip compressed if it ends in .gz)
+    #[arg(short, long)]
+    pub output: std::path::PathBuf,
+}
+
+/// Prints the metadata of a datafile
+#[derive(Args)]
+pub struct MetaArgs {
+    #[command(flatten)]
+    pub data: DataArgs,
+    /// Also count the provider_references and in_network items (reads the whole file)
+    #[arg(long)]
+    pub counts: bool,
+}
+
+/// Checks a datafile against the schema
+#[derive(Args)]
+pub struct ValidateArgs {
+    #[command(flatten)]
+    pub data: DataArgs,
+    /// Print at most this many violations (all of them are counted)
+    #[arg(long, default_value_t = 100)]
+    pub max_errors: u64,
+}
+
+/// Summarizes a datafile
+#[derive(Args)]
+pub struct StatsArgs {
+    #[command(flatten)]
+    pub data: DataArgs,
+    /// Instead write count, min, median, mean and max of negotiated_rate per group of these
+    /// fields as CSV, e.g. billing_code or billing_code,billing_class
+    #[arg(long, value_delimiter = ',', value_parser = parse_group_field)]
+    pub group_by: Vec<String>,
+    /// Percentiles of --group-by
+    #[arg(long, value_delimiter = ',', default_value = "25,50,75,90", value_parser = parse_percentile)]
+    pub percentiles: Vec<f64>,
+}
+
+/// Lists the billing codes of a datafile
+#[derive(Args)]
+pub struct CodesArgs {
+    #[command(flatten)]
+    pub data: DataArgs,
+    /// Add the number of in_network items for each code
+    #[arg(long)]
+    pub counts: bool,
+}
+
+/// Lists the provider groups of a datafile
+#[derive(Args)]
+pub struct ProvidersArgs {
+    #[command(flatten)]
+    pub data: DataArgs,
+}
+
+/// Lists the MRF files of a table of contents
+#[derive(Args)]
+pub struct TocArgs {
+    /// The path to the table of contents file
+    #[arg(required_unless_present = "url", conflicts_with = "url")]
+    pub toc_path: Option<std::path::PathBuf>,
+    /// Read the table of contents from this URL (requires `http` feature)
+    #[arg(long)]
+    pub url: Option<String>,
+}
+
+/// Builds the seek indexes of datafiles
+#[derive(Args)]
+pub struct IndexArgs {
+    /// The paths to the datafiles to index
+    #[arg(required = true)]
+    pub data_paths: Vec<std::path::PathBuf>,
+    /// Directory to keep the indexes in, by default DATA_PATH.index next to each datafile
+    #[arg(long)]
+    pub index_dir: Option<std::path::PathBuf>,
+}
+
+/// Serves rate lookups over HTTP from indexed datafiles
+#[derive(Args)]
+pub struct ServeArgs {
+    /// The paths to the datafiles to serve
+    #[arg(required = true)]
+    pub data_paths: Vec<std::path::PathBuf>,
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    pub listen: String,
+    /// Directory to keep the indexes in, by default DATA_PATH.index next to each datafile
+    #[arg(long)]
+    pub index_dir: Option<std::path::PathBuf>,
+}
+
+/// Runs the jobs of a batch manifest
+#[derive(Args)]
+pub struct BatchArgs {
+    /// The path to the manifest
+    pub manifest: std::path::PathBuf,
+    /// Number of jobs run at once, instead of the parallel setting of the manifest
+    #[arg(long, value_name = "N")]
+    pub parallel: Option<usize>,
+    /// Serve Prometheus metrics of the jobs (bytes read, objects scanned and matched, rows,
+    /// failed jobs, last progress) on this address at /metrics while they run, e.g. 0.0.0.0:9090
+    #[arg(long, value_name = "ADDR")]
+    pub metrics_addr: Option<String>,
+}
+
+/// Downloads MRF files to the cache
+#[derive(Args)]
+pub struct FetchArgs {
+    /// URL of the file to download, or with --toc the path or URL of a table of contents
+    pub target: String,
+    /// Download every in-network file listed in the table of contents TARGET
+    #[arg(long)]
+    pub toc: bool,
+    /// Directory to download to [default: MRFY_CACHE_DIR, else ~/.cache/mrfy]
+    #[arg(long, value_name = "DIR")]
+    pub cache_dir: Option<std::path::PathBuf>,
+    /// Bytes per second at most, with a K, M or G suffix (e.g. 500K, 10M)
+    #[arg(long, value_name = "RATE", value_parser = parse_rate_limit)]
+    pub limit_rate: Option<u64>,
+    /// Expected SHA-256 of the file (hex); a download that doesn't match is deleted
+    #[arg(long, value_name = "HEX", conflicts_with = "toc")]
+    pub sha256: Option<String>,
+    /// Attempts after the first before a download is given up
+    #[arg(long, value_name = "N", default_value_t = 8)]
+    pub retries: u32,
+}
+
+/// Writes an anonymized copy of a datafile
+#[derive(Args)]
+pub struct AnonymizeArgs {
+    #[command(flatten)]
+    pub data: DataArgs,
+    /// Where to write the anonymized MRF (gzip compressed if it ends in .gz)
+    #[arg(short, long)]
+    pub output: std::path::PathBuf,
+    /// Seed of the scrambling, to get the same file again [default: random]
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// Largest relative change of a rate, from 0 to 1
+    #[arg(long, default_value_t = 0.1, value_parser = parse_spread)]
+    pub spread: f64,
+}
+
+/// Splits a datafile into shards
+#[derive(Args)]
+pub struct SplitArgs {
+    #[command(flatten)]
+    pub data: DataArgs,
+    /// Number of shards to write
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub shards: u32,
+    /// Directory to write the shards to, as NAME-1-of-N.json.gz, ...
+    #[arg(short, long, value_name = "DIR", default_value = ".")]
+    pub output_dir: std::path::PathBuf,
+}
+
+/// Merges CSV outputs
+#[derive(Args)]
+pub struct MergeArgs {
+    /// The CSV files to merge (gzip compressed if they end in .gz)
+    #[arg(required = true)]
+    pub inputs: Vec<std::path::PathBuf>,
+    /// Where to write the merged CSV (gzip compressed if it ends in .gz) [default: stdout]
+    #[arg(short, long)]
+    pub output: Option<std::path::PathBuf>,
+    /// The files have no header (they were written with --no-header)
+    #[arg(long)]
+    pub no_header: bool,
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let args = Cli::parse();
 
-    // Use a default buffer size if none specified
-    const DEFAULT_BUFF_SIZE: usize = 1024 * 1024 * 128; // 128 MiB
-    let buff_size: usize = args.buff_size.unwrap_or(DEFAULT_BUFF_SIZE);
+    // Let the current record finish and report progress instead of dying mid-write
+    let cancel = asa::CancelToken::new();
+    let interrupt = cancel.clone();
+    ctrlc::set_handler(move || interrupt.cancel())?;
+
+    match (args.command, args.input) {
+        (Some(Command::Query(query)), _) => {
+            query_csv(&query.input, &query.csv, &cancel)
+        }
+        (Some(Command::Compare(compare)), _) => {
+            compare_files(&compare, &cancel)
+        }
+        (Some(Command::Extract(extract)), _) => {
+            let mut options = extract.input.options(&cancel);
+            options.extract = Some(extract.output.clone());
+            // No CSV to add NPPES columns to, --nppes is only read for --taxonomy
+            options.nppes = None;
+            // Records are still matched as usual, only the CSV is thrown away
+            execute_then(&extract.input, &options, &mut sink::CsvSink::new(std::io::sink()), None, |_| Ok(()))
+        }
+        (Some(Command::Meta(meta)), _) => {
+            // The metadata is at the start of the file, a big buffer only helps when counting
+            let buff_size = if meta.counts { DEFAULT_BUFF_SIZE } else { 64 * 1024 };
+            let file_meta = asa::read_meta(&meta.data.source(), buff_size, meta.counts)?;
+            file_meta.print_out(&mut std::io::stdout())?;
+            Ok(())
+        }
+        (Some(Command::Validate(validate)), _) => {
+            let source = validate.data.source();
+            let reporter = asa::SharedReporter::default();
+            let report = validate::validate_source(&source, DEFAULT_BUFF_SIZE, validate.max_errors,
+                                                   &mut std::io::stdout(), &reporter)?;
+            report.summarize(&reporter);
+            if report.violations > 0 {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        (Some(Command::Stats(stats)), _) if !stats.group_by.is_empty() => {
+            let groups = stats::aggregate(&stats.data.source(), DEFAULT_BUFF_SIZE, &stats.group_by)?;
+            stats::write_groups_csv(groups, &stats.group_by, &stats.percentiles, std::io::stdout())
+        }
+        (Some(Command::Stats(stats)), _) => {
+            let file_stats = stats::scan(&stats.data.source(), DEFAULT_BUFF_SIZE)?;
+            file_stats.print_out(&mut std::io::stdout())?;
+            Ok(())
+        }
+        (Some(Command::Codes(codes)), _) => {
+            let code_list = stats::list_codes(&codes.data.source(), DEFAULT_BUFF_SIZE)?;
+            eprintln!("Found {} codes", code_list.len());
+            stats::write_codes_csv(&code_list, codes.counts, std::io::stdout())
+        }
+        (Some(Command::Providers(providers)), _) => {
+            let records = providers::write_providers(&providers.data.source(), DEFAULT_BUFF_SIZE,
+                                                     std::io::stdout())?;
+            eprintln!("Wrote {} providers", records);
+            Ok(())
+        }
+        (Some(Command::Toc(toc)), _) => {
+            let source = match (toc.toc_path, toc.url) {
+                (Some(path), _) => source::Location::from(path),
+                (None, Some(url)) => source::Location::Url(url),
+                (None, None) => unreachable!("clap requires a toc path or url"),
+            };
+            let files = toc::read_toc(&source)?;
+            eprintln!("Found {} files", files.len());
+            toc::write_csv(&files, std::io::stdout())
+        }
+        (Some(Command::Index(args)), _) => {
+            for path in args.data_paths.iter() {
+                let source = source::Location::from(path.clone());
+                let dir = index_dir(path, &source, args.index_dir.as_deref())?;
+                eprintln!("Indexing {}...", source);
+                let (codes, npis) = index::Index::build(&source, DEFAULT_BUFF_SIZE, &dir,
+                                                        &asa::SharedReporter::default())?.counts();
+                eprintln!("{}: {} codes, {} npis in {}", source, codes, npis, dir.display());
+            }
+            Ok(())
+        }
+        (Some(Command::Serve(args)), _) => {
+            let mut options = asa::Options::new();
+            options.cancel = cancel.clone();
+            let mut files = Vec::new();
+            for path in args.data_paths.iter() {
+                let source = source::Location::from(path.clone());
+                let dir = index_dir(path, &source, args.index_dir.as_deref())?;
+                let file = index::IndexedFile::open(&source, DEFAULT_BUFF_SIZE, &dir, &options.reporter)?;
+                let (codes, npis) = file.index().counts();
+                eprintln!("{}: {} codes, {} npis", file.name, codes, npis);
+                files.push(file);
+            }
+            serve::serve(&files, &args.listen, &options, &cancel)
+        }
+        (Some(Command::Batch(args)), _) => {
+            let mut manifest = batch::Manifest::read(&args.manifest)?;
+            if let Some(parallel) = args.parallel {
+                manifest.parallel = parallel;
+            }
+            let base = args.manifest.parent().unwrap_or(std::path::Path::new(""));
+            let reporter = asa::SharedReporter::default();
+            let metrics = metrics::Metrics::new();
+            if let Some(addr) = &args.metrics_addr {
+                metrics::spawn_server(addr, metrics.clone(), reporter.clone())
+                    .map_err(|e| format!("Failed to serve metrics on {}: {}", addr, e))?;
+            }
+            let outcomes = batch::run(&manifest, base, &cancel, &metrics, &reporter);
+            batch::summarize(&outcomes, &reporter);
+            if let Some(path) = &manifest.summary {
+                batch::write_summary(&outcomes, std::fs::File::create(base.join(path))?)?;
+            }
+            if let Some(summary) = batch::merge_outputs(&manifest, &outcomes, base)? {
+                eprintln!("Merged the CSV outputs: {} rows, {} duplicates dropped", summary.rows, summary.duplicates);
+            }
+
+            let failed = outcomes.iter().filter(|o| o.result.is_err()).count();
+            if outcomes.len() < manifest.jobs.len() {
+                eprintln!("\nInterrupted, {} of {} jobs not run", manifest.jobs.len() - outcomes.len(), manifest.jobs.len());
+                std::process::exit(error::EXIT_INTERRUPTED);
+            }
+            if failed > 0 {
+                return Err(format!("{} of {} jobs failed", failed, outcomes.len()).into());
+            }
+            Ok(())
+        }
+        (Some(Command::Fetch(args)), _) => {
+            let urls = match args.toc {
+                true => {
+                    let source = match args.target.starts_with("http://") || args.target.starts_with("https://") {
+                        true => source::Location::Url(args.target.clone()),
+                        false => source::Location::from(std::path::PathBuf::from(&args.target)),
+                    };
+                    toc::read_toc(&source)?.into_iter()
+                        .filter(|file| file.file_type == "in_network")
+                        .map(|file| file.location)
+                        .collect()
+                }
+                false => vec![args.target.clone()],
+            };
+            let dir = args.cache_dir.unwrap_or_else(fetch::default_cache_dir);
+            let mut options = fetch::FetchOptions::new();
+            options.retries = args.retries;
+            options.limit_rate = args.limit_rate;
+            options.sha256 = args.sha256;
+            options.cancel = cancel.clone();
+
+            for (fetched, url) in urls.iter().enumerate() {
+                eprintln!("Fetching {}...", url);
+                match fetch::fetch(url, &dir, &options) {
+                    Ok(path) => println!("{}", path.display()),
+                    Err(_) if cancel.is_cancelled() => {
+                        eprintln!("\nInterrupted, {} of {} files fetched, run again to resume", fetched, urls.len());
+                        std::process::exit(error::EXIT_INTERRUPTED);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(())
+        }
+        (Some(Command::Anonymize(args)), _) => {
+            let mut options = anonymize::AnonymizeOptions::new();
+            if let Some(seed) = args.seed {
+                options.seed = seed;
+            }
+            options.spread = args.spread;
+            options.cancel = cancel.clone();
+            let summary = anonymize::anonymize_to(&args.data.source(), DEFAULT_BUFF_SIZE, &args.output, &options)?;
+            eprintln!("Scrambled {} npis and {} tins, perturbed {} rates", summary.npis, summary.tins, summary.rates);
+            Ok(())
+        }
+        (Some(Command::Split(args)), _) => {
+            let source = args.data.source();
+            let name = match (&args.data.data_path, &args.data.url) {
+                (Some(path), _) if path.as_os_str() != "-" => path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+                (_, Some(url)) => url.split(['?', '#']).next().unwrap_or_default()
+                    .rsplit('/').next().unwrap_or_default().to_string(),
+                _ => String::from("stdin"),
+            };
+            std::fs::create_dir_all(&args.output_dir)?;
+            let paths = split::shard_paths(&name, &args.output_dir, args.shards as usize);
+            let elements = split::split_to(&source, DEFAULT_BUFF_SIZE, &paths, &cancel)?;
+            for (path, count) in paths.iter().zip(elements) {
+                eprintln!("{}: {} in_network items", path.display(), count);
+            }
+            Ok(())
+        }
+        (Some(Command::Merge(args)), _) => {
+            let summary = match &args.output {
+                Some(path) => merge::merge_to(&args.inputs, !args.no_header, path)?,
+                None => merge::merge(&args.inputs, !args.no_header, std::io::stdout().lock())?,
+            };
+            eprintln!("Wrote {} rows, dropped {} duplicates", summary.rows, summary.duplicates);
+            Ok(())
+        }
+        (None, Some(input)) => {
+            query_csv(&input, &args.csv, &cancel)
+        }
+        (None, None) => unreachable!("clap requires the query arguments without a subcommand"),
+    }
+}
+
+/// Returns the index directory of the datafile at path read from source: under index_dir if
+/// given, else next to it.
+fn index_dir(path: &std::path::Path,
+             source: &source::Location,
+             index_dir: Option<&std::path::Path>) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+    match (index_dir, index::default_dir(source)) {
+        (Some(dir), _) => Ok(dir.join(format!("{}.index", path.file_name().unwrap_or_default().to_string_lossy()))),
+        (None, Some(dir)) => Ok(dir),
+        (None, None) => Err(format!("pass --index-dir to index {}", source).into()),
+    }
+}
 
-    let mut q = query::read_input(&args.input_path).unwrap();
+/// Runs the query and writes the matching records as CSV to stdout.
+fn query_csv(input: &InputArgs,
+             csv: &CsvArgs,
+             cancel: &asa::CancelToken) -> Result<(), Box<dyn std::error::Error>> {
+    let mut options = input.options(cancel);
+    options.capture_extra = csv.capture_extra;
+    options.explode_service_codes = csv.explode_service_codes;
+    options.explode_modifiers = csv.explode_modifiers;
+    options.rate_precision = csv.rate_precision;
+    options.no_header = csv.no_header;
+    options.min_per_code = csv.min_per_code;
+    options.code_descriptions = csv.code_descriptions.clone();
+    options.rate_summary = csv.rate_summary.clone();
+    options.percentiles = csv.percentiles.clone();
+    options.header_map = csv.header_map.clone();
+    options.list_delimiter = csv.list_delimiter.clone();
+    options.raw_out = csv.raw_out.clone();
+    options.limit = csv.limit;
+    options.sample = csv.sample;
+    options.sample_seed = csv.sample_seed;
+    options.filter = csv.filter.clone();
 
-    asa::run(&mut q, &args.data_path, buff_size, std::io::stdout())?;
+    // The rows go to a temporary file, and the result of the SQL query to stdout
+    if let Some(query) = &csv.sql {
+        let (rows, file) = sql::Rows::new()?;
+        let columns = asa::columns(&options);
+        let mut sink = sink::CsvSink::new(std::io::BufWriter::new(file));
+        return execute_then(input, &options, &mut sink, None, |_| {
+            let written = sql::query(rows.path(), &columns, &csv.sql_table, query, std::io::stdout().lock())?;
+            eprintln!("The SQL query gave {} rows", written);
+            Ok(())
+        });
+    }
+    if let Some(template) = &csv.template {
+        // Parsed against every column, some may not be written by this run
+        let template = template.bind(&asa::columns(&options))?;
+        let checksum = meta::Checksum::new();
+        let out = checksum_writer(csv, &checksum, std::io::stdout().lock());
+        let mut sink = template::TemplateSink::new(template, out);
+        return execute_then(input, &options, &mut sink, stdout_file(input).as_deref(), |summary| {
+            write_stream_checksum(csv.checksum.as_deref(), &checksum, None, summary)
+        });
+    }
+    if csv.output_format == table::OutputFormat::Table {
+        if csv.checksum.is_some() {
+            return Err("--checksum can't be used with --output-format table".into());
+        }
+        let columns = asa::columns(&options);
+        // Through a pager when writing to a terminal, waited for before the summary is printed
+        if !options.dry_run && let Some(mut pager) = table::pager() {
+            let stdin = pager.stdin.take().ok_or("the pager has no stdin")?;
+            let mut sink = table::TableSink::new(stdin, &columns);
+            return execute_then(input, &options, &mut sink, None, move |_| {
+                pager.wait()?;
+                Ok(())
+            });
+        }
+        let mut sink = table::TableSink::new(std::io::stdout().lock(), &columns);
+        return execute_then(input, &options, &mut sink, stdout_file(input).as_deref(), |_| Ok(()));
+    }
+    if let (Some(by), Some(dir)) = (csv.partition_by, &csv.partition_dir) {
+        // Found by position among the columns before --header-map renames them
+        let mut sink = partition::PartitionSink::new(dir, by, &asa::columns(&options), input.compress,
+                                                     options.reporter.clone())?;
+        return execute_then(input, &options, &mut sink, Some(dir), |_| Ok(()));
+    }
+    if let Some(table) = &csv.pg_table {
+        return copy_to_postgres(input, &options, table, csv.pg_url.as_deref());
+    }
+    if let Some(path) = &csv.avro {
+        return write_avro(input, &options, path, csv.checksum.as_deref());
+    }
+    if let Some(path) = &csv.xlsx {
+        // Typed by the columns before --header-map renames them
+        let types = asa::columns(&options).into_iter().map(xlsx::CellType::of).collect();
+        let mut sink = xlsx::XlsxSink::new(path.clone(), types, csv.xlsx_sheet_rows, options.reporter.clone());
+        return execute_then(input, &options, &mut sink, Some(path), |summary| {
+            write_file_checksum(csv.checksum.as_deref(), path, summary)
+        });
+    }
+
+    let rows_file = match &csv.output {
+        Some(dest) => Some(rows_file(dest, input.compress)?),
+        None => None,
+    };
+    // Hashed as written, after compression
+    let checksum = meta::Checksum::new();
+    let (out, codec) = match &rows_file {
+        Some(path) => {
+            let file = std::fs::File::create(path)
+                .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+            let codec = input.compress.unwrap_or_else(|| compress::Codec::for_path(path));
+            (checksum_writer(csv, &checksum, std::io::BufWriter::new(file)), Some(codec))
+        }
+        None => (checksum_writer(csv, &checksum, std::io::stdout()), input.compress),
+    };
+    let out = match codec {
+        Some(codec) => codec.wrap(out)?,
+        None => out,
+    };
+    let output = rows_file.clone().or_else(|| stdout_file(input));
+    execute_then(input, &options, &mut sink::CsvSink::new(out), output.as_deref(), |summary| {
+        write_stream_checksum(csv.checksum.as_deref(), &checksum, rows_file.as_deref(), summary)?;
+        match (&csv.output, &rows_file) {
+            (Some(source::Location::ObjectStore(dest)), Some(path)) => {
+                upload_outputs(dest, path, input, csv, &options.reporter)
+            }
+            _ => Ok(()),
+        }
+    })
+}
+
+/// Returns the file the rows of -o DEST are written to: DEST itself, or a file in a temporary
+/// directory to upload to DEST once the run is done, named after it (or rates.csv for a prefix).
+fn rows_file(dest: &source::Location,
+             codec: Option<compress::Codec>) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+    match dest {
+        source::Location::Path(path) => Ok(path.clone()),
+        source::Location::ObjectStore(url) => {
+            let default = match codec {
+                Some(compress::Codec::Gzip(_)) => "rates.csv.gz",
+                Some(compress::Codec::Zstd(_)) => "rates.csv.zst",
+                _ => "rates.csv",
+            };
+            let dir = std::env::temp_dir().join(format!("mrfy_upload_{}", std::process::id()));
+            std::fs::create_dir_all(&dir)?;
+            Ok(dir.join(upload::rows_name(url, default)))
+        }
+        _ => Err(format!("can't write the rows to {}", dest).into()),
+    }
+}
+
+/// Uploads the rows in the file at rows to dest, with the files of the reports of the run, then
+/// removes the temporary directory of rows.
+fn upload_outputs(dest: &str,
+                  rows: &std::path::Path,
+                  input: &InputArgs,
+                  csv: &CsvArgs,
+                  reporter: &asa::SharedReporter) -> Result<(), Box<dyn std::error::Error>> {
+    let sidecar = input.meta.then(|| meta::sidecar_path(rows));
+    let files: Vec<&std::path::Path> = [Some(rows), sidecar.as_deref(), csv.checksum.as_deref(),
+                                        input.report.as_deref(), input.report_html.as_deref(),
+                                        input.key_report.as_deref()]
+        .into_iter().flatten().collect();
+    upload::upload(dest, &files, reporter)?;
+    if let Some(dir) = rows.parent() {
+        std::fs::remove_dir_all(dir)?;
+    }
+    Ok(())
+}
+
+/// Returns out, adding what is written to checksum if --checksum is set.
+fn checksum_writer(csv: &CsvArgs,
+                   checksum: &meta::Checksum,
+                   out: impl std::io::Write + 'static) -> Box<dyn std::io::Write> {
+    match csv.checksum {
+        Some(_) => Box::new(checksum.writer(out)),
+        None => Box::new(out),
+    }
+}
 
-    q.warn_not_recorded();
+/// Writes the --checksum of the output file at path to checksum, if set.
+fn write_file_checksum(checksum: Option<&std::path::Path>,
+                       path: &std::path::Path,
+                       summary: &asa::RunSummary) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(checksum) = checksum {
+        meta::OutputChecksum::of_file(path, summary.stats.rows_written)?.write(checksum)?;
+    }
+    Ok(())
+}
+
+/// Writes the --checksum of the output written through a writer of stream to checksum, if set.
+/// The output went to the file at output if given, else to stdout.
+fn write_stream_checksum(checksum: Option<&std::path::Path>,
+                         stream: &meta::Checksum,
+                         output: Option<&std::path::Path>,
+                         summary: &asa::RunSummary) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(checksum) = checksum {
+        let output = output.map(std::path::Path::to_path_buf).or_else(meta::stdout_file);
+        meta::OutputChecksum::of_stream(stream, output.as_deref(), summary.stats.rows_written).write(checksum)?;
+    }
+    Ok(())
+}
+
+/// Runs the query and copies the matching records into table, over a connection to url (else
+/// DATABASE_URL).
+#[cfg(feature = "postgres")]
+fn copy_to_postgres(input: &InputArgs,
+                    options: &asa::Options,
+                    table: &str,
+                    url: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let url = match (url, std::env::var("DATABASE_URL")) {
+        (Some(url), _) => url.to_string(),
+        (None, Ok(url)) => url,
+        (None, Err(_)) => return Err("pass --pg-url or set DATABASE_URL to use --pg-table".into()),
+    };
+    let mut client = pg::connect(&url)?;
+    let mut sink = pg::PgSink::new(&mut client, table, options.reporter.clone());
+    execute_then(input, options, &mut sink, None, |_| Ok(()))
+}
+
+#[cfg(not(feature = "postgres"))]
+fn copy_to_postgres(_input: &InputArgs,
+                    _options: &asa::Options,
+                    _table: &str,
+                    _url: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    Err("rebuild mrfy with the `postgres` feature to use --pg-table".into())
+}
+
+/// Runs the query and writes the matching records to an Avro file at path, then its checksum to
+/// checksum if set.
+#[cfg(feature = "avro")]
+fn write_avro(input: &InputArgs,
+              options: &asa::Options,
+              path: &std::path::Path,
+              checksum: Option<&std::path::Path>) -> Result<(), Box<dyn std::error::Error>> {
+    // Typed by the columns before --header-map renames them
+    let types = asa::columns(options).into_iter().map(avro::FieldType::of).collect();
+    let file = std::fs::File::create(path)
+        .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+    let mut sink = avro::AvroSink::new(std::io::BufWriter::new(file), types);
+    execute_then(input, options, &mut sink, Some(path), |summary| write_file_checksum(checksum, path, summary))
+}
+
+#[cfg(not(feature = "avro"))]
+fn write_avro(_input: &InputArgs,
+              _options: &asa::Options,
+              _path: &std::path::Path,
+              _checksum: Option<&std::path::Path>) -> Result<(), Box<dyn std::error::Error>> {
+    Err("rebuild mrfy with the `avro` feature to use --avro".into())
+}
+
+/// Runs the query against the datafile of args and the --with datafiles and writes the table of
+/// their rates as CSV to stdout, with a column named after each file.
+fn compare_files(args: &CompareArgs,
+                 cancel: &asa::CancelToken) -> Result<(), Box<dyn std::error::Error>> {
+    let query = args.input.query()?;
+
+    let mut sources = vec![args.input.source()?];
+    let buff_size = args.input.buff_size(&sources[0]);
+    sources.extend(args.others.iter().map(|path| source::Location::from(path.clone())));
+    let names: Vec<String> = sources.iter().map(|source| source.to_string()).collect();
+
+    let options = args.input.options(cancel);
+    let (table, summaries) = compare::compare(&query, &sources, buff_size, &options)?;
+    compare::write_table(&table, &names, std::io::stdout())?;
+
+    for (name, summary) in names.iter().zip(summaries.iter()) {
+        eprintln!("\n{}", name);
+        summary.summarize(&options.reporter);
+    }
+    eprintln!("\nWrote {} rows", table.len());
+
+    Ok(())
+}
+
+/// Prints which npis of the query a --dry-run found in provider_references. Exits with
+/// EXIT_NO_MATCHES if none of them is in the file.
+fn dry_run_report(summary: &asa::RunSummary,
+                  reporter: &asa::SharedReporter) -> Result<(), Box<dyn std::error::Error>> {
+    summary.summarize(reporter);
+    let missing: Vec<u64> = summary.query.npis.iter().filter(|n| n.groups.is_empty()).map(|n| n.npi).collect();
+    for npi in missing.iter() {
+        eprintln!("WARNING: npi {} of the query is not in provider_references", npi);
+    }
+    let npis = summary.query.npis.len();
+    eprintln!("Dry run: {} of {} npis of the query found in provider_references", npis - missing.len(), npis);
+
+    if summary.no_providers {
+        std::process::exit(error::EXIT_NO_MATCHES);
+    }
+    Ok(())
+}
+
+/// Returns the file standard output is redirected to for --meta, if any.
+fn stdout_file(input: &InputArgs) -> Option<std::path::PathBuf> {
+    input.meta.then(meta::stdout_file).flatten()
+}
+
+/// Runs the query from input against its datafile, writing the records to sink, the file at output
+/// if it writes to one. Calls then with the summary of the run once they and the reports are
+/// written, then warns about parts of the query without matches and prints the run summary. With
+/// --notify-url, how the run went is posted before it exits, whether it failed or not.
+fn execute_then(input: &InputArgs,
+                options: &asa::Options,
+                sink: &mut dyn sink::RecordSink,
+                output: Option<&std::path::Path>,
+                then: impl FnOnce(&asa::RunSummary) -> Result<(), Box<dyn std::error::Error>>) -> Result<(), Box<dyn std::error::Error>> {
+    let started = std::time::Instant::now();
+    let source = input.source()?;
+    let notify_run = |code: i32, error: Option<String>, summary: Option<&asa::RunSummary>| {
+        if let Some(url) = &input.notify_url {
+            let mut notification = notify::Notification::new(code, error, &source.to_string(), started.elapsed());
+            if let Some(summary) = summary {
+                let outputs: Vec<&std::path::Path> = [output, options.extract.as_deref(), input.key_report.as_deref(),
+                                                      input.report.as_deref(), input.report_html.as_deref()]
+                    .into_iter().flatten().collect();
+                notification = notification.with_summary(summary, &outputs);
+            }
+            notify::send(url, &notification, &options.reporter);
+        }
+    };
+
+    let mut q = match input.query() {
+        Ok(q) => q,
+        Err(e) => {
+            eprintln!("ERROR in query: {}", e);
+            notify_run(error::EXIT_FATAL, Some(format!("query: {}", e)), None);
+            std::process::exit(error::EXIT_FATAL);
+        }
+    };
+
+    // A mistyped npi would only show up as "no match" at the end of a long run
+    let invalid_npis = q.invalid_npis();
+    for npi in invalid_npis.iter() {
+        eprintln!("WARNING: npi {} in the query fails the NPI check digit, is it mistyped?", npi);
+    }
+    if options.strict && !invalid_npis.is_empty() {
+        eprintln!("ERROR in query: invalid npis with --strict");
+        notify_run(error::EXIT_FATAL, Some(String::from("query: invalid npis with --strict")), None);
+        std::process::exit(error::EXIT_FATAL);
+    }
+
+    let buff_size = input.buff_size(&source);
+    // Before the run adds the providers of the groups it selects
+    let query_sha256 = input.meta.then(|| meta::sha256(q.canonical().as_bytes()));
+
+    let summary = match asa::run(&mut q, &source, buff_size, options, sink) {
+        Ok(summary) => summary,
+        Err(e) => {
+            if let Some(interrupted) = e.downcast_ref::<error::Interrupted>() {
+                eprintln!("\n{}", interrupted);
+                notify_run(error::EXIT_INTERRUPTED, Some(interrupted.to_string()), None);
+                std::process::exit(error::EXIT_INTERRUPTED);
+            }
+            if let Some(stale) = e.downcast_ref::<error::Stale>() {
+                eprintln!("\nSTALE FILE: {}", stale);
+                notify_run(error::EXIT_STALE, Some(stale.to_string()), None);
+                std::process::exit(error::EXIT_STALE);
+            }
+            if let Some(parse_error) = e.downcast_ref::<error::ParseError>() {
+                eprintln!("\nFATAL ERROR: {}", parse_error);
+                notify_run(error::EXIT_FATAL, Some(parse_error.to_string()), None);
+                std::process::exit(error::EXIT_FATAL);
+            }
+            notify_run(error::EXIT_FATAL, Some(e.to_string()), None);
+            return Err(e);
+        }
+    };
+
+    if options.dry_run {
+        return dry_run_report(&summary, &options.reporter);
+    }
+    let written = write_reports(input, options, &q, &summary, &source, output, query_sha256)
+        .and_then(|_| then(&summary));
+    if let Err(e) = written {
+        notify_run(error::EXIT_FATAL, Some(e.to_string()), Some(&summary));
+        return Err(e);
+    }
+
+    q.warn_not_recorded(&options.reporter);
+    summary.summarize(&options.reporter);
+
+    // Lets scripts tell "no data" and "data issues" apart from a clean run with matches
+    let code = summary.exit_code();
+    notify_run(code, None, Some(&summary));
+    if code != error::EXIT_MATCHES {
+        std::process::exit(code);
+    }
+
+    Ok(())
+}
 
+/// Writes the reports of input about the run of q over source, summed up by summary: --key-report,
+/// --report, --report-html and the --meta sidecars of the file at output and of the extract, the
+/// query hashed to query_sha256.
+fn write_reports(input: &InputArgs,
+                 options: &asa::Options,
+                 q: &query::Query,
+                 summary: &asa::RunSummary,
+                 source: &source::Location,
+                 output: Option<&std::path::Path>,
+                 query_sha256: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(path) = &input.key_report {
+        asa::write_key_report(path, &summary.unsupported_keys)?;
+    }
+    if let Some(path) = &input.report {
+        q.write_report(path)?;
+    }
+    if let Some(path) = &input.report_html {
+        html::write_report(path, summary, &source.to_string(), &options.percentiles)?;
+    }
+    if let Some(query_sha256) = query_sha256 {
+        let outputs: Vec<&std::path::Path> = output.into_iter().chain(options.extract.as_deref()).collect();
+        if outputs.is_empty() {
+            eprintln!("WARNING: --meta: the rows were not written to a file, no .meta.json written");
+        }
+        let sidecar = meta::Sidecar::new(source, summary, query_sha256)?;
+        for path in outputs {
+            sidecar.write_next_to(path)?;
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,209 @@
+//! # merge
+//!
+//! Combines the CSV outputs of several runs, e.g. the jobs of a batch over the files of many
+//! payers (`merge` in a manifest) or the shards of a split file (`mrfy merge`), into one file
+//! with its rows sorted and exact duplicates dropped.
+//!
+//! Rows are sorted by their fields as text, from the first column (npi) on. Up to MEMORY_ROWS
+//! rows are sorted in memory at a time and spilled to a temporary file, then the files are
+//! merged, so memory stays bounded however large the outputs are.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use flate2::read::MultiGzDecoder;
+
+/// Number of rows sorted in memory before they are spilled to disk.
+const MEMORY_ROWS: usize = 1 << 20;
+
+/// What merge did.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MergeSummary {
+    /// Rows written
+    pub rows: u64,
+    /// Rows dropped because the same row was written
+    pub duplicates: u64,
+}
+
+/// Sorted, deduplicated rows in a temporary CSV file, removed when dropped.
+struct Run {
+    file: File,
+    path: PathBuf,
+}
+
+impl Run {
+    /// Writes the sorted rows to a new temporary file.
+    fn new(rows: &[csv::StringRecord]) -> Result<Self, Box<dyn std::error::Error>> {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!("mrfy-merge-{}-{}.tmp",
+                                                    std::process::id(),
+                                                    FILES.fetch_add(1, Ordering::Relaxed)));
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        let mut run = Run { file, path };
+
+        let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(BufWriter::new(&mut run.file));
+        for row in rows {
+            writer.write_record(row)?;
+        }
+        writer.flush()?;
+        drop(writer);
+        run.file.seek(SeekFrom::Start(0))?;
+        Ok(run)
+    }
+
+    /// Returns a reader of the rows of the file.
+    fn reader(&self) -> Result<csv::Reader<BufReader<File>>, std::io::Error> {
+        Ok(csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(BufReader::new(self.file.try_clone()?)))
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Opens the CSV file at path, gzip compressed if it ends in .gz (as written by create_output).
+fn open_csv(path: &Path) -> Result<Box<dyn Read>, std::io::Error> {
+    let file = BufReader::new(File::open(path)?);
+    if path.extension().is_some_and(|e| e == "gz") {
+        Ok(Box::new(MultiGzDecoder::new(file)))
+    }
+    else {
+        Ok(Box::new(file))
+    }
+}
+
+/// Sorts rows and drops duplicates, counting them in summary.
+fn sort_rows(rows: &mut Vec<csv::StringRecord>, summary: &mut MergeSummary) {
+    rows.sort_unstable_by(|a, b| a.iter().cmp(b.iter()));
+    let before = rows.len();
+    rows.dedup_by(|a, b| a == b);
+    summary.duplicates += (before - rows.len()) as u64;
+}
+
+/// Writes the rows of the CSV files at inputs (plain or gzip compressed) to out, sorted and without
+/// duplicates. With headers, every file starts with the same header, which is written once.
+pub fn merge(inputs: &[PathBuf],
+             headers: bool,
+             out: impl Write) -> Result<MergeSummary, Box<dyn std::error::Error>> {
+    merge_with(inputs, headers, out, MEMORY_ROWS)
+}
+
+/// Does merge, spilling rows to disk every memory_rows rows.
+fn merge_with(inputs: &[PathBuf],
+              headers: bool,
+              out: impl Write,
+              memory_rows: usize) -> Result<MergeSummary, Box<dyn std::error::Error>> {
+    let mut summary = MergeSummary::default();
+    let mut header: Option<csv::StringRecord> = None;
+    let mut rows = Vec::new();
+    let mut runs = Vec::new();
+
+    for input in inputs {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(headers)
+            .flexible(true)
+            .from_reader(open_csv(input)?);
+        if headers {
+            let this = reader.headers()?.clone();
+            match &header {
+                Some(first) if *first != this => {
+                    return Err(format!("{} has other columns than {}", input.display(), inputs[0].display()).into());
+                }
+                Some(_) => {}
+                None => header = Some(this),
+            }
+        }
+        for row in reader.records() {
+            rows.push(row?);
+            if rows.len() >= memory_rows {
+                sort_rows(&mut rows, &mut summary);
+                runs.push(Run::new(&rows)?);
+                rows.clear();
+            }
+        }
+    }
+    sort_rows(&mut rows, &mut summary);
+
+    let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(out);
+    if let Some(header) = &header {
+        writer.write_record(header)?;
+    }
+
+    // The rows left in memory are one more sorted run, merged with the spilled ones
+    let mut readers = runs.iter().map(Run::reader).collect::<Result<Vec<_>, _>>()?;
+    let mut sources: Vec<Box<dyn Iterator<Item = Result<csv::StringRecord, csv::Error>>>> =
+        readers.iter_mut().map(|r| Box::new(r.records()) as Box<dyn Iterator<Item = _>>).collect();
+    sources.push(Box::new(rows.into_iter().map(Ok)));
+
+    let mut heads = BinaryHeap::new();
+    for (i, source) in sources.iter_mut().enumerate() {
+        if let Some(row) = source.next() {
+            heads.push(Reverse((row?.iter().map(String::from).collect::<Vec<String>>(), i)));
+        }
+    }
+    let mut last: Option<Vec<String>> = None;
+    while let Some(Reverse((row, i))) = heads.pop() {
+        if let Some(next) = sources[i].next() {
+            heads.push(Reverse((next?.iter().map(String::from).collect(), i)));
+        }
+        if last.as_ref() == Some(&row) {
+            summary.duplicates += 1;
+            continue;
+        }
+        writer.write_record(&row)?;
+        summary.rows += 1;
+        last = Some(row);
+    }
+    writer.flush()?;
+    Ok(summary)
+}
+
+/// Merges inputs (see merge) into the file at path, gzip compressed if it ends in .gz.
+pub fn merge_to(inputs: &[PathBuf],
+                headers: bool,
+                path: &Path) -> Result<MergeSummary, Box<dyn std::error::Error>> {
+    let mut out = crate::asa::create_output(path)?;
+    let summary = merge(inputs, headers, &mut out)?;
+    out.flush()?;
+    Ok(summary)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let dir = std::env::temp_dir().join(format!("mrfy_merge_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let a = dir.join("a.csv");
+        let b = dir.join("b.csv");
+        std::fs::write(&a, "npi,rate\n3,1.00\n1,\"2,5\"\n2,9.99\n1,\"2,5\"\n").unwrap();
+        std::fs::write(&b, "npi,rate\n2,9.99\n0,4.00\n").unwrap();
+
+        // Spills every 2 rows
+        let mut out = Vec::new();
+        let summary = merge_with(&[a.clone(), b.clone()], true, &mut out, 2).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "npi,rate\n0,4.00\n1,\"2,5\"\n2,9.99\n3,1.00\n");
+        assert_eq!(summary, MergeSummary { rows: 4, duplicates: 2 });
+
+        let mut out = Vec::new();
+        merge(&[a.clone(), b.clone()], true, &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().starts_with("npi,rate\n0,4.00\n"));
+
+        std::fs::write(&b, "npi,price\n2,9.99\n").unwrap();
+        assert!(merge(&[a, b], true, Vec::new()).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
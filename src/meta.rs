@@ -0,0 +1,244 @@
+//! # meta
+//!
+//! Writes a sidecar next to an output file (`--meta`), `rates.csv.meta.json` for `rates.csv`,
+//! recording where its rows came from so a consumer can check it without rerunning the query: the
+//! datafile and its SHA-256, the reporting entity and last_updated_on of the file, the SHA-256 of
+//! the query (see Query::canonical), the version of mrfy and the number of rows.
+//!
+//! The datafile is hashed once the run is done, by reading it again. A datafile streamed from a
+//! URL or stdin has no hash.
+//!
+//! The rows themselves can be checked with `--checksum`: the SHA-256 and length of the output as
+//! written (after compression), with the number of rows, hashed as the output is written by a
+//! ChecksumWriter, so a transfer of the output can be verified without the datafile.
+
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::asa::RunSummary;
+use crate::source::Location;
+
+/// Suffix of a sidecar, after the name of its output file.
+pub const SUFFIX: &str = ".meta.json";
+
+/// Returns the SHA-256 of bytes, as lowercase hex.
+pub fn sha256(bytes: &[u8]) -> String {
+    hex(&Sha256::digest(bytes))
+}
+
+/// Returns the SHA-256 of the file at path, as lowercase hex.
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex(&hasher.finalize()))
+}
+
+/// Returns bytes as lowercase hex.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Returns the path of the sidecar of the output file at path.
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Returns the file standard output is redirected to, if it is a file (found on Linux only).
+pub fn stdout_file() -> Option<PathBuf> {
+    let path = std::fs::read_link("/proc/self/fd/1").ok()?;
+    std::fs::metadata(&path).ok().filter(|m| m.is_file()).map(|_| path)
+}
+
+/// The provenance of an output file.
+#[derive(Clone, Debug, Serialize)]
+pub struct Sidecar {
+    /// Name of the output file
+    pub output: String,
+    pub rows: u64,
+    /// The datafile, a path or URL
+    pub source: String,
+    pub source_sha256: Option<String>,
+    pub reporting_entity_name: Option<String>,
+    pub last_updated_on: Option<String>,
+    pub query_sha256: String,
+    pub mrfy_version: &'static str,
+}
+
+impl Sidecar {
+    /// Creates the sidecar of a run of a query (its canonical text hashed to query_sha256) over
+    /// source, summed up by summary, hashing the datafile if it is on disk. The output is set by
+    /// write_next_to.
+    pub fn new(source: &Location, summary: &RunSummary, query_sha256: String) -> io::Result<Self> {
+        let source_sha256 = match source {
+            Location::Path(path) => Some(sha256_file(path)?),
+            _ => None,
+        };
+        let metadata = |key: &str| summary.metadata.iter().find(|(k, _)| *k == key).map(|(_, v)| v.clone());
+        Ok(Self {
+            output: String::new(),
+            rows: summary.stats.rows_written,
+            source: source.to_string(),
+            source_sha256,
+            reporting_entity_name: metadata("reporting_entity_name"),
+            last_updated_on: metadata("last_updated_on"),
+            query_sha256,
+            mrfy_version: env!("CARGO_PKG_VERSION"),
+        })
+    }
+
+    /// Writes the sidecar of the output file at path (see sidecar_path) as JSON.
+    pub fn write_next_to(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let sidecar = Self {
+            output: path.file_name().map_or_else(String::new, |n| n.to_string_lossy().into_owned()),
+            ..self.clone()
+        };
+        let meta_path = sidecar_path(path);
+        let file = std::fs::File::create(&meta_path)
+            .map_err(|e| format!("Failed to create {}: {}", meta_path.display(), e))?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), &sidecar)?;
+        Ok(())
+    }
+}
+
+/// The SHA-256 and length of what a ChecksumWriter wrote so far.
+#[derive(Clone, Default)]
+pub struct Checksum(Rc<RefCell<(Sha256, u64)>>);
+
+impl Checksum {
+    /// Creates the Checksum of a stream not written yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a writer to out adding what it writes to this checksum.
+    pub fn writer<W: Write>(&self, out: W) -> ChecksumWriter<W> {
+        ChecksumWriter { out, checksum: self.clone() }
+    }
+
+    /// Returns the SHA-256 (as lowercase hex) and the length of what was written.
+    pub fn value(&self) -> (String, u64) {
+        let state = self.0.borrow();
+        (hex(&state.0.clone().finalize()), state.1)
+    }
+}
+
+/// Writes to out, adding what is written to a Checksum.
+pub struct ChecksumWriter<W: Write> {
+    out: W,
+    checksum: Checksum,
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.out.write(buf)?;
+        let mut state = self.checksum.0.borrow_mut();
+        state.0.update(&buf[..n]);
+        state.1 += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// The checksum of an output, for --checksum.
+#[derive(Debug, Serialize)]
+pub struct OutputChecksum {
+    /// Name of the output file, - for stdout
+    pub output: String,
+    pub rows: u64,
+    pub bytes: u64,
+    pub sha256: String,
+}
+
+impl OutputChecksum {
+    /// Returns the checksum of the output file at path, holding rows, by reading it.
+    pub fn of_file(path: &Path, rows: u64) -> io::Result<Self> {
+        Ok(Self {
+            output: path.file_name().map_or_else(String::new, |n| n.to_string_lossy().into_owned()),
+            rows,
+            bytes: std::fs::metadata(path)?.len(),
+            sha256: sha256_file(path)?,
+        })
+    }
+
+    /// Returns the checksum of an output of rows written through a ChecksumWriter of checksum,
+    /// to the file at path if any, else stdout.
+    pub fn of_stream(checksum: &Checksum, path: Option<&Path>, rows: u64) -> Self {
+        let (sha256, bytes) = checksum.value();
+        let output = match path.and_then(|p| p.file_name()) {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => String::from("-"),
+        };
+        Self { output, rows, bytes, sha256 }
+    }
+
+    /// Writes the checksum to path as JSON.
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let file = std::fs::File::create(path)
+            .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sidecar() {
+        assert_eq!(sha256(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(sidecar_path(Path::new("out/rates.csv.gz")), Path::new("out/rates.csv.gz.meta.json"));
+
+        let data = std::env::temp_dir().join(format!("mrfy_meta_{}.json", std::process::id()));
+        std::fs::write(&data, "abc").unwrap();
+        let summary = RunSummary {
+            metadata: vec![("reporting_entity_name", String::from("Aetna")), ("last_updated_on", String::from("2025-04-05"))],
+            ..Default::default()
+        };
+        let sidecar = Sidecar::new(&Location::Path(data.clone()), &summary, sha256(b"code CPT:99213")).unwrap();
+        assert_eq!(sidecar.source_sha256.as_deref(), Some(sha256(b"abc").as_str()));
+
+        let output = data.with_extension("csv");
+        sidecar.write_next_to(&output).unwrap();
+        let meta_path = sidecar_path(&output);
+        let written: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&meta_path).unwrap()).unwrap();
+        assert_eq!(written["output"], output.file_name().unwrap().to_str().unwrap());
+        assert_eq!(written["reporting_entity_name"], "Aetna");
+        assert_eq!(written["last_updated_on"], "2025-04-05");
+        assert_eq!(written["rows"], 0);
+        assert_eq!(written["mrfy_version"], env!("CARGO_PKG_VERSION"));
+        std::fs::remove_file(&data).unwrap();
+        std::fs::remove_file(&meta_path).unwrap();
+    }
+
+    #[test]
+    fn test_checksum() {
+        let checksum = Checksum::new();
+        let mut out = checksum.writer(Vec::new());
+        out.write_all(b"a").unwrap();
+        out.write_all(b"bc").unwrap();
+        assert_eq!(out.out, b"abc");
+        let written = OutputChecksum::of_stream(&checksum, None, 1);
+        assert_eq!((written.output.as_str(), written.bytes), ("-", 3));
+        assert_eq!(written.sha256, sha256(b"abc"));
+    }
+}
@@ -0,0 +1,176 @@
+//! # metrics
+//!
+//! Prometheus metrics of the long-running modes, so a monitoring stack can alert on a stuck or
+//! failing ingestion job: `mrfy serve` answers `GET /metrics` on its own port, and `mrfy batch
+//! --metrics-addr ADDR` serves them from a background thread while its jobs run.
+//!
+//! The counters add up every run (bytes read, objects scanned and matched, rows written) and the
+//! errors, failed jobs or requests. `mrfy_last_progress_timestamp_seconds` is when a run last
+//! reported progress, every PROGRESS_INTERVAL objects, so a job that has stopped moving shows as
+//! a timestamp that stops moving.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::asa::{Progress, SharedReporter};
+
+/// Content type of the Prometheus text format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// The counters of a process, shared by its runs.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    bytes_read: AtomicU64,
+    objects_scanned: AtomicU64,
+    objects_matched: AtomicU64,
+    rows_written: AtomicU64,
+    /// Failed jobs or requests
+    errors: AtomicU64,
+    requests: AtomicU64,
+    runs_active: AtomicU64,
+    runs_finished: AtomicU64,
+    /// Unix time of the last progress report
+    last_progress: AtomicU64,
+}
+
+/// Returns the unix time now, in seconds.
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+impl Metrics {
+    /// Creates the metrics of a process, all zero.
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Returns a progress callback of a run adding what it reports to these metrics. A run
+    /// reports its totals so far, so what changed since its last report is added. The run is
+    /// active until the callback is dropped, when the run returns.
+    pub fn observer(self: &Arc<Self>) -> impl FnMut(&Progress) + 'static {
+        self.runs_active.fetch_add(1, Ordering::Relaxed);
+        let active = ActiveRun(Arc::clone(self));
+        let mut last = Progress::default();
+        move |progress: &Progress| {
+            let metrics = &active.0;
+            metrics.bytes_read.fetch_add(progress.bytes_read.saturating_sub(last.bytes_read), Ordering::Relaxed);
+            metrics.objects_scanned.fetch_add(progress.objects_scanned.saturating_sub(last.objects_scanned), Ordering::Relaxed);
+            metrics.objects_matched.fetch_add(progress.objects_matched.saturating_sub(last.objects_matched), Ordering::Relaxed);
+            metrics.rows_written.fetch_add(progress.rows_written.saturating_sub(last.rows_written), Ordering::Relaxed);
+            metrics.last_progress.store(now(), Ordering::Relaxed);
+            if progress.finished && !last.finished {
+                metrics.runs_finished.fetch_add(1, Ordering::Relaxed);
+            }
+            last = progress.clone();
+        }
+    }
+
+    /// Counts a failed job or request.
+    pub fn add_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a request answered with rows.
+    pub fn add_request(&self, rows: u64) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.rows_written.fetch_add(rows, Ordering::Relaxed);
+        self.last_progress.store(now(), Ordering::Relaxed);
+    }
+
+    /// Returns the metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let metrics = [
+            ("mrfy_bytes_read_total", "counter", "Decompressed bytes of datafiles read", &self.bytes_read),
+            ("mrfy_objects_scanned_total", "counter", "in_network objects scanned", &self.objects_scanned),
+            ("mrfy_objects_matched_total", "counter", "in_network objects matching a query", &self.objects_matched),
+            ("mrfy_rows_written_total", "counter", "Rows written or returned", &self.rows_written),
+            ("mrfy_errors_total", "counter", "Failed jobs or requests", &self.errors),
+            ("mrfy_requests_total", "counter", "Requests answered by mrfy serve", &self.requests),
+            ("mrfy_runs_active", "gauge", "Runs in progress", &self.runs_active),
+            ("mrfy_runs_finished_total", "counter", "Runs that read all of in_network", &self.runs_finished),
+            ("mrfy_last_progress_timestamp_seconds", "gauge", "Unix time of the last progress of a run or request", &self.last_progress),
+        ];
+        let mut text = String::new();
+        for (name, kind, help, value) in metrics {
+            text.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n{} {}\n",
+                                   name, help, name, kind, name, value.load(Ordering::Relaxed)));
+        }
+        text
+    }
+}
+
+/// Counts a run as active until dropped.
+struct ActiveRun(Arc<Metrics>);
+
+impl Drop for ActiveRun {
+    fn drop(&mut self) {
+        self.0.runs_active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Serves metrics on addr from a background thread, answering GET /metrics, for as long as the
+/// process runs. Requests that fail are reported to reporter.
+pub fn spawn_server(addr: &str, metrics: Arc<Metrics>, reporter: SharedReporter) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    let local = listener.local_addr()?;
+    reporter.status(format_args!("Serving metrics on http://{}/metrics", local));
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(e) = handle(stream, &metrics) {
+                reporter.warn(format_args!("metrics request failed: {}", e));
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Reads one request from stream and writes the metrics, or a 404 for another path.
+fn handle(stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", String::from("only GET /metrics is served\n")),
+    };
+    let mut stream = reader.into_inner();
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+           status, CONTENT_TYPE, body.len(), body)?;
+    stream.flush()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Metrics::new();
+        let mut observe = metrics.observer();
+        observe(&Progress { bytes_read: 100, objects_scanned: 10, ..Default::default() });
+        assert!(metrics.render().contains("\nmrfy_runs_active 1\n"));
+        observe(&Progress { bytes_read: 250, objects_scanned: 30, objects_matched: 2, rows_written: 5, finished: true });
+        drop(observe);
+        metrics.add_error();
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE mrfy_bytes_read_total counter\nmrfy_bytes_read_total 250\n"));
+        assert!(text.contains("\nmrfy_objects_scanned_total 30\n"));
+        assert!(text.contains("\nmrfy_rows_written_total 5\n"));
+        assert!(text.contains("\nmrfy_errors_total 1\n"));
+        assert!(text.contains("\nmrfy_runs_active 0\n"));
+        assert!(text.contains("\nmrfy_runs_finished_total 1\n"));
+        assert!(!text.contains("mrfy_last_progress_timestamp_seconds 0\n"));
+    }
+}
@@ -0,0 +1,75 @@
+//! # minrates
+//!
+//! Keeps only the lowest negotiated_rate of each billing code and npi (--min-per-code).
+//!
+//! Rows can't be streamed out in this mode, since a lower rate for the same code and npi can come
+//! later in the file. The row with the lowest rate of each key is held in memory instead and
+//! the rows are written at the end of the run, in the order their keys were first seen.
+
+use std::collections::HashMap;
+
+/// The row with the lowest rate so far for each key.
+#[derive(Debug, Default)]
+pub struct MinRates {
+    index: HashMap<Vec<String>, usize>,
+    rows: Vec<(Option<f64>, Vec<String>)>,
+}
+
+impl MinRates {
+    /// Creates an empty MinRates.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps row for key if it is the first row of key or its rate is lower than the rate of
+    /// the row kept. A rate that is not a number is never lower, so the first row stays on ties.
+    pub fn offer<T: AsRef<str>>(&mut self, key: &[&str], rate: &str, row: &[T]) {
+        let rate: Option<f64> = rate.parse().ok();
+        let row = || row.iter().map(|f| f.as_ref().to_string()).collect();
+
+        let key: Vec<String> = key.iter().map(|k| k.to_string()).collect();
+        match self.index.get(&key) {
+            Some(&i) => {
+                let kept = &mut self.rows[i];
+                let lower = match (rate, kept.0) {
+                    (Some(new), Some(old)) => new < old,
+                    (Some(_), None) => true,
+                    (None, _) => false,
+                };
+                if lower {
+                    *kept = (rate, row());
+                }
+            }
+            None => {
+                self.index.insert(key, self.rows.len());
+                self.rows.push((rate, row()));
+            }
+        }
+    }
+
+    /// Returns the rows kept, one per key.
+    pub fn into_rows(self) -> impl Iterator<Item = Vec<String>> {
+        self.rows.into_iter().map(|(_, row)| row)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_min_rates() {
+        let mut mins = MinRates::new();
+        mins.offer(&["CPT", "1", "11"], "null", &["11", "null"]);
+        mins.offer(&["CPT", "1", "11"], "9.50", &["11", "9.50"]);
+        mins.offer(&["CPT", "2", "11"], "3", &["11", "3"]);
+        mins.offer(&["CPT", "1", "11"], "10", &["11", "10"]);
+        mins.offer(&["CPT", "1", "11"], "9.5", &["11", "9.5"]);
+        mins.offer(&["CPT", "1", "11"], "2.25", &["11", "2.25"]);
+        mins.offer(&["CPT", "2", "11"], "null", &["11", "null"]);
+
+        let rows: Vec<Vec<String>> = mins.into_rows().collect();
+        assert_eq!(rows, vec![vec!["11", "2.25"], vec!["11", "3"]]);
+    }
+}
@@ -0,0 +1,128 @@
+//! # notify
+//!
+//! Tells a webhook how a run went (`--notify-url`), so an orchestrator can react without parsing
+//! stderr: once the run is done, or has failed, its Notification is POSTed as JSON. Posting needs
+//! the `http` feature, and a webhook that can't be reached only gives a warning.
+
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::asa::{RunSummary, SharedReporter};
+use crate::error::{EXIT_DATA_ISSUES, EXIT_INTERRUPTED, EXIT_MATCHES, EXIT_NO_MATCHES, EXIT_STALE};
+
+/// Time to wait for the webhook.
+#[cfg(feature = "http")]
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Returns the status of a run that exits with code.
+pub fn status(code: i32) -> &'static str {
+    match code {
+        EXIT_MATCHES => "ok",
+        EXIT_NO_MATCHES => "no matches",
+        EXIT_DATA_ISSUES => "data issues",
+        EXIT_STALE => "stale",
+        EXIT_INTERRUPTED => "interrupted",
+        _ => "failed",
+    }
+}
+
+/// How a run went, as POSTed.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Notification {
+    pub status: &'static str,
+    pub exit_code: i32,
+    /// What stopped a failed run
+    pub error: Option<String>,
+    /// The datafile
+    pub source: String,
+    pub duration_secs: f64,
+    pub rows_written: u64,
+    pub objects_scanned: u64,
+    pub objects_matched: u64,
+    pub in_network_items: u64,
+    pub decompressed_bytes: u64,
+    pub corrupt_skipped: u64,
+    /// The files written
+    pub outputs: Vec<String>,
+    pub mrfy_version: &'static str,
+}
+
+impl Notification {
+    /// Creates the notification of a run over source that took duration and exits with code,
+    /// stopped by error if it failed.
+    pub fn new(code: i32, error: Option<String>, source: &str, duration: Duration) -> Self {
+        Self {
+            status: status(code),
+            exit_code: code,
+            error,
+            source: source.to_string(),
+            duration_secs: duration.as_secs_f64(),
+            mrfy_version: env!("CARGO_PKG_VERSION"),
+            ..Default::default()
+        }
+    }
+
+    /// Adds the counts of summary and the files written, outputs.
+    pub fn with_summary(self, summary: &RunSummary, outputs: &[&Path]) -> Self {
+        let stats = &summary.stats;
+        Self {
+            rows_written: stats.rows_written,
+            objects_scanned: stats.objects_scanned,
+            objects_matched: stats.objects_matched,
+            in_network_items: stats.in_network_items,
+            decompressed_bytes: stats.decompressed_bytes,
+            corrupt_skipped: stats.corrupt_skipped,
+            outputs: outputs.iter().map(|p| p.display().to_string()).collect(),
+            ..self
+        }
+    }
+}
+
+/// POSTs notification to url as JSON.
+#[cfg(feature = "http")]
+pub fn post(url: &str, notification: &Notification) -> Result<(), Box<dyn std::error::Error>> {
+    let agent = ureq::AgentBuilder::new().timeout(TIMEOUT).build();
+    agent.post(url)
+        .set("Content-Type", "application/json")
+        .send_string(&serde_json::to_string(notification)?)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "http"))]
+pub fn post(_url: &str, _notification: &Notification) -> Result<(), Box<dyn std::error::Error>> {
+    Err("rebuild mrfy with the `http` feature to use --notify-url".into())
+}
+
+/// Does post, warning reporter instead of failing the run if the webhook can't be reached.
+pub fn send(url: &str, notification: &Notification, reporter: &SharedReporter) {
+    if let Err(e) = post(url, notification) {
+        reporter.warn(format_args!("failed to notify {}: {}", url, e));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notification() {
+        let summary = RunSummary {
+            stats: crate::asa::RunStats { rows_written: 12, ..Default::default() },
+            ..Default::default()
+        };
+        let notification = Notification::new(EXIT_MATCHES, None, "in-network.json.gz", Duration::from_millis(1500))
+            .with_summary(&summary, &[Path::new("rates.csv")]);
+        let json: serde_json::Value = serde_json::to_value(&notification).unwrap();
+        assert_eq!(json["status"], "ok");
+        assert_eq!(json["duration_secs"], 1.5);
+        assert_eq!(json["rows_written"], 12);
+        assert_eq!(json["outputs"], serde_json::json!(["rates.csv"]));
+        assert_eq!(json["error"], serde_json::Value::Null);
+
+        assert_eq!(status(EXIT_INTERRUPTED), "interrupted");
+        assert_eq!(status(crate::error::EXIT_FATAL), "failed");
+    }
+}
@@ -0,0 +1,191 @@
+//! # nppes
+//!
+//! Reads the NPPES downloadable file (npidata_pfile_*.csv, or gzip compressed) to add the name,
+//! primary taxonomy and practice address of each provider to the output rows.
+//!
+//! The full file has millions of rows and hundreds of columns. Only the columns used here are
+//! kept, and only for the npis of the run, so the lookup stays small.
+//!
+//! npis_with_taxonomy goes the other way, finding the npis of a specialty (--taxonomy) so a query
+//! can select providers without listing them.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, Read};
+
+use flate2::read::MultiGzDecoder;
+
+use crate::query::MatchPolicy;
+
+/// Output columns added from NPPES, after the columns of asa::COLUMNS.
+pub const COLUMNS: [&str; 3] = ["provider_name", "taxonomy", "practice_address"];
+
+/// Number of taxonomy columns (Healthcare Provider Taxonomy Code_1 to _15) of the file.
+const TAXONOMIES: usize = 15;
+
+/// What NPPES has about a provider.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProviderInfo {
+    /// Organization name, or "first last" for individuals
+    pub name: String,
+    /// Primary taxonomy code, or the first one if none is marked primary
+    pub taxonomy: String,
+    /// "line 1, line 2, city, state postal code" of the practice location
+    pub address: String,
+}
+
+/// Provider information by npi.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Nppes {
+    providers: HashMap<u64, ProviderInfo>,
+}
+
+/// Opens the NPPES file at path, gzip compressed if it ends in .gz.
+fn open(path: &std::path::Path) -> Result<csv::Reader<BufReader<Box<dyn Read>>>, std::io::Error> {
+    let file = File::open(path)?;
+    let reader: Box<dyn Read> = match path.extension().is_some_and(|e| e == "gz") {
+        true => Box::new(MultiGzDecoder::new(file)),
+        false => Box::new(file),
+    };
+    Ok(csv::Reader::from_reader(BufReader::new(reader)))
+}
+
+/// Returns the index of the column named name, or an error naming path if there is none.
+fn required(headers: &csv::StringRecord, name: &str, path: &std::path::Path) -> Result<usize, String> {
+    headers.iter().position(|h| h == name).ok_or_else(|| format!("{} has no '{}' column", path.display(), name))
+}
+
+/// Returns the (taxonomy code, primary switch) column pairs of headers.
+fn taxonomy_columns(headers: &csv::StringRecord) -> Vec<(usize, Option<usize>)> {
+    let column = |name: &str| headers.iter().position(|h| h == name);
+    (1..=TAXONOMIES).filter_map(|i| {
+        let code = column(&format!("Healthcare Provider Taxonomy Code_{}", i))?;
+        Some((code, column(&format!("Healthcare Provider Primary Taxonomy Switch_{}", i))))
+    }).collect()
+}
+
+/// Returns the npis of the NPPES file at path with a taxonomy code (primary or not) matching one
+/// of taxonomies. A taxonomy ending in '*' matches every code starting with the rest of it, e.g.
+/// 207X* for orthopaedic surgery and its subspecialties.
+pub fn npis_with_taxonomy(path: &std::path::Path,
+                          taxonomies: &[String]) -> Result<HashSet<u64>, Box<dyn std::error::Error>> {
+    let mut reader = open(path)?;
+    let headers = reader.headers()?.clone();
+    let npi = required(&headers, "NPI", path)?;
+    let columns = taxonomy_columns(&headers);
+    let policy = MatchPolicy::default();
+
+    let mut npis = HashSet::new();
+    for record in reader.records() {
+        let record = record?;
+        let matches = columns.iter()
+            .map(|(code, _)| &record[*code])
+            .any(|code| !code.is_empty() && taxonomies.iter().any(|t| policy.code_matches(t, code)));
+        if matches && let Ok(n) = record[npi].parse::<u64>() {
+            npis.insert(n);
+        }
+    }
+
+    Ok(npis)
+}
+
+impl Nppes {
+    /// Reads the NPPES file at path, keeping only the providers in npis.
+    pub fn read(path: &std::path::Path, npis: &HashSet<u64>) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut reader = open(path)?;
+        let headers = reader.headers()?.clone();
+        let column = |name: &str| headers.iter().position(|h| h == name);
+        let required = |name: &str| required(&headers, name, path);
+
+        let npi = required("NPI")?;
+        let organization = required("Provider Organization Name (Legal Business Name)")?;
+        let last = required("Provider Last Name (Legal Name)")?;
+        let first = required("Provider First Name")?;
+        let address: Vec<usize> = ["Provider First Line Business Practice Location Address",
+                                   "Provider Second Line Business Practice Location Address",
+                                   "Provider Business Practice Location Address City Name"]
+            .iter().filter_map(|name| column(name)).collect();
+        let state = column("Provider Business Practice Location Address State Name");
+        let postal_code = column("Provider Business Practice Location Address Postal Code");
+        let taxonomies = taxonomy_columns(&headers);
+
+        let mut providers = HashMap::new();
+        for record in reader.records() {
+            let record = record?;
+            let Ok(n) = record[npi].parse::<u64>() else {
+                continue;
+            };
+            if !npis.contains(&n) {
+                continue;
+            }
+            let field = |i: Option<usize>| i.and_then(|i| record.get(i)).unwrap_or("");
+
+            let name = match &record[organization] {
+                "" => format!("{} {}", &record[first], &record[last]).trim().to_string(),
+                org => org.to_string(),
+            };
+
+            let codes = taxonomies.iter().filter(|(code, _)| !record[*code].is_empty());
+            let primary = codes.clone().find(|(_, switch)| field(*switch) == "Y");
+            let taxonomy = primary.or(codes.clone().next())
+                .map(|(code, _)| record[*code].to_string())
+                .unwrap_or_default();
+
+            let mut parts: Vec<&str> = address.iter().map(|i| &record[*i]).filter(|p| !p.is_empty()).collect();
+            let region = format!("{} {}", field(state), field(postal_code)).trim().to_string();
+            if !region.is_empty() {
+                parts.push(&region);
+            }
+            let address = parts.join(", ");
+
+            providers.insert(n, ProviderInfo { name, taxonomy, address });
+        }
+
+        Ok(Nppes { providers })
+    }
+
+    /// Returns what NPPES has about npi, if the npi was read.
+    pub fn get(&self, npi: u64) -> Option<&ProviderInfo> {
+        self.providers.get(&npi)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_nppes() {
+        let path = std::path::Path::new("tests/testfiles/nppes/nppes_sample.csv");
+        let nppes = Nppes::read(path, &HashSet::from([1, 4, 99])).unwrap();
+
+        let jane = nppes.get(1).unwrap();
+        assert_eq!(jane.name, "JANE SMITH");
+        assert_eq!(jane.taxonomy, "207R00000X");
+        assert_eq!(jane.address, "1 MAIN ST, SUITE 2, SPRINGFIELD, IL 627010000");
+
+        let acme = nppes.get(4).unwrap();
+        assert_eq!(acme.name, "ACME ORTHOPEDICS, LLC");
+        assert_eq!(acme.address, "40 BONE AVE, PORTLAND, OR 97201");
+
+        // Not asked for, or not in the file
+        assert!(nppes.get(5).is_none());
+        assert!(nppes.get(99).is_none());
+    }
+
+    #[test]
+    fn test_npis_with_taxonomy() {
+        let path = std::path::Path::new("tests/testfiles/nppes/nppes_sample.csv");
+        let taxonomy = |t: &[&str]| {
+            let t: Vec<String> = t.iter().map(|s| s.to_string()).collect();
+            npis_with_taxonomy(path, &t).unwrap()
+        };
+
+        // Not only the primary taxonomy
+        assert_eq!(taxonomy(&["207X00000X"]), HashSet::from([1, 4]));
+        assert_eq!(taxonomy(&["207r00000x"]), HashSet::from([1]));
+        assert_eq!(taxonomy(&["2086*", "207R*"]), HashSet::from([1, 5]));
+        assert!(taxonomy(&["999"]).is_empty());
+    }
+}
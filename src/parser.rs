@@ -0,0 +1,94 @@
+//! # parser
+//!
+//! The JSON parser a run reads the datafile with, selected with `--parser`.
+//!
+//! `events` is json-event-parser, the default. `simd` is the tape scanner of the tape module,
+//! built with the `simd` feature, which finds the end of strings several bytes at a time and
+//! checks their UTF-8 with SIMD instructions; it gives the same events, faster. Both are driven
+//! the same way through JsonParser, so the rest of asa doesn't know which one it reads from.
+
+use std::io::Read;
+
+use json_event_parser::{JsonEvent, JsonParseError, ReaderJsonParser};
+
+/// The parsers mrfy can read a datafile with.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Backend {
+    /// json-event-parser
+    #[default]
+    Events,
+    /// The tape scanner (requires the `simd` feature)
+    Simd,
+}
+
+impl Backend {
+    /// Parses a --parser value, events or simd. Returns None for anything else, and for simd
+    /// in a build without the `simd` feature.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "events" => Some(Backend::Events),
+            "simd" if cfg!(feature = "simd") => Some(Backend::Simd),
+            _ => None,
+        }
+    }
+}
+
+/// Why a parser failed: reading, or JSON that is malformed at offset.
+#[derive(Debug)]
+pub enum ParseFailure {
+    Io(std::io::Error),
+    Syntax { offset: u64, message: String },
+}
+
+/// Anything that gives the events of a JSON document one by one.
+pub trait JsonEvents {
+    /// Returns the next event, JsonEvent::Eof after the last.
+    fn next_json_event(&mut self) -> Result<JsonEvent<'_>, ParseFailure>;
+}
+
+impl<R: Read> JsonEvents for ReaderJsonParser<R> {
+    fn next_json_event(&mut self) -> Result<JsonEvent<'_>, ParseFailure> {
+        self.parse_next().map_err(|e| match e {
+            JsonParseError::Io(e) => ParseFailure::Io(e),
+            JsonParseError::Syntax(e) => ParseFailure::Syntax {
+                offset: e.location().start.offset,
+                message: String::from(e.message()),
+            },
+        })
+    }
+}
+
+/// A parser of either backend.
+pub enum JsonParser<R: Read> {
+    Events(ReaderJsonParser<R>),
+    #[cfg(feature = "simd")]
+    Simd(crate::tape::TapeParser<R>),
+}
+
+impl<R: Read> JsonParser<R> {
+    /// Creates a json-event-parser parser reading from reader.
+    pub fn new(reader: R) -> Self {
+        JsonParser::Events(ReaderJsonParser::new(reader))
+    }
+
+    /// Creates a parser of backend reading from reader.
+    pub fn with_backend(reader: R, backend: Backend) -> Self {
+        match backend {
+            Backend::Events => Self::new(reader),
+            #[cfg(feature = "simd")]
+            Backend::Simd => JsonParser::Simd(crate::tape::TapeParser::new(reader)),
+            #[cfg(not(feature = "simd"))]
+            Backend::Simd => unreachable!("Backend::parse only gives simd with the `simd` feature"),
+        }
+    }
+}
+
+impl<R: Read> JsonEvents for JsonParser<R> {
+    fn next_json_event(&mut self) -> Result<JsonEvent<'_>, ParseFailure> {
+        match self {
+            JsonParser::Events(parser) => parser.next_json_event(),
+            #[cfg(feature = "simd")]
+            JsonParser::Simd(parser) => parser.next_json_event(),
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! # partition
+//!
+//! Writes the rows of a query to one CSV file per value of a column (`--partition-by`) under a
+//! directory, laid out Hive-style for partition-aware readers (Spark, DuckDB, Arrow):
+//! `DIR/billing_code=99213/part-0.csv`. As in Hive, the partition column is in the directory names
+//! and left out of the files, and an empty or null value goes to
+//! `billing_code=__HIVE_DEFAULT_PARTITION__`.
+//!
+//! At most MAX_OPEN files are open at once. Past that they are all closed, and a file written to
+//! again is reopened to append to it (a compressed file then holds several streams, which gzip and
+//! zstd readers read as one).
+
+use std::collections::{HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::asa::SharedReporter;
+use crate::compress::Codec;
+use crate::sink::{MatchedRecord, RecordSink};
+
+/// Files open at once at most.
+const MAX_OPEN: usize = 256;
+
+/// The partition of empty and null values, as Hive names it.
+const DEFAULT_PARTITION: &str = "__HIVE_DEFAULT_PARTITION__";
+
+/// The column rows are partitioned by.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PartitionBy {
+    BillingCode,
+    GroupId,
+}
+
+impl PartitionBy {
+    /// Parses a --partition-by column, billing_code or group_id.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "billing_code" => Some(PartitionBy::BillingCode),
+            "group_id" => Some(PartitionBy::GroupId),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the column (before --header-map).
+    pub fn column(&self) -> &'static str {
+        match self {
+            PartitionBy::BillingCode => "billing_code",
+            PartitionBy::GroupId => "group_id",
+        }
+    }
+}
+
+/// Returns the name of the directory of the partition of value in column, with the characters
+/// Hive escapes written as %XX.
+pub fn dir_name(column: &str, value: &str) -> String {
+    if value.is_empty() || value == "null" {
+        return format!("{}={}", column, DEFAULT_PARTITION);
+    }
+    let mut name = format!("{}=", column);
+    for c in value.chars() {
+        match c {
+            '"' | '#' | '%' | '\'' | '*' | '/' | ':' | '=' | '?' | '\\' | '[' | ']' | '^' | '{' | '}' => {
+                name.push_str(&format!("%{:02X}", c as u32))
+            }
+            c if c.is_control() => name.push_str(&format!("%{:02X}", c as u32)),
+            c => name.push(c),
+        }
+    }
+    name
+}
+
+/// Writes the records to a CSV file for each value of a column.
+pub struct PartitionSink {
+    dir: PathBuf,
+    /// Position of the column in the rows
+    index: usize,
+    /// Name of the column in the directory names, as renamed by the header
+    name: String,
+    codec: Codec,
+    /// The header of the files, without the column
+    header: Option<Vec<String>>,
+    open: HashMap<String, csv::Writer<Box<dyn Write>>>,
+    /// Partitions written to so far
+    written: HashSet<String>,
+    rows: u64,
+    reporter: SharedReporter,
+}
+
+impl PartitionSink {
+    /// Creates a PartitionSink writing under dir, which must be empty or not exist yet, the rows
+    /// partitioned by their value of column at index in columns. The files are compressed with
+    /// codec, if any. What was written goes to reporter.
+    pub fn new(dir: &Path,
+               by: PartitionBy,
+               columns: &[&str],
+               codec: Option<Codec>,
+               reporter: SharedReporter) -> Result<Self, Box<dyn std::error::Error>> {
+        let index = columns.iter().position(|c| *c == by.column())
+            .ok_or_else(|| format!("the rows have no {} column to partition by", by.column()))?;
+        if dir.read_dir().is_ok_and(|mut entries| entries.next().is_some()) {
+            return Err(format!("{} is not empty, partitions are written to a new directory", dir.display()).into());
+        }
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            index,
+            name: by.column().to_string(),
+            codec: codec.unwrap_or(Codec::None),
+            header: None,
+            open: HashMap::new(),
+            written: HashSet::new(),
+            rows: 0,
+            reporter,
+        })
+    }
+
+    /// Returns the name of the file of a partition.
+    fn file_name(&self) -> &'static str {
+        match self.codec {
+            Codec::None => "part-0.csv",
+            Codec::Gzip(_) => "part-0.csv.gz",
+            Codec::Zstd(_) => "part-0.csv.zst",
+        }
+    }
+
+    /// Returns the writer of the partition named partition, opening its file if needed.
+    fn writer(&mut self, partition: &str) -> Result<&mut csv::Writer<Box<dyn Write>>, Box<dyn std::error::Error>> {
+        if !self.open.contains_key(partition) {
+            if self.open.len() >= MAX_OPEN {
+                for (_, mut writer) in self.open.drain() {
+                    writer.flush()?;
+                }
+            }
+            let dir = self.dir.join(partition);
+            let path = dir.join(self.file_name());
+            let first = self.written.insert(partition.to_string());
+            let file = match first {
+                true => {
+                    std::fs::create_dir_all(&dir)?;
+                    std::fs::File::create(&path)?
+                }
+                false => OpenOptions::new().append(true).open(&path)?,
+            };
+            let mut writer = csv::Writer::from_writer(self.codec.wrap(std::io::BufWriter::new(file))?);
+            if first && let Some(header) = &self.header {
+                writer.write_record(header)?;
+            }
+            self.open.insert(partition.to_string(), writer);
+        }
+        Ok(self.open.get_mut(partition).expect("the writer was just opened"))
+    }
+}
+
+impl RecordSink for PartitionSink {
+    fn write_header(&mut self, columns: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(name) = columns.get(self.index) {
+            self.name = name.to_string();
+        }
+        let header = columns.iter().enumerate().filter(|(i, _)| *i != self.index).map(|(_, c)| c.to_string());
+        self.header = Some(header.collect());
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &MatchedRecord) -> Result<(), Box<dyn std::error::Error>> {
+        let value = record.values.get(self.index).copied().unwrap_or_default();
+        let partition = dir_name(&self.name, value);
+        let index = self.index;
+        let writer = self.writer(&partition)?;
+        writer.write_record(record.values.iter().enumerate().filter(|(i, _)| *i != index).map(|(_, v)| v))?;
+        writer.flush()?;
+        self.rows += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        // Dropped to end compressed streams
+        for (_, mut writer) in self.open.drain() {
+            writer.flush()?;
+        }
+        self.reporter.status(format_args!("Wrote {} rows to {} partitions under {}",
+                                          self.rows, self.written.len(), self.dir.display()));
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dir_name() {
+        assert_eq!(dir_name("billing_code", "99213"), "billing_code=99213");
+        assert_eq!(dir_name("group_id", "a/b=c"), "group_id=a%2Fb%3Dc");
+        assert_eq!(dir_name("billing_code", ""), "billing_code=__HIVE_DEFAULT_PARTITION__");
+        assert_eq!(dir_name("billing_code", "null"), "billing_code=__HIVE_DEFAULT_PARTITION__");
+    }
+
+    #[test]
+    fn test_partition_sink() {
+        let dir = std::env::temp_dir().join(format!("mrfy_partition_{}", std::process::id()));
+        let columns = ["npi", "billing_code", "negotiated_rate"];
+        let mut sink = PartitionSink::new(&dir, PartitionBy::BillingCode, &columns, None, SharedReporter::default()).unwrap();
+        sink.write_header(&["npi", "cpt", "negotiated_rate"]).unwrap();
+        for values in [["1", "99213", "10"], ["2", "99214", "20"], ["3", "99213", "30"]] {
+            sink.write_record(&MatchedRecord { values: &values }).unwrap();
+        }
+        sink.finish().unwrap();
+
+        let read = |code: &str| std::fs::read_to_string(dir.join(format!("cpt={}", code)).join("part-0.csv")).unwrap();
+        assert_eq!(read("99213"), "npi,negotiated_rate\n1,10\n3,30\n");
+        assert_eq!(read("99214"), "npi,negotiated_rate\n2,20\n");
+        assert!(PartitionSink::new(&dir, PartitionBy::BillingCode, &columns, None, SharedReporter::default()).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
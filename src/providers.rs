@@ -0,0 +1,137 @@
+//! # providers
+//!
+//! Dumps the provider_references section of a file as CSV, without an NPI query.
+//!
+//! One record is written for each NPI of each provider group, with the provider_group_id of the
+//! provider_references entry and the tin of the group. Key ordering is *not* assumed. Reading
+//! stops at the end of the provider_references array.
+
+use crate::asa::{next_event, unexpected_eof, ParseContext, SharedReporter};
+use crate::source::DataSource;
+
+use std::io::{BufReader, Write};
+
+use json_event_parser::{ReaderJsonParser, JsonEvent};
+
+/// A provider group of a provider_references entry.
+#[derive(Debug, Default)]
+struct Group {
+    npis: Vec<String>,
+    tin_type: Option<String>,
+    tin_value: Option<String>,
+}
+
+/// Reads provider_references from source and writes (group_id, npi, tin_type, tin_value) records
+/// as CSV to out. Returns the number of records written.
+pub fn write_providers(source: &dyn DataSource,
+                       buff_size: usize,
+                       out: impl Write) -> Result<u64, Box<dyn std::error::Error>> {
+    let mut ctx = ParseContext::new();
+    let reader = ctx.track_offsets(BufReader::with_capacity(buff_size, source.open(&SharedReporter::default())?));
+    let mut parser = ReaderJsonParser::new(reader);
+
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record(["group_id", "npi", "tin_type", "tin_value"])?;
+    let mut records: u64 = 0;
+
+    // Depths: 3 provider_references entry, 5 provider group, 6 its npi array and tin object
+    const ENTRY: u64 = 3;
+    const GROUP: u64 = 5;
+    const FIELD: u64 = 6;
+
+    let mut depth: u64 = 0;
+    let mut section = String::new();
+    let mut key = String::new();
+    let mut group_key = String::new();
+
+    // The group id can come after the groups, so they are kept until the entry ends
+    let mut group_id: Option<String> = None;
+    let mut groups: Vec<Group> = Vec::new();
+    let mut group = Group::default();
+
+    loop {
+        let event = {next_event(&mut parser, &mut ctx, "provider_references")?};
+        match event {
+            JsonEvent::StartObject | JsonEvent::StartArray => {
+                depth += 1;
+            }
+            JsonEvent::EndObject | JsonEvent::EndArray => {
+                let in_refs = section == "provider_references";
+                if in_refs && depth == GROUP {
+                    groups.push(std::mem::take(&mut group));
+                }
+                else if in_refs && depth == ENTRY {
+                    let gid = group_id.take().unwrap_or_else(|| String::from("null"));
+                    for g in groups.drain(..) {
+                        let tin_type = g.tin_type.as_deref().unwrap_or("null");
+                        let tin_value = g.tin_value.as_deref().unwrap_or("null");
+                        for npi in g.npis.iter() {
+                            writer.write_record([gid.as_str(), npi, tin_type, tin_value])?;
+                            records += 1;
+                        }
+                    }
+                }
+                depth -= 1;
+                if depth == 0 || (in_refs && depth == 1) {
+                    break;
+                }
+            }
+            JsonEvent::ObjectKey(k) => {
+                if depth == 1 {
+                    section = k.to_string();
+                }
+                else if depth == GROUP {
+                    group_key = k.to_string();
+                }
+                key.clear();
+                key.push_str(k.as_ref());
+            }
+            JsonEvent::String(value) | JsonEvent::Number(value) if section == "provider_references" => {
+                if depth == ENTRY && key == "provider_group_id" {
+                    group_id = Some(value.to_string());
+                }
+                else if depth == FIELD && group_key == "npi" {
+                    group.npis.push(value.to_string());
+                }
+                else if depth == FIELD && group_key == "tin" {
+                    if key == "type" {
+                        group.tin_type = Some(value.to_string());
+                    }
+                    else if key == "value" {
+                        group.tin_value = Some(value.to_string());
+                    }
+                }
+            }
+            JsonEvent::Eof => {
+                return Err(Box::new(unexpected_eof(&ctx, "provider_references")));
+            }
+            _ => {}
+        }
+    }
+    writer.flush()?;
+
+    Ok(records)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::Location;
+
+    #[test]
+    fn test_write_providers() {
+        let source = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz"));
+        let mut out: Vec<u8> = Vec::new();
+        let records = write_providers(&source, 256, &mut out).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "group_id,npi,tin_type,tin_value");
+        assert_eq!(records, 10);
+        assert_eq!(lines.len() as u64, records + 1);
+        assert!(lines.contains(&"3,2,ein,44"));
+        // An entry without provider_group_id
+        assert!(lines.contains(&"null,5,ein,5555"));
+    }
+}
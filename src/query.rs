@@ -3,12 +3,18 @@
 //! Handles parsing user input file.
 //! Converts input to a Query struct which contains a Vec of Code structs and a Vec of Provider
 //! structs.
+//!
+//! Besides the indentation based text format, a query can be written in TOML (`.toml`), JSON
+//! (`.json`) or YAML (`.yaml`, `.yml`), see QueryFile for its layout, or as CSV (`.csv`), see
+//! read_csv.
+
+use crate::asa::SharedReporter;
+use crate::error::QueryError;
 
 use anyhow::{Context, Result};
-use std::io::BufReader;
-use std::io::BufRead;
+use serde::{Deserialize, Serialize};
 use std::fs::File;
-use std::collections::{HashSet, HashMap};
+use std::collections::{BTreeMap, HashSet, HashMap};
 
 
 
@@ -19,23 +25,38 @@ pub struct Code {
     pub value:     String,
     pub seen:      bool,
     pub recorded:  bool,
+    /// billing_code_type_version the code is pinned to, if any
+    pub version:   Option<String>,
+    /// Number of matching items under another billing_code_type_version
+    pub version_mismatches: u64,
+    /// Number of records written for the code
+    pub records:   u64,
 }
 impl Code {
     /// Creates a new Code struct with cloned code type and code value stored in its fields. 
-    /// Sets boolean fields to false. 
+    /// Sets boolean fields to false and leaves the version unpinned.
     pub fn new(c_type: &String, c_value: &String) -> Self {
         Self {
             code_type: c_type.clone(), 
             value:     c_value.clone(),
             seen:      false,
             recorded:  false,
+            version:   None,
+            version_mismatches: 0,
+            records:   0,
         }
     }
 
-    /// Prints basic information about code to stderr. Used to warn about codes
+    /// Returns true if the code (a pattern, see MatchPolicy::code_matches) matches code c of type c_type.
+    /// If code type is '*' will match on all code types. Comparisons follow policy.
+    pub fn matches(&self, c_type: &str, c: &str, policy: MatchPolicy) -> bool {
+        policy.code_matches(&self.value, c) && policy.type_matches(&self.code_type, c_type)
+    }
+
+    /// Hands basic information about code to reporter. Used to warn about codes
     /// that didn't have matches in datafile.
-    pub fn eprint_no_match(&self) {
-        eprintln!("\nWARNING: No match found for Code\n  Type: {}   Value: {}", self.code_type, self.value);
+    pub fn warn_no_match(&self, reporter: &SharedReporter) {
+        reporter.warn(format_args!("No match found for Code\n  Type: {}   Value: {}", self.code_type, self.value));
     }
 }
 
@@ -50,10 +71,14 @@ pub struct Provider {
     pub needs_tin: bool,
     pub needs_gid: bool,
     pub recorded:  bool,
+    /// False for providers that were not listed by npi but found through a selected group
+    pub queried:   bool,
+    /// Number of records written for the provider
+    pub records:   u64,
 }
 impl Provider {
     /// Creates a new Provider struct with the given npi and all Option values None and all boolean
-    /// values false.
+    /// values false, except queried.
     pub fn new(npi_val: u64) -> Self {
         Self {
             npi: npi_val,
@@ -63,57 +88,636 @@ impl Provider {
             needs_tin: false,
             needs_gid: false,
             recorded:  false,
+            queried:   true,
+            records:   0,
         }
     }
 
     /// Used to warn user that there were no matches for the given npi, group id, tin type, tin
-    /// value. Does so by printing warning to standard error.
-    pub fn eprint_no_match(&self) {
-        eprintln!("\nWARNING: \
-        No match found for Provider\n  npi: {}\n  group_id: {:?}\n  tin_type: {:?}\n  tin_value: {:?}\n", 
-        self.npi, self.group_id, self.tin_type, self.tin_value);
+    /// value. Does so by handing the warning to reporter.
+    pub fn warn_no_match(&self, reporter: &SharedReporter) {
+        reporter.warn(format_args!("\
+        No match found for Provider\n  npi: {}\n  group_id: {:?}\n  tin_type: {:?}\n  tin_value: {:?}", 
+        self.npi, self.group_id, self.tin_type, self.tin_value));
+    }
+}
+
+/// Returns code trimmed and in upper case, with the leading zeros of an all digit code removed
+/// (a DRG of "023" and "23", or a revenue code of "0450" and "450", are the same code).
+pub fn normalize_code(code: &str) -> String {
+    let code = code.trim();
+    if !code.is_empty() && code.bytes().all(|b| b.is_ascii_digit()) {
+        let stripped = code.trim_start_matches('0');
+        return String::from(if stripped.is_empty() { "0" } else { stripped });
+    }
+    code.to_ascii_uppercase()
+}
+
+/// Returns code_type trimmed and in upper case, with common aliases mapped to one name
+/// (e.g. "HCPCS Level II" to "HCPCS" and "CPT-4" to "CPT").
+pub fn normalize_code_type(code_type: &str) -> String {
+    let words: Vec<String> = code_type
+        .split(|c: char| c.is_whitespace() || c == '-' || c == '_')
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_ascii_uppercase())
+        .collect();
+    let joined = words.join(" ");
+    let name = match joined.as_str() {
+        "HCPCS LEVEL II" | "HCPCS LEVEL 2" | "HCPCS II" | "HCPCS 2" | "LEVEL II HCPCS" => "HCPCS",
+        "CPT 4" | "CPT4" | "HCPCS LEVEL I" | "HCPCS LEVEL 1" => "CPT",
+        "MS DRG" | "MSDRG" => "MS-DRG",
+        "APR DRG" | "APRDRG" => "APR-DRG",
+        "REVENUE CODE" | "REV" => "RC",
+        _ => return code_type.trim().to_ascii_uppercase(),
+    };
+    String::from(name)
+}
+
+/// How the codes of a query are compared with the codes of a file. The default ignores ascii
+/// case and reads '*' and '?' as wildcards.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MatchPolicy {
+    /// Compare code values and code types exactly, for code systems where case matters
+    pub case_sensitive: bool,
+    /// Compare '*' and '?' literally, in code values and in a '*' code type
+    pub literal: bool,
+}
+
+impl MatchPolicy {
+    /// Returns true if code matches the code value pattern of a query.
+    /// A pattern ending in '*' matches every code starting with the rest of it ('*' alone matches
+    /// every code), and '?' matches any one character, unless the policy is literal.
+    pub fn code_matches(&self, pattern: &str, code: &str) -> bool {
+        let same_byte = |p: &u8, c: &u8| {
+            if self.case_sensitive { p == c } else { p.eq_ignore_ascii_case(c) }
+        };
+        if self.literal {
+            return pattern.len() == code.len() && pattern.as_bytes().iter().zip(code.as_bytes()).all(|(p, c)| same_byte(p, c));
+        }
+        let same = |pattern: &[u8], code: &[u8]| {
+            pattern.len() == code.len() &&
+            pattern.iter().zip(code).all(|(p, c)| *p == b'?' || same_byte(p, c))
+        };
+
+        match pattern.strip_suffix('*') {
+            Some(prefix) => code.len() >= prefix.len() && same(prefix.as_bytes(), &code.as_bytes()[..prefix.len()]),
+            None => same(pattern.as_bytes(), code.as_bytes()),
+        }
+    }
+
+    /// Returns true if code type c_type matches the code type of a query. A '*' code type
+    /// matches every code type, unless the policy is literal.
+    pub fn type_matches(&self, query_type: &str, c_type: &str) -> bool {
+        (query_type == "*" && !self.literal) ||
+        if self.case_sensitive { query_type == c_type } else { query_type.eq_ignore_ascii_case(c_type) }
+    }
+}
+
+/// Returns true if npi is 10 digits long and its last digit is the Luhn check digit of the
+/// others, prefixed with 80840 as the NPI standard says.
+pub fn is_valid_npi(npi: u64) -> bool {
+    if !(1_000_000_000..10_000_000_000).contains(&npi) {
+        return false;
+    }
+    let check = npi % 10;
+    // The 80840 prefix always adds 24 to the sum
+    let mut sum = 24;
+    let mut rest = npi / 10;
+    let mut double = true;
+    while rest > 0 {
+        let mut digit = rest % 10;
+        if double {
+            digit *= 2;
+            if digit > 9 {
+                digit -= 9;
+            }
+        }
+        sum += digit;
+        double = !double;
+        rest /= 10;
     }
+    (10 - sum % 10) % 10 == check
+}
+
+/// Matches billing codes against the code values of a query without trying every value.
+/// Plain values are looked up in a HashSet and prefixes ('0TJ*') by the length of each prefix,
+/// only values containing '?' are compared one at a time.
+#[derive(Clone, Debug, Default)]
+pub struct CodeMatcher {
+    policy: MatchPolicy,
+    any: bool,
+    exact: HashSet<String>,
+    prefixes: HashSet<String>,
+    prefix_lens: Vec<usize>,
+    patterns: Vec<String>,
 }
 
+impl CodeMatcher {
+    /// Creates a CodeMatcher of the code value patterns in values, converted to ascii uppercase
+    /// unless the policy is case sensitive.
+    pub fn new<'a>(values: impl Iterator<Item = &'a str>, policy: MatchPolicy) -> Self {
+        let mut matcher = Self { policy, ..Self::default() };
+        for value in values {
+            let value = if policy.case_sensitive { value.to_string() } else { value.to_ascii_uppercase() };
+            if policy.literal {
+                matcher.exact.insert(value);
+            }
+            else if value == "*" {
+                matcher.any = true;
+            }
+            else if value.contains('?') {
+                matcher.patterns.push(value);
+            }
+            else if let Some(prefix) = value.strip_suffix('*') {
+                if !matcher.prefix_lens.contains(&prefix.len()) {
+                    matcher.prefix_lens.push(prefix.len());
+                }
+                matcher.prefixes.insert(prefix.to_string());
+            }
+            else {
+                matcher.exact.insert(value);
+            }
+        }
+
+        matcher
+    }
+
+    /// Creates a CodeMatcher matching every code.
+    pub fn any() -> Self {
+        Self { any: true, ..Self::default() }
+    }
+
+    /// Returns true if code, in ascii uppercase unless the policy is case sensitive, matches any
+    /// of the values.
+    pub fn matches(&self, code: &str) -> bool {
+        self.any ||
+        self.exact.contains(code) ||
+        self.prefix_lens.iter().any(|&len| code.get(..len).is_some_and(|p| self.prefixes.contains(p))) ||
+        self.patterns.iter().any(|p| self.policy.code_matches(p, code))
+    }
+}
+
+/// Tracks which providers each code of a query still lacks a record for, so a run can stop
+/// reading in_network once every code has been recorded for every provider
+/// (--stop-when-complete). Only the providers found in provider_references are counted.
+#[derive(Clone, Debug)]
+pub struct Completion {
+    policy: MatchPolicy,
+    /// (code type, code, npis without a record yet) for each code of the query
+    codes: Vec<(String, String, HashSet<u64>)>,
+    /// Number of codes recorded for every npi
+    done: usize,
+}
+
+impl Completion {
+    /// Creates the Completion of query, once provider_references is read. Returns None for a
+    /// query that can't be known to be complete: one with '*' or '?' code patterns, selecting
+    /// every provider, selecting items by name only, or without any provider in the file.
+    pub fn new(query: &Query) -> Option<Self> {
+        let pattern = |value: &str| !query.match_policy.literal && value.contains(['*', '?']);
+        if query.all_providers || query.codes.is_empty() || query.codes.iter().any(|c| pattern(&c.value)) {
+            return None;
+        }
+        let npis: HashSet<u64> = query.providers.iter()
+            .filter(|p| p.group_id.is_some())
+            .map(|p| p.npi)
+            .collect();
+        if npis.is_empty() {
+            return None;
+        }
+        let codes = query.codes.iter()
+            .map(|c| (c.code_type.clone(), c.value.clone(), npis.clone()))
+            .collect();
+        Some(Self { policy: query.match_policy, codes, done: 0 })
+    }
+
+    /// Records that the item with code c of type c_type has records for npis.
+    pub fn record(&mut self, c_type: &str, c: &str, npis: impl Iterator<Item = u64> + Clone) {
+        for (code_type, value, left) in self.codes.iter_mut() {
+            if left.is_empty() || !self.policy.code_matches(value, c) || !self.policy.type_matches(code_type, c_type) {
+                continue;
+            }
+            for npi in npis.clone() {
+                left.remove(&npi);
+            }
+            if left.is_empty() {
+                self.done += 1;
+            }
+        }
+    }
+
+    /// Returns true once every code has been recorded for every npi.
+    pub fn is_complete(&self) -> bool {
+        self.done == self.codes.len()
+    }
+}
+
+/// Machine readable summary of which parts of a query matched, written by --report.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct QueryReport {
+    pub codes: Vec<CodeReport>,
+    pub npis: Vec<NpiReport>,
+    pub group_ids: Vec<SelectionReport>,
+    pub tins: Vec<SelectionReport>,
+}
+
+/// A code of the query and the number of records written for it.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct CodeReport {
+    pub code_type: String,
+    pub code: String,
+    pub version: Option<String>,
+    pub matched: bool,
+    pub records: u64,
+    pub version_mismatches: u64,
+}
+
+/// An npi of the query with the provider groups it matched under.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct NpiReport {
+    pub npi: u64,
+    pub matched: bool,
+    pub records: u64,
+    pub groups: Vec<GroupReport>,
+}
+
+/// A provider group an npi was found in.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct GroupReport {
+    pub group_id: String,
+    pub tin_type: Option<String>,
+    pub tin_value: Option<String>,
+    pub records: u64,
+}
+
+/// A group id or tin of the query.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct SelectionReport {
+    pub value: String,
+    pub matched: bool,
+    pub records: u64,
+}
+
+/// Matches the name or description of an in_network item.
+#[derive(Clone, Debug)]
+pub enum NameFilter {
+    /// Matches text containing the keyword, ignoring case. Stored in lowercase.
+    Keyword(String),
+    Regex(regex::Regex),
+}
+
+impl NameFilter {
+    /// Returns true if text matches the filter.
+    pub fn matches(&self, text: &str) -> bool {
+        match self {
+            NameFilter::Keyword(keyword) => text.to_lowercase().contains(keyword.as_str()),
+            NameFilter::Regex(re) => re.is_match(text),
+        }
+    }
+}
+
+impl PartialEq for NameFilter {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (NameFilter::Keyword(a), NameFilter::Keyword(b)) => a == b,
+            (NameFilter::Regex(a), NameFilter::Regex(b)) => a.as_str() == b.as_str(),
+            _ => false,
+        }
+    }
+}
+
+/// A provider of a provider group as found in the file.
+pub type Member = (u64, String, String); // (npi, tin_type, tin_value)
+
 /// Holds Vectors of Provider and Code structs to represent the user query.
+/// Every provider of a group in group_ids, and every provider with a tin value in tins, is
+/// selected without listing its npis. With all_providers ('*' in an npi section) every provider
+/// in the file is.
+/// Providers in excluded_npis are never selected that way, and items matching excluded_codes are
+/// not written. With selected_npis (see select_npis) only the providers it holds are selected. With name_filters, only items whose name or description matches one are written.
+/// billing_classes, min_rate and max_rate narrow the negotiated_prices kept, like the price
+/// filters of asa::Options. capture_extra and raw_out add to the output options of a run.
 #[derive(Clone, Debug, PartialEq)]
 pub struct Query {
     pub providers: Vec<Provider>,
     pub codes    : Vec<Code>,
+    pub group_ids: Vec<String>,
+    pub tins     : Vec<String>,
+    pub all_providers: bool,
+    pub excluded_npis: Vec<u64>,
+    pub selected_npis: Option<HashSet<u64>>,
+    pub excluded_codes: Vec<Code>,
+    pub name_filters: Vec<NameFilter>,
+    pub billing_classes: Vec<String>,
+    pub min_rate: Option<f64>,
+    pub max_rate: Option<f64>,
+    pub capture_extra: bool,
+    pub raw_out: Option<std::path::PathBuf>,
+    /// How codes are compared with the codes of the file
+    pub match_policy: MatchPolicy,
+    /// Indices into providers by npi and by group id, see index_providers
+    npi_index: HashMap<u64, Vec<usize>>,
+    gid_index: HashMap<String, Vec<usize>>,
+    /// Indices of the providers flagged as members of the provider group being read, waiting
+    /// for its tin and for its provider_group_id
+    awaiting_tin: Vec<usize>,
+    awaiting_gid: Vec<usize>,
 }
+
+impl Default for Query {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Query {
-    /// Creates a new Query struct with codes and providers set to empty Vectors. 
+    /// Creates a new Query struct with all Vectors empty and all_providers false.
     pub fn new() -> Self {
         Self { 
             codes: Vec::new(),
             providers: Vec::new(),
+            group_ids: Vec::new(),
+            tins: Vec::new(),
+            all_providers: false,
+            excluded_npis: Vec::new(),
+            selected_npis: None,
+            excluded_codes: Vec::new(),
+            name_filters: Vec::new(),
+            billing_classes: Vec::new(),
+            min_rate: None,
+            max_rate: None,
+            capture_extra: false,
+            raw_out: None,
+            match_policy: MatchPolicy::default(),
+            npi_index: HashMap::new(),
+            gid_index: HashMap::new(),
+            awaiting_tin: Vec::new(),
+            awaiting_gid: Vec::new(),
         }
     }
 
-    /// Creates a HashSet of all NPIs in self.providers
-    pub fn make_npi_set(&mut self) -> HashSet<u64> {
-        let mut npi_set = HashSet::new();
-        for p in self.providers.iter() {
-            npi_set.insert(p.npi);
+    /// Rebuilds the npi and group id indices of providers. Providers added afterwards through
+    /// push_provider, and group ids set through assign_group_id, keep them up to date.
+    /// Purpose is to keep provider lookups constant time for queries with very many npis.
+    pub fn index_providers(&mut self) {
+        self.npi_index.clear();
+        self.gid_index.clear();
+        for i in 0..self.providers.len() {
+            self.index_provider(i);
         }
+    }
 
-        npi_set
+    fn index_provider(&mut self, i: usize) {
+        let p = &self.providers[i];
+        self.npi_index.entry(p.npi).or_default().push(i);
+        if let Some(gid) = &p.group_id {
+            self.gid_index.entry(gid.clone()).or_default().push(i);
+        }
     }
 
-    /// Creates a HashSet of all the codes the user specified (independent of code_type).
-    /// NOTE:Converts the codes to ascii uppercase
-    ///      ASSERTION: The codes will all be valid in ascii
-    /// Returns a HashSet of codes from codes Vec where the code is converted to ASCII uppercase
-    /// Purpose is to expedite processing of in_network objects.
-    pub fn make_code_set(&mut self) -> HashSet<String> {
-        let mut codeset = HashSet::new();
-        for c in self.codes.iter() {
-            let code = c.value.clone().to_ascii_uppercase();
-            codeset.insert(code);
+    /// Adds p to providers and to the indices. Returns its index.
+    pub fn push_provider(&mut self, p: Provider) -> usize {
+        self.providers.push(p);
+        let i = self.providers.len() - 1;
+        self.index_provider(i);
+        i
+    }
+
+    /// Returns true if the query has a Provider for npi (see index_providers).
+    pub fn has_npi(&self, npi: u64) -> bool {
+        self.npi_index.contains_key(&npi)
+    }
+
+    /// Flags the Provider of npi as a member of the provider group being read: the last one added
+    /// if it has no group id and tin yet, else a new Provider for this group.
+    pub fn flag_member(&mut self, npi: u64) {
+        let last = self.npi_index.get(&npi).and_then(|ids| ids.last().copied());
+        let i = match last {
+            Some(i) if self.providers[i].group_id.is_none() && self.providers[i].tin_value.is_none() => i,
+            Some(_) => self.push_provider(Provider::new(npi)),
+            None => return,
+        };
+        let p = &mut self.providers[i];
+        if !p.needs_tin {
+            self.awaiting_tin.push(i);
+        }
+        if !p.needs_gid {
+            self.awaiting_gid.push(i);
+        }
+        p.needs_tin = true;
+        p.needs_gid = true;
+    }
+
+    /// Sets the tin of the Providers flagged by flag_member that are waiting for one.
+    pub fn assign_tin(&mut self, tin_type: &str, tin_value: &str) {
+        for i in self.awaiting_tin.drain(..) {
+            let p = &mut self.providers[i];
+            p.tin_type = Some(tin_type.to_string());
+            p.tin_value = Some(tin_value.to_string());
+            p.needs_tin = false;
+        }
+    }
+
+    /// Sets gid as the group id of the Providers flagged by flag_member, or only clears their
+    /// flag if there is no gid. Returns true if a group id was set.
+    pub fn assign_group_id(&mut self, gid: Option<&str>) -> bool {
+        let flagged = std::mem::take(&mut self.awaiting_gid);
+        for &i in flagged.iter() {
+            self.providers[i].needs_gid = false;
+            if let Some(gid) = gid {
+                self.providers[i].group_id = Some(gid.to_string());
+                self.gid_index.entry(gid.to_string()).or_default().push(i);
+            }
+        }
+        gid.is_some() && !flagged.is_empty()
+    }
+
+    /// Returns the npis of the query (listed or excluded) that fail the NPI check digit.
+    pub fn invalid_npis(&self) -> Vec<u64> {
+        let listed = self.providers.iter().filter(|p| p.queried).map(|p| p.npi);
+        let mut invalid: Vec<u64> = listed.chain(self.excluded_npis.iter().copied())
+            .filter(|npi| !is_valid_npi(*npi))
+            .collect();
+        invalid.dedup();
+        invalid
+    }
+
+    /// Merges other into this query. Codes (same type, value and version, ignoring ascii case),
+    /// npis, group ids and tins already in the query are not added again.
+    pub fn merge(&mut self, other: Query) {
+        fn push_new<T: PartialEq>(into: &mut Vec<T>, items: Vec<T>) {
+            for item in items {
+                if !into.contains(&item) {
+                    into.push(item);
+                }
+            }
+        }
+        fn same_code(a: &Code, b: &Code) -> bool {
+            a.code_type.eq_ignore_ascii_case(&b.code_type) &&
+            a.value.eq_ignore_ascii_case(&b.value) &&
+            a.version == b.version
+        }
+
+        for p in other.providers {
+            if !self.providers.iter().any(|known| known.npi == p.npi) {
+                self.providers.push(p);
+            }
+        }
+        for c in other.codes {
+            if !self.codes.iter().any(|known| same_code(known, &c)) {
+                self.codes.push(c);
+            }
+        }
+        for c in other.excluded_codes {
+            if !self.excluded_codes.iter().any(|known| same_code(known, &c)) {
+                self.excluded_codes.push(c);
+            }
+        }
+        push_new(&mut self.group_ids, other.group_ids);
+        push_new(&mut self.tins, other.tins);
+        push_new(&mut self.excluded_npis, other.excluded_npis);
+        push_new(&mut self.name_filters, other.name_filters);
+        push_new(&mut self.billing_classes, other.billing_classes);
+        self.all_providers |= other.all_providers;
+        self.min_rate = self.min_rate.or(other.min_rate);
+        self.max_rate = self.max_rate.or(other.max_rate);
+        self.capture_extra |= other.capture_extra;
+        self.raw_out = self.raw_out.take().or(other.raw_out);
+        self.selected_npis = self.selected_npis.take().or(other.selected_npis);
+    }
+
+    /// Narrows the providers of the query to npis (e.g. the npis of a taxonomy, see
+    /// nppes::npis_with_taxonomy). Listed providers outside npis are dropped and group members
+    /// outside npis are not added. A query without providers, groups or tins selects every
+    /// provider in npis. Returns the number of listed providers dropped.
+    pub fn select_npis(&mut self, npis: HashSet<u64>) -> usize {
+        if self.providers.is_empty() && !self.selects_groups() {
+            self.all_providers = true;
+        }
+        let listed = self.providers.len();
+        self.providers.retain(|p| npis.contains(&p.npi));
+        self.selected_npis = Some(npis);
+        listed - self.providers.len()
+    }
+
+    /// Normalizes the types and values of the codes and excluded codes of the query, see
+    /// normalize_code_type and normalize_code. Patterns keep their '*' and '?'.
+    pub fn normalize_codes(&mut self) {
+        for code in self.codes.iter_mut().chain(self.excluded_codes.iter_mut()) {
+            if code.code_type != "*" {
+                code.code_type = normalize_code_type(&code.code_type);
+            }
+            code.value = normalize_code(&code.value);
+        }
+    }
 
+    /// Adds npis ("*" for every provider) and (code_type, code) pairs given outside a query file,
+    /// e.g. on the command line.
+    pub fn add_inline(&mut self,
+                      npis: &[String],
+                      codes: &[(String, String)]) -> Result<(), Box<dyn std::error::Error>> {
+        for npi in npis {
+            if npi == "*" {
+                self.all_providers = true;
+                continue;
+            }
+            let npi_val: u64 = npi.trim().parse().map_err(|_| format!("'{}' is not a valid npi", npi))?;
+            self.providers.push(Provider::new(npi_val));
+        }
+        for (c_type, c_value) in codes {
+            self.codes.push(Code::new(c_type, c_value));
         }
+        Ok(())
+    }
 
-        codeset
+    /// Returns true if there are no name filters, or if name or description matches one of them.
+    pub fn matches_name(&self, name: &str, description: &str) -> bool {
+        self.name_filters.is_empty() ||
+        self.name_filters.iter().any(|f| f.matches(name) || f.matches(description))
+    }
+
+    /// Returns true if an item with code c of type c_type under billing_code_type_version version
+    /// is in a version the query accepts: a matching code is not pinned to a version, or is
+    /// pinned to this one. Otherwise counts a mismatch on the pinned codes and returns false.
+    pub fn check_version(&mut self, c_type: &str, c: &str, version: &str) -> bool {
+        let policy = self.match_policy;
+        let matching = || self.codes.iter().filter(|code| code.matches(c_type, c, policy));
+        let accepted = matching().any(|code| match &code.version {
+            Some(v) => v.eq_ignore_ascii_case(version),
+            None => true,
+        });
+        if accepted || matching().next().is_none() {
+            return true;
+        }
+
+        for code in self.codes.iter_mut().filter(|code| code.matches(c_type, c, policy)) {
+            code.version_mismatches += 1;
+        }
+        false
+    }
+
+    /// Returns true if the code of type c_type is excluded by the query.
+    /// An excluded code matches as a pattern (see MatchPolicy::code_matches), of the same type unless its type
+    /// is '*'. A '*' code excludes every code of its type.
+    pub fn excludes_code(&self, c_type: &str, c: &str) -> bool {
+        self.excluded_codes.iter().any(|code| code.matches(c_type, c, self.match_policy))
+    }
+
+    /// Returns true if providers can be selected by group, tin or wildcard, and so every member
+    /// of a group must be collected while reading provider_references.
+    pub fn selects_groups(&self) -> bool {
+        self.all_providers || !self.group_ids.is_empty() || !self.tins.is_empty()
+    }
+
+    /// Adds a Provider for each (npi, tin_type, tin_value) in members of group gid that the query
+    /// selects, by the group id, by the tin value or by wildcard. Members already resolved from
+    /// an npi in the query are not added twice. Returns true if any member is selected.
+    pub fn add_group_members(&mut self,
+                             gid: &str,
+                             members: impl Iterator<Item = Member>) -> bool {
+        let group_selected = self.all_providers || self.group_ids.iter().any(|id| id == gid);
+        let mut selected = false;
+
+        for (npi, tin_type, tin_value) in members {
+            if !group_selected && !self.tins.contains(&tin_value) {
+                continue;
+            }
+            if self.excluded_npis.contains(&npi) ||
+               self.selected_npis.as_ref().is_some_and(|npis| !npis.contains(&npi)) {
+                continue;
+            }
+            selected = true;
+
+            let providers = &self.providers;
+            let known = self.npi_index.get(&npi).into_iter().flatten().map(|&i| &providers[i]).any(|p| {
+                p.group_id.as_deref() == Some(gid) &&
+                p.tin_type.as_ref() == Some(&tin_type) &&
+                p.tin_value.as_ref() == Some(&tin_value)
+            });
+            if known {
+                continue;
+            }
+            let mut p = Provider::new(npi);
+            p.group_id = Some(gid.to_string());
+            p.tin_type = Some(tin_type);
+            p.tin_value = Some(tin_value);
+            p.queried = false;
+            self.push_provider(p);
+        }
+
+        selected
+    }
+
+    /// Creates a CodeMatcher of all the codes the user specified (independent of code_type).
+    /// A query with name filters but no codes matches every code, the filters select the items.
+    /// NOTE:Converts the codes to ascii uppercase, unless the match policy is case sensitive
+    ///      ASSERTION: The codes will all be valid in ascii
+    /// Purpose is to expedite processing of in_network objects.
+    pub fn make_code_matcher(&mut self) -> CodeMatcher {
+        if self.codes.is_empty() && !self.name_filters.is_empty() {
+            return CodeMatcher::any();
+        }
+        CodeMatcher::new(self.codes.iter().map(|c| c.value.as_str()), self.match_policy)
     }
 
     // Returns a HashMap with
@@ -149,25 +753,26 @@ impl Query {
         ref_map
     }
 
-    /// Sets recorded to true for all Provider stucts with matching gid in Query.providers
+    /// Sets recorded to true for all Provider stucts with matching gid in Query.providers, and
+    /// adds records to their count of records written.
     /// Intended to be used to track parts of query that had a match in the dataset. 
-    pub fn log_ref(&mut self, gid: &String) {
+    /// Providers are found through the group id index (see index_providers).
+    pub fn log_ref(&mut self, gid: &str, records: u64) {
 
-        for provider in self.providers.iter_mut() {
-            match &provider.group_id {
-                Some(id) if *id == *gid => {
-                    provider.recorded = true;
-                }
-                _ => {}
+        if let Some(ids) = self.gid_index.get(gid) {
+            for &i in ids.iter() {
+                self.providers[i].recorded = true;
+                self.providers[i].records += records;
             }
         }
 
     }
 
-    /// Used to log the codes from our query that had a record in the data.
+    /// Used to log the codes from our query that had a record in the data, records being the
+    /// number of records written for it.
     /// If code type is '*' will match on all code types.
     /// Matches are done assuming code and code type are ascii.
-    pub fn log_code(&mut self, c: &String, c_type: &String) {
+    pub fn log_code(&mut self, c: &str, c_type: &str, records: u64) {
 
         for code in self.codes.iter_mut() {
             match code {
@@ -180,17 +785,12 @@ impl Query {
                         code.recorded = true;
                     }
                     */
-                    if value.eq_ignore_ascii_case(c) && code_type.eq_ignore_ascii_case(c_type) {
-                        code.recorded = true;
-                    }
-                    else if value.eq_ignore_ascii_case(c) && code_type == "*" {
-                        code.recorded = true;
-                    }
-                    else if value == "*" && code_type == "*" {
-                        code.recorded = true;
-                    }
-                    else if value == "*" && code_type.eq_ignore_ascii_case(c_type) {
+                    // A '*' value is a pattern matching every code, unless the policy is literal
+                    if self.match_policy.code_matches(value, c) &&
+                       self.match_policy.type_matches(code_type, c_type)
+                    {
                         code.recorded = true;
+                        code.records += records;
                     }
                 }
             }
@@ -201,13 +801,15 @@ impl Query {
     /// Warns the users if any of the Provider structs in self.proviers has recorded set to false.
     /// Also warns the user if any npi in the input query had absolutely no matches in the dataset.
     /// Warns the user if any of the codes in self.codes has no matches in the data set. 
-    pub fn warn_not_recorded(&mut self) {
+    /// The warnings go to reporter.
+    pub fn warn_not_recorded(&mut self, reporter: &SharedReporter) {
         // Warn about npi, g_id, tin information that didn't have matches.
         // Make HashMap to track if an npi has absolutely no matches and warn about that as well.
         let occur_idx = 0;
         let recor_idx = 1;
         let mut npi_map = HashMap::new();
-        for p in self.providers.iter() {
+        // Providers found through a group are covered by the group warning below
+        for p in self.providers.iter().filter(|p| p.queried) {
             if !npi_map.contains_key(&p.npi) {
                 let mut v = Vec::new();
                 v.push(1); // Occurences at idx 0
@@ -221,7 +823,7 @@ impl Query {
                     v[recor_idx] += 1;
                 }
                 else {
-                    p.eprint_no_match();
+                    p.warn_no_match(reporter);
                 }
             }
         }
@@ -229,17 +831,138 @@ impl Query {
         for npi in npi_map.keys() {
             if let Some(v) = npi_map.get(&npi) {
                 if v[recor_idx] == 0 {
-                    eprintln!("WARNING: Zero matches found for npi: {}", &npi);
+                    reporter.warn(format_args!("Zero matches found for npi: {}", &npi));
                 }
             }
         }
 
+        // Warn about group ids without matches.
+        for gid in self.group_ids.iter() {
+            let recorded = self.providers.iter()
+                .any(|p| p.recorded && p.group_id.as_ref() == Some(gid));
+            if !recorded {
+                reporter.warn(format_args!("Zero matches found for group_id: {}", gid));
+            }
+        }
+
+        // Warn about tins without matches.
+        for tin in self.tins.iter() {
+            let recorded = self.providers.iter()
+                .any(|p| p.recorded && p.tin_value.as_ref() == Some(tin));
+            if !recorded {
+                reporter.warn(format_args!("Zero matches found for tin: {}", tin));
+            }
+        }
+
         // Warn about codes without matches.
         for c in self.codes.iter() {
             if !c.recorded {
-                c.eprint_no_match();
+                c.warn_no_match(reporter);
             }
         }
+
+        // Warn about items found under another version than the one asked for.
+        for c in self.codes.iter().filter(|c| c.version_mismatches > 0) {
+            reporter.warn(format_args!("{} items for Code Type: {} Value: {} had a billing_code_type_version other than {}",
+                                       c.version_mismatches, c.code_type, c.value, c.version.as_deref().unwrap_or("null")));
+        }
+    }
+
+    /// Returns a summary of what matched, for write_report. Call after a run.
+    pub fn report(&self) -> QueryReport {
+        let codes = self.codes.iter().map(|c| CodeReport {
+            code_type: c.code_type.clone(),
+            code: c.value.clone(),
+            version: c.version.clone(),
+            matched: c.recorded,
+            records: c.records,
+            version_mismatches: c.version_mismatches,
+        }).collect();
+
+        // Every Provider of a queried npi, in query order
+        let mut npis: Vec<NpiReport> = Vec::new();
+        let mut npi_pos: HashMap<u64, usize> = HashMap::new();
+        for p in self.providers.iter().filter(|p| p.queried) {
+            npi_pos.entry(p.npi).or_insert_with(|| {
+                npis.push(NpiReport { npi: p.npi, matched: false, records: 0, groups: Vec::new() });
+                npis.len() - 1
+            });
+        }
+        for p in self.providers.iter() {
+            let Some(&pos) = npi_pos.get(&p.npi) else { continue };
+            let npi = &mut npis[pos];
+            npi.matched |= p.recorded;
+            npi.records += p.records;
+            if let Some(group_id) = &p.group_id {
+                npi.groups.push(GroupReport {
+                    group_id: group_id.clone(),
+                    tin_type: p.tin_type.clone(),
+                    tin_value: p.tin_value.clone(),
+                    records: p.records,
+                });
+            }
+        }
+
+        let selected = |hit: &dyn Fn(&Provider) -> bool| {
+            self.providers.iter().filter(|p| hit(p)).fold((false, 0), |(matched, records), p| {
+                (matched || p.recorded, records + p.records)
+            })
+        };
+        let group_ids = self.group_ids.iter().map(|gid| {
+            let (matched, records) = selected(&|p| p.group_id.as_ref() == Some(gid));
+            SelectionReport { value: gid.clone(), matched, records }
+        }).collect();
+        let tins = self.tins.iter().map(|tin| {
+            let (matched, records) = selected(&|p| p.tin_value.as_ref() == Some(tin));
+            SelectionReport { value: tin.clone(), matched, records }
+        }).collect();
+
+        QueryReport { codes, npis, group_ids, tins }
+    }
+
+    /// Writes the report of the query (see report) to path as JSON.
+    pub fn write_report(&self, path: &std::path::Path) -> Result<(), Box<dyn std::error::Error>> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create report {}", path.display()))?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), &self.report())?;
+        Ok(())
+    }
+
+    /// Returns what the query selects as text, one line for each npi, code, filter, ... in sorted
+    /// order, the same for the same query however it was written. Call before a run, which adds
+    /// the providers of selected groups.
+    pub fn canonical(&self) -> String {
+        let code = |c: &Code| match &c.version {
+            Some(version) => format!("{}:{}@{}", c.code_type, c.value, version),
+            None => format!("{}:{}", c.code_type, c.value),
+        };
+        let mut lines: Vec<String> = Vec::new();
+        for p in self.providers.iter().filter(|p| p.queried) {
+            lines.push(format!("npi {} {} {} {}", p.npi,
+                               p.group_id.as_deref().unwrap_or("-"),
+                               p.tin_type.as_deref().unwrap_or("-"),
+                               p.tin_value.as_deref().unwrap_or("-")));
+        }
+        lines.extend(self.codes.iter().map(|c| format!("code {}", code(c))));
+        lines.extend(self.excluded_codes.iter().map(|c| format!("exclude_code {}", code(c))));
+        lines.extend(self.excluded_npis.iter().map(|npi| format!("exclude_npi {}", npi)));
+        lines.extend(self.selected_npis.iter().flatten().map(|npi| format!("select_npi {}", npi)));
+        lines.extend(self.group_ids.iter().map(|gid| format!("group_id {}", gid)));
+        lines.extend(self.tins.iter().map(|tin| format!("tin {}", tin)));
+        lines.extend(self.name_filters.iter().map(|filter| match filter {
+            NameFilter::Keyword(keyword) => format!("name {}", keyword),
+            NameFilter::Regex(re) => format!("name_regex {}", re.as_str()),
+        }));
+        lines.extend(self.billing_classes.iter().map(|class| format!("billing_class {}", class)));
+        lines.extend(self.min_rate.map(|rate| format!("min_rate {}", rate)));
+        lines.extend(self.max_rate.map(|rate| format!("max_rate {}", rate)));
+        if self.all_providers {
+            lines.push(String::from("all_providers"));
+        }
+        lines.sort();
+        lines.dedup();
+        lines.push(format!("case_sensitive {} literal {}", self.match_policy.case_sensitive, self.match_policy.literal));
+        lines.join("\n")
     }
 
     /// Returns true if at least one provider in self.providers has a group_id
@@ -262,49 +985,321 @@ impl Query {
 
 
 
+/// A number or a string in a structured query file, e.g. an npi, a group id or a code.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Scalar {
+    Number(u64),
+    Text(String),
+}
+
+impl Scalar {
+    fn into_string(self) -> String {
+        match self {
+            Scalar::Number(n) => n.to_string(),
+            Scalar::Text(s) => s,
+        }
+    }
+}
+
+/// The providers table of a structured query file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ProvidersSection {
+    /// npis, or "*" for every provider
+    npi: Vec<Scalar>,
+    group_id: Vec<Scalar>,
+    tin: Vec<Scalar>,
+    exclude_npi: Vec<u64>,
+}
+
+/// The filters table of a structured query file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FiltersSection {
+    keyword: Vec<String>,
+    regex: Vec<String>,
+    billing_class: Vec<String>,
+    min_rate: Option<f64>,
+    max_rate: Option<f64>,
+}
+
+/// The output table of a structured query file, same as the command line options.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct OutputSection {
+    capture_extra: bool,
+    raw_out: Option<std::path::PathBuf>,
+}
+
+/// A structured query file, the same schema in TOML, JSON or YAML. In TOML:
+/// ```toml
+/// [providers]
+/// npi = [1234567890, 2345678901]   # or ["*"]
+/// group_id = [12345]
+/// tin = ["881109921"]
+/// exclude_npi = [3456789012]
+///
+/// [codes]                          # code type = codes, same patterns as the text format
+/// CPT = ["99213", "0TJ*"]
+/// "*" = ["70071"]
+///
+/// [exclude_codes]
+/// CPT = ["00100"]
+///
+/// [versions]                       # billing_code_type_version pinned per code type
+/// CPT = "2023"
+///
+/// [filters]
+/// keyword = ["knee"]
+/// regex = ["(?i)arthroplasty"]
+/// billing_class = ["professional"]
+/// min_rate = 10.0
+/// max_rate = 5000.0
+///
+/// [output]
+/// capture_extra = true
+/// raw_out = "matched.ndjson.gz"
+/// ```
+/// Every table and key is optional. Code types are read in sorted order.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct QueryFile {
+    providers: ProvidersSection,
+    codes: BTreeMap<String, Vec<Scalar>>,
+    exclude_codes: BTreeMap<String, Vec<Scalar>>,
+    versions: BTreeMap<String, String>,
+    filters: FiltersSection,
+    output: OutputSection,
+}
+
+impl QueryFile {
+    /// Converts the file into a Query.
+    fn into_query(self) -> Result<Query, Box<dyn std::error::Error>> {
+        let mut query = Query::new();
+
+        for npi in self.providers.npi {
+            match npi {
+                Scalar::Text(s) if s == "*" => query.all_providers = true,
+                npi => {
+                    let npi = npi.into_string();
+                    let npi_val: u64 = npi.parse().map_err(|_| format!("'{}' is not a valid npi", npi))?;
+                    query.providers.push(Provider::new(npi_val));
+                }
+            }
+        }
+        query.group_ids = self.providers.group_id.into_iter().map(Scalar::into_string).collect();
+        query.tins = self.providers.tin.into_iter().map(Scalar::into_string).collect();
+        query.excluded_npis = self.providers.exclude_npi;
+
+        for (c_type, values) in self.codes {
+            for value in values {
+                let mut c = Code::new(&c_type, &value.into_string());
+                c.version = self.versions.get(&c_type).cloned();
+                query.codes.push(c);
+            }
+        }
+        for (c_type, values) in self.exclude_codes {
+            for value in values {
+                query.excluded_codes.push(Code::new(&c_type, &value.into_string()));
+            }
+        }
+
+        for keyword in self.filters.keyword {
+            query.name_filters.push(NameFilter::Keyword(keyword.to_lowercase()));
+        }
+        for re in self.filters.regex {
+            let compiled = regex::Regex::new(&re).with_context(|| format!("invalid regex '{}'", re))?;
+            query.name_filters.push(NameFilter::Regex(compiled));
+        }
+        query.billing_classes = self.filters.billing_class;
+        query.min_rate = self.filters.min_rate;
+        query.max_rate = self.filters.max_rate;
+        query.capture_extra = self.output.capture_extra;
+        query.raw_out = self.output.raw_out;
+
+        Ok(query)
+    }
+}
+
+/// Reads a structured query written in the format named by extension ("toml", "json" or "yaml").
+fn read_structured(input_path: &std::path::Path,
+                   extension: &str) -> Result<Query, Box<dyn std::error::Error>> {
+    let text = std::fs::read_to_string(input_path).with_context(
+        || format!("could not read file'{}'", input_path.display()))?;
+    let context = || format!("could not parse query file '{}'", input_path.display());
+    let file: QueryFile = match extension {
+        "toml" => toml::from_str(&text).with_context(context)?,
+        "json" => serde_json::from_str(&text).with_context(context)?,
+        _ => serde_yaml::from_str(&text).with_context(context)?,
+    };
+    file.into_query()
+}
+
+/// Parses a query from the text of a JSON query file (see QueryFile), e.g. one passed by code
+/// that embeds the parser.
+pub fn parse_json(text: &str) -> Result<Query, Box<dyn std::error::Error>> {
+    let file: QueryFile = serde_json::from_str(text).context("could not parse the JSON query")?;
+    file.into_query()
+}
+
+/// Reads a query from CSV, as exported from a spreadsheet.
+/// A row with one field is an npi, a row with two is a code_type,code pair, so one file can hold
+/// an npi column, a code list, or both one after the other. Header rows ("npi" or
+/// "code_type,code") and empty rows are skipped.
+fn read_csv(input_path: &std::path::Path) -> Result<Query, Box<dyn std::error::Error>> {
+    let f = File::open(input_path).with_context(
+        || format!("could not read file'{}'", input_path.display()))?;
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(f);
+
+    let mut query = Query::new();
+
+    for record in reader.records() {
+        let record = record?;
+        let line = record.position().map_or(0, |p| p.line());
+        let fields: Vec<&str> = record.iter().filter(|f| !f.is_empty()).collect();
+        match fields[..] {
+            [] | ["npi"] | ["code_type", "code"] => {}
+            [npi] => {
+                let npi_val: u64 = npi.parse()
+                    .map_err(|_| QueryError::InvalidNpi { line: line as usize, value: npi.to_string() })?;
+                query.providers.push(Provider::new(npi_val));
+            }
+            [c_type, c_value] => {
+                query.codes.push(Code::new(&c_type.to_string(), &c_value.to_string()));
+            }
+            _ => {
+                return Err(Box::new(QueryError::InvalidRow { line: line as usize }));
+            }
+        }
+    }
+
+    Ok(query)
+}
+
+/// Returns line without its trailing comment, a # after a space or tab.
+fn strip_comment(line: &str) -> &str {
+    let start = line.char_indices().find(|&(i, c)| c == '#' && line[..i].ends_with([' ', '\t']));
+    match start {
+        Some((i, _)) => line[..i].trim_end(),
+        None => line,
+    }
+}
+
 /// Reads the user supplied input and returns the necessary data structures to process the query
+/// Files ending in .toml, .json, .yaml or .yml are read as a structured query (see QueryFile),
+/// files ending in .csv as a CSV query (see read_csv).
+/// Warnings about the file, e.g. duplicate npis, go to stderr (see read_input_with).
 pub fn read_input(input_path: &std::path::PathBuf) -> Result<Query, Box<dyn std::error::Error>> {
+    read_input_with(input_path, &SharedReporter::default())
+}
+
+/// Same as read_input, but hands the warnings about the file to reporter.
+pub fn read_input_with(input_path: &std::path::PathBuf,
+                       reporter: &SharedReporter) -> Result<Query, Box<dyn std::error::Error>> {
+    match input_path.extension().and_then(|e| e.to_str()) {
+        Some("csv") => return read_csv(input_path),
+        Some("toml") => return read_structured(input_path, "toml"),
+        Some("json") => return read_structured(input_path, "json"),
+        Some("yaml") | Some("yml") => return read_structured(input_path, "yaml"),
+        _ => {}
+    }
     enum State {
         BillingCode,
         Npi,
+        GroupId,
+        Tin,
+        ExcludedNpi,
+        ExcludedCode,
+        Keyword,
+        Regex,
         Undefined,
     }
 
     let mut state = State::Undefined;
     let mut c_type: Option<String> = None; 
+    let mut c_version: Option<String> = None;
 
     // To hold the data for the query
     let mut query = Query::new();
 
-    let f = File::open(&input_path).with_context(
+    let text = std::fs::read_to_string(input_path).with_context(
         || format!("could not read file'{}'", input_path.display()))?;
-    let f = BufReader::new(f);
-
-    for line in f.lines() {
+    // Editors on Windows may start the file with a byte order mark
+    let text = text.strip_prefix('\u{feff}').unwrap_or(&text);
 
-        let line = line?;
+    // lines() also strips the '\r' of CRLF line endings
+    for (idx, line) in text.lines().enumerate() {
+        let line_no = idx + 1;
 
-        // Skip empty lines
-        if line.len() == 0 {
+        // Skip empty lines and comments, and drop the comment at the end of a line
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
             continue;
         }
+        let line = strip_comment(line);
+
+        let invalid_npi = |value: &str| QueryError::InvalidNpi { line: line_no, value: value.to_string() };
 
         // Process npi or billing codes based on State
-        if line.chars().nth(0) == Some(' ') { 
+        if line.starts_with([' ', '\t']) { 
             let line = line.trim();
             match state {
                 State::BillingCode => {
                     //let c = Code::new(c_type.as_ref().unwrap(), (*line).to_string());
-                    let c = Code::new(c_type.as_ref().unwrap(), &line.to_string());
+                    let mut c = Code::new(c_type.as_ref().unwrap(), &line.to_string());
+                    c.version = c_version.clone();
+                    let known = query.codes.iter().any(|k| {
+                        k.code_type == c.code_type && k.value.eq_ignore_ascii_case(&c.value) && k.version == c.version
+                    });
+                    if known {
+                        reporter.warn(format_args!("line {}: duplicate code '{}' for type {}", line_no, line, c.code_type));
+                        continue;
+                    }
                     query.codes.push(c);
                 }
+                State::Npi if line == "*" => {
+                    query.all_providers = true;
+                }
                 State::Npi => {
-                    let npi_val: u64 = line.trim().parse().expect("Error: Failed to parse npi");
+                    let npi_val: u64 = line.parse().map_err(|_| invalid_npi(line))?;
+                    if query.providers.iter().any(|p| p.npi == npi_val) {
+                        reporter.warn(format_args!("line {}: duplicate npi {}", line_no, npi_val));
+                        continue;
+                    }
                     let p = Provider::new(npi_val);
                     query.providers.push(p);
                 }
+                State::GroupId => {
+                    query.group_ids.push(line.to_string());
+                }
+                State::Tin => {
+                    query.tins.push(line.to_string());
+                }
+                State::ExcludedNpi => {
+                    let npi_val: u64 = line.parse().map_err(|_| invalid_npi(line))?;
+                    query.excluded_npis.push(npi_val);
+                }
+                State::ExcludedCode => {
+                    let c = Code::new(c_type.as_ref().unwrap(), &line.to_string());
+                    query.excluded_codes.push(c);
+                }
+                State::Keyword => {
+                    query.name_filters.push(NameFilter::Keyword(line.to_lowercase()));
+                }
+                State::Regex => {
+                    let re = regex::Regex::new(line).map_err(|e| QueryError::InvalidRegex {
+                        line: line_no,
+                        value: line.to_string(),
+                        message: e.to_string(),
+                    })?;
+                    query.name_filters.push(NameFilter::Regex(re));
+                }
                 State::Undefined => {
-                    panic!("Input file not formatted correctly, codes came before either billing type or npi specifier");
+                    return Err(Box::new(QueryError::NoSection { line: line_no, value: line.to_string() }));
                 }
             }
                      
@@ -315,8 +1310,34 @@ pub fn read_input(input_path: &std::path::PathBuf) -> Result<Query, Box<dyn std:
             if line == "npi" {
                 state = State::Npi;
             }
+            else if line == "group_id" {
+                state = State::GroupId;
+            }
+            else if line == "tin" {
+                state = State::Tin;
+            }
+            else if line == "keyword" {
+                state = State::Keyword;
+            }
+            else if line == "regex" {
+                state = State::Regex;
+            }
+            else if line == "!npi" {
+                state = State::ExcludedNpi;
+            }
+            else if let Some(excluded_type) = line.strip_prefix('!') {
+                c_type = Some(String::from(excluded_type.trim()));
+                state = State::ExcludedCode;
+            }
+            // A code type may pin a version, e.g. "CPT @2023"
+            else if let Some((code_type, version)) = line.rsplit_once(" @") {
+                c_type = Some(String::from(code_type.trim()));
+                c_version = Some(String::from(version.trim()));
+                state = State::BillingCode;
+            }
             else {
                 c_type = Some(String::from(line.trim()));
+                c_version = None;
                 state = State::BillingCode;
             }
 
@@ -360,7 +1381,210 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "Input file not formatted correctly, codes came before either billing type or npi specifier")]
+    fn test_toml_input_read() {
+        let filepath = std::path::PathBuf::from("tests/testfiles/input_testfiles/query_basic_input.toml");
+        let q_from_file = read_input(&filepath).unwrap();
+
+        let mut q = Query::new();
+        q.providers.push(Provider::new(1234567));
+        q.codes.push(Code::new(&String::from("Efile"), &String::from("123")));
+        let mut c1 = Code::new(&String::from("cpt"), &String::from("99995"));
+        let mut c2 = Code::new(&String::from("cpt"), &String::from("0001"));
+        c1.version = Some(String::from("2023"));
+        c2.version = Some(String::from("2023"));
+        q.codes.push(c1);
+        q.codes.push(c2);
+        q.group_ids.push(String::from("12345"));
+        q.billing_classes.push(String::from("professional"));
+        q.max_rate = Some(500.0);
+
+        assert_eq!(q_from_file, q);
+    }
+
+    #[test]
+    fn test_json_yaml_input_read() {
+        let toml = read_input(&std::path::PathBuf::from("tests/testfiles/input_testfiles/query_basic_input.toml")).unwrap();
+        let json = read_input(&std::path::PathBuf::from("tests/testfiles/input_testfiles/query_basic_input.json")).unwrap();
+        let yaml = read_input(&std::path::PathBuf::from("tests/testfiles/input_testfiles/query_basic_input.yaml")).unwrap();
+
+        let mut expected = toml.clone();
+        expected.capture_extra = true;
+        expected.raw_out = Some(std::path::PathBuf::from("matched.ndjson"));
+        assert_eq!(json, expected);
+        assert_eq!(yaml, toml);
+
+        let err = serde_json::from_str::<QueryFile>(r#"{"provider": {}}"#).unwrap_err();
+        assert!(err.to_string().contains("unknown field `provider`"));
+    }
+
+    #[test]
+    fn test_csv_input_read() {
+        let filepath = std::path::PathBuf::from("tests/testfiles/input_testfiles/query_basic_input.csv");
+        let q_from_file = read_input(&filepath).unwrap();
+        let q = read_input(&std::path::PathBuf::from("tests/testfiles/input_testfiles/query_basic_input")).unwrap();
+        assert_eq!(q_from_file, q);
+
+        let path = std::env::temp_dir().join(format!("mrfy_bad_query_{}.csv", std::process::id()));
+        std::fs::write(&path, "npi\n1234567\nnpi1234\n").unwrap();
+        let err = read_input(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(err.to_string(), "line 3: 'npi1234' is not a valid NPI");
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut q = read_input(&std::path::PathBuf::from("tests/testfiles/input_testfiles/query_basic_input")).unwrap();
+        let csv = read_input(&std::path::PathBuf::from("tests/testfiles/input_testfiles/query_basic_input.csv")).unwrap();
+        let toml = read_input(&std::path::PathBuf::from("tests/testfiles/input_testfiles/query_basic_input.toml")).unwrap();
+
+        // Same npi and codes, nothing added
+        let before = q.clone();
+        q.merge(csv);
+        assert_eq!(q, before);
+
+        // The cpt codes of the toml query are pinned to a version, so they are new
+        q.merge(toml);
+        assert_eq!(q.providers.len(), 1);
+        assert_eq!(q.codes.len(), 5);
+        assert_eq!(q.group_ids, vec![String::from("12345")]);
+        assert_eq!(q.max_rate, Some(500.0));
+    }
+
+    #[test]
+    fn test_is_valid_npi() {
+        // Example from the CMS NPI check digit document
+        assert!(is_valid_npi(1234567893));
+        assert!(!is_valid_npi(1234567890));
+        // Transposed digits
+        assert!(!is_valid_npi(1234576893));
+        assert!(!is_valid_npi(123456789));
+
+        let mut q = Query::new();
+        q.providers.push(Provider::new(1234567893));
+        q.providers.push(Provider::new(1234576893));
+        assert_eq!(q.invalid_npis(), vec![1234576893]);
+    }
+
+    #[test]
+    fn test_normalize_codes() {
+        assert_eq!(normalize_code(" 023 "), "23");
+        assert_eq!(normalize_code("0450"), "450");
+        assert_eq!(normalize_code("000"), "0");
+        assert_eq!(normalize_code("j1100 "), "J1100");
+        assert_eq!(normalize_code("0*"), "0*");
+        assert_eq!(normalize_code_type("HCPCS Level II"), "HCPCS");
+        assert_eq!(normalize_code_type(" hcpcs "), "HCPCS");
+        assert_eq!(normalize_code_type("cpt-4"), "CPT");
+        assert_eq!(normalize_code_type("ms_drg"), "MS-DRG");
+        assert_eq!(normalize_code_type("Type 1"), "TYPE 1");
+
+        let mut q = Query::new();
+        q.codes.push(Code::new(&String::from("Revenue Code"), &String::from("0450")));
+        q.codes.push(Code::new(&String::from("*"), &String::from("j*")));
+        q.normalize_codes();
+        assert_eq!((q.codes[0].value.as_str(), q.codes[0].code_type.as_str()), ("450", "RC"));
+        assert_eq!((q.codes[1].value.as_str(), q.codes[1].code_type.as_str()), ("J*", "*"));
+    }
+
+    #[test]
+    fn test_code_patterns() {
+        let code_matches = |pattern, code| MatchPolicy::default().code_matches(pattern, code);
+        assert!(code_matches("0TJ*", "0tj00zz"));
+        assert!(code_matches("0TJ*", "0TJ"));
+        assert!(!code_matches("0TJ*", "0T"));
+        assert!(code_matches("?9999", "09999"));
+        assert!(!code_matches("?9999", "9999"));
+        assert!(code_matches("*", "anything"));
+
+        let values = ["99213", "0TJ*", "0T*", "A?1*", "?9999"];
+        let matcher = CodeMatcher::new(values.into_iter(), MatchPolicy::default());
+        assert!(matcher.matches("99213"));
+        assert!(matcher.matches("0TJ00ZZ"));
+        assert!(matcher.matches("0TZ"));
+        assert!(matcher.matches("AB12"));
+        assert!(matcher.matches("19999"));
+        assert!(!matcher.matches("9921"));
+        assert!(!matcher.matches("0"));
+        assert!(!matcher.matches("B9999X"));
+    }
+
+    #[test]
+    fn test_provider_index() {
+        let mut q = Query::new();
+        for npi in 0..100_000 {
+            q.providers.push(Provider::new(npi));
+        }
+        q.index_providers();
+        assert!(q.has_npi(99_999));
+        assert!(!q.has_npi(100_000));
+
+        // First group of npi 7 takes its Provider, the second one a new Provider
+        for gid in ["10", "11"] {
+            q.flag_member(7);
+            q.assign_tin("ein", gid);
+            assert!(q.assign_group_id(Some(gid)));
+        }
+        assert_eq!(q.providers.len(), 100_001);
+        assert_eq!(q.providers[7].group_id.as_deref(), Some("10"));
+        assert_eq!(q.providers[100_000].tin_value.as_deref(), Some("11"));
+
+        q.log_ref("11", 1);
+        assert!(!q.providers[7].recorded);
+        assert!(q.providers[100_000].recorded);
+        assert!(!q.assign_group_id(Some("12")));
+    }
+
+    #[test]
+    fn test_select_npis() {
+        let mut q = Query::new();
+        q.providers.push(Provider::new(1));
+        q.providers.push(Provider::new(2));
+        q.group_ids.push(String::from("10"));
+        assert_eq!(q.select_npis(HashSet::from([2, 3])), 1);
+        assert_eq!(q.providers, vec![Provider::new(2)]);
+        assert!(!q.all_providers);
+
+        q.index_providers();
+        let members = [(3, String::from("ein"), String::from("t")), (4, String::from("ein"), String::from("t"))];
+        assert!(q.add_group_members("10", members.into_iter()));
+        assert_eq!(q.providers.iter().map(|p| p.npi).collect::<Vec<u64>>(), vec![2, 3]);
+
+        // Nothing else selects providers, so the npis do
+        let mut q = Query::new();
+        q.select_npis(HashSet::from([3]));
+        assert!(q.all_providers);
+    }
+
+    #[test]
+    fn test_match_policy() {
+        let sensitive = MatchPolicy { case_sensitive: true, literal: false };
+        assert!(sensitive.code_matches("Ab*", "Abc"));
+        assert!(!sensitive.code_matches("Ab*", "ABC"));
+        assert!(!sensitive.type_matches("local", "LOCAL"));
+        assert!(sensitive.type_matches("*", "LOCAL"));
+
+        let literal = MatchPolicy { case_sensitive: false, literal: true };
+        assert!(literal.code_matches("a*", "A*"));
+        assert!(!literal.code_matches("A*", "AB"));
+        assert!(!literal.code_matches("?1", "A1"));
+        assert!(!literal.type_matches("*", "CPT"));
+        assert!(literal.type_matches("cpt", "CPT"));
+
+        let matcher = CodeMatcher::new(["Ab1", "X*"].into_iter(), sensitive);
+        assert!(matcher.matches("Ab1"));
+        assert!(!matcher.matches("AB1"));
+        assert!(!matcher.matches("x1"));
+
+        let mut q = Query::new();
+        q.match_policy = literal;
+        q.codes.push(Code::new(&String::from("*"), &String::from("J*")));
+        q.log_code(&String::from("J1100"), &String::from("HCPCS"), 1);
+        assert!(!q.codes[0].recorded);
+        q.log_code(&String::from("J*"), &String::from("*"), 1);
+        assert!(q.codes[0].recorded);
+    }
+
+    #[test]
     fn test_input_file_codes_ambiguous() {
         // Tests a case where the user forgot to specifc code type for NPIs
         // Note: The user might forget specification in a way that won't be caught.
@@ -374,7 +1598,50 @@ mod tests {
         // Where the second code should have been of a different type
         let path_str = "tests/testfiles/input_testfiles/query_npi_not_specified";
         let filepath: std::path::PathBuf = std::path::PathBuf::from(path_str);
-        let _ = read_input(&filepath);
+        let err = read_input(&filepath).unwrap_err();
+        assert_eq!(err.to_string(),
+                   "line 1: '1234567' is indented but comes before any npi or billing code type line");
+    }
+
+    #[test]
+    fn test_input_diagnostics() {
+        let path = std::env::temp_dir().join(format!("mrfy_query_{}", std::process::id()));
+
+        // BOM, CRLF, comments, tabs and duplicates
+        std::fs::write(&path, "\u{feff}# my query\r\nnpi\r\n\t1234567\r\n  1234567\r\n\r\ncpt\r\n  # office visits\r\n  99213\r\n  99213\r\n").unwrap();
+        let q = read_input(&path).unwrap();
+        assert_eq!(q.providers, vec![Provider::new(1234567)]);
+        assert_eq!(q.codes, vec![Code::new(&String::from("cpt"), &String::from("99213"))]);
+
+        // Comments at the end of a line
+        std::fs::write(&path, "npi # providers\n  1234567 # one\n  1\t# two\ncpt\n  99#213  # the first # is kept\n").unwrap();
+        let q = read_input(&path).unwrap();
+        assert_eq!(q.providers, vec![Provider::new(1234567), Provider::new(1)]);
+        assert_eq!(q.codes, vec![Code::new(&String::from("cpt"), &String::from("99#213"))]);
+
+        std::fs::write(&path, "npi\n  1234567\n\n  npi10023\n").unwrap();
+        let err = read_input(&path).unwrap_err();
+        assert_eq!(err.to_string(), "line 4: 'npi10023' is not a valid NPI");
+
+        std::fs::write(&path, "regex\n  (unclosed\n").unwrap();
+        let err = read_input(&path).unwrap_err();
+        assert!(err.to_string().starts_with("line 2: invalid regex '(unclosed'"));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_canonical() {
+        let q = read_input(&std::path::PathBuf::from("tests/testfiles/input_testfiles/query_basic_input")).unwrap();
+        let mut inline = Query::new();
+        let codes = [("Efile", "123"), ("cpt", "0001"), ("cpt", "99995")]
+            .map(|(t, c)| (String::from(t), String::from(c)));
+        inline.add_inline(&[String::from("1234567")], &codes).unwrap();
+        assert_eq!(q.canonical(), inline.canonical());
+        assert!(q.canonical().starts_with("code Efile:123\ncode cpt:0001\n"));
+
+        inline.codes[0].version = Some(String::from("2023"));
+        assert_ne!(q.canonical(), inline.canonical());
     }
 
 }
@@ -0,0 +1,216 @@
+//! # rate
+//!
+//! Reading negotiated_rate values that are not plain decimal numbers.
+//!
+//! The schema has negotiated_rate as a JSON number, but some payer files write it as a string
+//! with a currency symbol and thousands separators ("$1,234.56"), or as a number in scientific
+//! notation (1.5e3). normalize_rate turns these into a canonical decimal ("1234.56", "1500")
+//! without going through a float, so no digits are lost or added.
+//!
+//! round_rate gives canonical decimals a fixed number of decimals, so the same rate is written
+//! the same way whether the file had 9.9 or 9.90.
+
+/// What to do with a negotiated_rate that is not a canonical decimal.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RateFormat {
+    /// Write the canonical decimal and warn (the first time)
+    #[default]
+    Warn,
+    /// Write the canonical decimal silently
+    Fix,
+    /// Stop the run with an error
+    Fail,
+}
+
+impl RateFormat {
+    /// Parses warn, fix or fail.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "warn" => Some(RateFormat::Warn),
+            "fix" => Some(RateFormat::Fix),
+            "fail" => Some(RateFormat::Fail),
+            _ => None,
+        }
+    }
+}
+
+/// Largest exponent written out in full, beyond it a rate is not a number we can use.
+const MAX_EXPONENT: i64 = 64;
+
+/// Returns value as a canonical decimal: digits with at most one '.', no leading zeros (other
+/// than a single one before the '.'), no exponent and a '-' only for negative values.
+/// Whitespace, '$' and ',' are dropped. Trailing zeros are kept.
+/// Returns None if value is not a number.
+pub fn normalize_rate(value: &str) -> Option<String> {
+    let cleaned: String = value.chars()
+        .filter(|c| !c.is_whitespace() && *c != '$' && *c != ',')
+        .collect();
+    let (negative, unsigned) = match cleaned.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, cleaned.strip_prefix('+').unwrap_or(&cleaned)),
+    };
+
+    let (mantissa, exponent) = match unsigned.split_once(['e', 'E']) {
+        Some((m, e)) => (m, e.parse::<i64>().ok()?),
+        None => (unsigned, 0),
+    };
+    if exponent.abs() > MAX_EXPONENT {
+        return None;
+    }
+    let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    if (int.is_empty() && frac.is_empty()) ||
+       !int.bytes().chain(frac.bytes()).all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    // Moves the decimal point of the digits by exponent
+    let digits = format!("{}{}", int, frac);
+    let point = int.len() as i64 + exponent;
+    let (int, frac) = if point <= 0 {
+        (String::new(), "0".repeat(-point as usize) + &digits)
+    }
+    else if point as usize >= digits.len() {
+        (digits.clone() + &"0".repeat(point as usize - digits.len()), String::new())
+    }
+    else {
+        let (i, f) = digits.split_at(point as usize);
+        (i.to_string(), f.to_string())
+    };
+
+    let int = match int.trim_start_matches('0') {
+        "" => "0",
+        i => i,
+    };
+    let mut rate = String::from(int);
+    if !frac.is_empty() {
+        rate.push('.');
+        rate.push_str(&frac);
+    }
+    if negative && rate.bytes().any(|b| b != b'0' && b != b'.') {
+        rate.insert(0, '-');
+    }
+    Some(rate)
+}
+
+/// Returns true if value is already a canonical decimal (see normalize_rate), the case of
+/// nearly every rate, without allocating.
+pub fn is_canonical_rate(value: &str) -> bool {
+    let unsigned = value.strip_prefix('-').unwrap_or(value);
+    let (int, frac) = match unsigned.split_once('.') {
+        Some((int, frac)) => (int, Some(frac)),
+        None => (unsigned, None),
+    };
+    let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if !digits(int) || (int.len() > 1 && int.starts_with('0')) || frac.is_some_and(|f| !digits(f)) {
+        return false;
+    }
+    // No "-0" or "-0.00"
+    unsigned.len() == value.len() || unsigned.bytes().any(|b| b != b'0' && b != b'.')
+}
+
+/// Rounds the canonical decimal rate (see normalize_rate) to precision decimals, half away from
+/// zero, padding it with zeros if it has fewer. Works on the digits, so the result doesn't
+/// depend on float formatting or the locale. Returns None if rate is not a canonical decimal.
+pub fn round_rate(rate: &str, precision: usize) -> Option<String> {
+    let (negative, unsigned) = match rate.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, rate),
+    };
+    let (int, frac) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+    if int.is_empty() || !int.bytes().chain(frac.bytes()).all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let mut digits: Vec<u8> = int.bytes().chain(frac.bytes().take(precision)).collect();
+    digits.resize(int.len() + precision, b'0');
+    if frac.as_bytes().get(precision).is_some_and(|d| *d >= b'5') {
+        // Adds one to the last digit kept, carrying to the left
+        let mut carry = true;
+        for d in digits.iter_mut().rev() {
+            if *d == b'9' {
+                *d = b'0';
+            }
+            else {
+                *d += 1;
+                carry = false;
+                break;
+            }
+        }
+        if carry {
+            digits.insert(0, b'1');
+        }
+    }
+
+    let point = digits.len() - precision;
+    let mut rounded = String::from_utf8(digits[..point].to_vec()).ok()?;
+    if precision > 0 {
+        rounded.push('.');
+        rounded.push_str(std::str::from_utf8(&digits[point..]).ok()?);
+    }
+    if negative && rounded.bytes().any(|b| b != b'0' && b != b'.') {
+        rounded.insert(0, '-');
+    }
+    Some(rounded)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_rate() {
+        assert_eq!(normalize_rate("9.99").as_deref(), Some("9.99"));
+        assert_eq!(normalize_rate("100").as_deref(), Some("100"));
+        assert_eq!(normalize_rate("9.90").as_deref(), Some("9.90"));
+        assert_eq!(normalize_rate("$1,234.56").as_deref(), Some("1234.56"));
+        assert_eq!(normalize_rate(" $ 12 ").as_deref(), Some("12"));
+        assert_eq!(normalize_rate("1.5e3").as_deref(), Some("1500"));
+        assert_eq!(normalize_rate("1.25E-2").as_deref(), Some("0.0125"));
+        assert_eq!(normalize_rate("12.5e1").as_deref(), Some("125"));
+        assert_eq!(normalize_rate("5e+0").as_deref(), Some("5"));
+        assert_eq!(normalize_rate(".5").as_deref(), Some("0.5"));
+        assert_eq!(normalize_rate("007.10").as_deref(), Some("7.10"));
+        assert_eq!(normalize_rate("-$3.00").as_deref(), Some("-3.00"));
+        assert_eq!(normalize_rate("-0.0").as_deref(), Some("0.0"));
+        assert_eq!(normalize_rate("+4").as_deref(), Some("4"));
+
+        assert_eq!(normalize_rate(""), None);
+        assert_eq!(normalize_rate("N/A"), None);
+        assert_eq!(normalize_rate("1.2.3"), None);
+        assert_eq!(normalize_rate("1e"), None);
+        assert_eq!(normalize_rate("1e1000"), None);
+        assert_eq!(normalize_rate("."), None);
+    }
+
+    #[test]
+    fn test_is_canonical_rate() {
+        for value in ["9.99", "100", "9.90", "0", "0.5", "-3.00", "1.5e3", "$1,234.56", ".5", "007.10",
+                      "-0.0", "-0", "+4", "", "N/A", "1.", "00", "-", "1.2.3", "0.0125"] {
+            assert_eq!(is_canonical_rate(value), normalize_rate(value).as_deref() == Some(value), "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_round_rate() {
+        assert_eq!(round_rate("9.9", 2).as_deref(), Some("9.90"));
+        assert_eq!(round_rate("9.90", 2).as_deref(), Some("9.90"));
+        assert_eq!(round_rate("100", 2).as_deref(), Some("100.00"));
+        assert_eq!(round_rate("1.005", 2).as_deref(), Some("1.01"));
+        assert_eq!(round_rate("1.0049", 2).as_deref(), Some("1.00"));
+        assert_eq!(round_rate("99.995", 2).as_deref(), Some("100.00"));
+        assert_eq!(round_rate("9.5", 0).as_deref(), Some("10"));
+        assert_eq!(round_rate("-2.675", 2).as_deref(), Some("-2.68"));
+        assert_eq!(round_rate("-0.001", 2).as_deref(), Some("0.00"));
+        assert_eq!(round_rate("0.0125", 3).as_deref(), Some("0.013"));
+        assert_eq!(round_rate("N/A", 2), None);
+        assert_eq!(round_rate(".5", 2), None);
+    }
+
+    #[test]
+    fn test_rate_format() {
+        assert_eq!(RateFormat::parse("fix"), Some(RateFormat::Fix));
+        assert_eq!(RateFormat::parse("Fix"), None);
+        assert_eq!(RateFormat::default(), RateFormat::Warn);
+    }
+}
@@ -0,0 +1,161 @@
+//! # refmap
+//!
+//! Maps provider group ids to the "{npi},{tin_type},{tin_value}" strings written with each record
+//! of the group.
+//!
+//! The map is kept in memory by default. For queries selecting millions of providers (e.g. every
+//! NPI in NPPES) the strings can be kept in a temporary file instead, with only the group ids
+//! and the position of their strings in the file left in memory.
+
+use crate::query::{Query, Provider};
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Provider group id -> "{npi},{tin_type},{tin_value}" of each member in the query.
+#[derive(Debug)]
+pub enum RefMap {
+    Memory(HashMap<String, Vec<String>>),
+    Disk(DiskRefMap),
+}
+
+/// The member strings of each group in a temporary file, removed when dropped.
+#[derive(Debug)]
+pub struct DiskRefMap {
+    /// Group id -> (offset, length) of its members in file
+    index: HashMap<String, (u64, u64)>,
+    file: RefCell<File>,
+    path: PathBuf,
+}
+
+impl From<HashMap<String, Vec<String>>> for RefMap {
+    fn from(map: HashMap<String, Vec<String>>) -> Self {
+        RefMap::Memory(map)
+    }
+}
+
+impl RefMap {
+    /// Creates a RefMap of the providers of query in a temporary file of std::env::temp_dir
+    /// (TMPDIR), holding the same entries as query.make_ref_map().
+    pub fn on_disk(query: &Query) -> Result<Self, std::io::Error> {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!("mrfy-refs-{}-{}.tmp",
+                                                    std::process::id(),
+                                                    FILES.fetch_add(1, Ordering::Relaxed)));
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        // Removes the file if writing fails
+        let mut map = DiskRefMap { index: HashMap::new(), file: RefCell::new(file), path };
+
+        // Members of a group are written next to each other
+        let mut members: Vec<(&str, String)> = query.providers.iter().filter_map(|p| match p {
+            Provider { npi, group_id: Some(group_id), tin_type: Some(tin_type), tin_value: Some(tin_value), .. } => {
+                Some((group_id.as_str(), format!("{},{},{}", npi, tin_type, tin_value)))
+            }
+            _ => None, // Skip Providers with missing data
+        }).collect();
+        members.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut offset: u64 = 0;
+        {
+            let mut file = map.file.borrow_mut();
+            let mut writer = BufWriter::new(&mut *file);
+            for (gid, member) in members.iter() {
+                // Each string is written after its length, so any byte may appear in it
+                writer.write_all(&(member.len() as u64).to_le_bytes())?;
+                writer.write_all(member.as_bytes())?;
+                let len = 8 + member.len() as u64;
+                map.index.entry(gid.to_string()).or_insert((offset, 0)).1 += len;
+                offset += len;
+            }
+            writer.flush()?;
+        }
+
+        Ok(RefMap::Disk(map))
+    }
+
+    /// Returns true if the map has the group id gid.
+    pub fn contains_key(&self, gid: &str) -> bool {
+        match self {
+            RefMap::Memory(map) => map.contains_key(gid),
+            RefMap::Disk(map) => map.index.contains_key(gid),
+        }
+    }
+
+    /// Returns the member strings of group gid, read from the file for a map on disk.
+    pub fn get(&self, gid: &str) -> Result<Option<Cow<'_, [String]>>, std::io::Error> {
+        match self {
+            RefMap::Memory(map) => Ok(map.get(gid).map(|v| Cow::Borrowed(v.as_slice()))),
+            RefMap::Disk(map) => Ok(map.get(gid)?.map(Cow::Owned)),
+        }
+    }
+}
+
+impl DiskRefMap {
+    /// Reads the member strings of group gid from the file.
+    fn get(&self, gid: &str) -> Result<Option<Vec<String>>, std::io::Error> {
+        let Some(&(offset, len)) = self.index.get(gid) else {
+            return Ok(None);
+        };
+
+        let mut buf = vec![0u8; len as usize];
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buf)?;
+
+        let mut members = Vec::new();
+        let mut rest = buf.as_slice();
+        while rest.len() >= 8 {
+            let (size, tail) = rest.split_at(8);
+            let size = u64::from_le_bytes(size.try_into().unwrap()) as usize;
+            let (member, tail) = tail.split_at(size);
+            members.push(String::from_utf8_lossy(member).into_owned());
+            rest = tail;
+        }
+        Ok(Some(members))
+    }
+}
+
+impl Drop for DiskRefMap {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_ref_map() {
+        let mut q = Query::new();
+        for (npi, gid, tin) in [(1, "10", "a"), (2, "11", "b"), (3, "10", "c,d"), (4, "12", "")] {
+            let mut p = Provider::new(npi);
+            p.group_id = Some(gid.to_string());
+            p.tin_type = Some(String::from("ein"));
+            p.tin_value = Some(tin.to_string());
+            q.providers.push(p);
+        }
+        // Not in any group yet
+        q.providers.push(Provider::new(5));
+
+        let memory = RefMap::from(q.make_ref_map());
+        let disk = RefMap::on_disk(&q).unwrap();
+        for gid in ["10", "11", "12", "13"] {
+            assert_eq!(disk.contains_key(gid), memory.contains_key(gid));
+            assert_eq!(disk.get(gid).unwrap(), memory.get(gid).unwrap());
+        }
+        assert_eq!(disk.get("10").unwrap().unwrap().as_ref(), ["1,ein,a", "3,ein,c,d"]);
+
+        let RefMap::Disk(map) = &disk else { panic!("not on disk") };
+        let path = map.path.clone();
+        assert!(path.exists());
+        drop(disk);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,186 @@
+//! # remote
+//!
+//! The async side of the pipeline, built with the `tokio` feature.
+//!
+//! Downloads from cloud storage run as tasks on one shared tokio runtime rather than each holding
+//! a thread, and hand their chunks to the thread parsing them through a bounded channel
+//! (ChannelReader). http(s) URLs are still streamed by source's HttpReader (the feature includes
+//! `http`), which resumes a dropped connection where it stopped. Parsing is CPU bound and stays
+//! synchronous: run is the async variant of asa::run, parsing on a blocking task, and
+//! run_concurrently parses several files at once, e.g. for compare.
+
+use std::io::{self, Read};
+use std::sync::OnceLock;
+
+use bytes::Bytes;
+use futures::{StreamExt, TryStreamExt};
+use tokio::sync::mpsc;
+
+use crate::asa::{self, Options, Progress, RunSummary};
+use crate::error::Interrupted;
+use crate::query::Query;
+use crate::sink::MemorySink;
+use crate::source::Location;
+
+/// Chunks a download may get ahead of the parser.
+const CHANNEL_CHUNKS: usize = 16;
+
+/// Error of an async run. Send, so it can leave the task it happened in.
+pub type RunError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Returns the runtime shared by every download and run of the process, started the first time.
+pub fn runtime() -> io::Result<&'static tokio::runtime::Runtime> {
+    static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+    if let Some(runtime) = RUNTIME.get() {
+        return Ok(runtime);
+    }
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+    // Another thread may have started one meanwhile, then this one is dropped
+    Ok(RUNTIME.get_or_init(|| runtime))
+}
+
+/// Blocking Read over the chunks a download task sends. Must not be read from inside an async
+/// task (run parses on a blocking task).
+pub struct ChannelReader {
+    chunks: mpsc::Receiver<io::Result<Bytes>>,
+    chunk: Bytes,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.chunks.blocking_recv() {
+                Some(Ok(chunk)) => self.chunk = chunk,
+                Some(Err(e)) => return Err(e),
+                None => return Ok(0),
+            }
+        }
+
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk.split_to(n));
+        Ok(n)
+    }
+}
+
+/// Starts downloading location (an s3://, gs:// or az:// URL) on the shared runtime and returns a
+/// reader over its bytes. Errors, including failing to connect, come out of the reader.
+/// Credentials are taken from the usual environment variables, as with the `object-store`
+/// feature.
+pub fn download(location: &Location) -> Result<ChannelReader, Box<dyn std::error::Error>> {
+    let (store, path) = match location {
+        Location::ObjectStore(url) => {
+            let url = url::Url::parse(url)?;
+            let options = std::env::vars().map(|(k, v)| (k.to_ascii_lowercase(), v));
+            object_store::parse_url_opts(&url, options)?
+        }
+        _ => return Err(format!("'{}' is not in cloud storage", location).into()),
+    };
+
+    let (sender, chunks) = mpsc::channel(CHANNEL_CHUNKS);
+    runtime()?.spawn(async move {
+        let stream = match store.get(&path).await {
+            Ok(result) => result.into_stream().map_err(io::Error::other),
+            Err(e) => {
+                let _ = sender.send(Err(io::Error::other(e))).await;
+                return;
+            }
+        };
+        let mut stream = std::pin::pin!(stream);
+        while let Some(chunk) = stream.next().await {
+            // The reader was dropped, e.g. the run stopped early
+            if sender.send(chunk).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    Ok(ChannelReader { chunks, chunk: Bytes::new() })
+}
+
+/// Async variant of asa::run: runs query against source on a blocking task of the shared runtime
+/// and returns the summary with the rows, kept in memory. No progress bar is drawn, since several
+/// runs may go at once. An interrupted run returns the Interrupted error, other errors as text.
+/// Status messages and warnings go to options.reporter, whichever thread the task runs on.
+pub async fn run(query: Query,
+                 source: Location,
+                 buff_size: usize,
+                 options: Options) -> Result<(RunSummary, MemorySink), RunError> {
+    let task = tokio::task::spawn_blocking(move || {
+        let mut query = query;
+        let mut sink = MemorySink::new();
+        match asa::run_with_progress(&mut query, &source, buff_size, &options, &mut sink, |_: &Progress| {}) {
+            Ok(summary) => Ok((summary, sink)),
+            Err(e) => match e.downcast::<Interrupted>() {
+                Ok(interrupted) => Err(interrupted as RunError),
+                Err(e) => Err(format!("{}: {}", source, e).into()),
+            },
+        }
+    });
+    task.await?
+}
+
+/// Runs query against each of sources, up to one per CPU at once, and returns their results in
+/// the order of sources. Blocks until every run is done, so call it from outside the runtime.
+pub fn run_concurrently(query: &Query,
+                        sources: &[Location],
+                        buff_size: usize,
+                        options: &Options) -> Result<Vec<(RunSummary, MemorySink)>, Box<dyn std::error::Error>> {
+    let parallel = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let runs = futures::stream::iter(sources.iter().cloned())
+        .map(|source| run(query.clone(), source, buff_size, options.clone()))
+        .buffered(parallel);
+
+    let results: Vec<_> = runtime()?.block_on(runs.collect());
+    let mut outcomes = Vec::new();
+    for result in results {
+        outcomes.push(result.map_err(|e| e as Box<dyn std::error::Error>)?);
+    }
+    Ok(outcomes)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asa::{Level, Reporter, SharedReporter};
+    use crate::query::{Code, Provider};
+    use std::sync::{Arc, Mutex};
+
+    /// Keeps the messages it is handed.
+    struct Collect(Arc<Mutex<Vec<String>>>);
+
+    impl Reporter for Collect {
+        fn report(&self, _level: Level, message: &str) {
+            self.0.lock().unwrap().push(message.to_string());
+        }
+    }
+
+    #[test]
+    fn test_run_concurrently() {
+        let mut q = Query::new();
+        q.providers.push(Provider::new(4));
+        q.codes.push(Code::new(&String::from("*"), &String::from("Code 3")));
+
+        let sources = [Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz")),
+                       Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/basic_test.json.gz")),
+                       Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz"))];
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let mut options = Options::new();
+        options.reporter = SharedReporter::new(Collect(messages.clone()));
+        let outcomes = run_concurrently(&q, &sources, 256, &options).unwrap();
+        assert_eq!(outcomes.len(), 3);
+        // The runs on the runtime report to the reporter of the options
+        assert_eq!(messages.lock().unwrap().iter().filter(|m| *m == "Processing provider_references...").count(), 3);
+
+        // Same rows as a run on its own, in the order of the sources
+        let mut sink = MemorySink::new();
+        let summary = asa::run(&mut q.clone(), &sources[0], 256, &Options::new(), &mut sink).unwrap();
+        assert!(!sink.records.is_empty());
+        assert_eq!(outcomes[0].1, sink);
+        assert_eq!(outcomes[2].1, sink);
+        assert_eq!(outcomes[0].0.stats.rows_written, summary.stats.rows_written);
+
+        let missing = [Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/missing.json.gz"))];
+        assert!(run_concurrently(&q, &missing, 256, &Options::new()).is_err());
+    }
+}
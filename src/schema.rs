@@ -0,0 +1,77 @@
+//! # schema
+//!
+//! Versions of the CMS Transparency in Coverage in-network rates schema, as declared by the top
+//! level version field of a file.
+//!
+//! mrfy reads the 1.x layout. Version 2.0 files keep that layout but add keys mrfy doesn't
+//! write out (V2_KEYS), which are skipped without being reported as unsupported when the file
+//! declares 2.x before them. Other versions are read as 1.x with a warning.
+
+/// A schema version mrfy knows.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SchemaVersion {
+    V1,
+    V2,
+}
+
+/// Versions mrfy fully supports, for messages.
+pub const SUPPORTED: &str = "1.x, 2.0";
+
+/// (context, key) of the keys version 2.0 adds that mrfy skips. Contexts are those of
+/// unsupported keys.
+const V2_KEYS: [(&str, &str); 4] = [("top level", "issuer_name"),
+                                    ("top level", "plan_sponsor_name"),
+                                    ("in_network", "severity_of_illness"),
+                                    ("negotiated_prices", "setting")];
+
+impl SchemaVersion {
+    /// Parses a version field such as "1.3.1", "2.0.0" or "v2". Returns None for versions mrfy
+    /// doesn't fully support.
+    pub fn parse(version: &str) -> Option<Self> {
+        let version = version.trim();
+        let version = version.strip_prefix(['v', 'V']).unwrap_or(version);
+        let (major, rest) = version.split_once('.').unwrap_or((version, ""));
+        if rest.split('.').any(|part| part.is_empty() && !rest.is_empty()) ||
+           !major.bytes().chain(rest.bytes()).all(|b| b.is_ascii_digit() || b == b'.') {
+            return None;
+        }
+        let minor = rest.split('.').next().unwrap_or("");
+        match (major, minor) {
+            ("1", _) => Some(SchemaVersion::V1),
+            ("2", "" | "0") => Some(SchemaVersion::V2),
+            _ => None,
+        }
+    }
+
+    /// Returns true if key found in context is part of this version of the schema, though mrfy
+    /// doesn't write it out.
+    pub fn defines_key(&self, context: &str, key: &str) -> bool {
+        match self {
+            SchemaVersion::V1 => false,
+            SchemaVersion::V2 => V2_KEYS.contains(&(context, key)),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema_version() {
+        assert_eq!(SchemaVersion::parse("1.3.1"), Some(SchemaVersion::V1));
+        assert_eq!(SchemaVersion::parse("1"), Some(SchemaVersion::V1));
+        assert_eq!(SchemaVersion::parse("2.0.0"), Some(SchemaVersion::V2));
+        assert_eq!(SchemaVersion::parse(" v2 "), Some(SchemaVersion::V2));
+        assert_eq!(SchemaVersion::parse("2.1"), None);
+        assert_eq!(SchemaVersion::parse("3.0"), None);
+        assert_eq!(SchemaVersion::parse("1..0"), None);
+        assert_eq!(SchemaVersion::parse("latest"), None);
+        assert_eq!(SchemaVersion::parse(""), None);
+
+        assert!(SchemaVersion::V2.defines_key("negotiated_prices", "setting"));
+        assert!(!SchemaVersion::V1.defines_key("negotiated_prices", "setting"));
+        assert!(!SchemaVersion::V2.defines_key("in_network", "setting"));
+    }
+}
@@ -0,0 +1,198 @@
+//! # serve
+//!
+//! `mrfy serve`: answers rate lookups over HTTP from the seek indexes of one or more files (see
+//! index), so a question reads a few elements of each file rather than all of it.
+//!
+//! `GET /rates?npi=<npi>&code=<billing code>` returns a JSON array with one object per output
+//! row, holding the columns of asa::COLUMNS and the file the row came from. Requests are served
+//! one at a time, which is plenty for an analyst's lookups on their own machine.
+//!
+//! `GET /metrics` returns the requests, errors and rows returned so far in the Prometheus text
+//! format (see metrics).
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use crate::asa::{CancelToken, Options};
+use crate::index::IndexedFile;
+use crate::metrics::{self, Metrics};
+
+/// A response: status code and JSON body.
+type Response = (u16, serde_json::Value);
+
+/// Listens on addr and answers requests from files until cancel is cancelled.
+pub fn serve(files: &[IndexedFile],
+             addr: &str,
+             options: &Options,
+             cancel: &CancelToken) -> Result<(), Box<dyn std::error::Error>> {
+    let listener = TcpListener::bind(addr)?;
+    // Polled so ctrl-C is noticed between requests
+    listener.set_nonblocking(true)?;
+    options.reporter.status(format_args!("Serving {} files on http://{}/rates?npi=...&code=...",
+                                         files.len(), listener.local_addr()?));
+
+    let metrics = Metrics::default();
+    while !cancel.is_cancelled() {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(e) = handle(stream, files, options, &metrics) {
+                    options.reporter.warn(format_args!("request failed: {}", e));
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads one request from stream and writes the response, counting it in metrics.
+fn handle(stream: TcpStream,
+          files: &[IndexedFile],
+          options: &Options,
+          metrics: &Metrics) -> Result<(), Box<dyn std::error::Error>> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Headers aren't used, but are read so the client sees its request consumed
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => None,
+        (Some("GET"), Some(target)) => Some(respond(target, files, options)),
+        (Some(_), Some(_)) => Some(error(405, "only GET is supported")),
+        _ => Some(error(400, "malformed request")),
+    };
+
+    let (status, content_type, body) = match response {
+        Some((status, body)) => {
+            metrics.add_request(body.as_array().map_or(0, |rows| rows.len() as u64));
+            if status != 200 {
+                metrics.add_error();
+            }
+            (status, "application/json", body.to_string())
+        }
+        None => (200, metrics::CONTENT_TYPE, metrics.render()),
+    };
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    };
+    let mut stream = reader.into_inner();
+    write!(stream, "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+           status, reason, content_type, body.len(), body)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Returns an error response with message.
+fn error(status: u16, message: &str) -> Response {
+    (status, serde_json::json!({ "error": message }))
+}
+
+/// Answers a request for target (path and query string).
+fn respond(target: &str, files: &[IndexedFile], options: &Options) -> Response {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if path != "/rates" {
+        return error(404, "unknown path, use /rates?npi=...&code=...");
+    }
+
+    let params: Vec<(String, String)> = query.split('&')
+        .filter_map(|p| p.split_once('='))
+        .map(|(k, v)| (decode(k), decode(v)))
+        .collect();
+    let param = |name: &str| params.iter().find(|(k, _)| k == name).map(|(_, v)| v.trim());
+
+    let Some(npi) = param("npi").and_then(|n| n.parse::<u64>().ok()) else {
+        return error(400, "npi is missing or not a number");
+    };
+    let Some(code) = param("code").filter(|c| !c.is_empty()) else {
+        return error(400, "code is missing");
+    };
+
+    let mut rows = Vec::new();
+    for file in files {
+        let sink = match file.lookup(npi, code, options) {
+            Ok(sink) => sink,
+            Err(e) => return error(500, &format!("{}: {}", file.name, e)),
+        };
+        for record in sink.records {
+            let mut row = serde_json::Map::new();
+            row.insert(String::from("file"), file.name.clone().into());
+            for (column, value) in sink.header.iter().zip(record) {
+                row.insert(column.clone(), value.into());
+            }
+            rows.push(serde_json::Value::Object(row));
+        }
+    }
+
+    (200, serde_json::Value::Array(rows))
+}
+
+/// Decodes a query string component: + is a space and %XX a byte.
+fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'+', _) => decoded.push(b' '),
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                i += 2;
+            }
+            (b, _) => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::Location;
+
+    #[test]
+    fn test_respond() {
+        assert_eq!(decode("Code+3%2c%zz"), "Code 3,%zz");
+
+        let source = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz"));
+        let dir = std::env::temp_dir().join(format!("mrfy_serve_{}", std::process::id()));
+        let options = Options::new();
+        let files = [IndexedFile::open(&source, 256, &dir, &options.reporter).unwrap()];
+
+        let (status, body) = respond("/rates?npi=4&code=Code%203", &files, &options);
+        assert_eq!(status, 200);
+        let rows = body.as_array().unwrap();
+        assert!(!rows.is_empty());
+        assert_eq!(rows[0]["npi"], "4");
+        assert_eq!(rows[0]["billing_code"], "CODE 3");
+        assert_eq!(rows[0]["file"], source.to_string());
+
+        assert_eq!(respond("/rates?npi=4&code=nope", &files, &options), (200, serde_json::json!([])));
+        assert_eq!(respond("/rates?code=Code%203", &files, &options).0, 400);
+        assert_eq!(respond("/rates?npi=4", &files, &options).0, 400);
+        assert_eq!(respond("/other", &files, &options).0, 404);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
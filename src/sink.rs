@@ -0,0 +1,171 @@
+//! # sink
+//!
+//! Where the records matched by a run go. asa::run hands each output row to a RecordSink:
+//! CsvSink writes them as CSV (what the command line does) and MemorySink collects them, e.g.
+//! for code that embeds the parser. Other output formats implement the same trait.
+
+use std::io::Write;
+use std::time::Duration;
+
+use crate::clock::Stopwatch;
+
+/// One output row, the values of the columns given to RecordSink::write_header in order. The
+/// first ones are always those of asa::COLUMNS.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MatchedRecord<'a> {
+    pub values: &'a [&'a str],
+}
+
+/// Receives the header and rows of a run.
+pub trait RecordSink {
+    /// Called once with the column names, before the first record (not with Options::no_header).
+    fn write_header(&mut self, columns: &[&str]) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Called for each output row. The row should be complete (e.g. flushed) on return, a
+    /// cancelled run stops between two rows.
+    fn write_record(&mut self, record: &MatchedRecord) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Called once no more records will be written, at the end of in_network or when the run is
+    /// cancelled.
+    fn finish(&mut self) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+/// Writes the header and records as CSV, flushing each row. The output is dropped when the sink
+/// is finished, e.g. to end a compressed stream.
+pub struct CsvSink<W: Write> {
+    /// None once finished
+    writer: Option<csv::Writer<W>>,
+}
+
+impl<W: Write> CsvSink<W> {
+    /// Creates a CsvSink writing to out.
+    pub fn new(out: W) -> Self {
+        Self { writer: Some(csv::Writer::from_writer(out)) }
+    }
+
+    /// Returns the writer, or an error once finished.
+    fn writer(&mut self) -> Result<&mut csv::Writer<W>, Box<dyn std::error::Error>> {
+        self.writer.as_mut().ok_or_else(|| "the CSV output is already finished".into())
+    }
+}
+
+impl<W: Write> RecordSink for CsvSink<W> {
+    fn write_header(&mut self, columns: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+        let writer = self.writer()?;
+        writer.write_record(columns)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &MatchedRecord) -> Result<(), Box<dyn std::error::Error>> {
+        let writer = self.writer()?;
+        writer.write_record(record.values)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps the header and records in memory.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MemorySink {
+    pub header: Vec<String>,
+    pub records: Vec<Vec<String>>,
+}
+
+impl MemorySink {
+    /// Creates an empty MemorySink.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl RecordSink for MemorySink {
+    fn write_header(&mut self, columns: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+        self.header = columns.iter().map(|c| c.to_string()).collect();
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &MatchedRecord) -> Result<(), Box<dyn std::error::Error>> {
+        self.records.push(record.values.iter().map(|v| v.to_string()).collect());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+}
+
+/// Hands records on to another sink, adding up the time spent in it.
+pub struct TimedSink<'a> {
+    inner: &'a mut dyn RecordSink,
+    /// Time spent writing the header and records and finishing so far
+    pub elapsed: Duration,
+}
+
+impl<'a> TimedSink<'a> {
+    /// Creates a TimedSink writing to inner.
+    pub fn new(inner: &'a mut dyn RecordSink) -> Self {
+        Self { inner, elapsed: Duration::ZERO }
+    }
+
+    /// Returns the result of f called on the inner sink, timing it.
+    fn timed<T>(&mut self, f: impl FnOnce(&mut dyn RecordSink) -> T) -> T {
+        let start = Stopwatch::start();
+        let result = f(&mut *self.inner);
+        self.elapsed += start.elapsed();
+        result
+    }
+}
+
+impl RecordSink for TimedSink<'_> {
+    fn write_header(&mut self, columns: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+        self.timed(|sink| sink.write_header(columns))
+    }
+
+    fn write_record(&mut self, record: &MatchedRecord) -> Result<(), Box<dyn std::error::Error>> {
+        self.timed(|sink| sink.write_record(record))
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.timed(|sink| sink.finish())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sinks() {
+        let values = ["1", "a,b", "2.5"];
+        let record = MatchedRecord { values: &values };
+
+        let mut out = Vec::new();
+        let mut sink = CsvSink::new(&mut out);
+        sink.write_header(&["npi", "name", "negotiated_rate"]).unwrap();
+        sink.write_record(&record).unwrap();
+        sink.finish().unwrap();
+        drop(sink);
+        assert_eq!(String::from_utf8(out).unwrap(), "npi,name,negotiated_rate\n1,\"a,b\",2.5\n");
+
+        let mut sink = MemorySink::new();
+        sink.write_header(&["npi", "name", "negotiated_rate"]).unwrap();
+        sink.write_record(&record).unwrap();
+        assert_eq!(sink.header, vec!["npi", "name", "negotiated_rate"]);
+        assert_eq!(sink.records, vec![vec!["1", "a,b", "2.5"]]);
+
+        let mut inner = MemorySink::new();
+        let mut timed = TimedSink::new(&mut inner);
+        timed.write_record(&record).unwrap();
+        assert!(!timed.elapsed.is_zero());
+        assert_eq!(inner.records.len(), 1);
+    }
+}
@@ -0,0 +1,567 @@
+//! # source
+//!
+//! Opens the data file for stream parsing and wraps it in the matching decoder.
+//!
+//! Anything implementing DataSource can be read: it hands out the stored bytes and the
+//! decompression is done here. Location is the DataSource of the command line. Data can come
+//! from a local file, from standard input (a path of -), or (with the `http` feature) be
+//! streamed straight from a payer's transparency URL, so multi-GB files never need to be staged
+//! on disk. With the `object-store` feature s3://, gs:// and az:// paths are read from cloud
+//! storage. With the `tokio` feature both kinds of remote file are downloaded asynchronously
+//! (see remote).
+//!
+//! BuffSize is the size of the buffer the decompressed data is read through, given in bytes or
+//! worked out from the memory available and the size of the file.
+
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::rc::Rc;
+use std::time::Duration;
+
+use flate2::read::MultiGzDecoder;
+
+use crate::asa::SharedReporter;
+use crate::clock::Stopwatch;
+
+/// Something MRF data can be read from, possibly more than once.
+pub trait DataSource: std::fmt::Display {
+    /// Opens the data from the beginning and returns a reader over the stored bytes, which may be
+    /// compressed. What the reader recovers from while reading, e.g. a dropped connection, goes
+    /// to reporter.
+    fn open_raw(&self, reporter: &SharedReporter) -> Result<Box<dyn Read>, Box<dyn std::error::Error>>;
+
+    /// Opens the data from the beginning again, for a second pass over a file that lists its
+    /// provider_references after in_network. Opens it the same way by default.
+    fn reopen_raw(&self, reporter: &SharedReporter) -> Result<Box<dyn Read>, Box<dyn std::error::Error>> {
+        self.open_raw(reporter)
+    }
+
+    /// Returns the size in bytes of the stored data, if it is known before reading it.
+    fn stored_size(&self) -> Option<u64> {
+        None
+    }
+
+    /// Opens the data from the beginning and returns a reader over the decompressed JSON.
+    fn open(&self, reporter: &SharedReporter) -> Result<Box<dyn Read>, Box<dyn std::error::Error>> {
+        decompress(self.open_raw(reporter)?, &self.to_string())
+    }
+
+    /// Same as open, but for a second pass (see reopen_raw). Called by asa::run when the file
+    /// needs to be re-read.
+    fn reopen(&self, reporter: &SharedReporter) -> Result<Box<dyn Read>, Box<dyn std::error::Error>> {
+        decompress(self.reopen_raw(reporter)?, &self.to_string())
+    }
+}
+
+/// Where the MRF data comes from, as given on the command line.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Location {
+    /// A file on local disk
+    Path(std::path::PathBuf),
+    /// Standard input, given as a path of -
+    Stdin,
+    /// An http(s) URL streamed with retry and resume (requires the `http` feature)
+    Url(String),
+    /// An s3://, gs:// or az:// URL (requires the `object-store` feature)
+    ObjectStore(String),
+}
+
+impl From<std::path::PathBuf> for Location {
+    /// Treats - as standard input and paths with a cloud storage scheme as object store URLs,
+    /// anything else as a file.
+    fn from(path: std::path::PathBuf) -> Self {
+        const SCHEMES: [&str; 5] = ["s3://", "s3a://", "gs://", "az://", "abfs://"];
+
+        match path.to_str() {
+            Some("-") => Location::Stdin,
+            Some(s) if SCHEMES.iter().any(|scheme| s.starts_with(scheme)) => {
+                Location::ObjectStore(String::from(s))
+            }
+            _ => Location::Path(path),
+        }
+    }
+}
+
+impl DataSource for Location {
+    #[cfg_attr(not(feature = "http"), allow(unused_variables))]
+    fn open_raw(&self, reporter: &SharedReporter) -> Result<Box<dyn Read>, Box<dyn std::error::Error>> {
+        match self {
+            Location::Path(path) => Ok(Box::new(File::open(path)?)),
+            Location::Stdin => Ok(Box::new(std::io::stdin())),
+            #[cfg(feature = "http")]
+            Location::Url(url) => Ok(Box::new(http::HttpReader::connect(url, reporter.clone())?)),
+            #[cfg(not(feature = "http"))]
+            Location::Url(url) => {
+                Err(format!("cannot stream '{}'; rebuild mrfy with the `http` feature to read URLs", url).into())
+            }
+            // With the `tokio` feature cloud storage is downloaded on the shared runtime
+            #[cfg(feature = "tokio")]
+            Location::ObjectStore(_) => Ok(Box::new(crate::remote::download(self)?)),
+            #[cfg(all(feature = "object-store", not(feature = "tokio")))]
+            Location::ObjectStore(url) => Ok(Box::new(object::ObjectReader::open(url)?)),
+            #[cfg(not(feature = "object-store"))]
+            Location::ObjectStore(url) => {
+                Err(format!("cannot read '{}'; rebuild mrfy with the `object-store` feature to read cloud storage", url).into())
+            }
+        }
+    }
+
+    /// Standard input can't be read twice.
+    fn reopen_raw(&self, reporter: &SharedReporter) -> Result<Box<dyn Read>, Box<dyn std::error::Error>> {
+        match self {
+            Location::Stdin => {
+                Err("provider_references comes after in_network, so the file must be read twice, \
+                     which standard input can't be; save it to a file first".into())
+            }
+            _ => self.open_raw(reporter),
+        }
+    }
+
+    fn stored_size(&self) -> Option<u64> {
+        match self {
+            Location::Path(path) => std::fs::metadata(path).ok().map(|meta| meta.len()),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Location::Path(path) => write!(f, "{}", path.display()),
+            Location::Stdin => write!(f, "standard input"),
+            Location::Url(url) | Location::ObjectStore(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+/// Buffer size used when none is given, or when auto can't tell how much memory there is.
+pub const DEFAULT_BUFF_SIZE: usize = 1024 * 1024 * 128; // 128 MiB
+
+/// Bounds of an auto buffer size.
+const AUTO_MIN: usize = 64 * 1024;
+const AUTO_MAX: usize = 1024 * 1024 * 1024;
+
+/// Size of the buffer the data file is read through.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BuffSize {
+    /// This many bytes
+    Bytes(usize),
+    /// Sized from the available memory and the size of the file, see resolve
+    Auto,
+}
+
+impl BuffSize {
+    /// Parses "auto", or a size in bytes with an optional K, M or G suffix (powers of 1024, in
+    /// any case and with an optional trailing B or iB), e.g. 65536, 256M, 1G or 512KiB.
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("auto") {
+            return Some(BuffSize::Auto);
+        }
+        let upper = s.to_ascii_uppercase();
+        let unit = upper.strip_suffix("IB").or(upper.strip_suffix('B')).unwrap_or(&upper);
+        let (digits, shift) = match unit.as_bytes().last()? {
+            b'K' => (&unit[..unit.len() - 1], 10),
+            b'M' => (&unit[..unit.len() - 1], 20),
+            b'G' => (&unit[..unit.len() - 1], 30),
+            _ if unit.len() == upper.len() || unit.len() + 1 == upper.len() => (unit, 0),
+            _ => return None,
+        };
+        let size: usize = digits.trim().parse().ok()?;
+        match size.checked_mul(1 << shift) {
+            Some(bytes) if bytes > 0 => Some(BuffSize::Bytes(bytes)),
+            _ => None,
+        }
+    }
+
+    /// Returns the buffer size in bytes for reading source. Auto takes an eighth of the available
+    /// memory, but no more than the stored size of the data (the decompressed data is larger), kept
+    /// between 64 KiB and 1 GiB. Without a memory figure (other than on Linux) it is the default.
+    pub fn resolve(&self, source: &dyn DataSource) -> usize {
+        let available = match self {
+            BuffSize::Bytes(bytes) => return *bytes,
+            BuffSize::Auto => available_memory(),
+        };
+        let Some(available) = available else {
+            return DEFAULT_BUFF_SIZE;
+        };
+        let mut size = (available / 8) as usize;
+        if let Some(stored) = source.stored_size() {
+            size = size.min(stored as usize);
+        }
+        size.clamp(AUTO_MIN, AUTO_MAX)
+    }
+}
+
+/// Returns the memory available for new allocations in bytes (MemAvailable of /proc/meminfo),
+/// None other than on Linux.
+fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+/// Read adapter that counts the bytes passing through it.
+/// The count is shared so it can still be read after the reader is handed to the parser.
+pub struct CountingReader<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R: Read> CountingReader<R> {
+    /// Wraps inner with a fresh counter and returns the counter alongside it.
+    pub fn new(inner: R) -> (Self, Rc<Cell<u64>>) {
+        Self::with_counter(inner, Rc::new(Cell::new(0)))
+    }
+
+    /// Wraps inner, adding to an existing counter.
+    pub fn with_counter(inner: R, count: Rc<Cell<u64>>) -> (Self, Rc<Cell<u64>>) {
+        (Self { inner, count: count.clone() }, count)
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+/// Read adapter that adds the time spent reading through it to a shared total, to tell how
+/// much of a run went to reading and decompressing the data file.
+pub struct TimedReader<R> {
+    inner: R,
+    elapsed: Rc<Cell<Duration>>,
+}
+
+impl<R: Read> TimedReader<R> {
+    /// Wraps inner, adding to elapsed.
+    pub fn new(inner: R, elapsed: Rc<Cell<Duration>>) -> Self {
+        Self { inner, elapsed }
+    }
+}
+
+impl<R: Read> Read for TimedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let start = Stopwatch::start();
+        let n = self.inner.read(buf);
+        self.elapsed.set(self.elapsed.get() + start.elapsed());
+        n
+    }
+}
+
+/// Compression formats the data file may be stored in.
+#[derive(Debug, PartialEq)]
+enum Compression {
+    Gzip,
+    Zstd,
+    None,
+}
+
+/// Works out the compression of the data from its leading magic bytes.
+/// Data that starts like JSON is read as is (e.g. table of contents files).
+/// Falls back to the extension of name when the magic bytes are not recognized,
+/// and finally to gzip (the format of the Aetna files).
+fn detect_compression(name: &str, magic: &[u8]) -> Compression {
+    const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+    const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+    const UTF8_BOM: [u8; 3] = [0xef, 0xbb, 0xbf];
+
+    if magic.starts_with(&GZIP_MAGIC) {
+        return Compression::Gzip;
+    }
+    if magic.starts_with(&ZSTD_MAGIC) {
+        return Compression::Zstd;
+    }
+
+    let text = magic.strip_prefix(&UTF8_BOM).unwrap_or(magic);
+    match text.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'{') | Some(b'[') => return Compression::None,
+        _ => {}
+    }
+
+    if name.ends_with(".zst") || name.ends_with(".zstd") {
+        Compression::Zstd
+    }
+    else {
+        Compression::Gzip
+    }
+}
+
+/// Peeks at the start of the stream (without consuming it) and wraps it in the matching decoder.
+/// zstd support requires building with the `zstd` feature.
+fn decompress<R: Read + 'static>(read: R, name: &str) -> Result<Box<dyn Read>, Box<dyn std::error::Error>> {
+    let mut reader = BufReader::new(read);
+    let magic = reader.fill_buf()?;
+    let bom = magic.starts_with(&[0xef, 0xbb, 0xbf]);
+
+    match detect_compression(name, magic) {
+        // Some payer files are several gzip members cat-ed together, so keep reading past the
+        // end of the first member
+        Compression::Gzip => Ok(Box::new(MultiGzDecoder::new(reader))),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => Ok(Box::new(zstd::stream::read::Decoder::with_buffer(reader)?)),
+        #[cfg(not(feature = "zstd"))]
+        Compression::Zstd => {
+            Err(format!("'{}' is zstd compressed; rebuild mrfy with the `zstd` feature to read it", name).into())
+        }
+        Compression::None => {
+            // The JSON parser doesn't expect a byte order mark
+            if bom {
+                reader.consume(3);
+            }
+            Ok(Box::new(reader))
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+mod http {
+    //! Streams a file over http(s), reconnecting with a Range request to resume from the last
+    //! byte received whenever the connection drops.
+
+    use std::io::{self, Read};
+    use std::time::Duration;
+
+    use crate::asa::SharedReporter;
+
+    const MAX_RETRIES: u32 = 8;
+
+    /// Read adapter over an http response body that resumes on failure.
+    pub struct HttpReader {
+        url: String,
+        inner: Box<dyn Read + Send + Sync>,
+        offset: u64,
+        total: Option<u64>,
+        /// Told about the reconnects
+        reporter: SharedReporter,
+    }
+
+    impl HttpReader {
+        /// Starts streaming url from the first byte.
+        pub fn connect(url: &str, reporter: SharedReporter) -> io::Result<Self> {
+            let (inner, total) = request(url, 0)?;
+            Ok(Self { url: String::from(url), inner, offset: 0, total, reporter })
+        }
+
+        /// Re-establishes the connection at self.offset, backing off between attempts.
+        fn resume(&mut self, attempt: u32, cause: &io::Error) -> io::Result<()> {
+            self.reporter.warn(format_args!("connection to {} failed ({}), resuming at byte {} (attempt {} of {})...",
+                                            self.url, cause, self.offset, attempt, MAX_RETRIES));
+            std::thread::sleep(Duration::from_secs(2u64.pow(attempt.min(5))));
+            let (inner, _) = request(&self.url, self.offset)?;
+            self.inner = inner;
+            Ok(())
+        }
+    }
+
+    impl Read for HttpReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut attempt = 0;
+            loop {
+                let err = match self.inner.read(buf) {
+                    // A short body means the server hung up early
+                    Ok(0) if !buf.is_empty() && self.total.is_some_and(|t| self.offset < t) => {
+                        io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed early")
+                    }
+                    Ok(n) => {
+                        self.offset += n as u64;
+                        return Ok(n);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => e,
+                };
+
+                attempt += 1;
+                if attempt > MAX_RETRIES {
+                    return Err(err);
+                }
+                // Failed reconnects count against the same retry budget
+                if let Err(e) = self.resume(attempt, &err) {
+                    self.reporter.warn(format_args!("reconnect failed: {}", e));
+                }
+            }
+        }
+    }
+
+    /// Issues a GET for url starting at offset.
+    /// Returns the body reader and the total size of the file if the server reported it.
+    fn request(url: &str, offset: u64) -> io::Result<(Box<dyn Read + Send + Sync>, Option<u64>)> {
+        let mut req = ureq::get(url);
+        if offset > 0 {
+            req = req.set("Range", &format!("bytes={}-", offset));
+        }
+        let resp = req.call().map_err(io::Error::other)?;
+
+        let length: Option<u64> = resp.header("Content-Length").and_then(|l| l.parse().ok());
+        let ranged = resp.status() == 206;
+        let mut body = resp.into_reader();
+
+        if offset > 0 && !ranged {
+            // Server ignored the Range header, so throw away what we already have
+            io::copy(&mut (&mut body).take(offset), &mut io::sink())?;
+        }
+
+        let total = if ranged { length.map(|l| l + offset) } else { length };
+
+        Ok((body, total))
+    }
+}
+
+#[cfg(all(feature = "object-store", not(feature = "tokio")))]
+mod object {
+    //! Reads from S3, GCS or Azure through the object_store crate.
+    //! object_store is async, so a single threaded tokio runtime drives the download and the
+    //! chunks are handed out through a blocking Read.
+
+    use std::io::{self, Read};
+
+    use bytes::Bytes;
+    use futures::StreamExt;
+    use futures::stream::BoxStream;
+
+    /// Blocking Read adapter over an object store download.
+    pub struct ObjectReader {
+        runtime: tokio::runtime::Runtime,
+        stream: BoxStream<'static, object_store::Result<Bytes>>,
+        chunk: Bytes,
+    }
+
+    impl ObjectReader {
+        /// Starts downloading url. Credentials and region are taken from the usual environment
+        /// variables (AWS_ACCESS_KEY_ID, GOOGLE_SERVICE_ACCOUNT, AZURE_STORAGE_ACCOUNT_NAME, ...).
+        pub fn open(url: &str) -> Result<Self, Box<dyn std::error::Error>> {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+
+            let url = url::Url::parse(url)?;
+            let options = std::env::vars().map(|(k, v)| (k.to_ascii_lowercase(), v));
+            let (store, path) = object_store::parse_url_opts(&url, options)?;
+
+            let stream = runtime.block_on(async move {
+                store.get(&path).await.map(|result| result.into_stream())
+            })?;
+
+            Ok(Self { runtime, stream, chunk: Bytes::new() })
+        }
+    }
+
+    impl Read for ObjectReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            while self.chunk.is_empty() {
+                match self.runtime.block_on(self.stream.next()) {
+                    Some(Ok(chunk)) => self.chunk = chunk,
+                    Some(Err(e)) => return Err(io::Error::other(e)),
+                    None => return Ok(0),
+                }
+            }
+
+            let n = buf.len().min(self.chunk.len());
+            buf[..n].copy_from_slice(&self.chunk.split_to(n));
+            Ok(n)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_compression() {
+        // Magic bytes win over the extension
+        assert_eq!(detect_compression("file.json.zst", &[0x1f, 0x8b, 0x08, 0x00]), Compression::Gzip);
+        assert_eq!(detect_compression("file.json.gz", &[0x28, 0xb5, 0x2f, 0xfd]), Compression::Zstd);
+
+        // Unknown magic falls back to the extension, then gzip
+        assert_eq!(detect_compression("file.json.zst", &[0x00]), Compression::Zstd);
+        assert_eq!(detect_compression("file.json", &[]), Compression::Gzip);
+
+        // Uncompressed JSON
+        assert_eq!(detect_compression("toc.json", b"  \n{\"reporting"), Compression::None);
+        assert_eq!(detect_compression("toc.json.gz", b"\xef\xbb\xbf["), Compression::None);
+    }
+
+    #[test]
+    fn test_multi_member_gzip() {
+        // multi_member.json.gz is basic_test.json split in two and gzipped as separate members
+        let single = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/basic_test.json.gz"));
+        let multi = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/multi_member.json.gz"));
+
+        let mut expected = String::new();
+        let mut actual = String::new();
+        let reporter = SharedReporter::default();
+        single.open(&reporter).unwrap().read_to_string(&mut expected).unwrap();
+        multi.open(&reporter).unwrap().read_to_string(&mut actual).unwrap();
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_buff_size() {
+        assert_eq!(BuffSize::parse("65536"), Some(BuffSize::Bytes(65_536)));
+        assert_eq!(BuffSize::parse("256M"), Some(BuffSize::Bytes(256 << 20)));
+        assert_eq!(BuffSize::parse("1g"), Some(BuffSize::Bytes(1 << 30)));
+        assert_eq!(BuffSize::parse("512KiB"), Some(BuffSize::Bytes(512 << 10)));
+        assert_eq!(BuffSize::parse("64kb"), Some(BuffSize::Bytes(64 << 10)));
+        assert_eq!(BuffSize::parse("100B"), Some(BuffSize::Bytes(100)));
+        assert_eq!(BuffSize::parse("Auto"), Some(BuffSize::Auto));
+        assert_eq!(BuffSize::parse("0"), None);
+        assert_eq!(BuffSize::parse("12T"), None);
+        assert_eq!(BuffSize::parse("M"), None);
+        assert_eq!(BuffSize::parse("1.5G"), None);
+        assert_eq!(BuffSize::parse("100iB"), None);
+
+        // Never more than a small local file, never less than the minimum
+        let source = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/basic_test.json.gz"));
+        assert_eq!(BuffSize::Bytes(100).resolve(&source), 100);
+        let auto = BuffSize::Auto.resolve(&source);
+        assert!(auto == AUTO_MIN || auto == DEFAULT_BUFF_SIZE, "{}", auto);
+    }
+
+    #[test]
+    fn test_source_from_path() {
+        let s3 = Location::from(std::path::PathBuf::from("s3://bucket/key.json.gz"));
+        let local = Location::from(std::path::PathBuf::from("data/key.json.gz"));
+
+        assert_eq!(s3, Location::ObjectStore(String::from("s3://bucket/key.json.gz")));
+        assert_eq!(local, Location::Path(std::path::PathBuf::from("data/key.json.gz")));
+        assert_eq!(Location::from(std::path::PathBuf::from("-")), Location::Stdin);
+        assert!(Location::Stdin.reopen_raw(&SharedReporter::default()).is_err());
+    }
+
+    /// A DataSource over bytes in memory.
+    struct Bytes(Vec<u8>);
+
+    impl std::fmt::Display for Bytes {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "bytes")
+        }
+    }
+
+    impl DataSource for Bytes {
+        fn open_raw(&self, _reporter: &SharedReporter) -> Result<Box<dyn Read>, Box<dyn std::error::Error>> {
+            Ok(Box::new(std::io::Cursor::new(self.0.clone())))
+        }
+    }
+
+    #[test]
+    fn test_data_source_trait() {
+        let path = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/basic_test.json.gz"));
+        let source = Bytes(std::fs::read("tests/testfiles/data_files/basic_test.json.gz").unwrap());
+        assert_eq!(source.stored_size(), None);
+        assert_eq!(path.stored_size(), Some(source.0.len() as u64));
+
+        // Decompressed the same way whatever the source, on every pass
+        let mut expected = String::new();
+        let mut actual = String::new();
+        let reporter = SharedReporter::default();
+        path.open(&reporter).unwrap().read_to_string(&mut expected).unwrap();
+        source.reopen(&reporter).unwrap().read_to_string(&mut actual).unwrap();
+        assert_eq!(actual, expected);
+    }
+}
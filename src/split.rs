@@ -0,0 +1,148 @@
+//! # split
+//!
+//! Splits an MRF into smaller MRFs (`mrfy split`), to process the parts in parallel, e.g. one per
+//! machine of a cluster.
+//!
+//! Each shard is a valid MRF with the metadata and all of provider_references of the file, and
+//! every Nth element of in_network, so the shards are about the same size and a query run on
+//! every shard finds the same rows as on the whole file. The file is read once and the shards are
+//! written as it is read, whatever the order of its sections.
+
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use json_event_parser::{JsonEvent, ReaderJsonParser, WriterJsonSerializer};
+
+use crate::asa::{create_output, next_event, unexpected_eof, CancelToken, ParseContext, SharedReporter};
+use crate::error::Interrupted;
+use crate::source::DataSource;
+
+/// Returns the paths of the shards of a file called name in dir: NAME-1-of-N.json.gz, ...
+pub fn shard_paths(name: &str, dir: &Path, shards: usize) -> Vec<PathBuf> {
+    let mut stem = name;
+    for extension in [".gz", ".zst", ".json"] {
+        stem = stem.strip_suffix(extension).unwrap_or(stem);
+    }
+    (1..=shards).map(|i| dir.join(format!("{}-{}-of-{}.json.gz", stem, i, shards))).collect()
+}
+
+/// Reads source and writes it split in as many shards as outs, returning the number of
+/// in_network elements written to each.
+pub fn split<W: Write>(source: &dyn DataSource,
+                       buff_size: usize,
+                       outs: Vec<W>,
+                       cancel: &CancelToken) -> Result<Vec<u64>, Box<dyn std::error::Error>> {
+    let mut ctx = ParseContext::new();
+    let reader = ctx.track_offsets(BufReader::with_capacity(buff_size, source.open(&SharedReporter::default())?));
+    let mut parser = ReaderJsonParser::new(reader);
+    let mut shards: Vec<WriterJsonSerializer<W>> = outs.into_iter().map(WriterJsonSerializer::new).collect();
+    let mut elements = vec![0u64; shards.len()];
+
+    let mut depth: u64 = 0;
+    let mut section = String::new();
+    // Shard of the in_network element being copied
+    let mut shard: Option<usize> = None;
+    let mut next_shard = 0;
+
+    loop {
+        let event = next_event(&mut parser, &mut ctx, "split")?;
+        if let JsonEvent::Eof = event {
+            return Err(Box::new(unexpected_eof(&ctx, "split")));
+        }
+
+        if shard.is_none() && section == "in_network" && depth == 2 && matches!(event, JsonEvent::StartObject) {
+            if cancel.is_cancelled() {
+                return Err(Box::new(Interrupted { objects: elements.iter().sum(), records: 0 }));
+            }
+            shard = Some(next_shard);
+            elements[next_shard] += 1;
+            next_shard = (next_shard + 1) % shards.len();
+        }
+
+        match &event {
+            JsonEvent::StartObject | JsonEvent::StartArray => depth += 1,
+            JsonEvent::EndObject | JsonEvent::EndArray => depth -= 1,
+            JsonEvent::ObjectKey(key) if depth == 1 => section = key.to_string(),
+            _ => {}
+        }
+
+        match shard {
+            Some(i) => {
+                shards[i].serialize_event(event)?;
+                if depth == 2 {
+                    shard = None;
+                }
+            }
+            None => {
+                for json in shards.iter_mut() {
+                    json.serialize_event(event.clone())?;
+                }
+            }
+        }
+        if depth == 0 {
+            break;
+        }
+    }
+
+    for json in shards {
+        json.finish()?.flush()?;
+    }
+    Ok(elements)
+}
+
+/// Splits source into shards gzip compressed files at paths (see shard_paths).
+pub fn split_to(source: &dyn DataSource,
+                buff_size: usize,
+                paths: &[PathBuf],
+                cancel: &CancelToken) -> Result<Vec<u64>, Box<dyn std::error::Error>> {
+    let mut outs = Vec::new();
+    for path in paths {
+        outs.push(create_output(path)?);
+    }
+    split(source, buff_size, outs, cancel)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asa::{self, Options};
+    use crate::query::{Code, Provider, Query};
+    use crate::sink::MemorySink;
+    use crate::source::Location;
+
+    #[test]
+    fn test_split() {
+        assert_eq!(shard_paths("big.json.gz", Path::new("out"), 2),
+                   [PathBuf::from("out/big-1-of-2.json.gz"), PathBuf::from("out/big-2-of-2.json.gz")]);
+
+        let source = Location::Path(PathBuf::from("tests/testfiles/data_files/intermediate.json.gz"));
+        let dir = std::env::temp_dir().join(format!("mrfy_split_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths = shard_paths("intermediate.json.gz", &dir, 3);
+        let elements = split_to(&source, 256, &paths, &CancelToken::new()).unwrap();
+        assert_eq!(elements.len(), 3);
+        assert!(elements.iter().max().unwrap() - elements.iter().min().unwrap() <= 1);
+
+        // The shards together have the rows of the whole file
+        let mut q = Query::new();
+        q.providers.push(Provider::new(4));
+        q.codes.push(Code::new(&String::from("*"), &String::from("*")));
+        let mut whole = MemorySink::new();
+        asa::run(&mut q.clone(), &source, 256, &Options::new(), &mut whole).unwrap();
+        assert!(!whole.records.is_empty());
+
+        let mut rows = Vec::new();
+        for path in paths.iter() {
+            let mut shard = MemorySink::new();
+            asa::run(&mut q.clone(), &Location::Path(path.clone()), 256, &Options::new(), &mut shard).unwrap();
+            rows.extend(shard.records);
+        }
+        let mut expected = whole.records.clone();
+        expected.sort();
+        rows.sort();
+        assert_eq!(rows, expected);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,142 @@
+//! # sql
+//!
+//! Shapes the rows of a query with SQL (`--sql`), built with the `sql` feature.
+//!
+//! The rows are written to a temporary CSV file (Rows) as the run goes, then read by DataFusion
+//! as the table `records`, with negotiated_rate as a number (null where it isn't one) and every
+//! other column as text. More CSV files can be registered as tables with --sql-table, e.g. a
+//! lookup to join against, with the types of their columns inferred. The result of the SQL query
+//! is written as CSV.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The rows of a run in a temporary CSV file, removed when dropped.
+pub struct Rows {
+    path: PathBuf,
+}
+
+impl Rows {
+    /// Creates the temporary file, returning it to write the rows to.
+    pub fn new() -> Result<(Self, File), std::io::Error> {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        // DataFusion only reads files with the extension it is told, .csv by default
+        let path = std::env::temp_dir().join(format!("mrfy-sql-{}-{}.csv",
+                                                    std::process::id(),
+                                                    FILES.fetch_add(1, Ordering::Relaxed)));
+        let file = File::options().write(true).create_new(true).open(&path)?;
+        Ok((Rows { path }, file))
+    }
+
+    /// Returns the path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Rows {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Runs sql over the CSV file at rows, with a header and the columns named by columns, as the
+/// table records, and over the CSV files of tables, and writes its result as CSV to out.
+/// Returns the number of rows written.
+#[cfg(feature = "sql")]
+pub fn query(rows: &Path,
+             columns: &[&str],
+             tables: &[(String, PathBuf)],
+             sql: &str,
+             out: impl Write) -> Result<u64, Box<dyn std::error::Error>> {
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+    runtime.block_on(datafusion_query(rows, columns, tables, sql, out))
+}
+
+#[cfg(not(feature = "sql"))]
+pub fn query(_rows: &Path,
+             _columns: &[&str],
+             _tables: &[(String, PathBuf)],
+             _sql: &str,
+             _out: impl Write) -> Result<u64, Box<dyn std::error::Error>> {
+    Err("rebuild mrfy with the `sql` feature to use --sql".into())
+}
+
+#[cfg(feature = "sql")]
+async fn datafusion_query(rows: &Path,
+                          columns: &[&str],
+                          tables: &[(String, PathBuf)],
+                          sql: &str,
+                          out: impl Write) -> Result<u64, Box<dyn std::error::Error>> {
+    use datafusion::arrow::csv::WriterBuilder;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::prelude::{CsvReadOptions, SessionContext};
+
+    let ctx = SessionContext::new();
+
+    // Read as text, so codes and tins keep their leading zeros, then the rate made a number
+    let schema = Schema::new(columns.iter().map(|c| Field::new(*c, DataType::Utf8, true)).collect::<Vec<_>>());
+    let path = rows.to_str().ok_or("the temporary directory isn't valid UTF-8")?;
+    ctx.register_csv("mrfy_rows", path, CsvReadOptions::new().has_header(true).schema(&schema)).await?;
+    let select: Vec<String> = columns.iter().map(|c| match *c {
+        "negotiated_rate" => format!("TRY_CAST(\"{0}\" AS DOUBLE) AS \"{0}\"", c),
+        _ => format!("\"{}\"", c),
+    }).collect();
+    ctx.sql(&format!("CREATE VIEW records AS SELECT {} FROM mrfy_rows", select.join(", "))).await?;
+
+    for (name, path) in tables {
+        let extension = path.extension().map_or(String::new(), |e| format!(".{}", e.to_string_lossy()));
+        let path = path.to_str().ok_or_else(|| format!("{} isn't valid UTF-8", path.display()))?;
+        ctx.register_csv(name.as_str(), path, CsvReadOptions::new().has_header(true).file_extension(&extension)).await?;
+    }
+
+    let frame = ctx.sql(sql).await?;
+    let names: Vec<String> = frame.schema().fields().iter().map(|f| f.name().clone()).collect();
+    let batches = frame.collect().await?;
+
+    let mut written = 0;
+    if batches.is_empty() {
+        // The header alone
+        let mut writer = csv::Writer::from_writer(out);
+        writer.write_record(&names)?;
+        writer.flush()?;
+        return Ok(written);
+    }
+    let mut writer = WriterBuilder::new().with_header(true).build(out);
+    for batch in batches.iter() {
+        writer.write(batch)?;
+        written += batch.num_rows() as u64;
+    }
+    Ok(written)
+}
+
+
+#[cfg(all(test, feature = "sql"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query() {
+        let (rows, mut file) = Rows::new().unwrap();
+        file.write_all(b"npi,billing_code,negotiated_rate\n1,0450,10.5\n1,0450,null\n2,0450,30\n2,99213,7\n").unwrap();
+        drop(file);
+        let dir = std::env::temp_dir().join(format!("mrfy_sql_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let fees = dir.join("fees.csv");
+        std::fs::write(&fees, "code,fee\n99213,20\n").unwrap();
+
+        let sql = "SELECT r.billing_code, COUNT(r.negotiated_rate) AS n, MAX(r.negotiated_rate) AS high, f.fee \
+                   FROM records r JOIN fees f ON r.billing_code = CAST(f.code AS VARCHAR) \
+                   GROUP BY r.billing_code, f.fee";
+        let mut out = Vec::new();
+        let written = query(rows.path(), &["npi", "billing_code", "negotiated_rate"],
+                            &[(String::from("fees"), fees)], sql, &mut out).unwrap();
+        assert_eq!(written, 1);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("billing_code,n,high,fee\n99213,1,7"), "{}", out);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,476 @@
+//! # stats
+//!
+//! Summarizes a file without a query, to scope it before running extractions.
+//!
+//! Counts in_network items per billing_code_type, distinct billing codes, provider groups and
+//! distinct NPIs, and summarizes negotiated rates for each negotiated_type (a percentage and a
+//! dollar amount can't be compared, so they are kept apart).
+//!
+//! With --group-by, aggregate summarizes the negotiated rates of the whole file by billing code
+//! (or other fields) instead: count, min, percentiles, mean and max of each group, computed while
+//! streaming so only one t-digest per group is kept in memory. Query runs write the same table
+//! for the rates they match with --rate-summary.
+//!
+//! Also lists the billing codes in a file, to write queries against what is actually there.
+
+use crate::asa::{next_event, unexpected_eof, ParseContext, SharedReporter};
+use crate::digest::TDigest;
+use crate::rate::normalize_rate;
+use crate::source::DataSource;
+
+use std::collections::{BTreeMap, HashSet};
+use std::io::{BufReader, Write};
+
+use json_event_parser::{ReaderJsonParser, JsonEvent};
+
+/// Count, min, max, mean and median of a set of rates, built one rate at a time.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateSummary {
+    pub count: u64,
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+    pub digest: TDigest,
+}
+
+impl Default for RateSummary {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RateSummary {
+    /// Creates an empty RateSummary.
+    pub fn new() -> Self {
+        Self {
+            count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            sum: 0.0,
+            digest: TDigest::new(),
+        }
+    }
+
+    /// Adds rate to the summary.
+    pub fn add(&mut self, rate: f64) {
+        self.count += 1;
+        self.min = self.min.min(rate);
+        self.max = self.max.max(rate);
+        self.sum += rate;
+        self.digest.add(rate);
+    }
+
+    /// Returns the mean of the rates added.
+    pub fn mean(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+
+    /// Returns the percentile p (0 to 100) of the rates added (estimated for large sets, see
+    /// digest).
+    pub fn percentile(&mut self, p: f64) -> f64 {
+        self.digest.quantile(p / 100.0).unwrap_or(f64::NAN)
+    }
+}
+
+/// Default percentiles of the rate summary tables.
+pub const PERCENTILES: [f64; 4] = [25.0, 50.0, 75.0, 90.0];
+
+/// Returns the column name of percentile p: median for 50, p25 for 25.
+pub fn percentile_label(p: f64) -> String {
+    match p {
+        50.0 => String::from("median"),
+        _ => format!("p{}", p),
+    }
+}
+
+/// Summary statistics of a file. Returned by scan.
+#[derive(Debug, Default)]
+pub struct FileStats {
+    /// Number of in_network items for each billing_code_type
+    pub items_by_code_type: BTreeMap<String, u64>,
+    /// Distinct (billing_code_type, billing_code) pairs
+    pub billing_codes: HashSet<(String, String)>,
+    /// Number of provider_references entries
+    pub provider_groups: u64,
+    /// Distinct NPIs in provider_references
+    pub npis: HashSet<u64>,
+    /// Negotiated rates for each negotiated_type
+    pub rates: BTreeMap<String, RateSummary>,
+}
+
+impl FileStats {
+    /// Prints the statistics to out.
+    pub fn print_out(&self, out: &mut impl Write) -> Result<(), std::io::Error> {
+        let items: u64 = self.items_by_code_type.values().sum();
+        writeln!(out, "in_network items: {}", items)?;
+        for (code_type, count) in self.items_by_code_type.iter() {
+            writeln!(out, "  {}: {}", code_type, count)?;
+        }
+        writeln!(out, "distinct billing codes: {}", self.billing_codes.len())?;
+        writeln!(out, "provider groups: {}", self.provider_groups)?;
+        writeln!(out, "distinct npis: {}", self.npis.len())?;
+        writeln!(out, "negotiated rates by negotiated_type (count, min, mean, max):")?;
+        for (neg_type, r) in self.rates.iter() {
+            writeln!(out, "  {}: {}, {:.2}, {:.2}, {:.2}", neg_type, r.count, r.min, r.mean(), r.max)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns value, or "null" if it is empty.
+fn or_null(value: String) -> String {
+    if value.is_empty() {
+        String::from("null")
+    }
+    else {
+        value
+    }
+}
+
+/// Reads the whole file from source and returns its summary statistics.
+pub fn scan(source: &dyn DataSource, buff_size: usize) -> Result<FileStats, Box<dyn std::error::Error>> {
+    let mut ctx = ParseContext::new();
+    let reader = ctx.track_offsets(BufReader::with_capacity(buff_size, source.open(&SharedReporter::default())?));
+    let mut parser = ReaderJsonParser::new(reader);
+
+    let mut stats = FileStats::default();
+
+    // Objects of interest are found by their depth:
+    // 1 top level, 3 provider_references entry or in_network item, 7 negotiated price
+    const ITEM: u64 = 3;
+    const PRICE: u64 = 7;
+
+    let mut depth: u64 = 0;
+    let mut section = String::new();
+    let mut key = String::new();
+
+    // Fields of the in_network item and negotiated price being read
+    let mut code_type = String::new();
+    let mut code = String::new();
+    let mut neg_type = String::new();
+    let mut rate: Option<f64> = None;
+
+    loop {
+        let event = {next_event(&mut parser, &mut ctx, "stats")?};
+        match event {
+            JsonEvent::StartObject | JsonEvent::StartArray => {
+                depth += 1;
+                if depth == ITEM && section == "provider_references" {
+                    stats.provider_groups += 1;
+                }
+            }
+            JsonEvent::EndObject | JsonEvent::EndArray => {
+                if depth == ITEM && section == "in_network" {
+                    let code_type = or_null(std::mem::take(&mut code_type));
+                    *stats.items_by_code_type.entry(code_type.clone()).or_insert(0) += 1;
+                    stats.billing_codes.insert((code_type, or_null(std::mem::take(&mut code))));
+                }
+                else if depth == PRICE && section == "in_network" {
+                    let neg_type = or_null(std::mem::take(&mut neg_type));
+                    if let Some(rate) = rate.take() {
+                        stats.rates.entry(neg_type).or_default().add(rate);
+                    }
+                }
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+            JsonEvent::ObjectKey(k) => {
+                if depth == 1 {
+                    section = k.to_string();
+                }
+                key.clear();
+                key.push_str(k.as_ref());
+            }
+            JsonEvent::String(value) | JsonEvent::Number(value) => {
+                if section == "provider_references" && key == "npi" {
+                    if let Ok(npi) = value.parse::<u64>() {
+                        stats.npis.insert(npi);
+                    }
+                }
+                else if section == "in_network" && depth == ITEM {
+                    if key == "billing_code_type" {
+                        code_type = value.to_string();
+                    }
+                    else if key == "billing_code" {
+                        code = value.to_string();
+                    }
+                }
+                else if section == "in_network" && depth == PRICE {
+                    if key == "negotiated_type" {
+                        neg_type = value.to_string();
+                    }
+                    else if key == "negotiated_rate" {
+                        // Rates like "$1,234.56" count as well
+                        rate = normalize_rate(&value).and_then(|r| r.parse().ok());
+                    }
+                }
+            }
+            JsonEvent::Eof => {
+                return Err(Box::new(unexpected_eof(&ctx, "stats")));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(stats)
+}
+
+/// Fields the rates of aggregate can be grouped by. The first three are fields of the in_network
+/// item, the others of the negotiated price.
+pub const GROUP_FIELDS: [&str; 5] = ["billing_code_type", "billing_code", "negotiation_arrangement",
+                                     "negotiated_type", "billing_class"];
+
+/// Reads the whole file from source and returns the summary of the negotiated rates of each
+/// group, keyed by the values of the group_by fields (from GROUP_FIELDS), in that order.
+/// Missing values are "null". Rates that are not numbers are left out.
+pub fn aggregate(source: &dyn DataSource,
+                 buff_size: usize,
+                 group_by: &[String]) -> Result<BTreeMap<Vec<String>, RateSummary>, Box<dyn std::error::Error>> {
+    let mut ctx = ParseContext::new();
+    let reader = ctx.track_offsets(BufReader::with_capacity(buff_size, source.open(&SharedReporter::default())?));
+    let mut parser = ReaderJsonParser::new(reader);
+
+    let mut groups: BTreeMap<Vec<String>, RateSummary> = BTreeMap::new();
+
+    // Depths as in scan: 3 in_network item, 7 negotiated price
+    const ITEM: u64 = 3;
+    const PRICE: u64 = 7;
+
+    let mut depth: u64 = 0;
+    let mut section = String::new();
+    let mut key = String::new();
+
+    // Field values of the item (by GROUP_FIELDS index), and of each of its prices with the
+    // rate. Keys are in any order, so prices are kept until the item ends.
+    let mut item: [String; 3] = Default::default();
+    let mut price: [String; 2] = Default::default();
+    let mut rate: Option<f64> = None;
+    let mut prices: Vec<([String; 2], f64)> = Vec::new();
+
+    loop {
+        let event = {next_event(&mut parser, &mut ctx, "stats")?};
+        match event {
+            JsonEvent::StartObject | JsonEvent::StartArray => {
+                depth += 1;
+            }
+            JsonEvent::EndObject | JsonEvent::EndArray => {
+                if depth == ITEM && section == "in_network" {
+                    let item = std::mem::take(&mut item).map(or_null);
+                    for (price, rate) in prices.drain(..) {
+                        let values: Vec<String> = item.iter().chain(price.iter()).cloned().collect();
+                        let group: Vec<String> = group_by.iter().map(|field| {
+                            let i = GROUP_FIELDS.iter().position(|f| f == field).unwrap();
+                            values[i].clone()
+                        }).collect();
+                        groups.entry(group).or_default().add(rate);
+                    }
+                }
+                else if depth == PRICE && section == "in_network" {
+                    let price = std::mem::take(&mut price).map(or_null);
+                    if let Some(rate) = rate.take() {
+                        prices.push((price, rate));
+                    }
+                }
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+            JsonEvent::ObjectKey(k) => {
+                if depth == 1 {
+                    section = k.to_string();
+                }
+                key.clear();
+                key.push_str(k.as_ref());
+            }
+            JsonEvent::String(value) | JsonEvent::Number(value) if section == "in_network" => {
+                if depth == ITEM && let Some(i) = GROUP_FIELDS[..3].iter().position(|f| *f == key) {
+                    item[i] = value.to_string();
+                }
+                else if depth == PRICE && key == "negotiated_rate" {
+                    rate = normalize_rate(&value).and_then(|r| r.parse().ok());
+                }
+                else if depth == PRICE && let Some(i) = GROUP_FIELDS[3..].iter().position(|f| *f == key) {
+                    price[i] = value.to_string();
+                }
+            }
+            JsonEvent::Eof => {
+                return Err(Box::new(unexpected_eof(&ctx, "stats")));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(groups)
+}
+
+/// Writes rate summaries as CSV to out: the group_by fields (the names of the group's values),
+/// then count, min, the percentiles, mean and max of the rates of each group.
+pub fn write_groups_csv<S: AsRef<str>>(groups: BTreeMap<Vec<String>, RateSummary>,
+                                       group_by: &[S],
+                                       percentiles: &[f64],
+                                       out: impl Write) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writer = csv::Writer::from_writer(out);
+    let mut header: Vec<String> = group_by.iter().map(|f| f.as_ref().to_string()).collect();
+    header.extend([String::from("count"), String::from("min")]);
+    header.extend(percentiles.iter().map(|p| percentile_label(*p)));
+    header.extend([String::from("mean"), String::from("max")]);
+    writer.write_record(&header)?;
+
+    for (mut group, mut r) in groups.into_iter() {
+        let mut values = vec![r.min];
+        values.extend(percentiles.iter().map(|p| r.percentile(*p)));
+        values.extend([r.mean(), r.max]);
+        group.push(r.count.to_string());
+        group.extend(values.iter().map(|v| format!("{:.2}", v)));
+        writer.write_record(&group)?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// A billing code as listed in an in_network item.
+pub type Code = (String, String, String); // (billing_code_type, billing_code, name)
+
+/// Reads the in_network items from source and returns each distinct code with its item count.
+pub fn list_codes(source: &dyn DataSource, buff_size: usize) -> Result<BTreeMap<Code, u64>, Box<dyn std::error::Error>> {
+    let mut ctx = ParseContext::new();
+    let reader = ctx.track_offsets(BufReader::with_capacity(buff_size, source.open(&SharedReporter::default())?));
+    let mut parser = ReaderJsonParser::new(reader);
+
+    let mut codes: BTreeMap<Code, u64> = BTreeMap::new();
+
+    // in_network items are at depth 3, their fields are read from there
+    const ITEM: u64 = 3;
+
+    let mut depth: u64 = 0;
+    let mut section = String::new();
+    let mut key = String::new();
+    let mut code: Code = Default::default();
+
+    loop {
+        let event = {next_event(&mut parser, &mut ctx, "codes")?};
+        match event {
+            JsonEvent::StartObject | JsonEvent::StartArray => {
+                depth += 1;
+            }
+            JsonEvent::EndObject | JsonEvent::EndArray => {
+                if depth == ITEM && section == "in_network" {
+                    let (code_type, billing_code, name) = std::mem::take(&mut code);
+                    let code = (or_null(code_type), or_null(billing_code), name);
+                    *codes.entry(code).or_insert(0) += 1;
+                }
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+            JsonEvent::ObjectKey(k) => {
+                if depth == 1 {
+                    section = k.to_string();
+                }
+                key.clear();
+                key.push_str(k.as_ref());
+            }
+            JsonEvent::String(value) | JsonEvent::Number(value) if section == "in_network" && depth == ITEM => {
+                if key == "billing_code_type" {
+                    code.0 = value.to_string();
+                }
+                else if key == "billing_code" {
+                    code.1 = value.to_string();
+                }
+                else if key == "name" {
+                    code.2 = value.to_string();
+                }
+            }
+            JsonEvent::Eof => {
+                return Err(Box::new(unexpected_eof(&ctx, "codes")));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(codes)
+}
+
+/// Writes codes as CSV to out, with the item count of each code if with_counts.
+pub fn write_codes_csv(codes: &BTreeMap<Code, u64>,
+                       with_counts: bool,
+                       out: impl Write) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writer = csv::Writer::from_writer(out);
+    let mut header = vec!["billing_code_type", "billing_code", "name"];
+    if with_counts {
+        header.push("count");
+    }
+    writer.write_record(&header)?;
+
+    for ((code_type, code, name), count) in codes.iter() {
+        let mut record = vec![code_type.clone(), code.clone(), name.clone()];
+        if with_counts {
+            record.push(count.to_string());
+        }
+        writer.write_record(&record)?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::Location;
+
+    #[test]
+    fn test_scan() {
+        let source = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz"));
+        let stats = scan(&source, 256).unwrap();
+
+        assert_eq!(stats.items_by_code_type.values().sum::<u64>(), 9);
+        assert_eq!(stats.items_by_code_type.get("Type 1"), Some(&1));
+        assert_eq!(stats.billing_codes.len(), 9);
+        assert_eq!(stats.provider_groups, 6);
+        assert_eq!(stats.npis.len(), 9);
+
+        let r = stats.rates.get("neg type 1").unwrap();
+        assert_eq!((r.count, r.min, r.max), (2, 9.99, 100.99));
+        assert!((r.mean() - 55.49).abs() < 1e-9);
+        assert_eq!(stats.rates.len(), 8);
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let source = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz"));
+        let group_by = vec![String::from("billing_code")];
+        let groups = aggregate(&source, 256, &group_by).unwrap();
+
+        let r = groups.get(&vec![String::from("Code 8")]).unwrap();
+        assert_eq!((r.count, r.min, r.max), (2, 8.45, 89.17));
+
+        let mut out: Vec<u8> = Vec::new();
+        write_groups_csv(groups, &group_by, &[50.0, 90.0], &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("billing_code,count,min,median,p90,mean,max\n"));
+        assert!(out.contains("Code 8,2,8.45,48.81,89.17,48.81,89.17\n"));
+    }
+
+    #[test]
+    fn test_list_codes() {
+        let source = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/intermediate.json.gz"));
+        let codes = list_codes(&source, 256).unwrap();
+
+        assert_eq!(codes.len(), 9);
+        assert!(codes.values().all(|count| *count == 1));
+
+        let mut out: Vec<u8> = Vec::new();
+        write_codes_csv(&codes, true, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let mut lines = out.lines();
+        assert_eq!(lines.next(), Some("billing_code_type,billing_code,name,count"));
+        assert_eq!(lines.count(), 9);
+    }
+}
@@ -0,0 +1,208 @@
+//! # table
+//!
+//! Writes the rows of a query as an aligned text table (`--output-format table`), to read a small
+//! lookup in the terminal instead of as CSV.
+//!
+//! Rows are written a page at a time, PAGE_ROWS rows under a header of their own, with the width
+//! of each column taken from the values of the page, so a run doesn't have to end before the first
+//! rows show. Values longer than MAX_CELL_WIDTH characters are cut with `...`, and
+//! negotiated_rate is aligned to the right. When standard output is a terminal the table goes
+//! through a pager (PAGER, else `less`), see pager.
+
+use std::io::Write;
+use std::process::{Child, Command, Stdio};
+
+use crate::sink::{MatchedRecord, RecordSink};
+
+/// Rows of a page.
+pub const PAGE_ROWS: usize = 50;
+
+/// Characters of a value shown at most.
+pub const MAX_CELL_WIDTH: usize = 40;
+
+/// The formats the rows of a query can be written in with --output-format.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    Table,
+}
+
+impl OutputFormat {
+    /// Parses an --output-format value, csv or table.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "csv" => Some(OutputFormat::Csv),
+            "table" => Some(OutputFormat::Table),
+            _ => None,
+        }
+    }
+}
+
+/// Writes the header and records as pages of an aligned table.
+pub struct TableSink<W: Write> {
+    /// None once finished, or once the reader went away (e.g. quit the pager)
+    out: Option<W>,
+    header: Vec<String>,
+    /// Columns aligned to the right, by position
+    right: Vec<bool>,
+    page: Vec<Vec<String>>,
+}
+
+impl<W: Write> TableSink<W> {
+    /// Creates a TableSink writing to out, with the columns named by columns (before
+    /// --header-map) aligned as their values are.
+    pub fn new(out: W, columns: &[&str]) -> Self {
+        Self {
+            out: Some(out),
+            header: Vec::new(),
+            right: columns.iter().map(|c| *c == "negotiated_rate").collect(),
+            page: Vec::with_capacity(PAGE_ROWS),
+        }
+    }
+
+    /// Writes the rows of the page under the header, and empties it. Output to a reader that went
+    /// away is dropped.
+    fn write_page(&mut self) -> Result<(), std::io::Error> {
+        let Some(out) = self.out.as_mut() else {
+            self.page.clear();
+            return Ok(());
+        };
+        match write_table(out, &self.header, &self.page, &self.right) {
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => self.out = None,
+            result => result?,
+        }
+        self.page.clear();
+        Ok(())
+    }
+}
+
+/// Returns value on one line, cut to MAX_CELL_WIDTH characters.
+fn cell(value: &str) -> String {
+    let value = value.replace(['\n', '\r', '\t'], " ");
+    match value.chars().count() > MAX_CELL_WIDTH {
+        true => value.chars().take(MAX_CELL_WIDTH - 3).chain("...".chars()).collect(),
+        false => value,
+    }
+}
+
+/// Writes header (if any), a rule and rows, columns separated by two spaces, to out.
+fn write_table(out: &mut impl Write,
+               header: &[String],
+               rows: &[Vec<String>],
+               right: &[bool]) -> Result<(), std::io::Error> {
+    let columns = rows.iter().map(|r| r.len()).chain([header.len()]).max().unwrap_or(0);
+    let mut widths = vec![0; columns];
+    for row in std::iter::once(header).chain(rows.iter().map(|r| r.as_slice())) {
+        for (width, value) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(value.chars().count());
+        }
+    }
+
+    let line = |out: &mut dyn Write, row: &[String]| -> Result<(), std::io::Error> {
+        let mut text = String::new();
+        for (i, width) in widths.iter().enumerate() {
+            let value = row.get(i).map_or("", |v| v.as_str());
+            if i > 0 {
+                text.push_str("  ");
+            }
+            match right.get(i).copied().unwrap_or(false) {
+                true => text.push_str(&format!("{:>1$}", value, width)),
+                false => text.push_str(&format!("{:<1$}", value, width)),
+            }
+        }
+        writeln!(out, "{}", text.trim_end())
+    };
+
+    if !header.is_empty() {
+        line(out, header)?;
+        let rule: Vec<String> = widths.iter().map(|w| "-".repeat(*w)).collect();
+        line(out, &rule)?;
+    }
+    for row in rows.iter() {
+        line(out, row)?;
+    }
+    writeln!(out)?;
+    out.flush()
+}
+
+impl<W: Write> RecordSink for TableSink<W> {
+    fn write_header(&mut self, columns: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+        self.header = columns.iter().map(|c| cell(c)).collect();
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &MatchedRecord) -> Result<(), Box<dyn std::error::Error>> {
+        self.page.push(record.values.iter().map(|v| cell(v)).collect());
+        if self.page.len() >= PAGE_ROWS {
+            self.write_page()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.page.is_empty() {
+            self.write_page()?;
+        }
+        // Closes a pipe to a pager, which waits for the end of its input
+        self.out = None;
+        Ok(())
+    }
+}
+
+/// Starts the pager of PAGER (`less` if it isn't set) reading from a pipe when standard output
+/// is a terminal, with LESS set to FRSX if it isn't (quit if the table fits on one screen,
+/// don't wrap lines). Returns None when standard output isn't a terminal, PAGER is empty or cat,
+/// or the pager can't be started.
+pub fn pager() -> Option<Child> {
+    use std::io::IsTerminal;
+
+    if !std::io::stdout().is_terminal() {
+        return None;
+    }
+    let pager = std::env::var("PAGER").unwrap_or_else(|_| String::from("less"));
+    let mut words = pager.split_whitespace();
+    let program = words.next().filter(|p| *p != "cat")?;
+    let mut command = Command::new(program);
+    command.args(words).stdin(Stdio::piped());
+    if std::env::var_os("LESS").is_none() {
+        command.env("LESS", "FRSX");
+    }
+    command.spawn().ok()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_sink() {
+        let columns = ["npi", "negotiated_rate", "name"];
+        let mut sink = TableSink::new(Vec::new(), &columns);
+        sink.write_header(&columns).unwrap();
+        sink.write_record(&MatchedRecord { values: &["1234567893", "75.5", "office visit"] }).unwrap();
+        let long = "x".repeat(50);
+        sink.write_record(&MatchedRecord { values: &["1", "1200", long.as_str()] }).unwrap();
+        sink.write_page().unwrap();
+        let out = String::from_utf8(sink.out.take().unwrap()).unwrap();
+        let cut = format!("{}...", "x".repeat(MAX_CELL_WIDTH - 3));
+        assert_eq!(out, format!("npi         negotiated_rate  name\n\
+                                 ----------  ---------------  {}\n\
+                                 1234567893             75.5  office visit\n\
+                                 1                      1200  {}\n\n", "-".repeat(MAX_CELL_WIDTH), cut));
+
+        // A header again on every page
+        let mut sink = TableSink::new(Vec::new(), &columns);
+        sink.write_header(&columns).unwrap();
+        for _ in 0..PAGE_ROWS + 1 {
+            sink.write_record(&MatchedRecord { values: &["1", "2", "3"] }).unwrap();
+        }
+        assert_eq!(sink.page.len(), 1);
+        let out = String::from_utf8(sink.out.take().unwrap()).unwrap();
+        assert_eq!(out.lines().count(), PAGE_ROWS + 3);
+
+        assert_eq!(OutputFormat::parse("table"), Some(OutputFormat::Table));
+        assert_eq!(OutputFormat::parse("json"), None);
+    }
+}
@@ -0,0 +1,519 @@
+//! # tape
+//!
+//! A JSON parser tuned for MRFs, the `simd` backend of `--parser` (see parser).
+//!
+//! MRFs are mostly short strings and numbers, so most of the time of json-event-parser goes to
+//! looking at strings one byte at a time. TapeParser reads into one buffer and scans it in place:
+//! the end of a string is found eight bytes at a time (find_special), its UTF-8 is checked with
+//! the SIMD instructions of the CPU (simdutf8), and strings without escapes and numbers are
+//! handed out as slices of the buffer rather than copied. It gives the same events as
+//! json-event-parser, and rejects the same malformed JSON, with its own error messages.
+
+use std::borrow::Cow;
+use std::io::{self, Read};
+
+use json_event_parser::JsonEvent;
+
+use crate::parser::{JsonEvents, ParseFailure};
+
+/// Starting size of the buffer, it grows to hold the longest token.
+const INITIAL_BUFFER: usize = 64 * 1024;
+
+/// What the parser expects next.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    /// A value: at the top, after ':' or after ',' in an array
+    Value,
+    /// The first element of an array, or its end
+    FirstElement,
+    /// The first key of an object, or its end
+    FirstKey,
+    /// A key, after ',' in an object
+    Key,
+    /// The ':' after a key
+    Colon,
+    /// ',' or the end of the innermost array or object
+    Next,
+    /// Nothing but whitespace, after the top level value
+    Done,
+}
+
+/// A string of the buffer: raw bytes a..b, or unescaped into scratch.
+#[derive(Clone, Copy)]
+enum Text {
+    Raw(usize, usize),
+    Scratch,
+}
+
+/// An event, with its text not yet borrowed from the parser.
+enum Token {
+    StartObject,
+    EndObject,
+    StartArray,
+    EndArray,
+    Key(Text),
+    String(Text),
+    Number(usize, usize),
+    Boolean(bool),
+    Null,
+    Eof,
+}
+
+/// Streaming JSON parser over a reader, see the module documentation.
+pub struct TapeParser<R> {
+    reader: R,
+    buf: Vec<u8>,
+    /// Next byte to parse
+    pos: usize,
+    /// End of the bytes read into buf
+    len: usize,
+    eof: bool,
+    /// Bytes dropped from the front of buf so far, for error offsets
+    consumed: u64,
+    /// b'{' or b'[' for each open object or array
+    containers: Vec<u8>,
+    state: State,
+    scratch: String,
+}
+
+/// Returns the index of the first '"', '\\' or control character of bytes. Looks at eight bytes
+/// at a time: a byte of a word equal to b has a zero in word ^ (b * ONES), and a zero byte (or a
+/// byte under 0x20) is the lowest one whose high bit survives x - ONES & !x.
+fn find_special(bytes: &[u8]) -> Option<usize> {
+    const ONES: u64 = 0x0101_0101_0101_0101;
+    const HIGHS: u64 = 0x8080_8080_8080_8080;
+    let zero_bytes = |x: u64| x.wrapping_sub(ONES) & !x & HIGHS;
+
+    let mut chunks = bytes.chunks_exact(8);
+    let mut at = 0;
+    for chunk in chunks.by_ref() {
+        let word = u64::from_le_bytes(chunk.try_into().expect("chunks of 8"));
+        let found = zero_bytes(word ^ (ONES * b'"' as u64)) |
+                    zero_bytes(word ^ (ONES * b'\\' as u64)) |
+                    word.wrapping_sub(ONES * 0x20) & !word & HIGHS;
+        if found != 0 {
+            return Some(at + (found.trailing_zeros() / 8) as usize);
+        }
+        at += 8;
+    }
+    chunks.remainder().iter().position(|b| *b == b'"' || *b == b'\\' || *b < 0x20).map(|i| at + i)
+}
+
+/// Returns true if bytes is a JSON number.
+fn is_number(bytes: &[u8]) -> bool {
+    let mut i = 0;
+    let digits = |i: &mut usize| {
+        let start = *i;
+        while bytes.get(*i).is_some_and(u8::is_ascii_digit) {
+            *i += 1;
+        }
+        *i > start
+    };
+
+    if bytes.first() == Some(&b'-') {
+        i += 1;
+    }
+    match bytes.get(i) {
+        Some(b'0') => i += 1,
+        Some(b'1'..=b'9') => {
+            digits(&mut i);
+        }
+        _ => return false,
+    }
+    if bytes.get(i) == Some(&b'.') {
+        i += 1;
+        if !digits(&mut i) {
+            return false;
+        }
+    }
+    if matches!(bytes.get(i), Some(b'e' | b'E')) {
+        i += 1;
+        if matches!(bytes.get(i), Some(b'+' | b'-')) {
+            i += 1;
+        }
+        if !digits(&mut i) {
+            return false;
+        }
+    }
+    i == bytes.len()
+}
+
+impl<R: Read> TapeParser<R> {
+    /// Creates a parser reading from reader.
+    pub fn new(reader: R) -> Self {
+        Self::with_capacity(reader, INITIAL_BUFFER)
+    }
+
+    /// Creates a parser reading from reader with a buffer of capacity bytes to start with.
+    fn with_capacity(reader: R, capacity: usize) -> Self {
+        Self {
+            reader,
+            buf: vec![0; capacity.max(8)],
+            pos: 0,
+            len: 0,
+            eof: false,
+            consumed: 0,
+            containers: Vec::new(),
+            state: State::Value,
+            scratch: String::new(),
+        }
+    }
+
+    /// Returns a syntax error at index at of buf.
+    fn error_at(&self, at: usize, message: impl Into<String>) -> ParseFailure {
+        ParseFailure::Syntax { offset: self.consumed + at as u64, message: message.into() }
+    }
+
+    /// Reads more bytes, after dropping the bytes before pos (so a token being read, which starts
+    /// at pos, moves to the front). Returns false at the end of the input.
+    fn fill(&mut self) -> Result<bool, ParseFailure> {
+        if self.eof {
+            return Ok(false);
+        }
+        if self.pos > 0 {
+            self.buf.copy_within(self.pos..self.len, 0);
+            self.len -= self.pos;
+            self.consumed += self.pos as u64;
+            self.pos = 0;
+        }
+        if self.len == self.buf.len() {
+            self.buf.resize(self.buf.len() * 2, 0);
+        }
+        loop {
+            match self.reader.read(&mut self.buf[self.len..]) {
+                Ok(0) => {
+                    self.eof = true;
+                    return Ok(false);
+                }
+                Ok(n) => {
+                    self.len += n;
+                    return Ok(true);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(ParseFailure::Io(e)),
+            }
+        }
+    }
+
+    /// Skips whitespace and returns the next byte, without consuming it.
+    fn peek(&mut self) -> Result<Option<u8>, ParseFailure> {
+        loop {
+            while self.pos < self.len {
+                match self.buf[self.pos] {
+                    b' ' | b'\n' | b'\r' | b'\t' => self.pos += 1,
+                    c => return Ok(Some(c)),
+                }
+            }
+            if !self.fill()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Returns the next token.
+    fn next_token(&mut self) -> Result<Token, ParseFailure> {
+        loop {
+            let Some(c) = self.peek()? else {
+                return match self.state {
+                    State::Done => Ok(Token::Eof),
+                    _ => Err(self.error_at(self.len, "unexpected end of file")),
+                };
+            };
+
+            return match (self.state, c) {
+                (State::Done, _) => Err(self.error_at(self.pos, "unexpected data after the end of the document")),
+                (State::FirstElement, b']') | (State::FirstKey, b'}') => Ok(self.end()),
+                (State::Value | State::FirstElement, _) => self.value(c),
+                (State::FirstKey | State::Key, b'"') => {
+                    let text = self.string()?;
+                    self.state = State::Colon;
+                    Ok(Token::Key(text))
+                }
+                (State::FirstKey | State::Key, _) => Err(self.error_at(self.pos, "expected a key")),
+                (State::Colon, b':') => {
+                    self.pos += 1;
+                    self.state = State::Value;
+                    continue;
+                }
+                (State::Colon, _) => Err(self.error_at(self.pos, "expected ':' after a key")),
+                (State::Next, b',') => {
+                    self.pos += 1;
+                    self.state = match self.containers.last() {
+                        Some(b'{') => State::Key,
+                        _ => State::Value,
+                    };
+                    continue;
+                }
+                // '{' and '[' are two before '}' and ']'
+                (State::Next, b'}' | b']') if self.containers.last() == Some(&(c - 2)) => Ok(self.end()),
+                (State::Next, _) => Err(self.error_at(self.pos, "expected ',' or the end of the array or object")),
+            };
+        }
+    }
+
+    /// Sets the state after a value.
+    fn after_value(&mut self) {
+        self.state = match self.containers.is_empty() {
+            true => State::Done,
+            false => State::Next,
+        };
+    }
+
+    /// Consumes the '}' or ']' at pos, which closes the innermost container.
+    fn end(&mut self) -> Token {
+        self.pos += 1;
+        let closed = self.containers.pop();
+        self.after_value();
+        match closed {
+            Some(b'{') => Token::EndObject,
+            _ => Token::EndArray,
+        }
+    }
+
+    /// Reads the value starting with c at pos.
+    fn value(&mut self, c: u8) -> Result<Token, ParseFailure> {
+        let token = match c {
+            b'{' | b'[' => {
+                self.pos += 1;
+                self.containers.push(c);
+                return Ok(match c {
+                    b'{' => {
+                        self.state = State::FirstKey;
+                        Token::StartObject
+                    }
+                    _ => {
+                        self.state = State::FirstElement;
+                        Token::StartArray
+                    }
+                });
+            }
+            b'"' => Token::String(self.string()?),
+            b'-' | b'0'..=b'9' => {
+                let (start, end) = self.number()?;
+                Token::Number(start, end)
+            }
+            b't' => {
+                self.literal(b"true")?;
+                Token::Boolean(true)
+            }
+            b'f' => {
+                self.literal(b"false")?;
+                Token::Boolean(false)
+            }
+            b'n' => {
+                self.literal(b"null")?;
+                Token::Null
+            }
+            _ => return Err(self.error_at(self.pos, format!("unexpected '{}'", c.escape_ascii()))),
+        };
+        self.after_value();
+        Ok(token)
+    }
+
+    /// Consumes the literal word at pos.
+    fn literal(&mut self, word: &[u8]) -> Result<(), ParseFailure> {
+        while self.len - self.pos < word.len() {
+            if !self.fill()? {
+                return Err(self.error_at(self.len, "unexpected end of file"));
+            }
+        }
+        if &self.buf[self.pos..self.pos + word.len()] != word {
+            return Err(self.error_at(self.pos, "invalid literal"));
+        }
+        self.pos += word.len();
+        Ok(())
+    }
+
+    /// Consumes the number at pos and returns where it is in buf.
+    fn number(&mut self) -> Result<(usize, usize), ParseFailure> {
+        let mut i = 0;
+        loop {
+            while self.pos + i < self.len && matches!(self.buf[self.pos + i], b'0'..=b'9' | b'-' | b'+' | b'.' | b'e' | b'E') {
+                i += 1;
+            }
+            if self.pos + i < self.len || !self.fill()? {
+                break;
+            }
+        }
+        let (start, end) = (self.pos, self.pos + i);
+        if !is_number(&self.buf[start..end]) {
+            return Err(self.error_at(start, "invalid number"));
+        }
+        self.pos = end;
+        Ok((start, end))
+    }
+
+    /// Consumes the string starting with the '"' at pos. Returns the raw bytes if it has no
+    /// escapes, otherwise unescapes it into scratch.
+    fn string(&mut self) -> Result<Text, ParseFailure> {
+        // Offset from pos of the first byte not scanned yet
+        let mut scanned = 1;
+        let mut escaped = false;
+        loop {
+            let from = self.pos + scanned;
+            let Some(found) = find_special(&self.buf[from..self.len]).map(|n| from + n) else {
+                scanned = self.len - self.pos;
+                if !self.fill()? {
+                    return Err(self.error_at(self.len, "unterminated string"));
+                }
+                continue;
+            };
+
+            match self.buf[found] {
+                b'"' => {
+                    let (start, end) = (self.pos + 1, found);
+                    self.pos = found + 1;
+                    if !escaped {
+                        return Ok(Text::Raw(start, end));
+                    }
+                    self.unescape(start, end)?;
+                    return Ok(Text::Scratch);
+                }
+                b'\\' if found + 1 < self.len => {
+                    escaped = true;
+                    scanned = found + 2 - self.pos;
+                }
+                b'\\' => {
+                    // The escaped byte isn't read yet
+                    scanned = found - self.pos;
+                    if !self.fill()? {
+                        return Err(self.error_at(self.len, "unterminated string"));
+                    }
+                }
+                _ => return Err(self.error_at(found, "control character in string")),
+            }
+        }
+    }
+
+    /// Unescapes the string at start..end of buf into scratch.
+    fn unescape(&mut self, start: usize, end: usize) -> Result<(), ParseFailure> {
+        let mut out = std::mem::take(&mut self.scratch).into_bytes();
+        out.clear();
+        let raw = &self.buf[start..end];
+        let hex = |i: usize| -> Option<u32> {
+            let digits = std::str::from_utf8(raw.get(i..i + 4)?).ok()?;
+            u32::from_str_radix(digits, 16).ok()
+        };
+
+        let mut i = 0;
+        while i < raw.len() {
+            if raw[i] != b'\\' {
+                out.push(raw[i]);
+                i += 1;
+                continue;
+            }
+            let unescaped = match raw[i + 1] {
+                b'"' => '"',
+                b'\\' => '\\',
+                b'/' => '/',
+                b'b' => '\u{8}',
+                b'f' => '\u{c}',
+                b'n' => '\n',
+                b'r' => '\r',
+                b't' => '\t',
+                b'u' => {
+                    let high = hex(i + 2).ok_or_else(|| self.error_at(start + i, "invalid \\u escape"))?;
+                    let code = match high {
+                        0xd800..=0xdbff if raw.get(i + 6..i + 8) == Some(b"\\u") => {
+                            let low = hex(i + 8).filter(|l| (0xdc00..=0xdfff).contains(l))
+                                .ok_or_else(|| self.error_at(start + i, "invalid surrogate pair"))?;
+                            i += 6;
+                            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+                        }
+                        code => code,
+                    };
+                    i += 4;
+                    char::from_u32(code).ok_or_else(|| self.error_at(start + i, "invalid \\u escape"))?
+                }
+                _ => return Err(self.error_at(start + i, "invalid escape")),
+            };
+            let mut utf8 = [0; 4];
+            out.extend_from_slice(unescaped.encode_utf8(&mut utf8).as_bytes());
+            i += 2;
+        }
+
+        self.scratch = String::from_utf8(out).map_err(|_| self.error_at(start, "invalid UTF-8 in string"))?;
+        Ok(())
+    }
+
+    /// Returns the text of a string token.
+    fn text(&self, text: Text) -> Result<Cow<'_, str>, ParseFailure> {
+        match text {
+            Text::Raw(start, end) => simdutf8::basic::from_utf8(&self.buf[start..end])
+                .map(Cow::Borrowed)
+                .map_err(|_| self.error_at(start, "invalid UTF-8 in string")),
+            Text::Scratch => Ok(Cow::Borrowed(self.scratch.as_str())),
+        }
+    }
+}
+
+impl<R: Read> JsonEvents for TapeParser<R> {
+    fn next_json_event(&mut self) -> Result<JsonEvent<'_>, ParseFailure> {
+        Ok(match self.next_token()? {
+            Token::StartObject => JsonEvent::StartObject,
+            Token::EndObject => JsonEvent::EndObject,
+            Token::StartArray => JsonEvent::StartArray,
+            Token::EndArray => JsonEvent::EndArray,
+            Token::Key(text) => JsonEvent::ObjectKey(self.text(text)?),
+            Token::String(text) => JsonEvent::String(self.text(text)?),
+            // Checked by is_number, so ASCII
+            Token::Number(start, end) => JsonEvent::Number(Cow::Borrowed(
+                std::str::from_utf8(&self.buf[start..end]).map_err(|_| self.error_at(start, "invalid number"))?)),
+            Token::Boolean(b) => JsonEvent::Boolean(b),
+            Token::Null => JsonEvent::Null,
+            Token::Eof => JsonEvent::Eof,
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asa::SharedReporter;
+    use crate::source::{DataSource, Location};
+    use json_event_parser::ReaderJsonParser;
+
+    /// Returns every event of parser, or the first error.
+    fn events(parser: &mut impl JsonEvents) -> Result<Vec<String>, String> {
+        let mut events = Vec::new();
+        loop {
+            match parser.next_json_event() {
+                Ok(JsonEvent::Eof) => return Ok(events),
+                Ok(event) => events.push(format!("{:?}", event)),
+                Err(e) => return Err(format!("{:?}", e)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_tape_parser() {
+        assert_eq!(find_special(b"abcdefgh\"ij"), Some(8));
+        assert_eq!(find_special(b"abc\\defghij"), Some(3));
+        assert_eq!(find_special("\u{e9}\u{e9}\u{e9}\u{e9}\n".as_bytes()), Some(8));
+        assert_eq!(find_special(b"abcdefghij"), None);
+        assert!(is_number(b"-0.5e+10") && is_number(b"12") && !is_number(b"01") && !is_number(b"1.") && !is_number(b"-"));
+
+        // The same events as json-event-parser, with buffers small enough to split every token
+        for file in ["basic_test.json.gz", "intermediate.json.gz", "backward_basic.json.gz"] {
+            let source = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files").join(file));
+            let reporter = SharedReporter::default();
+            let expected = events(&mut ReaderJsonParser::new(source.open(&reporter).unwrap())).unwrap();
+            for capacity in [8, 13, 4096] {
+                assert_eq!(events(&mut TapeParser::with_capacity(source.open(&reporter).unwrap(), capacity)).unwrap(), expected);
+            }
+        }
+
+        let json = r#" {"a\"b": ["x\u00e9\ud83d\ude00\n", -1.5e3, true, false, null, {}, []], "": "é"} "#;
+        let parsed = events(&mut TapeParser::with_capacity(json.as_bytes(), 8)).unwrap();
+        assert_eq!(parsed.len(), 16);
+        assert_eq!(parsed[1], format!("{:?}", JsonEvent::ObjectKey("a\"b".into())));
+        assert_eq!(parsed[3], format!("{:?}", JsonEvent::String("x\u{e9}\u{1f600}\n".into())));
+        assert_eq!(parsed[4], format!("{:?}", JsonEvent::Number("-1.5e3".into())));
+        assert_eq!(parsed[14], format!("{:?}", JsonEvent::String("é".into())));
+
+        for malformed in ["[1,]", "{\"a\" 1}", "[01]", "\"abc", "[1] x", "{\"a\":1", "[tru]", "\"\\x\"", "\"a\nb\"", "{1:2}", "[1}"] {
+            assert!(events(&mut TapeParser::with_capacity(malformed.as_bytes(), 8)).is_err(), "{}", malformed);
+        }
+    }
+}
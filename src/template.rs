@@ -0,0 +1,161 @@
+//! # template
+//!
+//! Writes each output row as a line of text made from a template (`--template`), e.g.
+//! `{npi}\t{billing_code}\t{negotiated_rate}`, instead of CSV.
+//!
+//! `{column}` is replaced by the value of the column, as is, without quoting. `{{` and `}}` are
+//! a literal brace, and `\t`, `\n` and `\\` a tab, a newline and a backslash, as shells leave them
+//! alone between single quotes. Every row ends with a newline.
+
+use std::io::Write;
+
+use crate::sink::{MatchedRecord, RecordSink};
+
+/// A part of a template, text or a column by name (Column) or position (Index).
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Column(String),
+    Index(usize),
+}
+
+/// A parsed --template.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /// Parses text, checking its columns are among columns.
+    pub fn parse(text: &str, columns: &[&str]) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (c, chars.peek()) {
+                ('{', Some('{')) | ('}', Some('}')) => {
+                    chars.next();
+                    literal.push(c);
+                }
+                ('\\', Some('t')) => {
+                    chars.next();
+                    literal.push('\t');
+                }
+                ('\\', Some('n')) => {
+                    chars.next();
+                    literal.push('\n');
+                }
+                ('\\', Some('\\')) => {
+                    chars.next();
+                    literal.push('\\');
+                }
+                ('{', _) => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("missing }} after {{{}", name)),
+                        }
+                    }
+                    if !literal.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Column(name.trim().to_string()));
+                }
+                ('}', _) => return Err(String::from("unmatched }, write }} for a brace")),
+                _ => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Text(literal));
+        }
+        let template = Template { parts };
+        template.bind(columns)?;
+        Ok(template)
+    }
+
+    /// Returns the template with its column names replaced by their positions in columns.
+    pub fn bind(&self, columns: &[&str]) -> Result<Self, String> {
+        let parts = self.parts.iter().map(|part| match part {
+            Part::Column(name) => columns.iter().position(|c| c == name).map(Part::Index)
+                .ok_or_else(|| format!("no column {} in the output", name)),
+            other => Ok(other.clone()),
+        }).collect::<Result<_, _>>()?;
+        Ok(Template { parts })
+    }
+
+    /// Writes the line of row, without its newline, to out. The template must be bound to the
+    /// columns of row.
+    pub fn render(&self, row: &[&str], out: &mut impl Write) -> Result<(), std::io::Error> {
+        for part in self.parts.iter() {
+            match part {
+                Part::Text(text) => out.write_all(text.as_bytes())?,
+                Part::Index(i) => out.write_all(row.get(*i).copied().unwrap_or("").as_bytes())?,
+                Part::Column(_) => unreachable!("the template is bound before it is used"),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes each record as a line of a template bound to the columns of the run, and no header.
+pub struct TemplateSink<W: Write> {
+    template: Template,
+    out: W,
+}
+
+impl<W: Write> TemplateSink<W> {
+    /// Creates a TemplateSink writing to out.
+    pub fn new(template: Template, out: W) -> Self {
+        Self { template, out }
+    }
+}
+
+impl<W: Write> RecordSink for TemplateSink<W> {
+    fn write_header(&mut self, _columns: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &MatchedRecord) -> Result<(), Box<dyn std::error::Error>> {
+        self.template.render(record.values, &mut self.out)?;
+        self.out.write_all(b"\n")?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_template() {
+        let columns = ["npi", "billing_code", "negotiated_rate"];
+        let template = Template::parse("{npi}\\t{ billing_code }\\t{negotiated_rate} {{x}} \\\\", &columns).unwrap();
+        let mut sink = TemplateSink::new(template.bind(&columns).unwrap(), Vec::new());
+        sink.write_header(&columns).unwrap();
+        sink.write_record(&MatchedRecord { values: &["1234567893", "99213", "75.5"] }).unwrap();
+        sink.write_record(&MatchedRecord { values: &["1234567893", "\"a,b\"", "null"] }).unwrap();
+        sink.finish().unwrap();
+        assert_eq!(String::from_utf8(sink.out).unwrap(),
+                   "1234567893\t99213\t75.5 {x} \\\n1234567893\t\"a,b\"\tnull {x} \\\n");
+
+        // Columns of another run are positions of its own
+        let template = Template::parse("{negotiated_rate}", &columns).unwrap();
+        let mut out = Vec::new();
+        template.bind(&["negotiated_rate"]).unwrap().render(&["9"], &mut out).unwrap();
+        assert_eq!(out, b"9");
+        assert!(template.bind(&["npi"]).is_err());
+
+        assert!(Template::parse("{rate}", &columns).is_err());
+        assert!(Template::parse("{npi", &columns).is_err());
+        assert!(Template::parse("npi}", &columns).is_err());
+    }
+}
@@ -0,0 +1,202 @@
+//! # toc
+//!
+//! Reads a table of contents file and lists the MRF files it points to.
+//!
+//! Payers publish one table of contents that maps their plans to the in_network (and allowed
+//! amount) files. The expected format is
+//! ```json
+//! {
+//!    "reporting_entity_name": "Aetna Signature Administrators",
+//!    "reporting_entity_type": "Third Party Vendor",
+//!    "reporting_structure": [
+//!        {"reporting_plans": [
+//!             {"plan_name": "Plan", "plan_id_type": "EIN", "plan_id": "123", "plan_market_type": "group"}
+//!         ],
+//!         "in_network_files": [
+//!             {"description": "in-network file", "location": "https://example.com/in_network.json.gz"}
+//!         ],
+//!         "allowed_amount_file": {"description": "allowed amounts", "location": "https://..."}
+//!        }
+//!    ],
+//!    "version": "1.0.0"
+//!}
+//!```
+//!
+//! Key ordering is *not* assumed, keys that aren't needed are skipped.
+
+use crate::asa::{next_event, unexpected_eof, ParseContext, SharedReporter};
+use crate::source::DataSource;
+
+use std::io::{BufReader, Write};
+
+use json_event_parser::{ReaderJsonParser, JsonEvent};
+
+/// An MRF file listed in a table of contents.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TocFile {
+    /// Index of the reporting_structure entry the file was listed in
+    pub structure: u64,
+    /// "in_network" or "allowed_amount"
+    pub file_type: &'static str,
+    pub description: String,
+    pub location: String,
+    /// plan_id of every plan in the reporting_structure entry
+    pub plan_ids: Vec<String>,
+}
+
+impl TocFile {
+    /// Creates an empty TocFile of file_type.
+    fn new(structure: u64, file_type: &'static str) -> Self {
+        Self {
+            structure,
+            file_type,
+            description: String::new(),
+            location: String::new(),
+            plan_ids: Vec::new(),
+        }
+    }
+}
+
+/// Reads the table of contents from source and returns every file it lists,
+/// in_network files before the allowed amount file of each reporting_structure entry.
+pub fn read_toc(source: &dyn DataSource) -> Result<Vec<TocFile>, Box<dyn std::error::Error>> {
+    let mut ctx = ParseContext::new();
+    let mut parser = ReaderJsonParser::new(ctx.track_offsets(BufReader::new(source.open(&SharedReporter::default())?)));
+
+    let mut files: Vec<TocFile> = Vec::new();
+
+    // Contents of the reporting_structure entry being read
+    let mut structure: u64 = 0;
+    let mut plan_ids: Vec<String> = Vec::new();
+    let mut structure_files: Vec<TocFile> = Vec::new();
+    let mut file: Option<TocFile> = None;
+
+    // Number of open objects and arrays, and the last key seen at each level that matters
+    let mut depth = 0;
+    let mut top_key = String::new();
+    let mut section = String::new();
+    let mut field = String::new();
+
+    loop {
+        let event = {next_event(&mut parser, &mut ctx, "table of contents")?};
+        match event {
+            JsonEvent::StartObject | JsonEvent::StartArray => {
+                depth += 1;
+                if top_key != "reporting_structure" {
+                    continue;
+                }
+                // in_network_files entries are one level deeper than allowed_amount_file
+                if depth == 5 && section == "in_network_files" {
+                    file = Some(TocFile::new(structure, "in_network"));
+                }
+                else if depth == 4 && section == "allowed_amount_file" && event == JsonEvent::StartObject {
+                    file = Some(TocFile::new(structure, "allowed_amount"));
+                }
+            }
+            JsonEvent::EndObject | JsonEvent::EndArray => {
+                depth -= 1;
+                if top_key != "reporting_structure" {
+                    if depth == 0 {
+                        break;
+                    }
+                    continue;
+                }
+                if (depth == 4 && section == "in_network_files") ||
+                   (depth == 3 && section == "allowed_amount_file")
+                {
+                    if let Some(f) = file.take() {
+                        structure_files.push(f);
+                    }
+                }
+                else if depth == 2 {
+                    // End of a reporting_structure entry
+                    structure_files.sort_by_key(|f| f.file_type != "in_network");
+                    for mut f in structure_files.drain(..) {
+                        f.plan_ids = plan_ids.clone();
+                        files.push(f);
+                    }
+                    plan_ids.clear();
+                    structure += 1;
+                }
+                else if depth == 0 {
+                    break;
+                }
+            }
+            JsonEvent::ObjectKey(key) => {
+                if depth == 1 {
+                    top_key = key.to_string();
+                }
+                else if depth == 3 {
+                    section = key.to_string();
+                }
+                else if depth == 4 || depth == 5 {
+                    field = key.to_string();
+                }
+            }
+            JsonEvent::String(value) | JsonEvent::Number(value) => {
+                if top_key != "reporting_structure" {
+                    continue;
+                }
+                if section == "reporting_plans" && depth == 5 && field == "plan_id" {
+                    plan_ids.push(value.to_string());
+                }
+                else if let Some(f) = file.as_mut() {
+                    if field == "description" {
+                        f.description = value.to_string();
+                    }
+                    else if field == "location" {
+                        f.location = value.to_string();
+                    }
+                }
+            }
+            JsonEvent::Eof => {
+                return Err(Box::new(unexpected_eof(&ctx, "table of contents")));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(files)
+}
+
+/// Writes files as CSV to out, with plan ids ' ' delimited.
+pub fn write_csv(files: &[TocFile], out: impl Write) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record(["reporting_structure", "file_type", "description", "location", "plan_ids"])?;
+
+    for f in files.iter() {
+        let structure = f.structure.to_string();
+        let plan_ids = f.plan_ids.join(" ");
+        writer.write_record([structure.as_str(), f.file_type, &f.description, &f.location, &plan_ids])?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::Location;
+
+    #[test]
+    fn test_read_toc() {
+        let source = Location::Path(std::path::PathBuf::from("tests/testfiles/data_files/toc.json"));
+        let files = read_toc(&source).unwrap();
+
+        let locations: Vec<(u64, &str, &str)> = files.iter()
+            .map(|f| (f.structure, f.file_type, f.location.as_str()))
+            .collect();
+        assert_eq!(locations, vec![
+            (0, "in_network", "https://example.com/in_network_1.json.gz"),
+            (0, "in_network", "https://example.com/in_network_2.json.gz"),
+            (0, "allowed_amount", "https://example.com/allowed_1.json"),
+            (1, "in_network", "https://example.com/in_network_3.json.gz"),
+        ]);
+
+        assert_eq!(files[0].description, "in-network file 1");
+        assert_eq!(files[0].plan_ids, vec![String::from("111"), String::from("222")]);
+        assert_eq!(files[3].plan_ids, vec![String::from("333")]);
+    }
+}
@@ -0,0 +1,110 @@
+//! # upload
+//!
+//! Uploads the outputs of a run to cloud storage once it is done (`-o s3://bucket/prefix/`),
+//! built with the `object-store` feature, for workers without a disk to keep them on. The rows
+//! are written to a temporary directory first, then uploaded with the report files of the run
+//! (`--report`, `--report-html`, `--key-report`, `--checksum` and the `--meta` sidecar), each under
+//! its file name. Files are uploaded in parts, a few at a time, so memory stays bounded however
+//! big the output is.
+//!
+//! Credentials and region are taken from the usual environment variables, as for reading.
+
+use std::path::Path;
+
+use crate::asa::SharedReporter;
+
+#[cfg(feature = "object-store")]
+use object_store::{ObjectStore, WriteMultipart};
+
+/// Bytes read from a file at a time.
+#[cfg(feature = "object-store")]
+const CHUNK: usize = 8 * 1024 * 1024;
+
+/// Parts of a file being uploaded at once at most.
+#[cfg(feature = "object-store")]
+const PARTS_IN_FLIGHT: usize = 4;
+
+/// Returns the URL of the object of a file named name written to dest: under dest if it is a
+/// prefix (ends in /), else next to it.
+#[cfg(feature = "object-store")]
+pub fn object_url(dest: &str, name: &str) -> String {
+    match dest.rfind('/') {
+        Some(slash) => format!("{}{}", &dest[..=slash], name),
+        None => format!("{}/{}", dest, name),
+    }
+}
+
+/// Returns the name of the file of the rows written to dest: the last part of dest, or default
+/// if dest is a prefix.
+pub fn rows_name<'a>(dest: &'a str, default: &'a str) -> &'a str {
+    match dest.rsplit('/').next() {
+        Some(name) if !name.is_empty() => name,
+        _ => default,
+    }
+}
+
+/// Uploads each of files to dest (see object_url), reporting each upload to reporter.
+#[cfg(feature = "object-store")]
+pub fn upload(dest: &str, files: &[&Path], reporter: &SharedReporter) -> Result<(), Box<dyn std::error::Error>> {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    for file in files {
+        let name = file.file_name().ok_or_else(|| format!("{} is not a file", file.display()))?;
+        let url = url::Url::parse(&object_url(dest, &name.to_string_lossy()))?;
+        let options = std::env::vars().map(|(k, v)| (k.to_ascii_lowercase(), v));
+        let (store, path) = object_store::parse_url_opts(&url, options)?;
+        runtime.block_on(put_file(&*store, &path, file))
+            .map_err(|e| format!("Failed to upload {} to {}: {}", file.display(), url, e))?;
+        reporter.status(format_args!("Uploaded {} to {}", file.display(), url));
+    }
+    Ok(())
+}
+
+/// Uploads the file at file to path of store, in parts.
+#[cfg(feature = "object-store")]
+async fn put_file(store: &dyn ObjectStore,
+                  path: &object_store::path::Path,
+                  file: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Read;
+
+    let mut file = std::fs::File::open(file)?;
+    let mut writer = WriteMultipart::new(store.put_multipart(path).await?);
+    let mut buf = vec![0; CHUNK];
+    loop {
+        let n = match file.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) => {
+                writer.abort().await?;
+                return Err(e.into());
+            }
+        };
+        if n == 0 {
+            break;
+        }
+        writer.wait_for_capacity(PARTS_IN_FLIGHT).await?;
+        writer.write(&buf[..n]);
+    }
+    writer.finish().await?;
+    Ok(())
+}
+
+#[cfg(not(feature = "object-store"))]
+pub fn upload(_dest: &str, _files: &[&Path], _reporter: &SharedReporter) -> Result<(), Box<dyn std::error::Error>> {
+    Err("rebuild mrfy with the `object-store` feature to upload to cloud storage".into())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_object_url() {
+        #[cfg(feature = "object-store")]
+        {
+            assert_eq!(object_url("s3://bucket/out/", "rates.csv"), "s3://bucket/out/rates.csv");
+            assert_eq!(object_url("s3://bucket/out/aetna.csv.gz", "report.json"), "s3://bucket/out/report.json");
+        }
+        assert_eq!(rows_name("s3://bucket/out/", "rates.csv"), "rates.csv");
+        assert_eq!(rows_name("s3://bucket/out/aetna.csv.gz", "rates.csv"), "aetna.csv.gz");
+    }
+}
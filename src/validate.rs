@@ -0,0 +1,484 @@
+//! # validate
+//!
+//! Stream checks an in-network rates file against the CMS Transparency in Coverage schema.
+//!
+//! Checks that required keys are present, that values have the right type, that dates are
+//! YYYY-MM-DD and that enumerated fields (billing_class, negotiated_type, ...) hold one of the
+//! allowed values, and that the version is one mrfy supports (see schema). Keys the schema
+//! doesn't describe are not checked.
+//! Each violation is reported with its path in the JSON and the approximate byte offset.
+
+use crate::asa::{next_event, parse_date, unexpected_eof, ParseContext, SharedReporter};
+use crate::schema::{self, SchemaVersion};
+use crate::source::DataSource;
+
+use std::collections::BTreeMap;
+use std::io::{BufReader, Read, Write};
+
+use json_event_parser::{ReaderJsonParser, JsonEvent};
+
+/// The type expected for a value.
+enum Kind {
+    String,
+    /// A string in YYYY-MM-DD format
+    Date,
+    Number,
+    /// A number without a fraction or exponent
+    Integer,
+    /// A string that must be one of the given values
+    Enum(&'static [&'static str]),
+    /// A string with a schema version mrfy supports
+    Version,
+    Array(&'static Kind),
+    Object(&'static Object),
+    /// Anything, not checked
+    Any,
+}
+
+/// A key of an object and the kind of its value.
+struct Field {
+    name: &'static str,
+    kind: Kind,
+    required: bool,
+}
+
+/// The keys of an object. At least one of the keys in one_of must be present.
+struct Object {
+    fields: &'static [Field],
+    one_of: &'static [&'static str],
+}
+
+const fn field(name: &'static str, kind: Kind, required: bool) -> Field {
+    Field { name, kind, required }
+}
+
+const TIN: Object = Object {
+    fields: &[
+        field("type", Kind::Enum(&["ein", "npi"]), true),
+        field("value", Kind::String, true),
+    ],
+    one_of: &[],
+};
+
+const PROVIDER_GROUP: Object = Object {
+    fields: &[
+        field("npi", Kind::Array(&Kind::Integer), true),
+        field("tin", Kind::Object(&TIN), true),
+    ],
+    one_of: &[],
+};
+
+const PROVIDER_REFERENCE: Object = Object {
+    fields: &[
+        field("provider_group_id", Kind::Integer, true),
+        field("provider_groups", Kind::Array(&Kind::Object(&PROVIDER_GROUP)), false),
+        field("location", Kind::String, false),
+    ],
+    one_of: &["provider_groups", "location"],
+};
+
+const NEGOTIATED_PRICE: Object = Object {
+    fields: &[
+        field("negotiated_type",
+              Kind::Enum(&["negotiated", "derived", "fee schedule", "percentage", "per diem"]), true),
+        field("negotiated_rate", Kind::Number, true),
+        field("expiration_date", Kind::Date, true),
+        field("service_code", Kind::Array(&Kind::String), false),
+        field("billing_class", Kind::Enum(&["professional", "institutional", "both"]), true),
+        field("billing_code_modifier", Kind::Array(&Kind::String), false),
+        field("additional_information", Kind::String, false),
+    ],
+    one_of: &[],
+};
+
+const NEGOTIATED_RATE: Object = Object {
+    fields: &[
+        field("provider_references", Kind::Array(&Kind::Integer), false),
+        field("provider_groups", Kind::Array(&Kind::Object(&PROVIDER_GROUP)), false),
+        field("negotiated_prices", Kind::Array(&Kind::Object(&NEGOTIATED_PRICE)), true),
+    ],
+    one_of: &["provider_references", "provider_groups"],
+};
+
+const IN_NETWORK: Object = Object {
+    fields: &[
+        field("negotiation_arrangement", Kind::Enum(&["ffs", "bundle", "capitation"]), true),
+        field("name", Kind::String, true),
+        field("billing_code_type",
+              Kind::Enum(&["CPT", "NDC", "HCPCS", "RC", "ICD", "MS-DRG", "R-DRG", "S-DRG",
+                           "APS-DRG", "AP-DRG", "APR-DRG", "APC", "LOCAL", "EAPG", "HIPPS",
+                           "CDT", "CSTM-ALL"]), true),
+        field("billing_code_type_version", Kind::String, true),
+        field("billing_code", Kind::String, true),
+        field("description", Kind::String, true),
+        field("negotiated_rates", Kind::Array(&Kind::Object(&NEGOTIATED_RATE)), true),
+        field("covered_services", Kind::Any, false),
+        field("bundled_codes", Kind::Any, false),
+    ],
+    one_of: &[],
+};
+
+const TOP_LEVEL: Object = Object {
+    fields: &[
+        field("reporting_entity_name", Kind::String, true),
+        field("reporting_entity_type", Kind::String, true),
+        field("plan_name", Kind::String, false),
+        field("plan_id_type", Kind::String, false),
+        field("plan_id", Kind::String, false),
+        field("plan_market_type", Kind::Enum(&["group", "individual"]), false),
+        field("in_network", Kind::Array(&Kind::Object(&IN_NETWORK)), true),
+        field("provider_references", Kind::Array(&Kind::Object(&PROVIDER_REFERENCE)), false),
+        field("last_updated_on", Kind::Date, true),
+        field("version", Kind::Version, true),
+    ],
+    one_of: &[],
+};
+
+/// A place in the file that doesn't match the schema.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Violation {
+    /// Where in the JSON, e.g. in_network[3].negotiated_rates[0].negotiated_prices[1].billing_class
+    pub path: String,
+    /// Approximate byte offset in the decompressed JSON
+    pub offset: u64,
+    /// Short name of the rule that was broken, used to group violations in the summary
+    pub rule: &'static str,
+    pub message: String,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {} (near byte {})", self.path, self.message, self.offset)
+    }
+}
+
+/// Result of validating a file.
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+    /// Total number of violations found
+    pub violations: u64,
+    /// Number of violations for each (path without array indexes, rule)
+    pub by_rule: BTreeMap<(String, &'static str), u64>,
+}
+
+impl Report {
+    /// Hands the violation counts to reporter.
+    pub fn summarize(&self, reporter: &SharedReporter) {
+        if self.violations == 0 {
+            reporter.status(format_args!("No schema violations found"));
+            return;
+        }
+        reporter.status(format_args!("{} schema violations found", self.violations));
+        for ((path, rule), count) in self.by_rule.iter() {
+            reporter.status(format_args!("  {} ({}): {}", path, rule, count));
+        }
+    }
+}
+
+/// One open object or array.
+enum Frame {
+    Object {
+        schema: &'static Object,
+        seen: Vec<&'static str>,
+        // Field of the value being read, None for keys the schema doesn't have
+        current: Option<&'static Field>,
+    },
+    Array {
+        item: &'static Kind,
+        index: Option<u64>,
+    },
+    /// Contents are not checked
+    Skip,
+}
+
+/// Builds the path of the value being read from the open frames.
+/// With indexes false array indexes are left out, so violations can be grouped.
+fn path(stack: &[Frame], indexes: bool) -> String {
+    let mut path = String::new();
+    for frame in stack.iter() {
+        match frame {
+            Frame::Object { current: Some(f), .. } => {
+                if !path.is_empty() {
+                    path.push('.');
+                }
+                path.push_str(f.name);
+            }
+            Frame::Array { index: Some(i), .. } => {
+                if indexes {
+                    path.push_str(&format!("[{}]", i));
+                }
+                else {
+                    path.push_str("[]");
+                }
+            }
+            _ => {}
+        }
+    }
+    if path.is_empty() {
+        path.push_str("top level");
+    }
+    path
+}
+
+/// Returns true if s is a date in YYYY-MM-DD format.
+fn is_date(s: &str) -> bool {
+    parse_date(s).is_some()
+}
+
+/// Describes an event for violation messages.
+fn describe(event: &JsonEvent) -> String {
+    match event {
+        JsonEvent::String(s) => format!("string \"{}\"", s),
+        JsonEvent::Number(n) => format!("number {}", n),
+        JsonEvent::Boolean(b) => format!("boolean {}", b),
+        JsonEvent::Null => String::from("null"),
+        JsonEvent::StartArray => String::from("an array"),
+        JsonEvent::StartObject => String::from("an object"),
+        _ => String::from("something else"),
+    }
+}
+
+/// Describes a kind for violation messages.
+fn expected(kind: &Kind) -> String {
+    match kind {
+        Kind::String => String::from("a string"),
+        Kind::Date => String::from("a YYYY-MM-DD date"),
+        Kind::Number => String::from("a number"),
+        Kind::Integer => String::from("an integer"),
+        Kind::Enum(values) => format!("one of \"{}\"", values.join("\", \"")),
+        Kind::Version => format!("a supported schema version ({})", schema::SUPPORTED),
+        Kind::Array(_) => String::from("an array"),
+        Kind::Object(_) => String::from("an object"),
+        Kind::Any => String::from("anything"),
+    }
+}
+
+/// Checks the JSON read from reader against the schema.
+/// Writes up to max_shown violations to out, one per line, and returns the counts of all of them.
+pub fn validate<R: Read>(reader: R,
+                         max_shown: u64,
+                         out: &mut impl Write,
+                        ) -> Result<Report, Box<dyn std::error::Error>> {
+
+    let mut ctx = ParseContext::new();
+    let mut parser = ReaderJsonParser::new(ctx.track_offsets(reader));
+    let mut report = Report::default();
+    let mut stack: Vec<Frame> = Vec::new();
+
+    let mut violation = |stack: &[Frame], offset: u64, rule: &'static str, message: String, out: &mut dyn Write|
+                         -> Result<(), std::io::Error> {
+        report.violations += 1;
+        *report.by_rule.entry((path(stack, false), rule)).or_insert(0) += 1;
+        if report.violations <= max_shown {
+            let v = Violation { path: path(stack, true), offset, rule, message };
+            writeln!(out, "{}", v)?;
+        }
+        Ok(())
+    };
+
+    loop {
+        let event = {next_event(&mut parser, &mut ctx, "validate")?};
+
+        // Ends of objects and arrays, and keys
+        match &event {
+            JsonEvent::EndObject => {
+                if let Some(Frame::Object { schema, seen, .. }) = stack.pop() {
+                    for f in schema.fields.iter().filter(|f| f.required && !seen.contains(&f.name)) {
+                        violation(&stack, ctx.offset(), "missing key",
+                                  format!("missing required key \"{}\"", f.name), out)?;
+                    }
+                    if !schema.one_of.is_empty() && !schema.one_of.iter().any(|k| seen.contains(k)) {
+                        violation(&stack, ctx.offset(), "missing key",
+                                  format!("needs one of \"{}\"", schema.one_of.join("\", \"")), out)?;
+                    }
+                }
+                if stack.is_empty() {
+                    break;
+                }
+                continue;
+            }
+            JsonEvent::EndArray => {
+                stack.pop();
+                if stack.is_empty() {
+                    break;
+                }
+                continue;
+            }
+            JsonEvent::ObjectKey(key) => {
+                if let Some(Frame::Object { schema, seen, current }) = stack.last_mut() {
+                    *current = schema.fields.iter().find(|f| f.name == key.as_ref());
+                    if let Some(f) = current {
+                        seen.push(f.name);
+                    }
+                }
+                continue;
+            }
+            JsonEvent::Eof => {
+                return Err(Box::new(unexpected_eof(&ctx, "validate")));
+            }
+            _ => {}
+        }
+
+        // Start of a value, work out what it should be
+        let kind: Option<&'static Kind> = match stack.last_mut() {
+            None => Some(&Kind::Object(&TOP_LEVEL)),
+            Some(Frame::Object { current, .. }) => current.map(|f| &f.kind),
+            Some(Frame::Array { item, index }) => {
+                *index = Some(index.map_or(0, |i| i + 1));
+                Some(*item)
+            }
+            Some(Frame::Skip) => None,
+        };
+
+        let is_container = matches!(event, JsonEvent::StartObject | JsonEvent::StartArray);
+        let frame = match (kind, &event) {
+            (Some(Kind::Object(schema)), JsonEvent::StartObject) => {
+                Frame::Object { schema, seen: Vec::new(), current: None }
+            }
+            (Some(Kind::Array(item)), JsonEvent::StartArray) => {
+                Frame::Array { item, index: None }
+            }
+            (None, _) | (Some(Kind::Any), _) => Frame::Skip,
+            (Some(Kind::String), JsonEvent::String(_)) |
+            (Some(Kind::Number), JsonEvent::Number(_)) => Frame::Skip,
+            (Some(Kind::Integer), JsonEvent::Number(n)) => {
+                if !n.bytes().all(|b| b.is_ascii_digit() || b == b'-') {
+                    violation(&stack, ctx.offset(), "wrong type", format!("expected an integer, found number {}", n), out)?;
+                }
+                Frame::Skip
+            }
+            (Some(Kind::Date), JsonEvent::String(s)) => {
+                if !is_date(s) {
+                    violation(&stack, ctx.offset(), "invalid date", format!("expected a YYYY-MM-DD date, found \"{}\"", s), out)?;
+                }
+                Frame::Skip
+            }
+            (Some(Kind::Enum(values)), JsonEvent::String(s)) => {
+                if !values.contains(&s.as_ref()) {
+                    violation(&stack, ctx.offset(), "invalid value",
+                              format!("expected {}, found \"{}\"", expected(kind.unwrap()), s), out)?;
+                }
+                Frame::Skip
+            }
+            (Some(Kind::Version), JsonEvent::String(s)) => {
+                if SchemaVersion::parse(s).is_none() {
+                    violation(&stack, ctx.offset(), "unsupported version",
+                              format!("expected {}, found \"{}\"", expected(kind.unwrap()), s), out)?;
+                }
+                Frame::Skip
+            }
+            (Some(kind), _) => {
+                violation(&stack, ctx.offset(), "wrong type",
+                          format!("expected {}, found {}", expected(kind), describe(&event)), out)?;
+                Frame::Skip
+            }
+        };
+
+        if is_container {
+            stack.push(frame);
+        }
+        else if stack.is_empty() {
+            break;
+        }
+    }
+
+    out.flush()?;
+    Ok(report)
+}
+
+/// Validates the file from source, see validate. What happens while reading source goes to
+/// reporter.
+pub fn validate_source(source: &dyn DataSource,
+                       buff_size: usize,
+                       max_shown: u64,
+                       out: &mut impl Write,
+                       reporter: &SharedReporter,
+                      ) -> Result<Report, Box<dyn std::error::Error>> {
+    validate(BufReader::with_capacity(buff_size, source.open(reporter)?), max_shown, out)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_is_date() {
+        assert!(is_date("2025-04-05"));
+        assert!(is_date("9999-12-31"));
+        assert!(!is_date("2025-4-5"));
+        assert!(!is_date("2025-13-01"));
+        assert!(!is_date("04/05/2025"));
+    }
+
+    #[test]
+    fn test_validate() {
+        let json = r#"{
+            "reporting_entity_name": "Payer",
+            "reporting_entity_type": "Third Party Vendor",
+            "last_updated_on": "2025-04-05",
+            "version": "1.3.1",
+            "provider_references": [
+                {"provider_group_id": 1, "provider_groups": [{"npi": [1234567890], "tin": {"type": "ein", "value": "1"}}]},
+                {"provider_group_id": "2", "provider_groups": [{"npi": [1.5], "tin": {"type": "ein", "value": "1"}}]}
+            ],
+            "in_network": [
+                {"negotiation_arrangement": "ffs", "name": "Visit", "billing_code_type": "CPT",
+                 "billing_code_type_version": "2022", "billing_code": "99213", "description": "Visit",
+                 "negotiated_rates": [
+                     {"provider_references": [1],
+                      "negotiated_prices": [
+                          {"negotiated_type": "negotiated", "negotiated_rate": 100.5,
+                           "expiration_date": "9999-12-31", "billing_class": "professional",
+                           "service_code": ["11"], "unknown": {"a": [1]}},
+                          {"negotiated_type": "neg type 1", "negotiated_rate": "100",
+                           "expiration_date": "12/31/9999", "billing_class": "professional"}
+                      ]},
+                     {"negotiated_prices": []}
+                 ]}
+            ]
+        }"#;
+
+        let mut out = Vec::new();
+        let report = validate(Cursor::new(json), 100, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert_eq!(report.violations, 6, "{}", out);
+        assert!(lines[0].starts_with("provider_references[1].provider_group_id: expected an integer, found string \"2\""));
+        assert!(lines[1].starts_with("provider_references[1].provider_groups[0].npi[0]: expected an integer, found number 1.5"));
+        assert!(lines[2].starts_with("in_network[0].negotiated_rates[0].negotiated_prices[1].negotiated_type: expected one of"));
+        assert!(lines[3].starts_with("in_network[0].negotiated_rates[0].negotiated_prices[1].negotiated_rate: expected a number, found string \"100\""));
+        assert!(lines[4].starts_with("in_network[0].negotiated_rates[0].negotiated_prices[1].expiration_date: expected a YYYY-MM-DD date"));
+        assert!(lines[5].starts_with("in_network[0].negotiated_rates[1]: needs one of \"provider_references\", \"provider_groups\""));
+
+        assert_eq!(report.by_rule.get(&(String::from("in_network[].negotiated_rates[].negotiated_prices[].negotiated_type"), "invalid value")), Some(&1));
+
+        // Only max_shown are written but all are counted
+        let mut out = Vec::new();
+        let report = validate(Cursor::new(json), 2, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap().lines().count(), 2);
+        assert_eq!(report.violations, 6);
+    }
+
+    #[test]
+    fn test_validate_missing_keys() {
+        let json = r#"{"reporting_entity_name": "Payer", "version": "1", "in_network": []}"#;
+
+        let mut out = Vec::new();
+        let report = validate(Cursor::new(json), 100, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert_eq!(report.violations, 2);
+        assert_eq!(out.lines().next().unwrap().split(" (near").next().unwrap(),
+                   "top level: missing required key \"reporting_entity_type\"");
+        assert!(out.contains("missing required key \"last_updated_on\""));
+
+        let json = r#"{"reporting_entity_name": "Payer", "reporting_entity_type": "Payer",
+                       "last_updated_on": "2025-04-05", "version": "3.0", "in_network": []}"#;
+        let mut out = Vec::new();
+        let report = validate(Cursor::new(json), 100, &mut out).unwrap();
+        assert_eq!(report.by_rule.get(&(String::from("version"), "unsupported version")), Some(&1));
+    }
+}
@@ -0,0 +1,150 @@
+//! # wasm
+//!
+//! The exports of the wasm32 build, to run a query on a modest MRF sample in a browser, e.g. a
+//! file an analyst drags onto a page.
+//! `cargo build --lib --release --no-default-features --target wasm32-unknown-unknown` writes
+//! `mrfy.wasm`, which JS calls directly:
+//!
+//! ```js
+//! const { memory, mrfy_alloc, mrfy_free, mrfy_query_bytes, mrfy_free_string } = instance.exports;
+//! const put = bytes => {
+//!     const ptr = mrfy_alloc(bytes.length);
+//!     new Uint8Array(memory.buffer, ptr, bytes.length).set(bytes);
+//!     return ptr;
+//! };
+//! const data = new Uint8Array(await file.arrayBuffer());
+//! const query = new TextEncoder().encode(JSON.stringify({ codes: { CPT: ["99213"] }, providers: { npi: ["*"] } }));
+//! const [dataPtr, queryPtr] = [put(data), put(query)];
+//! const out = mrfy_query_bytes(dataPtr, data.length, queryPtr, query.length);
+//! const bytes = new Uint8Array(memory.buffer, out);
+//! const result = JSON.parse(new TextDecoder().decode(bytes.subarray(0, bytes.indexOf(0))));
+//! mrfy_free_string(out);
+//! mrfy_free(dataPtr, data.length);
+//! mrfy_free(queryPtr, query.length);
+//! ```
+//!
+//! The file may be gzip compressed. A browser has no file system, threads or clock for mrfy to
+//! use, so the file is read from memory in one pass on the calling thread, the timings of the run
+//! are zero, and the options writing to disk or checking the age of the file are left off.
+//!
+//! Only the exports are wasm32 specific, run is built for every target.
+
+#[cfg(target_arch = "wasm32")]
+use std::ffi::{c_char, CString};
+use std::rc::Rc;
+
+use crate::asa::{self, Options, Progress, SharedReporter};
+use crate::query;
+use crate::sink::MemorySink;
+use crate::source::DataSource;
+
+/// Size of the buffer the decompressed sample is read through.
+const BUFF_SIZE: usize = 1024 * 1024;
+
+/// An MRF sample in memory.
+struct Sample(Rc<[u8]>);
+
+impl std::fmt::Display for Sample {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "sample of {} bytes", self.0.len())
+    }
+}
+
+impl DataSource for Sample {
+    fn open_raw(&self, _reporter: &SharedReporter) -> Result<Box<dyn std::io::Read>, Box<dyn std::error::Error>> {
+        Ok(Box::new(std::io::Cursor::new(Rc::clone(&self.0))))
+    }
+
+    fn stored_size(&self) -> Option<u64> {
+        Some(self.0.len() as u64)
+    }
+}
+
+/// Returns a buffer of len bytes for the caller to fill, freed with mrfy_free.
+#[cfg(target_arch = "wasm32")]
+#[unsafe(no_mangle)]
+pub extern "C" fn mrfy_alloc(len: usize) -> *mut u8 {
+    Box::into_raw(vec![0u8; len].into_boxed_slice()).cast()
+}
+
+/// Frees the buffer of len bytes at ptr.
+///
+/// # Safety
+/// ptr must be returned by mrfy_alloc(len) and not freed yet.
+#[cfg(target_arch = "wasm32")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mrfy_free(ptr: *mut u8, len: usize) {
+    if !ptr.is_null() {
+        drop(unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, len)) });
+    }
+}
+
+/// Runs query, the JSON text of a query file, over the MRF in data, returning the matching rows
+/// as JSON objects of the columns of the CSV output.
+pub fn run(data: &[u8], query: &str) -> Result<Vec<serde_json::Value>, Box<dyn std::error::Error>> {
+    let mut q = query::parse_json(query)?;
+    let mut sink = MemorySink::new();
+    asa::run_with_progress(&mut q, &Sample(Rc::from(data)), BUFF_SIZE, &Options::new(), &mut sink, |_: &Progress| {})?;
+    let header = sink.header;
+    let records = sink.records.into_iter().map(|record| {
+        let row = header.iter().cloned().zip(record.into_iter().map(serde_json::Value::from)).collect();
+        serde_json::Value::Object(row)
+    });
+    Ok(records.collect())
+}
+
+/// Runs the query in the query_len bytes at query, the JSON text of a query file, over the MRF
+/// in the data_len bytes at data. Returns `{"records": [...]}`, one object per matching row, or
+/// `{"error": "..."}`, as a NUL-terminated string freed with mrfy_free_string.
+///
+/// # Safety
+/// data must point to data_len bytes and query to query_len bytes.
+#[cfg(target_arch = "wasm32")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mrfy_query_bytes(data: *const u8,
+                                          data_len: usize,
+                                          query: *const u8,
+                                          query_len: usize) -> *mut c_char {
+    let data = unsafe { std::slice::from_raw_parts(data, data_len) };
+    let query = unsafe { std::slice::from_raw_parts(query, query_len) };
+    let result = match std::str::from_utf8(query) {
+        Ok(query) => run(data, query),
+        Err(_) => Err("the query is not UTF-8".into()),
+    };
+    let json = match result {
+        Ok(records) => serde_json::json!({ "records": records }),
+        Err(e) => serde_json::json!({ "error": e.to_string() }),
+    };
+    // JSON escapes NULs, so there are none in the text
+    CString::new(json.to_string()).expect("JSON has no NULs").into_raw()
+}
+
+/// Frees a string returned by mrfy_query_bytes.
+///
+/// # Safety
+/// s must be returned by mrfy_query_bytes and not freed yet.
+#[cfg(target_arch = "wasm32")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mrfy_free_string(s: *mut c_char) {
+    if !s.is_null() {
+        drop(unsafe { CString::from_raw(s) });
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run() {
+        let data = std::fs::read("tests/testfiles/data_files/basic_test.json.gz").unwrap();
+        let records = run(&data, r#"{"providers": {"npi": [1701]}, "codes": {"*": ["Code 1"]}}"#).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["npi"], "1701");
+        assert_eq!(records[0]["billing_code"], "CODE 1");
+        assert_eq!(records[0]["negotiated_rate"], "9.99");
+
+        assert!(run(&data, r#"{"nope": 1}"#).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+//! # xlsx
+//!
+//! Writes the rows of a query to an Excel workbook (`--xlsx`), built with the `xlsx` feature, for
+//! people who open the result in a spreadsheet rather than read CSV.
+//!
+//! The rows are held in memory and the workbook is written when the run ends (or is cancelled),
+//! so this is meant for small result sets. A sheet holds at most --xlsx-sheet-rows rows below its
+//! header, the rows after that go on to the next sheet (`rates`, `rates 2`, ...). negotiated_rate
+//! is written as a number and expiration_date as a date where they are one, and every other
+//! column as text, so npis, tins and codes keep their leading zeros and aren't shown as
+//! 1.23457E+09.
+
+use std::path::PathBuf;
+
+use crate::asa::SharedReporter;
+use crate::sink::{MatchedRecord, RecordSink};
+
+/// Most rows of a sheet, below its header: Excel has 1048576 rows.
+pub const MAX_SHEET_ROWS: u32 = 1_048_575;
+
+/// How the values of a column are written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CellType {
+    Text,
+    Number,
+    Date,
+}
+
+impl CellType {
+    /// Returns the type of the column named column (before --header-map).
+    pub fn of(column: &str) -> Self {
+        match column {
+            "negotiated_rate" => CellType::Number,
+            "expiration_date" => CellType::Date,
+            _ => CellType::Text,
+        }
+    }
+}
+
+/// Collects the header and records and writes them as a workbook to a file when finished.
+pub struct XlsxSink {
+    path: PathBuf,
+    types: Vec<CellType>,
+    sheet_rows: u32,
+    header: Option<Vec<String>>,
+    rows: Vec<Vec<String>>,
+    reporter: SharedReporter,
+}
+
+impl XlsxSink {
+    /// Creates an XlsxSink writing to path, the columns of the rows having types, with at most
+    /// sheet_rows rows on a sheet. What was written goes to reporter.
+    pub fn new(path: PathBuf, types: Vec<CellType>, sheet_rows: u32, reporter: SharedReporter) -> Self {
+        Self { path, types, sheet_rows: sheet_rows.clamp(1, MAX_SHEET_ROWS), header: None, rows: Vec::new(), reporter }
+    }
+}
+
+impl RecordSink for XlsxSink {
+    fn write_header(&mut self, columns: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+        self.header = Some(columns.iter().map(|c| c.to_string()).collect());
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &MatchedRecord) -> Result<(), Box<dyn std::error::Error>> {
+        self.rows.push(record.values.iter().map(|v| v.to_string()).collect());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let sheets = write_workbook(&self.path, self.header.as_deref(), &self.types, &self.rows, self.sheet_rows)?;
+        self.reporter.status(format_args!("Wrote {} rows on {} sheets to {}", self.rows.len(), sheets, self.path.display()));
+        Ok(())
+    }
+}
+
+/// Returns the name of the sheet at index (from 0).
+#[cfg(feature = "xlsx")]
+fn sheet_name(index: usize) -> String {
+    match index {
+        0 => String::from("rates"),
+        _ => format!("rates {}", index + 1),
+    }
+}
+
+/// Writes header and rows to a workbook at path, sheet_rows rows to a sheet, and returns the
+/// number of sheets. A workbook without rows has one sheet with the header.
+#[cfg(feature = "xlsx")]
+fn write_workbook(path: &std::path::Path,
+                  header: Option<&[String]>,
+                  types: &[CellType],
+                  rows: &[Vec<String>],
+                  sheet_rows: u32) -> Result<usize, Box<dyn std::error::Error>> {
+    use rust_xlsxwriter::{ExcelDateTime, Format, Workbook};
+
+    let bold = Format::new().set_bold();
+    let date = Format::new().set_num_format("yyyy-mm-dd");
+    let mut workbook = Workbook::new();
+
+    let chunks: Vec<&[Vec<String>]> = match rows.is_empty() {
+        true => vec![&[]],
+        false => rows.chunks(sheet_rows as usize).collect(),
+    };
+    for (index, chunk) in chunks.iter().enumerate() {
+        let sheet = workbook.add_worksheet();
+        sheet.set_name(sheet_name(index))?;
+
+        let first = match header {
+            Some(header) => {
+                for (col, name) in header.iter().enumerate() {
+                    sheet.write_string_with_format(0, col as u16, name, &bold)?;
+                }
+                sheet.set_freeze_panes(1, 0)?;
+                1
+            }
+            None => 0,
+        };
+        for (i, row) in chunk.iter().enumerate() {
+            let r = first + i as u32;
+            for (col, value) in row.iter().enumerate() {
+                let col16 = col as u16;
+                // Values that aren't of the type of their column, e.g. a rate of null, stay text
+                let written = match types.get(col).copied().unwrap_or(CellType::Text) {
+                    CellType::Number => match value.parse::<f64>() {
+                        Ok(number) if number.is_finite() => sheet.write_number(r, col16, number).map(|_| true)?,
+                        _ => false,
+                    },
+                    CellType::Date => match ExcelDateTime::parse_from_str(value) {
+                        Ok(day) => sheet.write_datetime_with_format(r, col16, &day, &date).map(|_| true)?,
+                        Err(_) => false,
+                    },
+                    CellType::Text => false,
+                };
+                if !written {
+                    sheet.write_string(r, col16, value)?;
+                }
+            }
+        }
+        if let Some(header) = header && !header.is_empty() {
+            sheet.autofilter(0, 0, chunk.len() as u32, header.len() as u16 - 1)?;
+        }
+        sheet.autofit();
+    }
+    workbook.save(path)?;
+    Ok(chunks.len())
+}
+
+#[cfg(not(feature = "xlsx"))]
+fn write_workbook(_path: &std::path::Path,
+                  _header: Option<&[String]>,
+                  _types: &[CellType],
+                  _rows: &[Vec<String>],
+                  _sheet_rows: u32) -> Result<usize, Box<dyn std::error::Error>> {
+    Err("rebuild mrfy with the `xlsx` feature to use --xlsx".into())
+}
+
+
+#[cfg(all(test, feature = "xlsx"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xlsx_sink() {
+        let dir = std::env::temp_dir().join(format!("mrfy_xlsx_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rates.xlsx");
+
+        let columns = ["npi", "negotiated_rate", "expiration_date"];
+        let mut sink = XlsxSink::new(path.clone(), columns.iter().map(|c| CellType::of(c)).collect(), 2, SharedReporter::default());
+        sink.write_header(&columns).unwrap();
+        for values in [["0123456789", "75.5", "9999-12-31"], ["2", "null", "none"], ["3", "1", "2025-01-01"]] {
+            sink.write_record(&MatchedRecord { values: &values }).unwrap();
+        }
+        sink.finish().unwrap();
+
+        // An xlsx file is a zip archive
+        assert!(std::fs::read(&path).unwrap().starts_with(b"PK"));
+        assert_eq!(write_workbook(&path, None, &[], &[], 2).unwrap(), 1);
+        assert_eq!(sheet_name(0), "rates");
+        assert_eq!(sheet_name(1), "rates 2");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
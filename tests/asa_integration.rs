@@ -8,27 +8,180 @@ fn intermediate_integration_test() -> Result<(), Box<dyn std::error::Error>> {
     cmd.arg("tests/testfiles/input_testfiles/input_for_intermediate").arg("tests/testfiles/data_files/intermediate.json.gz");
 
 
+    // Exit code 3: one provider_references entry has no provider_group_id
     cmd.assert()
-       .success()
+       .code(3)
        .stdout(String::from("\
 npi,tin_type,tin_value,group_id,negotiation_arrangement,name,billing_code_type,billing_code_type_version,billing_code,\
 description,negotiated_type,negotiated_rate,expiration_date,service_code,billing_class,billing_code_modifier\n\
-1,ein,123,2,alpha,Item 1,Type 1,2022,CODE 1,Item 1,neg type 1,100.99,9999-12-31,A B C ,class 1,null\n\
-1,ein,246,2,alpha,Item 1,Type 1,2022,CODE 1,Item 1,neg type 1,100.99,9999-12-31,A B C ,class 1,null\n\
-4,ein,777,5,alpha,Item 3,Type 3,2022,CODE 3,Item 3,neg type 3,3000.17,9999-12-31,A ,class 3,null\n\
-1,ein,123,2,beta,Item 4,Type 4,null,CODE 4,Item 4,neg type 4,4120.99,9999-12-31,A Z ,class 4,null\n\
-1,ein,246,2,beta,Item 4,Type 4,null,CODE 4,Item 4,neg type 4,4120.99,9999-12-31,A Z ,class 4,null\n\
+1,ein,123,2,alpha,Item 1,Type 1,2022,CODE 1,Item 1,neg type 1,100.99,9999-12-31,A B C,class 1,null\n\
+1,ein,246,2,alpha,Item 1,Type 1,2022,CODE 1,Item 1,neg type 1,100.99,9999-12-31,A B C,class 1,null\n\
+4,ein,777,5,alpha,Item 3,Type 3,2022,CODE 3,Item 3,neg type 3,3000.17,9999-12-31,A,class 3,null\n\
+1,ein,123,2,beta,Item 4,Type 4,null,CODE 4,Item 4,neg type 4,4120.99,9999-12-31,A Z,class 4,null\n\
+1,ein,246,2,beta,Item 4,Type 4,null,CODE 4,Item 4,neg type 4,4120.99,9999-12-31,A Z,class 4,null\n\
 1,ein,123,2,alpha,Item 5,Type 5,2022,CODE 5,Item 5,null,null,null,null,null,null\n\
 1,ein,246,2,alpha,Item 5,Type 5,2022,CODE 5,Item 5,null,null,null,null,null,null\n\
 2,ein,44,3,alpha,Item 5,Type 5,2022,CODE 5,Item 5,null,null,null,null,null,null\n\
 3,ein,55,3,alpha,Item 5,Type 5,2022,CODE 5,Item 5,null,null,null,null,null,null\n\
-1,ein,123,2,alpha,\"Item 7,thing\",Type 7,2022,CODE 7,\"Item 7,thing\",neg type 7,739.99,3030-10-31,A B C ,class 7,null\n\
-1,ein,246,2,alpha,\"Item 7,thing\",Type 7,2022,CODE 7,\"Item 7,thing\",neg type 7,739.99,3030-10-31,A B C ,class 7,null\n\
-2,ein,44,3,alpha,\"Item 7,thing\",Type 7,2022,CODE 7,\"Item 7,thing\",neg type 7,739.99,3030-10-31,A B C ,class 7,null\n\
-3,ein,55,3,alpha,\"Item 7,thing\",Type 7,2022,CODE 7,\"Item 7,thing\",neg type 7,739.99,3030-10-31,A B C ,class 7,null\n\
-4,ein,777,5,gamma,Item 8,Type 8,2017,CODE 8,Item 8,neg type 8,89.17,9999-12-31,A ,class 8,null\n\
-4,ein,777,5,gamma,Item 8,Type 8,2017,CODE 8,Item 8,neg type 8 deluxe,8.45,9999-12-31,Z ,class 8 deluxe,DX\n"));
+1,ein,123,2,alpha,\"Item 7,thing\",Type 7,2022,CODE 7,\"Item 7,thing\",neg type 7,739.99,3030-10-31,A B C,class 7,null\n\
+1,ein,246,2,alpha,\"Item 7,thing\",Type 7,2022,CODE 7,\"Item 7,thing\",neg type 7,739.99,3030-10-31,A B C,class 7,null\n\
+2,ein,44,3,alpha,\"Item 7,thing\",Type 7,2022,CODE 7,\"Item 7,thing\",neg type 7,739.99,3030-10-31,A B C,class 7,null\n\
+3,ein,55,3,alpha,\"Item 7,thing\",Type 7,2022,CODE 7,\"Item 7,thing\",neg type 7,739.99,3030-10-31,A B C,class 7,null\n\
+4,ein,777,5,gamma,Item 8,Type 8,2017,CODE 8,Item 8,neg type 8,89.17,9999-12-31,A,class 8,null\n\
+4,ein,777,5,gamma,Item 8,Type 8,2017,CODE 8,Item 8,neg type 8 deluxe,8.45,9999-12-31,Z,class 8 deluxe,DX\n"));
        
 
     Ok(())
 }
+
+#[test]
+fn query_subcommand_matches_default() -> Result<(), Box<dyn std::error::Error>> {
+    let args = ["tests/testfiles/input_testfiles/input_for_intermediate",
+                "tests/testfiles/data_files/intermediate.json.gz"];
+
+    let default = Command::cargo_bin("mrfy")?.args(args).output()?;
+    let query = Command::cargo_bin("mrfy")?.arg("query").args(args).output()?;
+
+    assert_eq!(query.status.code(), default.status.code());
+    assert_eq!(query.stdout, default.stdout);
+
+    Ok(())
+}
+
+#[test]
+fn inline_query_flags() -> Result<(), Box<dyn std::error::Error>> {
+    let output = Command::cargo_bin("mrfy")?
+        .args(["--npi", "4", "--code", "Type 3:Code 3", "--code", "*:code 8"])
+        .arg("tests/testfiles/data_files/intermediate.json.gz")
+        .output()?;
+
+    assert_eq!(output.status.code(), Some(3));
+    let stdout = String::from_utf8(output.stdout)?;
+    let rows: Vec<&str> = stdout.lines().skip(1).collect();
+    assert_eq!(rows.len(), 3);
+    assert!(rows[0].starts_with("4,ein,777,5,alpha,Item 3,"));
+
+    let bad = Command::cargo_bin("mrfy")?
+        .args(["--code", "99213", "tests/testfiles/data_files/intermediate.json.gz"])
+        .output()?;
+    assert!(!bad.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn query_report_file() -> Result<(), Box<dyn std::error::Error>> {
+    let report = std::env::temp_dir().join(format!("mrfy-report-{}.json", std::process::id()));
+    let output = Command::cargo_bin("mrfy")?
+        .args(["--npi", "4", "--npi", "99", "--code", "Type 3:Code 3", "--code", "Type 2:Code 2"])
+        .arg("--report").arg(&report)
+        .arg("tests/testfiles/data_files/intermediate.json.gz")
+        .output()?;
+    assert_eq!(output.status.code(), Some(3));
+
+    let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&report)?)?;
+    std::fs::remove_file(&report)?;
+
+    let codes = json["codes"].as_array().unwrap();
+    assert_eq!(codes.len(), 2);
+    assert_eq!(codes[0]["code"], "Code 3");
+    assert_eq!(codes[0]["matched"], true);
+    assert_eq!(codes[0]["records"], 1);
+    assert_eq!(codes[1]["matched"], false);
+
+    let npis = json["npis"].as_array().unwrap();
+    assert_eq!(npis[0]["npi"], 4);
+    assert_eq!(npis[0]["records"], 1);
+    assert_eq!(npis[0]["groups"][0]["group_id"], "5");
+    assert_eq!(npis[1]["npi"], 99);
+    assert_eq!(npis[1]["matched"], false);
+
+    Ok(())
+}
+
+#[test]
+fn exit_codes() -> Result<(), Box<dyn std::error::Error>> {
+    let data = "tests/testfiles/data_files/basic_test.json.gz";
+
+    let matches = Command::cargo_bin("mrfy")?.args(["--npi", "1701", "--code", "*:Code 1", data]).output()?;
+    assert_eq!(matches.status.code(), Some(0));
+
+    let no_matches = Command::cargo_bin("mrfy")?.args(["--npi", "1701", "--code", "*:Code 2", data]).output()?;
+    assert_eq!(no_matches.status.code(), Some(2));
+
+    let missing = Command::cargo_bin("mrfy")?
+        .args(["--npi", "1701", "--code", "*:Code 1", "tests/testfiles/data_files/missing.json.gz"])
+        .output()?;
+    assert_eq!(missing.status.code(), Some(1));
+
+    let stale = Command::cargo_bin("mrfy")?
+        .args(["--npi", "1701", "--code", "*:Code 1", "--max-age-days", "1", data])
+        .output()?;
+    assert_eq!(stale.status.code(), Some(4));
+    assert!(stale.stdout.is_empty());
+
+    let warned = Command::cargo_bin("mrfy")?
+        .args(["--npi", "1701", "--code", "*:Code 1", "--max-age-days", "1", "--warn-stale", data])
+        .output()?;
+    assert_eq!(warned.status.code(), Some(0));
+
+    Ok(())
+}
+
+#[test]
+fn min_per_code() -> Result<(), Box<dyn std::error::Error>> {
+    let output = Command::cargo_bin("mrfy")?
+        .args(["--min-per-code", "--npi", "4", "--code", "*:code 8"])
+        .arg("tests/testfiles/data_files/intermediate.json.gz")
+        .output()?;
+
+    // Of the two prices of code 8 only the lower one is written
+    let stdout = String::from_utf8(output.stdout)?;
+    let rows: Vec<&str> = stdout.lines().skip(1).collect();
+    assert_eq!(rows.len(), 1);
+    assert!(rows[0].contains(",8.45,"));
+
+    Ok(())
+}
+
+#[test]
+fn rate_summary_file() -> Result<(), Box<dyn std::error::Error>> {
+    let summary = std::env::temp_dir().join(format!("mrfy-rate-summary-{}.csv", std::process::id()));
+    Command::cargo_bin("mrfy")?
+        .args(["--npi", "4", "--code", "Type 3:Code 3", "--code", "*:code 8", "--percentiles", "50,90"])
+        .arg("--rate-summary").arg(&summary)
+        .arg("tests/testfiles/data_files/intermediate.json.gz")
+        .output()?;
+
+    let table = std::fs::read_to_string(&summary)?;
+    std::fs::remove_file(&summary)?;
+    let lines: Vec<&str> = table.lines().collect();
+    assert_eq!(lines, vec!["billing_code_type,billing_code,count,min,median,p90,mean,max",
+                           "Type 3,CODE 3,1,3000.17,3000.17,3000.17,3000.17,3000.17",
+                           "Type 8,CODE 8,2,8.45,48.81,89.17,48.81,89.17"]);
+
+    Ok(())
+}
+
+#[test]
+fn index_subcommand() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir().join(format!("mrfy-index-{}", std::process::id()));
+    let output = Command::cargo_bin("mrfy")?
+        .arg("index").arg("--index-dir").arg(&dir)
+        .arg("tests/testfiles/data_files/intermediate.json.gz")
+        .output()?;
+
+    // One index per datafile, named after it
+    let index = dir.join("intermediate.json.gz.index");
+    let built = index.join("index.json").exists() && index.join("elements.gz").exists();
+    std::fs::remove_dir_all(&dir)?;
+    assert_eq!(output.status.code(), Some(0));
+    assert!(built);
+    assert!(String::from_utf8(output.stderr)?.contains("intermediate.json.gz: "));
+
+    // Without --index-dir stdin has nowhere to keep its index
+    let stdin = Command::cargo_bin("mrfy")?.args(["index", "-"]).output()?;
+    assert_ne!(stdin.status.code(), Some(0));
+
+    Ok(())
+}
@@ -0,0 +1,118 @@
+//! The API of the mrfy library, used the way code that embeds the parser uses it.
+
+use std::sync::{Arc, Mutex};
+
+use mrfy::asa::{self, CancelToken, Options, Progress};
+use mrfy::error::Interrupted;
+use mrfy::query::{Code, Provider, Query};
+use mrfy::sink::{MatchedRecord, MemorySink, RecordSink};
+use mrfy::source::Location;
+
+const DATA: &str = "tests/testfiles/data_files/intermediate.json.gz";
+
+/// Query for the one CODE 3 row of npi 4 in DATA.
+fn code_3_query() -> Query {
+    let mut q = Query::new();
+    q.providers.push(Provider::new(4));
+    q.codes.push(Code::new(&String::from("Type 3"), &String::from("Code 3")));
+    q
+}
+
+#[test]
+fn run_returns_summary() -> Result<(), Box<dyn std::error::Error>> {
+    let mut sink = MemorySink::new();
+    let summary = asa::run(&mut code_3_query(), &Location::Path(DATA.into()), 256, &Options::new(), &mut sink)?;
+
+    assert_eq!(summary.stats.rows_written, 1);
+    assert_eq!(sink.records.len(), 1);
+    assert!(!summary.no_providers);
+    assert_eq!(summary.query.codes.len(), 1);
+    assert!(summary.query.codes[0].matched);
+    assert_eq!(summary.query.codes[0].records, 1);
+    assert_eq!(summary.query.npis.len(), 1);
+    assert_eq!(summary.query.npis[0].npi, 4);
+    assert!(summary.query.npis[0].matched);
+    // The file has keys mrfy doesn't read
+    assert!(summary.unsupported_keys.iter().any(|k| k.key == "CASE" && k.count > 0));
+
+    Ok(())
+}
+
+#[test]
+fn run_with_progress_reports_to_callback() -> Result<(), Box<dyn std::error::Error>> {
+    let reports = Arc::new(Mutex::new(Vec::<Progress>::new()));
+    let seen = reports.clone();
+    let summary = asa::run_with_progress(&mut code_3_query(), &Location::Path(DATA.into()), 256, &Options::new(),
+                                         &mut MemorySink::new(), move |p| seen.lock().unwrap().push(p.clone()))?;
+
+    let reports = reports.lock().unwrap();
+    assert!(!reports.is_empty());
+    assert!(reports.iter().all(|p| p.bytes_read > 0));
+    // Only the last report is finished, with the totals of the run
+    let last = reports.last().unwrap();
+    assert!(last.finished);
+    assert!(reports[..reports.len() - 1].iter().all(|p| !p.finished));
+    assert_eq!(last.objects_scanned, summary.stats.objects_scanned);
+    assert_eq!(last.rows_written, summary.stats.rows_written);
+
+    Ok(())
+}
+
+#[test]
+fn cancel_token_stops_run() -> Result<(), Box<dyn std::error::Error>> {
+    // Cancelled from another thread through a clone
+    let options = Options::new();
+    let cancel = options.cancel.clone();
+    assert_ne!(cancel, CancelToken::new());
+    std::thread::spawn(move || cancel.cancel()).join().unwrap();
+    assert!(options.cancel.is_cancelled());
+
+    let mut sink = MemorySink::new();
+    let err = asa::run(&mut code_3_query(), &Location::Path(DATA.into()), 256, &options, &mut sink).unwrap_err();
+    assert!(err.downcast_ref::<Interrupted>().is_some());
+    assert!(sink.records.is_empty());
+
+    // Other runs are not affected
+    asa::run(&mut code_3_query(), &Location::Path(DATA.into()), 256, &Options::new(), &mut MemorySink::new())?;
+
+    Ok(())
+}
+
+/// A sink of the host application, keeping the rate of each row.
+#[derive(Default)]
+struct Rates {
+    columns: usize,
+    rates: Vec<String>,
+    finished: bool,
+}
+
+impl RecordSink for Rates {
+    fn write_header(&mut self, columns: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+        self.columns = columns.len();
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &MatchedRecord) -> Result<(), Box<dyn std::error::Error>> {
+        self.rates.push(record.values[11].to_string());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.finished = true;
+        Ok(())
+    }
+}
+
+#[test]
+fn run_writes_to_record_sink() -> Result<(), Box<dyn std::error::Error>> {
+    assert_eq!(asa::COLUMNS[11], "negotiated_rate");
+
+    let mut sink = Rates::default();
+    asa::run(&mut code_3_query(), &Location::Path(DATA.into()), 256, &Options::new(), &mut sink)?;
+
+    assert_eq!(sink.columns, asa::COLUMNS.len());
+    assert_eq!(sink.rates, vec!["3000.17"]);
+    assert!(sink.finished);
+
+    Ok(())
+}